@@ -0,0 +1,245 @@
+//! Minimizes the mutated bytes a crash needs
+//!
+//! Takes the mutation log of a run that crashed, written by the runtime's
+//! `mutation_log` option, and re-runs the target with subsets of it as its
+//! `mutation_plan` until no byte can be left out without losing the crash.
+//! The target must see the same detections in the same order on every run,
+//! so its input must be fixed. A run counts as crashing if it is killed by
+//! the same signal as with the whole log.
+//!
+//! Usage: `df-minimize <mutation log> [-o <plan>] -- <target> [args...]`
+//!
+//! The minimal plan is written to `<mutation log>.min` unless `-o` says
+//! otherwise, and can be handed to the target as its `mutation_plan` to
+//! reproduce the crash.
+
+use std::env;
+use std::io;
+use std::process::{self, Command, ExitStatus, Stdio};
+
+use asan_double_fetch::minimize::{self, Entry};
+
+const OPTIONS_VAR: &str = "ASAN_DOUBLE_FETCH_OPTIONS";
+
+struct Options {
+    log: String,
+    output: String,
+    target: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: df-minimize <mutation log> [-o <plan>] -- <target> [args...]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let log = args.next().unwrap_or_else(|| usage());
+    let mut output = None;
+
+    loop {
+        match args.next().as_deref() {
+            Some("-o") => output = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--") => break,
+            _ => usage(),
+        }
+    }
+    let target: Vec<String> = args.collect();
+    if target.is_empty() {
+        usage();
+    }
+
+    Options {
+        output: output.unwrap_or_else(|| format!("{}.min", log)),
+        log,
+        target,
+    }
+}
+
+/// The signal that killed the target, if one did
+#[cfg(unix)]
+fn crash_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn crash_signal(status: ExitStatus) -> Option<i32> {
+    status.code().filter(|_| !status.success())
+}
+
+/// Runs the target with its mutations planned by `plan`
+struct Target {
+    argv: Vec<String>,
+    /// Options of the target's environment, without any mutation log or plan
+    options: String,
+    plan: String,
+    runs: usize,
+}
+
+impl Target {
+    fn new(argv: Vec<String>) -> Self {
+        let options = env::var(OPTIONS_VAR).unwrap_or_default();
+        let options: Vec<&str> = options
+            .split(',')
+            .filter(|option| {
+                let key = option.split('=').next().unwrap_or_default().trim();
+                !key.is_empty() && key != "mutation_log" && key != "mutation_plan"
+            })
+            .collect();
+
+        Self {
+            argv,
+            options: options.join(","),
+            plan: env::temp_dir()
+                .join(format!("df-minimize-{}.plan", process::id()))
+                .to_string_lossy()
+                .into_owned(),
+            runs: 0,
+        }
+    }
+
+    fn run(&mut self, plan: &[Entry]) -> io::Result<Option<i32>> {
+        minimize::write(&self.plan, plan)?;
+        self.runs += 1;
+
+        let mut options = format!("mutation_plan={}", self.plan);
+        if !self.options.is_empty() {
+            options = format!("{},{}", self.options, options);
+        }
+        let status = Command::new(&self.argv[0])
+            .args(&self.argv[1..])
+            .env(OPTIONS_VAR, options)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        Ok(crash_signal(status))
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.plan);
+    }
+}
+
+/// Delta debugging: shrinks `entries` to a subset `crashes` still holds for,
+/// which it doesn't hold for with any one entry left out
+fn ddmin<E: Clone>(
+    entries: Vec<E>,
+    mut crashes: impl FnMut(&[E]) -> io::Result<bool>,
+) -> io::Result<Vec<E>> {
+    let mut current = entries;
+    let mut n = 2;
+
+    while current.len() >= 2 {
+        let chunk_len = current.len().div_ceil(n);
+        let chunks: Vec<Vec<E>> = current.chunks(chunk_len).map(<[E]>::to_vec).collect();
+
+        let mut reduced = None;
+        for (i, chunk) in chunks.iter().enumerate() {
+            if crashes(chunk)? {
+                reduced = Some((chunk.clone(), 2));
+                break;
+            }
+            if chunks.len() == 2 {
+                continue;
+            }
+            let complement: Vec<E> = chunks
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .flat_map(|(_, chunk)| chunk.iter().cloned())
+                .collect();
+            if crashes(&complement)? {
+                reduced = Some((complement, (n - 1).max(2)));
+                break;
+            }
+        }
+
+        match reduced {
+            Some((entries, granularity)) => {
+                current = entries;
+                n = granularity;
+            }
+            None if n >= current.len() => break,
+            None => n = (n * 2).min(current.len()),
+        }
+    }
+
+    Ok(current)
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let entries = minimize::read(&options.log)?;
+    let mut target = Target::new(options.target);
+
+    let signal = match target.run(&entries)? {
+        Some(signal) => signal,
+        None => {
+            return Err(io::Error::other(
+                "the target doesn't crash with the whole log",
+            ))
+        }
+    };
+    println!(
+        "(minimize) {} mutated bytes crash the target with signal {}",
+        entries.len(),
+        signal
+    );
+
+    let minimal = if target.run(&[])? == Some(signal) {
+        println!("(minimize) the target crashes without any mutation");
+        Vec::new()
+    } else {
+        ddmin(entries, |plan| Ok(target.run(plan)? == Some(signal)))?
+    };
+    minimize::write(&options.output, &minimal)?;
+
+    println!(
+        "(minimize) {} byte{} needed, found in {} runs:",
+        minimal.len(),
+        if minimal.len() == 1 { "" } else { "s" },
+        target.runs
+    );
+    for entry in &minimal {
+        println!(
+            "(minimize)   detection #{}: flip byte at region offset +{:#X} from {:02x} to {:02x}",
+            entry.detection, entry.offset, entry.old, entry.new
+        );
+    }
+    println!(
+        "(minimize) reproduce with {}=mutation_plan={}",
+        OPTIONS_VAR, options.output
+    );
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(parse_args()) {
+        eprintln!("(minimize) error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_needed_entries() {
+        let mut runs = 0;
+        let minimal = ddmin((0..40).collect(), |entries: &[u32]| {
+            runs += 1;
+            Ok(entries.contains(&7) && entries.contains(&31))
+        })
+        .unwrap();
+        assert_eq!(minimal, [7, 31]);
+        assert!(runs < 40);
+
+        let minimal = ddmin((0..5).collect(), |entries: &[u32]| Ok(entries.len() == 5)).unwrap();
+        assert_eq!(minimal, [0, 1, 2, 3, 4]);
+    }
+}
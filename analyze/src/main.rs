@@ -0,0 +1,172 @@
+//! Offline double-fetch trace analyzer
+//!
+//! Replays a trace recorded with the runtime's `trace_file` option and
+//! re-runs detection under a different policy, so settings can be tuned
+//! without re-running the slow instrumented target. Double fetches are
+//! grouped by region offset, size and PC, and groups re-fetched fewer than
+//! `--threshold` times are left out.
+//!
+//! Usage: `df-analyze <trace> [--granularity <bytes>] [--window-ms <ms>]
+//! [--per-thread] [--writes <track|reset|ignore>] [--keep-across-shutdown]
+//! [--threshold <n>]`
+//!
+//! `--window-ms` forgets every fetch at the end of each window of that many
+//! milliseconds of trace time. `--per-thread` only reports re-fetches by the
+//! thread that fetched first. `--writes` picks whether a checked write
+//! counts as a fetch like in the runtime, forgets that the written bytes
+//! were fetched, or is ignored.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+
+use asan_double_fetch::trace::Reader;
+use asan_double_fetch::Address;
+
+mod replay;
+
+use replay::{Policy, Replay, Writes};
+
+struct Options {
+    path: String,
+    policy: Policy,
+    threshold: u64,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: df-analyze <trace> [--granularity <bytes>] [--window-ms <ms>] [--per-thread] [--writes <track|reset|ignore>] [--keep-across-shutdown] [--threshold <n>]"
+    );
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| usage());
+
+    let mut options = Options {
+        path,
+        policy: Policy::default(),
+        threshold: 1,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--granularity" => {
+                let granularity = value()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|granularity| granularity.is_power_of_two())
+                    .unwrap_or_else(|| usage());
+                options.policy.granularity = Some(granularity);
+            }
+            "--window-ms" => {
+                let ms = value()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|ms| *ms > 0)
+                    .unwrap_or_else(|| usage());
+                options.policy.window_ns = Some(ms.saturating_mul(1_000_000));
+            }
+            "--per-thread" => options.policy.per_thread = true,
+            "--writes" => {
+                options.policy.writes = Writes::parse(&value()).unwrap_or_else(|| usage())
+            }
+            "--keep-across-shutdown" => options.policy.keep_across_shutdown = true,
+            "--threshold" => options.threshold = value().parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+/// Double fetches of the same bytes of a region by the same instruction
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Site {
+    region: Address,
+    offset: usize,
+    len: usize,
+    pc: Option<Address>,
+}
+
+struct Group {
+    count: u64,
+    first_ns: u64,
+    threads: Vec<u32>,
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let reader = Reader::new(BufReader::new(File::open(&options.path)?))?;
+    let threshold = options.threshold;
+    let mut replay = Replay::new(options.policy);
+    let mut groups: BTreeMap<Site, Group> = BTreeMap::new();
+    let mut detections = 0u64;
+
+    for record in reader {
+        let detection = match replay.apply(&record?) {
+            Some(detection) => detection,
+            None => continue,
+        };
+        detections += 1;
+
+        let site = Site {
+            region: detection.region.start(),
+            offset: detection.addr.wrapping_sub(detection.region.start()),
+            len: detection.len,
+            pc: detection.pc,
+        };
+        let group = groups.entry(site).or_insert(Group {
+            count: 0,
+            first_ns: detection.time_ns,
+            threads: Vec::new(),
+        });
+        group.count += 1;
+        if !group.threads.contains(&detection.thread) {
+            group.threads.push(detection.thread);
+        }
+    }
+
+    let mut reported: Vec<(&Site, &Group)> = groups
+        .iter()
+        .filter(|(_, group)| group.count >= threshold)
+        .collect();
+    reported.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)));
+
+    for (site, group) in &reported {
+        print!(
+            "(analyze) {} double-fetches of region {:#X} +{:#X} len={:#X}",
+            group.count, site.region, site.offset, site.len
+        );
+        if let Some(pc) = site.pc {
+            print!(" at pc {:#X}", pc);
+        }
+        println!(
+            ", first at {:.3}ms, by {} thread{}",
+            group.first_ns as f64 / 1e6,
+            group.threads.len(),
+            if group.threads.len() == 1 { "" } else { "s" }
+        );
+    }
+
+    println!(
+        "(analyze) {} checks replayed: {} double-fetches at {} sites, {} shown; {} reported when recorded",
+        replay.checks,
+        detections,
+        groups.len(),
+        reported.len(),
+        replay.recorded
+    );
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(parse_args()) {
+        eprintln!("(analyze) error: {}", e);
+        process::exit(1);
+    }
+}
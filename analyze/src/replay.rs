@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+
+use asan_double_fetch::memory_tracking::MemoryTracker;
+use asan_double_fetch::span::Span;
+use asan_double_fetch::trace::{Event, Record};
+use asan_double_fetch::Address;
+
+/// What a checked write does to the fetch history
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Writes {
+    /// Counts as a fetch, like the runtime does
+    Track,
+    /// Forgets the written bytes were fetched
+    Reset,
+    /// Nothing
+    Ignore,
+}
+
+impl Writes {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "track" => Some(Writes::Track),
+            "reset" => Some(Writes::Reset),
+            "ignore" => Some(Writes::Ignore),
+            _ => None,
+        }
+    }
+}
+
+/// How a trace is re-run
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Granule size to use for every region instead of the recorded one
+    pub granularity: Option<usize>,
+    /// Forget all fetches every this many nanoseconds
+    pub window_ns: Option<u64>,
+    /// Only count re-fetches by the thread that fetched first
+    pub per_thread: bool,
+    pub writes: Writes,
+    /// Keep fetch history when the runtime was shut down, for regions that
+    /// are watched again
+    pub keep_across_shutdown: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            granularity: None,
+            window_ns: None,
+            per_thread: false,
+            writes: Writes::Track,
+            keep_across_shutdown: false,
+        }
+    }
+}
+
+/// A double fetch found by replaying
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Detection {
+    pub time_ns: u64,
+    pub thread: u32,
+    pub addr: Address,
+    pub len: usize,
+    pub pc: Option<Address>,
+    pub region: Span,
+}
+
+struct Region {
+    span: Span,
+    granularity: usize,
+    /// Fetch history, per thread if the policy asks for it
+    trackers: HashMap<Option<u32>, MemoryTracker>,
+}
+
+impl Region {
+    /// `[addr, addr + len)` rounded out to granules and clipped to the region
+    fn granules(&self, addr: Address, len: usize) -> Option<Span> {
+        let g = self.granularity;
+        let start = addr & !(g - 1);
+        let end = addr.saturating_add(len).saturating_add(g - 1) & !(g - 1);
+        Span::new(start, end).intersect(&self.span)
+    }
+}
+
+/// Replays trace records under a [`Policy`]
+pub struct Replay {
+    policy: Policy,
+    regions: BTreeMap<Address, Region>,
+    window_start: u64,
+    /// Checks of watched regions seen
+    pub checks: u64,
+    /// Checks the runtime reported as double fetches when recording
+    pub recorded: u64,
+}
+
+impl Replay {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            regions: BTreeMap::new(),
+            window_start: 0,
+            checks: 0,
+            recorded: 0,
+        }
+    }
+
+    fn region_mut(&mut self, addr: Address) -> Option<&mut Region> {
+        self.regions
+            .range_mut(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.span.contains(addr))
+    }
+
+    fn forget_all(&mut self) {
+        for region in self.regions.values_mut() {
+            region.trackers.clear();
+        }
+    }
+
+    /// Applies `record`, returning the double fetch it is under the policy
+    pub fn apply(&mut self, record: &Record) -> Option<Detection> {
+        if let Some(window) = self.policy.window_ns {
+            let elapsed = record.time_ns.saturating_sub(self.window_start);
+            if elapsed >= window {
+                self.forget_all();
+                self.window_start += elapsed - elapsed % window;
+            }
+        }
+
+        match record.event {
+            Event::Watch {
+                addr,
+                len,
+                granularity,
+            } => {
+                let span = Span::with_len(addr, len);
+                let granularity = self.policy.granularity.unwrap_or(granularity).max(1);
+                // watching a region again keeps its history
+                match self.regions.get(&addr) {
+                    Some(region) if region.span == span && region.granularity == granularity => {}
+                    _ => {
+                        self.regions.insert(
+                            addr,
+                            Region {
+                                span,
+                                granularity,
+                                trackers: HashMap::new(),
+                            },
+                        );
+                    }
+                }
+                None
+            }
+            Event::Unwatch { addr } => {
+                self.regions.remove(&addr);
+                None
+            }
+            Event::Shutdown => {
+                if !self.policy.keep_across_shutdown {
+                    self.regions.clear();
+                }
+                None
+            }
+            Event::Check {
+                addr,
+                len,
+                pc,
+                is_write,
+                double_fetch,
+            } => {
+                self.checks += 1;
+                if double_fetch {
+                    self.recorded += 1;
+                }
+
+                let thread = if self.policy.per_thread {
+                    Some(record.thread)
+                } else {
+                    None
+                };
+                let writes = self.policy.writes;
+                let region = self.region_mut(addr)?;
+                let granules = region.granules(addr, len)?;
+                let span = region.span.clone();
+                let tracker = region.trackers.entry(thread).or_default();
+
+                if is_write {
+                    let updated = match writes {
+                        Writes::Track => tracker.track_access(granules.start(), granules.len()),
+                        Writes::Reset => tracker.remove_access(granules.start(), granules.len()),
+                        Writes::Ignore => Ok(()),
+                    };
+                    if let Err(e) = updated {
+                        eprintln!("(analyze) {}", e);
+                    }
+                    return None;
+                }
+
+                if tracker.check(granules.start(), granules.len()).is_err() {
+                    return Some(Detection {
+                        time_ns: record.time_ns,
+                        thread: record.thread,
+                        addr,
+                        len,
+                        pc,
+                        region: span,
+                    });
+                }
+                if let Err(e) = tracker.track_access(granules.start(), granules.len()) {
+                    eprintln!("(analyze) {}", e);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time_ns: u64, thread: u32, event: Event) -> Record {
+        Record {
+            time_ns,
+            thread,
+            event,
+        }
+    }
+
+    fn check(time_ns: u64, thread: u32, addr: Address, is_write: bool) -> Record {
+        record(
+            time_ns,
+            thread,
+            Event::Check {
+                addr,
+                len: 1,
+                pc: None,
+                is_write,
+                double_fetch: false,
+            },
+        )
+    }
+
+    fn detections(policy: Policy, records: &[Record]) -> usize {
+        let mut replay = Replay::new(policy);
+        records
+            .iter()
+            .filter(|record| replay.apply(record).is_some())
+            .count()
+    }
+
+    fn watch(granularity: usize) -> Record {
+        record(
+            0,
+            0,
+            Event::Watch {
+                addr: 0x1000,
+                len: 0x100,
+                granularity,
+            },
+        )
+    }
+
+    #[test]
+    fn policies() {
+        let records = [
+            watch(1),
+            check(10, 0, 0x1000, false),
+            check(20, 1, 0x1000, false),
+            check(30, 1, 0x1001, true),
+            check(40, 1, 0x1001, false),
+            check(50, 0, 0x2000, false),
+        ];
+
+        assert_eq!(detections(Policy::default(), &records), 2);
+        let per_thread = Policy {
+            per_thread: true,
+            ..Policy::default()
+        };
+        assert_eq!(detections(per_thread, &records), 1);
+        let reset = Policy {
+            writes: Writes::Reset,
+            ..Policy::default()
+        };
+        assert_eq!(detections(reset, &records), 1);
+        let window = Policy {
+            window_ns: Some(10),
+            ..Policy::default()
+        };
+        assert_eq!(detections(window, &records), 0);
+    }
+
+    #[test]
+    fn granularity_and_lifetime() {
+        let records = [
+            watch(1),
+            check(10, 0, 0x1000, false),
+            check(20, 0, 0x1003, false),
+            record(30, 0, Event::Shutdown),
+            watch(1),
+            check(40, 0, 0x1000, false),
+        ];
+
+        assert_eq!(detections(Policy::default(), &records), 0);
+        let coarse = Policy {
+            granularity: Some(4),
+            ..Policy::default()
+        };
+        assert_eq!(detections(coarse, &records), 1);
+        let keep = Policy {
+            keep_across_shutdown: true,
+            ..Policy::default()
+        };
+        assert_eq!(detections(keep, &records), 1);
+
+        let mut replay = Replay::new(Policy::default());
+        replay.apply(&watch(4));
+        replay.apply(&check(10, 0, 0x1000, false));
+        let detection = replay.apply(&check(20, 0, 0x1002, false)).unwrap();
+        assert_eq!(detection.region, Span::with_len(0x1000, 0x100));
+        replay.apply(&record(30, 0, Event::Unwatch { addr: 0x1000 }));
+        assert_eq!(replay.apply(&check(40, 0, 0x1000, false)), None);
+        assert_eq!(replay.checks, 3);
+    }
+}
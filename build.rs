@@ -0,0 +1,84 @@
+//! Generates the C header declaring the runtime's entry points into
+//! `$OUT_DIR/asan_double_fetch.h`. The copy under `include/` is the one
+//! consumers use, and a test checks it matches.
+
+use std::env;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+/// Entry points generated by `sized_checks!`, which the header declares by
+/// hand
+const SIZED_CHECKS: [&str; 5] = [
+    "__asan_double_fetch_check1",
+    "__asan_double_fetch_check2",
+    "__asan_double_fetch_check4",
+    "__asan_double_fetch_check8",
+    "__asan_double_fetch_check16",
+];
+
+/// Name `name` is exported as in `prefixed_symbols` builds, see `symbol!`
+fn prefixed(name: &str, abi_version: &str) -> String {
+    let stem = ["__asan_double_fetch_", "__asan_", "asan_"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    format!("adf_{}_v{}", stem, abi_version)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("failed to read cbindgen.toml");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("asan_double_fetch.h");
+
+    let mut bindings = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/lib.rs"))
+        .generate()
+        .expect("failed to generate the C header");
+
+    // The declarations keep the __asan_ names; in prefixed builds, macros
+    // rename them to the symbols actually exported before they're declared,
+    // and are taken back after unless the __asan_ names are wanted as aliases
+    let after_includes = bindings.config.after_includes.take().unwrap_or_default();
+    let abi_version = after_includes
+        .lines()
+        .find_map(|line| line.strip_prefix("#define ASAN_DOUBLE_FETCH_ABI_VERSION "))
+        .expect("cbindgen.toml doesn't define ASAN_DOUBLE_FETCH_ABI_VERSION")
+        .to_owned();
+    let mut names: Vec<String> = bindings
+        .functions
+        .iter()
+        .map(|function| function.path().name().to_owned())
+        .chain(SIZED_CHECKS.iter().map(|name| name.to_string()))
+        .collect();
+    names.sort();
+
+    let mut renames = String::from("\n#if defined(ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS)\n");
+    let mut aliases = String::from(
+        "\n#if defined(ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS) && defined(ASAN_DOUBLE_FETCH_NO_ASAN_ALIASES)\n",
+    );
+    for name in &names {
+        let _ = writeln!(renames, "#define {} {}", name, prefixed(name, &abi_version));
+        let _ = writeln!(aliases, "#undef {}", name);
+    }
+    renames.push_str("#endif\n");
+    aliases.push_str("#endif");
+
+    let (head, prototypes) = after_includes
+        .split_once("\n#ifdef __cplusplus")
+        .expect("cbindgen.toml has no extern \"C\" block");
+    bindings.config.after_includes = Some(format!(
+        "{}{}\n#ifdef __cplusplus{}",
+        head, renames, prototypes
+    ));
+    // which has to stay inside the include guard, so it's written here
+    let guard = bindings.config.include_guard.take().unwrap();
+    let header = bindings.config.header.take().unwrap_or_default();
+    bindings.config.header = Some(format!("{}\n\n#ifndef {1}\n#define {1}", header, guard));
+    bindings.config.trailer = Some(format!("{}\n\n#endif  /* {} */", aliases, guard));
+    bindings.write_to_file(out);
+}
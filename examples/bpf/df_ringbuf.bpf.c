@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Example BPF program forwarding asan-double-fetch reports into a ring buffer
+ *
+ * A starting point for agents that ship kernel findings off the box: attach
+ * to the asan_double_fetch:df_report tracepoint and consume `reports` from
+ * userspace, see df_ringbuf.c.
+ *
+ * Build:
+ *   clang -O2 -g -target bpf -c df_ringbuf.bpf.c -o df_ringbuf.bpf.o
+ */
+
+#include <linux/bpf.h>
+#include <linux/types.h>
+
+#include <bpf/bpf_helpers.h>
+
+#include "df_ringbuf.h"
+
+/* Layout of the tracepoint's record, see
+ * /sys/kernel/tracing/events/asan_double_fetch/df_report/format */
+struct df_report_ctx {
+    __u64 common;
+    char bug[DF_BUG_LEN];
+    unsigned long addr;
+    __u64 len;
+    unsigned long pc;
+    __s32 pid;
+    long nr;
+    unsigned long ioctl_cmd;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 256 * 1024);
+} reports SEC(".maps");
+
+SEC("tracepoint/asan_double_fetch/df_report")
+int df_report(struct df_report_ctx *ctx)
+{
+    struct df_event *event = bpf_ringbuf_reserve(&reports, sizeof(*event), 0);
+
+    /* dropped if userspace falls behind */
+    if (!event)
+        return 0;
+
+    __builtin_memcpy(event->bug, ctx->bug, sizeof(event->bug));
+    bpf_get_current_comm(event->comm, sizeof(event->comm));
+    event->addr = ctx->addr;
+    event->len = ctx->len;
+    event->pc = ctx->pc;
+    event->pid = ctx->pid;
+    event->nr = ctx->nr;
+    event->ioctl_cmd = ctx->ioctl_cmd;
+    bpf_ringbuf_submit(event, 0);
+    return 0;
+}
+
+char LICENSE[] SEC("license") = "GPL";
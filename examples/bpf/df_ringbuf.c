@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Example collector printing the reports df_ringbuf.bpf.c forwards
+ *
+ * Build:
+ *   cc df_ringbuf.c -o df_ringbuf -lbpf
+ *
+ * Run, as root, next to df_ringbuf.bpf.o:
+ *   ./df_ringbuf
+ */
+
+#include <errno.h>
+#include <stdio.h>
+
+#include <linux/types.h>
+
+#include <bpf/libbpf.h>
+
+#include "df_ringbuf.h"
+
+static int handle_event(void *ctx, void *data, size_t size)
+{
+    const struct df_event *event = data;
+
+    printf("%s pid=%d comm=%s addr=0x%llx len=%llu pc=0x%llx nr=%lld ioctl_cmd=0x%llx\n",
+           event->bug, event->pid, event->comm, event->addr, event->len, event->pc, event->nr,
+           event->ioctl_cmd);
+    fflush(stdout);
+    return 0;
+}
+
+int main(void)
+{
+    struct bpf_object *obj;
+    struct bpf_program *prog;
+    struct ring_buffer *reports;
+    int err;
+
+    obj = bpf_object__open_file("df_ringbuf.bpf.o", NULL);
+    if (!obj) {
+        fprintf(stderr, "failed to open df_ringbuf.bpf.o\n");
+        return 1;
+    }
+    err = bpf_object__load(obj);
+    if (err) {
+        fprintf(stderr, "failed to load df_ringbuf.bpf.o: %d\n", err);
+        return 1;
+    }
+
+    prog = bpf_object__find_program_by_name(obj, "df_report");
+    if (!prog || !bpf_program__attach(prog)) {
+        fprintf(stderr, "failed to attach, is the runtime loaded?\n");
+        return 1;
+    }
+
+    reports = ring_buffer__new(bpf_object__find_map_fd_by_name(obj, "reports"), handle_event,
+                               NULL, NULL);
+    if (!reports) {
+        fprintf(stderr, "failed to open the ring buffer\n");
+        return 1;
+    }
+
+    while ((err = ring_buffer__poll(reports, -1)) >= 0 || err == -EINTR)
+        ;
+
+    fprintf(stderr, "polling failed: %d\n", err);
+    return 1;
+}
@@ -0,0 +1,24 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+/* Events df_ringbuf.bpf.c submits to its `reports` ring buffer */
+
+#ifndef DF_RINGBUF_H
+#define DF_RINGBUF_H
+
+/* ASAN_DOUBLE_FETCH_BUG_LEN in kernel/asan_double_fetch_trace.h */
+#define DF_BUG_LEN 24
+
+struct df_event {
+    char bug[DF_BUG_LEN];
+    char comm[16];
+    __u64 addr;
+    __u64 len;
+    /* 0 if unknown */
+    __u64 pc;
+    __s32 pid;
+    /* syscall the report happened in, -1 outside one */
+    __s64 nr;
+    /* command of an ioctl, 0 for other syscalls */
+    __u64 ioctl_cmd;
+};
+
+#endif /* DF_RINGBUF_H */
@@ -0,0 +1,164 @@
+/*
+ * Example DynamoRIO client feeding every memory access into asan-double-fetch
+ *
+ * Build:
+ *   cargo build --release --features dbi
+ *   mkdir build && cd build
+ *   cmake -DDynamoRIO_DIR=$DYNAMORIO_HOME/cmake \
+ *         -DASAN_DOUBLE_FETCH_LIB=$PWD/../../../target/release/libasan_double_fetch.so ..
+ *   make
+ *
+ * Run:
+ *   drrun -c ./libdf_client.so -- ./target
+ *
+ * Every successful shmat() in the target is watched automatically. Each
+ * memory operand of each application instruction gets a clean call to
+ * __asan_dbi_check_pc() with its effective address, size, and PC.
+ */
+
+#include <stddef.h>
+#include <string.h>
+#include <sys/ipc.h>
+#include <sys/shm.h>
+
+#include "dr_api.h"
+#include "drmgr.h"
+#include "drreg.h"
+#include "drutil.h"
+#include "drwrap.h"
+
+#include "asan_double_fetch_dbi.h"
+
+static void
+report(const asan_dbi_report_t *r, void *user_data)
+{
+    dr_fprintf(STDERR,
+               "df_client: double fetch of %u bytes at " PFX " (region " PFX "+0x%x) by pc " PFX
+               "\n",
+               (uint)r->len, r->addr, r->region_start, (uint)(r->addr - r->region_start), r->pc);
+}
+
+static void
+check(app_pc addr, uint size, uint is_write, app_pc pc)
+{
+    __asan_dbi_check_pc((uintptr_t)addr, size, (int)is_write, (uintptr_t)pc);
+}
+
+static void
+instrument_mem(void *drcontext, instrlist_t *ilist, instr_t *where, app_pc pc, opnd_t ref,
+               bool is_write)
+{
+    reg_id_t reg_addr, reg_scratch;
+
+    if (drreg_reserve_register(drcontext, ilist, where, NULL, &reg_addr) != DRREG_SUCCESS ||
+        drreg_reserve_register(drcontext, ilist, where, NULL, &reg_scratch) != DRREG_SUCCESS) {
+        DR_ASSERT_MSG(false, "failed to reserve registers");
+        return;
+    }
+
+    if (drutil_insert_get_mem_addr(drcontext, ilist, where, ref, reg_addr, reg_scratch)) {
+        dr_insert_clean_call(drcontext, ilist, where, (void *)check, false, 4,
+                             opnd_create_reg(reg_addr),
+                             OPND_CREATE_INT32(drutil_opnd_mem_size_in_bytes(ref, where)),
+                             OPND_CREATE_INT32(is_write ? 1 : 0), OPND_CREATE_INTPTR(pc));
+    }
+
+    drreg_unreserve_register(drcontext, ilist, where, reg_scratch);
+    drreg_unreserve_register(drcontext, ilist, where, reg_addr);
+}
+
+static dr_emit_flags_t
+event_bb_app2app(void *drcontext, void *tag, instrlist_t *bb, bool for_trace, bool translating)
+{
+    /* turn rep string loops into plain loops so each iteration is checked */
+    if (!drutil_expand_rep_string(drcontext, bb))
+        DR_ASSERT(false);
+    return DR_EMIT_DEFAULT;
+}
+
+static dr_emit_flags_t
+event_app_instruction(void *drcontext, void *tag, instrlist_t *bb, instr_t *instr,
+                      bool for_trace, bool translating, void *user_data)
+{
+    app_pc pc;
+    int i;
+
+    if (!instr_is_app(instr) || (!instr_reads_memory(instr) && !instr_writes_memory(instr)))
+        return DR_EMIT_DEFAULT;
+
+    pc = instr_get_app_pc(instr);
+    for (i = 0; i < instr_num_srcs(instr); i++) {
+        if (opnd_is_memory_reference(instr_get_src(instr, i)))
+            instrument_mem(drcontext, bb, instr, pc, instr_get_src(instr, i), false);
+    }
+    for (i = 0; i < instr_num_dsts(instr); i++) {
+        if (opnd_is_memory_reference(instr_get_dst(instr, i)))
+            instrument_mem(drcontext, bb, instr, pc, instr_get_dst(instr, i), true);
+    }
+
+    return DR_EMIT_DEFAULT;
+}
+
+static void
+shmat_pre(void *wrapcxt, OUT void **user_data)
+{
+    *user_data = (void *)(ptr_int_t)drwrap_get_arg(wrapcxt, 0);
+}
+
+static void
+shmat_post(void *wrapcxt, void *user_data)
+{
+    int shmid = (int)(ptr_int_t)user_data;
+    void *addr = drwrap_get_retval(wrapcxt);
+    struct shmid_ds ds;
+
+    if (addr == (void *)-1 || shmctl(shmid, IPC_STAT, &ds) != 0)
+        return;
+
+    if (__asan_dbi_watch((uintptr_t)addr, ds.shm_segsz) != 0)
+        dr_fprintf(STDERR, "df_client: failed to watch segment %d\n", shmid);
+}
+
+static void
+event_module_load(void *drcontext, const module_data_t *mod, bool loaded)
+{
+    app_pc shmat = (app_pc)dr_get_proc_address(mod->handle, "shmat");
+    if (shmat != NULL)
+        drwrap_wrap(shmat, shmat_pre, shmat_post);
+}
+
+static void
+event_exit(void)
+{
+    __asan_dbi_set_report_callback(NULL, NULL);
+    drwrap_exit();
+    drutil_exit();
+    drreg_exit();
+    drmgr_exit();
+}
+
+DR_EXPORT void
+dr_client_main(client_id_t id, int argc, const char *argv[])
+{
+    drreg_options_t ops = { sizeof(ops), 3 /* max slots needed */, false };
+
+    dr_set_client_name("asan-double-fetch example client", "");
+
+    if (__asan_dbi_abi_version() != ASAN_DBI_ABI_VERSION) {
+        dr_fprintf(STDERR, "df_client: runtime ABI version %u, expected %u\n",
+                   __asan_dbi_abi_version(), ASAN_DBI_ABI_VERSION);
+        dr_abort();
+    }
+
+    if (!drmgr_init() || drreg_init(&ops) != DRREG_SUCCESS || !drutil_init() || !drwrap_init())
+        DR_ASSERT(false);
+
+    __asan_dbi_init();
+    __asan_dbi_set_report_callback(report, NULL);
+
+    dr_register_exit_event(event_exit);
+    if (!drmgr_register_module_load_event(event_module_load) ||
+        !drmgr_register_bb_app2app_event(event_bb_app2app, NULL) ||
+        !drmgr_register_bb_instrumentation_event(NULL, event_app_instruction, NULL))
+        DR_ASSERT(false);
+}
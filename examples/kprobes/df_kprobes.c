@@ -0,0 +1,71 @@
+/*
+ * Companion module feeding copy-from-user paths to asan-double-fetch via kprobes
+ *
+ * For kernels that can't be rebuilt with the compiler pass or the uaccess
+ * header. Function-entry kprobes are ftrace-backed on kernels with
+ * CONFIG_KPROBES_ON_FTRACE, so the overhead is that of an ftrace hook.
+ *
+ * Build alongside a kernel built with the runtime (`--features linux_kasan`):
+ *   make -C /lib/modules/$(uname -r)/build M=$PWD modules
+ *
+ * Load:
+ *   insmod df_kprobes.ko
+ *
+ * Every _copy_from_user source range is watched for the rest of the syscall
+ * and checked as a fetch before the copy runs; the ranges are dropped on the
+ * way back to user mode.
+ */
+
+#include <linux/kprobes.h>
+#include <linux/module.h>
+#include <linux/ptrace.h>
+
+void __asan_double_fetch_kprobe_fetch(unsigned long from, size_t n);
+void __asan_double_fetch_syscall_exit(void);
+
+/* unsigned long _copy_from_user(void *to, const void __user *from, unsigned long n) */
+static int copy_from_user_pre(struct kprobe *p, struct pt_regs *regs)
+{
+    __asan_double_fetch_kprobe_fetch(regs_get_kernel_argument(regs, 1),
+                                     regs_get_kernel_argument(regs, 2));
+    return 0;
+}
+
+static int syscall_exit_pre(struct kprobe *p, struct pt_regs *regs)
+{
+    __asan_double_fetch_syscall_exit();
+    return 0;
+}
+
+static struct kprobe probes[] = {
+    {
+        .symbol_name = "_copy_from_user",
+        .pre_handler = copy_from_user_pre,
+    },
+    {
+        .symbol_name = "syscall_exit_to_user_mode",
+        .pre_handler = syscall_exit_pre,
+    },
+};
+
+static struct kprobe *probe_ptrs[ARRAY_SIZE(probes)];
+
+static int __init df_kprobes_init(void)
+{
+    int i;
+
+    for (i = 0; i < ARRAY_SIZE(probes); i++)
+        probe_ptrs[i] = &probes[i];
+
+    return register_kprobes(probe_ptrs, ARRAY_SIZE(probe_ptrs));
+}
+
+static void __exit df_kprobes_exit(void)
+{
+    unregister_kprobes(probe_ptrs, ARRAY_SIZE(probe_ptrs));
+}
+
+module_init(df_kprobes_init);
+module_exit(df_kprobes_exit);
+MODULE_LICENSE("GPL");
+MODULE_DESCRIPTION("asan-double-fetch copy_from_user kprobes");
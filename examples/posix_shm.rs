@@ -0,0 +1,94 @@
+//! A dispatcher that bounds-checks a command index in POSIX shared memory,
+//! then fetches the index again to pick the handler
+//!
+//! Loads from the mapping are checked the way the instrumentation pass
+//! checks them, and the mapping is watched explicitly, as a harness does
+//! for memory its interceptors don't see. With `mutation=canary`, the
+//! re-fetched index runs past the table:
+//!
+//! ```text
+//! ASAN_DOUBLE_FETCH_OPTIONS=mutation=canary cargo run --example posix_shm
+//! ```
+
+#[cfg(unix)]
+fn main() {
+    use std::ffi::CString;
+
+    use asan_double_fetch::stats::{__asan_double_fetch_get_stats, Stats};
+    use asan_double_fetch::{
+        __asan_double_fetch_check2, __asan_shared_memory_region_shutdown,
+        __asan_watch_shared_memory_region,
+    };
+
+    const COMMANDS: [&str; 3] = ["open", "read", "close"];
+    const LEN: usize = 0x1000;
+
+    /// A 2-byte load, as instrumented
+    unsafe fn load_u16(p: *const u16) -> u16 {
+        __asan_double_fetch_check2(p as usize, false);
+        p.read_volatile()
+    }
+
+    /// Looks up the command whose index is at `index`
+    unsafe fn dispatch(index: *const u16) -> Result<&'static str, String> {
+        if usize::from(load_u16(index)) >= COMMANDS.len() {
+            return Err("rejected an unknown command".to_owned());
+        }
+        // the bug: the index is fetched again after the bounds check
+        let command = usize::from(load_u16(index));
+        COMMANDS.get(command).copied().ok_or_else(|| {
+            format!(
+                "index changed to {:#x} after the bounds check, the lookup overflows",
+                command
+            )
+        })
+    }
+
+    unsafe {
+        let name =
+            CString::new(format!("/asan-double-fetch-example-{}", std::process::id())).unwrap();
+        let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o600);
+        assert!(fd >= 0, "shm_open failed");
+        libc::shm_unlink(name.as_ptr());
+        assert_eq!(
+            libc::ftruncate(fd, LEN as libc::off_t),
+            0,
+            "ftruncate failed"
+        );
+        let addr = libc::mmap(
+            core::ptr::null_mut(),
+            LEN,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        assert!(addr != libc::MAP_FAILED, "mmap failed");
+        assert_eq!(__asan_watch_shared_memory_region(addr as usize, LEN), 0);
+
+        // the client's command, a little way into the mapping
+        let index = addr.cast::<u8>().add(0x10).cast::<u16>();
+        *index = 1;
+
+        match dispatch(index) {
+            Ok(command) => println!("dispatched {}", command),
+            Err(e) => println!("{}", e),
+        }
+
+        let mut stats = Stats::default();
+        __asan_double_fetch_get_stats(&mut stats);
+        println!(
+            "detections: {}, mutations: {}",
+            stats.detections, stats.mutations
+        );
+
+        __asan_shared_memory_region_shutdown();
+        libc::munmap(addr, LEN);
+        libc::close(fd);
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("POSIX shared memory needs a unix target");
+}
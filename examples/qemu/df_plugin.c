@@ -0,0 +1,72 @@
+/*
+ * Example QEMU TCG plugin forwarding guest memory accesses to asan-double-fetch
+ *
+ * Build:
+ *   cargo build --release --features qemu
+ *   cc -shared -fPIC -I$QEMU_SRC/include/qemu $(pkg-config --cflags glib-2.0) \
+ *      df_plugin.c -o libdf_plugin.so \
+ *      -L../../target/release -lasan_double_fetch -Wl,-rpath,$PWD/../../target/release
+ *
+ * Run (system emulation):
+ *   qemu-system-x86_64 ... \
+ *     -plugin ./libdf_plugin.so,watch=0x7f000000:0x1000,watch=0x80000000:0x2000
+ *
+ * Each `watch=<gpa>:<len>` argument watches a guest-physical range. Accesses
+ * to MMIO are skipped; only RAM-backed accesses have a guest-physical address.
+ */
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <qemu-plugin.h>
+
+QEMU_PLUGIN_EXPORT int qemu_plugin_version = QEMU_PLUGIN_VERSION;
+
+void __asan_qemu_watch_gpa(uint64_t gpa, uint64_t len);
+int __asan_qemu_mem_access(uint32_t vcpu, uint64_t gpa, uint32_t len, int is_write, uint64_t pc);
+
+static void
+vcpu_mem(unsigned int vcpu_index, qemu_plugin_meminfo_t info, uint64_t vaddr, void *udata)
+{
+    struct qemu_plugin_hwaddr *hwaddr = qemu_plugin_get_hwaddr(info, vaddr);
+
+    if (hwaddr == NULL || qemu_plugin_hwaddr_is_io(hwaddr))
+        return;
+
+    __asan_qemu_mem_access(vcpu_index, qemu_plugin_hwaddr_phys_addr(hwaddr),
+                           1u << qemu_plugin_mem_size_shift(info),
+                           qemu_plugin_mem_is_store(info), (uint64_t)(uintptr_t)udata);
+}
+
+static void
+vcpu_tb_trans(qemu_plugin_id_t id, struct qemu_plugin_tb *tb)
+{
+    size_t n = qemu_plugin_tb_n_insns(tb);
+
+    for (size_t i = 0; i < n; i++) {
+        struct qemu_plugin_insn *insn = qemu_plugin_tb_get_insn(tb, i);
+        uint64_t pc = qemu_plugin_insn_vaddr(insn);
+
+        qemu_plugin_register_vcpu_mem_cb(insn, vcpu_mem, QEMU_PLUGIN_CB_NO_REGS,
+                                         QEMU_PLUGIN_MEM_RW, (void *)(uintptr_t)pc);
+    }
+}
+
+QEMU_PLUGIN_EXPORT int
+qemu_plugin_install(qemu_plugin_id_t id, const qemu_info_t *info, int argc, char **argv)
+{
+    for (int i = 0; i < argc; i++) {
+        uint64_t gpa, len;
+
+        if (sscanf(argv[i], "watch=%" SCNx64 ":%" SCNx64, &gpa, &len) != 2) {
+            fprintf(stderr, "df_plugin: unknown argument %s\n", argv[i]);
+            return -1;
+        }
+        __asan_qemu_watch_gpa(gpa, len);
+    }
+
+    qemu_plugin_register_vcpu_tb_trans_cb(id, vcpu_tb_trans);
+    return 0;
+}
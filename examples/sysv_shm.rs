@@ -0,0 +1,89 @@
+//! A server that validates a request's length in SysV shared memory, then
+//! fetches the length again to copy the payload
+//!
+//! Loads from the request are checked the way the instrumentation pass
+//! checks them, and `shmget`/`shmat` are reported the way its interceptors
+//! report them. With `mutation=canary`, the runtime hands the re-fetch a
+//! different length, as a client racing the server could:
+//!
+//! ```text
+//! ASAN_DOUBLE_FETCH_OPTIONS=mutation=canary cargo run --example sysv_shm
+//! ```
+
+#[cfg(unix)]
+fn main() {
+    use std::mem::size_of;
+
+    use asan_double_fetch::stats::{__asan_double_fetch_get_stats, Stats};
+    use asan_double_fetch::{
+        __asan_double_fetch_check4, __asan_shared_memory_region_shutdown, asan_register_shmat,
+        asan_remember_shm_id,
+    };
+
+    const PAYLOAD_LEN: usize = 60;
+
+    #[repr(C)]
+    struct Request {
+        len: u32,
+        payload: [u8; PAYLOAD_LEN],
+    }
+
+    /// A 4-byte load, as instrumented
+    unsafe fn load_u32(p: *const u32) -> u32 {
+        __asan_double_fetch_check4(p as usize, false);
+        p.read_volatile()
+    }
+
+    /// Copies out the request's payload
+    unsafe fn handle(request: *const Request) -> Result<Vec<u8>, String> {
+        let len = load_u32(&(*request).len) as usize;
+        if len > PAYLOAD_LEN {
+            return Err(format!("rejected a {}-byte payload", len));
+        }
+        // the bug: the length is fetched again instead of reusing `len`
+        let len = load_u32(&(*request).len) as usize;
+        match (*request).payload.get(..len) {
+            Some(payload) => Ok(payload.to_vec()),
+            None => Err(format!(
+                "length changed to {:#x} after validation, the copy overflows",
+                len
+            )),
+        }
+    }
+
+    unsafe {
+        let size = size_of::<Request>();
+        let id = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+        assert!(id >= 0, "shmget failed");
+        asan_remember_shm_id(id, size);
+        let addr = libc::shmat(id, core::ptr::null(), 0);
+        assert!(addr as isize != -1, "shmat failed");
+        asan_register_shmat(id, addr);
+
+        // the client's request
+        let request = addr as *mut Request;
+        (*request).len = 16;
+        (*request).payload = [0x41; PAYLOAD_LEN];
+
+        match handle(request) {
+            Ok(payload) => println!("handled a {}-byte payload", payload.len()),
+            Err(e) => println!("{}", e),
+        }
+
+        let mut stats = Stats::default();
+        __asan_double_fetch_get_stats(&mut stats);
+        println!(
+            "detections: {}, mutations: {}",
+            stats.detections, stats.mutations
+        );
+
+        __asan_shared_memory_region_shutdown();
+        libc::shmdt(addr);
+        libc::shmctl(id, libc::IPC_RMID, core::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("SysV shared memory needs a unix target");
+}
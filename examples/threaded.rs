@@ -0,0 +1,91 @@
+//! Worker threads that each validate a request's length in a shared ring,
+//! then fetch the length again to copy the payload
+//!
+//! Every worker handles its own slot, so each slot's re-fetch is detected
+//! once, however the threads interleave. Loads are checked the way the
+//! instrumentation pass checks them:
+//!
+//! ```text
+//! ASAN_DOUBLE_FETCH_OPTIONS=mutation=canary cargo run --example threaded
+//! ```
+
+use std::thread;
+
+use asan_double_fetch::stats::{__asan_double_fetch_get_stats, Stats};
+use asan_double_fetch::{
+    __asan_double_fetch_check4, __asan_shared_memory_region_shutdown,
+    __asan_watch_shared_memory_region,
+};
+
+const WORKERS: usize = 4;
+const PAYLOAD_LEN: usize = 28;
+
+#[repr(C)]
+struct Slot {
+    len: u32,
+    payload: [u8; PAYLOAD_LEN],
+}
+
+/// A 4-byte load, as instrumented
+unsafe fn load_u32(p: *const u32) -> u32 {
+    __asan_double_fetch_check4(p as usize, false);
+    p.read_volatile()
+}
+
+/// Copies out the payload of the request in `slot`
+unsafe fn handle(slot: *const Slot) -> Result<usize, String> {
+    let len = load_u32(&(*slot).len) as usize;
+    if len > PAYLOAD_LEN {
+        return Err(format!("rejected a {}-byte payload", len));
+    }
+    // the bug: the length is fetched again instead of reusing `len`
+    let len = load_u32(&(*slot).len) as usize;
+    match (*slot).payload.get(..len) {
+        Some(payload) => Ok(payload.len()),
+        None => Err(format!(
+            "length changed to {:#x} after validation, the copy overflows",
+            len
+        )),
+    }
+}
+
+fn main() {
+    let ring: &'static mut [Slot] = Vec::leak(
+        (0..WORKERS)
+            .map(|i| Slot {
+                len: i as u32 + 1,
+                payload: [0x41; PAYLOAD_LEN],
+            })
+            .collect(),
+    );
+    let ring_len = std::mem::size_of_val(ring);
+    assert_eq!(
+        __asan_watch_shared_memory_region(ring.as_ptr() as usize, ring_len),
+        0
+    );
+
+    let ring_addr = ring.as_ptr() as usize;
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|i| {
+            thread::spawn(move || {
+                let slot = (ring_addr as *const Slot).wrapping_add(i);
+                match unsafe { handle(slot) } {
+                    Ok(len) => println!("worker {} handled a {}-byte payload", i, len),
+                    Err(e) => println!("worker {}: {}", i, e),
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let mut stats = Stats::default();
+    unsafe { __asan_double_fetch_get_stats(&mut stats) };
+    println!(
+        "detections: {}, mutations: {}",
+        stats.detections, stats.mutations
+    );
+
+    __asan_shared_memory_region_shutdown();
+}
@@ -0,0 +1,99 @@
+// Frida agent routing memory accesses of selected functions through the
+// asan-double-fetch runtime.
+//
+// Build the runtime with `cargo build --release --features frida`, then:
+//
+//   frida -p <pid> -l frida/double_fetch.js
+//   [Local::target]-> DoubleFetch.load('/path/to/libasan_double_fetch.so')
+//   [Local::target]-> DoubleFetch.watch(ptr('0x7f0000000000'), 0x1000)
+//   [Local::target]-> DoubleFetch.traceFunction(Module.getExportByName(null, 'handle_request'))
+//
+// Every thread that enters a traced function is followed with Stalker until
+// the function returns; each instruction with a memory operand gets a callout
+// that computes the effective address and calls `__asan_frida_check`.
+
+'use strict';
+
+const DoubleFetch = (function () {
+  let api = null;
+
+  function load(path) {
+    const module = Module.load(path);
+    const fn = (name, ret, args) => new NativeFunction(module.getExportByName(name), ret, args);
+
+    api = {
+      init: fn('__asan_frida_init', 'int', []),
+      watch: fn('__asan_frida_watch', 'int', ['pointer', 'size_t']),
+      unwatch: fn('__asan_frida_unwatch', 'int', ['pointer']),
+      check: fn('__asan_frida_check', 'int', ['pointer', 'size_t', 'int']),
+    };
+    api.init();
+  }
+
+  function requireLoaded() {
+    if (api === null) {
+      throw new Error('call DoubleFetch.load(path) first');
+    }
+    return api;
+  }
+
+  function register(context, name) {
+    if (name === undefined || name === '') {
+      return ptr(0);
+    }
+    const value = context[name];
+    if (value === undefined) {
+      // sub-registers (eax, r8d, ...) are not exposed on CpuContext
+      return ptr(0);
+    }
+    return value;
+  }
+
+  function effectiveAddress(context, mem) {
+    return register(context, mem.base)
+      .add(register(context, mem.index).shl(Math.log2(mem.scale || 1)))
+      .add(mem.disp);
+  }
+
+  function memoryOperands(instruction) {
+    return instruction.operands.filter((op) => op.type === 'mem' && op.value.segment === undefined);
+  }
+
+  function traceFunction(target) {
+    const { check } = requireLoaded();
+
+    Interceptor.attach(target, {
+      onEnter() {
+        Stalker.follow(this.threadId, {
+          transform(iterator) {
+            let instruction;
+            while ((instruction = iterator.next()) !== null) {
+              for (const op of memoryOperands(instruction)) {
+                const isWrite = op.access !== undefined && op.access.indexOf('w') !== -1 ? 1 : 0;
+                const size = op.size;
+                const mem = op.value;
+                iterator.putCallout((context) => {
+                  check(effectiveAddress(context, mem), size, isWrite);
+                });
+              }
+              iterator.keep();
+            }
+          },
+        });
+      },
+      onLeave() {
+        Stalker.unfollow(this.threadId);
+        Stalker.flush();
+      },
+    });
+  }
+
+  return {
+    load,
+    traceFunction,
+    watch: (addr, len) => requireLoaded().watch(addr, len),
+    unwatch: (addr) => requireLoaded().unwatch(addr),
+  };
+})();
+
+globalThis.DoubleFetch = DoubleFetch;
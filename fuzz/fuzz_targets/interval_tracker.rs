@@ -0,0 +1,123 @@
+//! Runs sequences of tracker operations against a byte map
+//!
+//! Addresses are `u8`s, so the whole address space fits in the model and
+//! accesses regularly get clipped at its end. After every operation the
+//! tracker's spans must be non-empty, sorted, and neither overlap nor touch,
+//! and together cover exactly the bytes the model has marked. Checks must
+//! report what the model says they should.
+//!
+//! ```text
+//! cargo +nightly fuzz run interval_tracker
+//! ```
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use asan_double_fetch::{MemoryTracker, Span};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Track(u8, u8),
+    TrackMany(Vec<(u8, u8)>),
+    Remove(u8, u8),
+    Check(u8, u8),
+    Clear,
+}
+
+/// Which bytes are tracked
+struct Model([bool; 256]);
+
+impl Model {
+    /// Indices of `[a, a + sz)`, cut short at the end of the address space
+    /// like the tracker's spans
+    fn range(a: u8, sz: u8) -> core::ops::Range<usize> {
+        usize::from(a)..usize::from(a.saturating_add(sz))
+    }
+
+    fn set(&mut self, a: u8, sz: u8, tracked: bool) {
+        for byte in &mut self.0[Self::range(a, sz)] {
+            *byte = tracked;
+        }
+    }
+
+    /// Runs of tracked bytes overlapping `[a, a + sz)`
+    fn runs(&self, a: u8, sz: u8) -> Vec<Span<u8>> {
+        let range = Self::range(a, sz);
+        let mut runs = Vec::new();
+        let mut i = range.start;
+        while i < range.end {
+            if !self.0[i] {
+                i += 1;
+                continue;
+            }
+            let mut start = i;
+            while start > 0 && self.0[start - 1] {
+                start -= 1;
+            }
+            let mut end = i;
+            while end < self.0.len() && self.0[end] {
+                end += 1;
+            }
+            runs.push(Span::new(start as u8, end as u8));
+            i = end;
+        }
+        runs
+    }
+}
+
+fn assert_matches(tracker: &MemoryTracker<u8>, model: &Model) {
+    let redzones: Vec<_> = tracker.redzones().collect();
+    for pair in redzones.windows(2) {
+        let ((a, a_len), (b, _)) = (pair[0], pair[1]);
+        assert!(a + a_len < b, "{:x?} overlap or touch", redzones);
+    }
+
+    let mut covered = [false; 256];
+    for &(start, len) in &redzones {
+        assert!(len > 0, "empty span in {:x?}", redzones);
+        covered[usize::from(start)..usize::from(start) + usize::from(len)]
+            .iter_mut()
+            .for_each(|byte| *byte = true);
+    }
+    assert!(covered == model.0, "tracker holds {:x?}", redzones);
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut tracker: MemoryTracker<u8> = MemoryTracker::default();
+    let mut model = Model([false; 256]);
+
+    for op in ops {
+        match op {
+            Op::Track(a, sz) => {
+                tracker.track_access(a, sz).unwrap();
+                model.set(a, sz, true);
+            }
+            Op::TrackMany(accesses) => {
+                tracker.track_accesses(accesses.iter().copied()).unwrap();
+                for (a, sz) in accesses {
+                    model.set(a, sz, true);
+                }
+            }
+            Op::Remove(a, sz) => {
+                tracker.remove_access(a, sz).unwrap();
+                model.set(a, sz, false);
+            }
+            Op::Check(a, sz) => {
+                let runs = model.runs(a, sz);
+                let conflicts: Vec<_> = tracker.check_all(a, sz).cloned().collect();
+                assert_eq!(conflicts, runs, "check_all({:#x}, {:#x})", a, sz);
+                let expected = match runs.last() {
+                    Some(last) => Err(last.start()),
+                    None => Ok(()),
+                };
+                assert_eq!(tracker.check(a, sz), expected, "check({:#x}, {:#x})", a, sz);
+            }
+            Op::Clear => {
+                tracker.clear();
+                model.0 = [false; 256];
+            }
+        }
+        assert_matches(&tracker, &model);
+    }
+});
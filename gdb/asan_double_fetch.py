@@ -0,0 +1,174 @@
+"""gdb commands for inspecting the double-fetch runtime
+
+Load with `source gdb/asan_double_fetch.py`, preferably under rust-gdb so
+the standard library's pretty printers are importable. Adds:
+
+    adf-regions [max spans]   walk the region table and print every watched
+                              region with its fetched spans; works on core
+                              files, as it only reads memory
+    adf-dump                  call __asan_double_fetch_debug_dump() in the
+                              live target
+
+adf-regions needs debug info for the runtime and understands userspace
+builds without `allocator_api`; kernel and `heapless` builds keep their
+regions in different structures.
+"""
+
+import gdb
+
+try:
+    from gdb_providers import children_of_btree_map
+except ImportError:
+    children_of_btree_map = None
+
+REGIONS = "asan_double_fetch::TRACKED_MEMORY_REGIONS"
+MAX_SPANS = 64
+
+
+def fields(value):
+    return [f for f in value.type.strip_typedefs().fields() if not f.artificial]
+
+
+def variant(value):
+    """Name and contents of the active variant of a Rust enum value
+
+    gdb resolves an enum value's type to the variant it holds, so its only
+    field is that variant.
+    """
+    field = fields(value)[0]
+    return field.name, value[field]
+
+
+def some(option):
+    name, contents = variant(option)
+    return contents["__0"] if name == "Some" else None
+
+
+def non_null(ptr):
+    """The raw pointer in a NonNull or Unique"""
+    ptr = ptr["pointer"]
+    return ptr if ptr.type.code == gdb.TYPE_CODE_PTR else ptr[fields(ptr)[0]]
+
+
+def vec_items(vec):
+    length = int(vec["len"])
+    data = non_null(vec["buf"]["inner"]["ptr"])
+    data = data.reinterpret_cast(vec.type.template_argument(0).pointer())
+    for i in range(length):
+        yield (data + i).dereference()
+
+
+def span(value):
+    value = value["__0"]
+    return int(value["start"]), int(value["end"])
+
+
+def bitmap_spans(bitmap):
+    """Runs of fetched granules in a BitmapTracker"""
+    base = int(bitmap["base"])
+    granule = 1 << int(bitmap["shift"])
+    bits = bitmap["bits"]
+    words = int(bits["len"])
+    if words == 0:
+        return
+
+    data = int(non_null(bits["buf"]["inner"]["ptr"]))
+    raw = bytes(gdb.selected_inferior().read_memory(data, words * 8))
+    order = "big" if "big endian" in gdb.execute("show endian", to_string=True) else "little"
+
+    start = None
+    for w in range(words):
+        word = int.from_bytes(raw[w * 8 : w * 8 + 8], order)
+        for b in range(64):
+            addr = base + (w * 64 + b) * granule
+            if word >> b & 1:
+                if start is None:
+                    start = addr
+            elif start is not None:
+                yield start, addr
+                start = None
+    if start is not None:
+        yield start, base + words * 64 * granule
+
+
+def btree_entries(map):
+    if children_of_btree_map is None:
+        raise gdb.GdbError("gdb_providers not found, run under rust-gdb")
+    for key, value in children_of_btree_map(map):
+        yield key, value
+
+
+def tracker_spans(kind, backend):
+    if kind == "Tree":
+        # MemoryTracker(SpanSet(BTreeSet, PhantomData))
+        for key, _ in btree_entries(backend["__0"]["__0"]["map"]):
+            yield span(key)
+    elif kind == "Bitmap":
+        for run in bitmap_spans(backend):
+            yield run
+    elif kind == "Chunked":
+        for _, chunk in btree_entries(backend["chunks"]):
+            for run in bitmap_spans(chunk["bitmap"]):
+                yield run
+
+
+def regions():
+    symbol = gdb.lookup_static_symbol(REGIONS) or gdb.lookup_global_symbol(REGIONS)
+    if symbol is None:
+        raise gdb.GdbError("{} not found, is the runtime built with debug info?".format(REGIONS))
+
+    # OnceCell(imp::OnceCell { queue, value: UnsafeCell<Option<SwapList>> })
+    cell = symbol.value()["__0"]["value"]["value"]
+    swap_list = some(cell)
+    if swap_list is None:
+        return []
+
+    # ArcSwap { ptr: AtomicPtr<Vec>, .. }, the pointer into the published Arc
+    vec = swap_list["current"]["ptr"]["p"]["value"].dereference()
+    return list(vec_items(vec))
+
+
+class Regions(gdb.Command):
+    """Print watched regions and their fetched spans: adf-regions [max spans]"""
+
+    def __init__(self):
+        super().__init__("adf-regions", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        max_spans = int(arg) if arg.strip() else MAX_SPANS
+        table = regions()
+        print("{} watched region{}".format(len(table), "" if len(table) == 1 else "s"))
+
+        for n, entry in enumerate(table):
+            start, end = span(entry["__0"])
+            # Arc<RwLock<RegionTracker>>
+            tracker = non_null(entry["__1"]["ptr"])["data"]["data"]["value"]
+            kind, backend = variant(tracker["backend"])
+            backend = backend["__0"]
+            print(
+                "#{} {:#x}..{:#x} len={:#x}, {} backend, granularity {:#x}".format(
+                    n, start, end, end - start, kind.lower(), int(tracker["granularity"])
+                )
+            )
+
+            shown = 0
+            for span_start, span_end in tracker_spans(kind, backend):
+                if shown == max_spans:
+                    print("    ...")
+                    break
+                print("    {:#x}..{:#x} +{:#x}".format(span_start, span_end, span_start - start))
+                shown += 1
+
+
+class Dump(gdb.Command):
+    """Print the region table from the live target: adf-dump"""
+
+    def __init__(self):
+        super().__init__("adf-dump", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        gdb.execute("call __asan_double_fetch_debug_dump()")
+
+
+Regions()
+Dump()
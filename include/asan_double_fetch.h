@@ -0,0 +1,953 @@
+/*
+ * asan-double-fetch: runtime entry points
+ *
+ * Generated from the runtime's sources by build.rs, do not edit. Link
+ * against libasan_double_fetch.so or libasan_double_fetch.a.
+ *
+ * Entry points of optional features are declared if the matching
+ * ASAN_DOUBLE_FETCH_<FEATURE> macro is defined, e.g. ASAN_DOUBLE_FETCH_DBI
+ * for a runtime built with `--features dbi`.
+ *
+ * A runtime built with `--features prefixed_symbols` exports each entry
+ * point as adf_<name>_v<ABI version> instead, e.g. adf_check_v1 for
+ * __asan_double_fetch_check. Define ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS to
+ * link against it; the __asan_ names remain usable as macros for the
+ * prefixed ones, unless ASAN_DOUBLE_FETCH_NO_ASAN_ALIASES is defined too.
+ */
+
+#ifndef ASAN_DOUBLE_FETCH_H
+#define ASAN_DOUBLE_FETCH_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#if defined(__unix__)
+#include <sys/types.h>
+#include <sys/uio.h>
+#endif
+
+/* Version of the contract described in this header */
+#define ASAN_DOUBLE_FETCH_ABI_VERSION 1
+
+#if defined(ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS)
+#define __asan_dbi_abi_version adf_dbi_abi_version_v1
+#define __asan_dbi_check_pc adf_dbi_check_pc_v1
+#define __asan_dbi_init adf_dbi_init_v1
+#define __asan_dbi_set_report_callback adf_dbi_set_report_callback_v1
+#define __asan_dbi_unwatch adf_dbi_unwatch_v1
+#define __asan_dbi_watch adf_dbi_watch_v1
+#define __asan_double_fetch_abi_version adf_abi_version_v1
+#define __asan_double_fetch_after_fork adf_after_fork_v1
+#define __asan_double_fetch_canary_scan adf_canary_scan_v1
+#define __asan_double_fetch_check adf_check_v1
+#define __asan_double_fetch_check1 adf_check1_v1
+#define __asan_double_fetch_check16 adf_check16_v1
+#define __asan_double_fetch_check2 adf_check2_v1
+#define __asan_double_fetch_check4 adf_check4_v1
+#define __asan_double_fetch_check8 adf_check8_v1
+#define __asan_double_fetch_check_signal_safe adf_check_signal_safe_v1
+#define __asan_double_fetch_debug_dump adf_debug_dump_v1
+#define __asan_double_fetch_drain_reports adf_drain_reports_v1
+#define __asan_double_fetch_fetch_pair_counters adf_fetch_pair_counters_v1
+#define __asan_double_fetch_first_fetched adf_first_fetched_v1
+#define __asan_double_fetch_get_region_stats adf_get_region_stats_v1
+#define __asan_double_fetch_get_stats adf_get_stats_v1
+#define __asan_double_fetch_group_add adf_group_add_v1
+#define __asan_double_fetch_group_reset adf_group_reset_v1
+#define __asan_double_fetch_memcpy adf_memcpy_v1
+#define __asan_double_fetch_memmove adf_memmove_v1
+#define __asan_double_fetch_memset adf_memset_v1
+#define __asan_double_fetch_name_region adf_name_region_v1
+#define __asan_double_fetch_pread adf_pread_v1
+#define __asan_double_fetch_prepare_exec adf_prepare_exec_v1
+#define __asan_double_fetch_print_heatmap adf_print_heatmap_v1
+#define __asan_double_fetch_process_vm_readv adf_process_vm_readv_v1
+#define __asan_double_fetch_read adf_read_v1
+#define __asan_double_fetch_region_coverage adf_region_coverage_v1
+#define __asan_double_fetch_reset_stats adf_reset_stats_v1
+#define __asan_double_fetch_set_access_hook adf_set_access_hook_v1
+#define __asan_double_fetch_set_allocator adf_set_allocator_v1
+#define __asan_double_fetch_set_mutation_hook adf_set_mutation_hook_v1
+#define __asan_double_fetch_set_print_hook adf_set_print_hook_v1
+#define __asan_double_fetch_strcpy adf_strcpy_v1
+#define __asan_double_fetch_strlen adf_strlen_v1
+#define __asan_double_fetch_strnlen adf_strnlen_v1
+#define __asan_double_fetch_write_graph adf_write_graph_v1
+#define __asan_double_fetch_write_metrics adf_write_metrics_v1
+#define __asan_frida_check adf_frida_check_v1
+#define __asan_frida_init adf_frida_init_v1
+#define __asan_frida_unwatch adf_frida_unwatch_v1
+#define __asan_frida_watch adf_frida_watch_v1
+#define __asan_hw_unwatch_region adf_hw_unwatch_region_v1
+#define __asan_hw_watch_region adf_hw_watch_region_v1
+#define __asan_hw_window_end adf_hw_window_end_v1
+#define __asan_mpk_enter_thread adf_mpk_enter_thread_v1
+#define __asan_mpk_unwatch_region adf_mpk_unwatch_region_v1
+#define __asan_mpk_watch_region adf_mpk_watch_region_v1
+#define __asan_qemu_mem_access adf_qemu_mem_access_v1
+#define __asan_qemu_reset_window adf_qemu_reset_window_v1
+#define __asan_qemu_unwatch_gpa adf_qemu_unwatch_gpa_v1
+#define __asan_qemu_watch_gpa adf_qemu_watch_gpa_v1
+#define __asan_shared_memory_region_init adf_shared_memory_region_init_v1
+#define __asan_shared_memory_region_shutdown adf_shared_memory_region_shutdown_v1
+#define __asan_trap_reset_window adf_trap_reset_window_v1
+#define __asan_trap_unwatch_region adf_trap_unwatch_region_v1
+#define __asan_trap_watch_region adf_trap_watch_region_v1
+#define __asan_uffd_init adf_uffd_init_v1
+#define __asan_uffd_reset_window adf_uffd_reset_window_v1
+#define __asan_uffd_unwatch_region adf_uffd_unwatch_region_v1
+#define __asan_uffd_watch_region adf_uffd_watch_region_v1
+#define __asan_unwatch_shared_memory_region adf_unwatch_shared_memory_region_v1
+#define __asan_valgrind_client_request adf_valgrind_client_request_v1
+#define __asan_watch_shared_memory_region adf_watch_shared_memory_region_v1
+#define __asan_watch_shared_memory_region_granular adf_watch_shared_memory_region_granular_v1
+#define asan_register_shmat adf_register_shmat_v1
+#define asan_remember_shm_id adf_remember_shm_id_v1
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Fast paths for fixed-size accesses, mirroring ASan's __asan_loadN and
+ * __asan_storeN. */
+bool __asan_double_fetch_check1(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check2(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check4(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check8(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check16(uintptr_t addr, bool is_write);
+
+#ifdef __cplusplus
+}
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Detection details passed to the report callback
+ */
+typedef struct asan_dbi_report_t {
+  /**
+   * `size_of::<DbiReport>()`, lets newer clients detect appended fields
+   */
+  uint32_t size;
+  uintptr_t addr;
+  size_t len;
+  /**
+   * PC of the re-fetch, 0 if unknown
+   */
+  uintptr_t pc;
+  uintptr_t region_start;
+  size_t region_len;
+} asan_dbi_report_t;
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_ALLOCATOR_API)
+/**
+ * `alloc(size, align)` callback of [`__asan_double_fetch_set_allocator`]
+ */
+typedef uint8_t *(*asan_double_fetch_alloc_fn_t)(size_t, size_t);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_ALLOCATOR_API)
+/**
+ * `free(ptr, size, align)` callback of [`__asan_double_fetch_set_allocator`]
+ */
+typedef void (*asan_double_fetch_free_fn_t)(uint8_t*, size_t, size_t);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Counters and totals filled in by [`__asan_double_fetch_get_stats`]
+ */
+typedef struct asan_double_fetch_stats_t {
+  /**
+   * Checks made since init or the last reset, except for fixed-size
+   * checks of unwatched memory, which return before counting
+   */
+  uint64_t checks;
+  /**
+   * Of those, checks that hit a watched region
+   */
+  uint64_t region_hits;
+  /**
+   * Double-fetches detected
+   */
+  uint64_t detections;
+  /**
+   * Detections whose bytes were mutated
+   */
+  uint64_t mutations;
+  /**
+   * Regions currently watched
+   */
+  uint64_t watched_regions;
+  /**
+   * Bytes currently tracked as fetched, across all watched regions
+   */
+  uint64_t tracked_bytes;
+} asan_double_fetch_stats_t;
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Statistics of one watched region, filled in by
+ * [`__asan_double_fetch_get_region_stats`]
+ */
+typedef struct asan_double_fetch_region_stats_t {
+  /**
+   * Start of the region
+   */
+  uint64_t start;
+  /**
+   * Length of the region
+   */
+  uint64_t len;
+  /**
+   * Fetches from the region checked since it was watched or the last reset
+   */
+  uint64_t fetches;
+  /**
+   * Of those, double-fetches detected
+   */
+  uint64_t detections;
+  /**
+   * Detections whose bytes were mutated
+   */
+  uint64_t mutations;
+  /**
+   * Bytes of the region currently tracked as fetched
+   */
+  uint64_t tracked_bytes;
+} asan_double_fetch_region_stats_t;
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+typedef void (*asan_dbi_report_callback_t)(const struct asan_dbi_report_t *report, void *user_data);
+#endif
+
+#if (defined(__unix__) && defined(ASAN_DOUBLE_FETCH_FETCH_FEED))
+/**
+ * One fetch, as sent to the `fetch_feed` socket in native byte order.
+ * Offsets are relative to the region, which the attacker maps at an
+ * address of its own.
+ */
+typedef struct asan_double_fetch_fetch_event_t {
+  /**
+   * Size of the event in bytes, lets listeners detect fields appended later
+   */
+  uint32_t size;
+  /**
+   * Process that made the fetch
+   */
+  uint32_t pid;
+  /**
+   * Start of the region in the target's address space, identifying it
+   */
+  uint64_t region_start;
+  uint64_t region_len;
+  /**
+   * Offset of the fetch into the region
+   */
+  uint64_t offset;
+  uint64_t len;
+} asan_double_fetch_fetch_event_t;
+#endif
+
+/**
+ * Mutates the `len` double-fetched bytes at `data`, fetched from `addr`, in
+ * place of the built-in mutator
+ */
+typedef void (*asan_double_fetch_mutation_hook_t)(uintptr_t addr, uint8_t *data, size_t len);
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Receives each line of runtime output, `len` bytes at `line` followed by
+ * a NUL, in place of stdout
+ */
+typedef void (*asan_double_fetch_print_hook_t)(const char *line, size_t len);
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+void asan_remember_shm_id(int id, size_t size);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+void asan_register_shmat(int id, void *addr);
+#endif
+
+/**
+ * Initializes the runtime. Calling this again, including after
+ * [`__asan_shared_memory_region_shutdown`], does nothing. Entry points
+ * reached before this was called either initialize the runtime themselves
+ * (watching a region) or do nothing (checks, unwatching), since nothing can
+ * be tracked yet.
+ */
+void __asan_shared_memory_region_init(void);
+
+/**
+ * Flushes pending reports and output, prints a summary, and forgets all
+ * watched regions, remembered shm ids and detection counts so the runtime
+ * can be cycled between fuzzing iterations. The runtime stays initialized,
+ * so regions may be watched again right away.
+ */
+void __asan_shared_memory_region_shutdown(void);
+
+/**
+ * Creates a new memory tracker for the given address + its size
+ *
+ * A region overlapping watched ones is merged with them into one region,
+ * or not watched with the `overlap=reject` option. Returns 0, or -1 if the
+ * region isn't watched, including when `addr` is null, `len` is 0 or the
+ * region would wrap around the address space.
+ */
+int __asan_watch_shared_memory_region(uintptr_t addr, size_t len);
+
+/**
+ * Like [`__asan_watch_shared_memory_region`], but tracks the region in
+ * `granularity`-sized aligned granules instead of the `granularity` option.
+ * `granularity` must be a power of two; 0 uses the option. Kernel builds
+ * always track single bytes.
+ */
+int __asan_watch_shared_memory_region_granular(uintptr_t addr, size_t len, size_t granularity);
+
+/**
+ * Destroys the memory tracker of the watched region containing `addr`.
+ * Returns 0, or -1 if no watched region contains `addr`.
+ */
+int __asan_unwatch_shared_memory_region(uintptr_t addr);
+
+bool __asan_double_fetch_check(uintptr_t addr, size_t len, bool is_write);
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Fraction of the watched region containing `addr` that has been fetched
+ * since it was watched, or -1 if `addr` isn't in a watched region
+ */
+double __asan_double_fetch_region_coverage(uintptr_t addr);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Calls `hook` with every access to the watched region containing `addr`
+ * from now on, or stops calling the region's hook if `hook` is null.
+ * Returns 0, or -1 if no watched region contains `addr`.
+ */
+int __asan_double_fetch_set_access_hook(uintptr_t addr, void (*hook)(uintptr_t addr,
+                                                                     size_t len,
+                                                                     bool is_write,
+                                                                     uintptr_t pc));
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Searches `[addr, addr + len)` for canaries planted by `mutation=canary`,
+ * e.g. a structure parsed from a watched region, and reports where each one
+ * turned up. Returns how many were found.
+ *
+ * # Safety
+ *
+ * `[addr, addr + len)` must be readable.
+ */
+size_t __asan_double_fetch_canary_scan(uintptr_t addr, size_t len);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Returns [`DBI_ABI_VERSION`]; clients should refuse to run on a mismatch
+ */
+uint32_t __asan_dbi_abi_version(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Initializes the runtime if it isn't already. Returns 0, or -1 if that
+ * failed.
+ */
+int __asan_dbi_init(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Watches `[addr, addr + len)`. Returns 0, or -1 if the region isn't
+ * watched, as [`crate::__asan_watch_shared_memory_region`] does.
+ */
+int __asan_dbi_watch(uintptr_t addr, size_t len);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Stops watching the region containing `addr`. Returns 0, or -1 if no
+ * watched region contains it.
+ */
+int __asan_dbi_unwatch(uintptr_t addr);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Checks an access made by the instruction at `pc`
+ */
+int __asan_dbi_check_pc(uintptr_t addr, size_t len, int is_write, uintptr_t pc);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_DBI)
+/**
+ * Registers `callback` to be invoked for every detection, replacing any
+ * previous one. Pass a null callback to unregister.
+ */
+void __asan_dbi_set_report_callback(void (*callback)(const struct asan_dbi_report_t *report,
+                                                     void *user_data), void *user_data);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Prints every watched region with its backend and fetched spans, for
+ * calling from a debugger. Output bypasses the `verbosity` option and any
+ * logger the host installed.
+ */
+void __asan_double_fetch_debug_dump(void);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Writes the fetch graph recorded so far to the file at `path`. Returns 0,
+ * or -1 on failure. Nothing is recorded unless the `dot_file` option is
+ * set.
+ *
+ * # Safety
+ *
+ * `path` must be a valid NUL-terminated string.
+ */
+int __asan_double_fetch_write_graph(const char *path);
+#endif
+
+#if (defined(__linux__) && !defined(ASAN_DOUBLE_FETCH_NO_STD))
+/**
+ * Hands the SysV shm segments remembered from `shmget()` off to the image
+ * about to be exec'd; other watched regions aren't handed off and have to
+ * be watched again after the exec. Returns the memfd holding them, to be
+ * passed in
+ * `ASAN_DOUBLE_FETCH_HANDOFF_FD` in the environment the launcher execs
+ * with, or -1 on failure. Later calls reuse the same memfd.
+ */
+int __asan_double_fetch_prepare_exec(void);
+#endif
+
+/**
+ * Returns [`ABI_VERSION`]; callers built against a header with another
+ * `ASAN_DOUBLE_FETCH_ABI_VERSION` should refuse to run
+ */
+uint32_t __asan_double_fetch_abi_version(void);
+
+#if (defined(__unix__) && !defined(ASAN_DOUBLE_FETCH_NO_STD))
+/**
+ * Applies the `fork` option to the regions inherited from the parent. Runs
+ * automatically in children of `fork()`; call it from children created by
+ * a raw `clone` without `CLONE_VM`.
+ */
+void __asan_double_fetch_after_fork(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_FRIDA)
+/**
+ * Initializes the runtime if it isn't already. Safe to call any number of
+ * times; returns 0, or -1 if initialization failed.
+ */
+int __asan_frida_init(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_FRIDA)
+/**
+ * Watches `[addr, addr + len)`. Returns 0, or -1 if the region isn't
+ * watched, as [`crate::__asan_watch_shared_memory_region`] does.
+ */
+int __asan_frida_watch(uintptr_t addr, size_t len);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_FRIDA)
+/**
+ * Stops watching the region containing `addr`. Returns 0, or -1 if no
+ * watched region contains it.
+ */
+int __asan_frida_unwatch(uintptr_t addr);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_FRIDA)
+/**
+ * Stalker callout target. Returns 1 if the access was to a watched region,
+ * so an agent can stop instrumenting code that never touches one.
+ */
+int __asan_frida_check(uintptr_t addr, size_t len, int is_write);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Adds the watched region containing `addr` to the group called `name`,
+ * creating the group if needed. A region is in one group at most, so this
+ * moves it out of any other. Returns 0, or -1 if `name` is invalid or no
+ * watched region contains `addr`.
+ *
+ * # Safety
+ *
+ * `name` must be a valid NUL-terminated string.
+ */
+int __asan_double_fetch_group_add(const char *name, uintptr_t addr);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Forgets every fetch from the regions in the group called `name`, keeping
+ * them watched, e.g. once the request spanning them has been handled.
+ * Returns how many regions were reset, or -1 if there's no such group.
+ *
+ * # Safety
+ *
+ * `name` must be a valid NUL-terminated string.
+ */
+int __asan_double_fetch_group_reset(const char *name);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Prints the heat map of the watched region containing `addr`. Returns 0,
+ * or -1 if `addr` isn't in a watched region or heat maps are disabled.
+ */
+int __asan_double_fetch_print_heatmap(uintptr_t addr);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_HW_WATCHPOINT))
+/**
+ * Watches a small region with hardware breakpoints.
+ *
+ * Returns 0 on success and -1 if the region needs more debug registers than
+ * are free or the kernel refuses the breakpoint.
+ */
+int __asan_hw_watch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_HW_WATCHPOINT))
+/**
+ * Removes all hardware watchpoints covering `[addr, addr + len)`
+ */
+void __asan_hw_unwatch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_HW_WATCHPOINT))
+/**
+ * Ends the current window: reports every watched word that was read more
+ * than once since the last call, then resets the counters. Writes don't
+ * count, so a word written and then read once isn't reported.
+ *
+ * Returns the number of double fetches found.
+ */
+size_t __asan_hw_window_end(void);
+#endif
+
+/**
+ * `memcpy` that checks `src` as one fetch of `n` bytes and `dst` as a write
+ */
+void *__asan_double_fetch_memcpy(void *dst, const void *src, size_t n);
+
+/**
+ * `memmove` that checks `src` as one fetch of `n` bytes and `dst` as a write
+ */
+void *__asan_double_fetch_memmove(void *dst, const void *src, size_t n);
+
+/**
+ * `memset` that checks `dst` as a write of `n` bytes
+ */
+void *__asan_double_fetch_memset(void *dst, int32_t c, size_t n);
+
+/**
+ * `strlen` that checks the scanned bytes, including the terminator, as one
+ * fetch. A later copy of the same string is then a re-fetch, catching the
+ * canonical length-then-copy pattern where the string grows in between.
+ */
+size_t __asan_double_fetch_strlen(const char *s);
+
+/**
+ * `strnlen` that checks the scanned bytes as one fetch. The terminator is
+ * only part of the fetch if it was found within `maxlen` bytes.
+ */
+size_t __asan_double_fetch_strnlen(const char *s, size_t maxlen);
+
+/**
+ * `strcpy` that checks `src`, including the terminator, as one fetch and
+ * the bytes written to `dst` as a write
+ */
+char *__asan_double_fetch_strcpy(char *dst, const char *src);
+
+#if (defined(__unix__) && defined(ASAN_DOUBLE_FETCH_SYSCALL_INTERCEPTORS))
+/**
+ * `read` that checks the bytes the kernel stored into `buf` as a write
+ */
+ptrdiff_t __asan_double_fetch_read(int fd, void *buf, size_t count);
+#endif
+
+#if (defined(__unix__) && defined(ASAN_DOUBLE_FETCH_SYSCALL_INTERCEPTORS))
+/**
+ * `pread` that checks the bytes the kernel stored into `buf` as a write
+ */
+ptrdiff_t __asan_double_fetch_pread(int fd, void *buf, size_t count, off_t offset);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_SYSCALL_INTERCEPTORS))
+/**
+ * `process_vm_readv` that checks the transferred bytes of `local_iov` as
+ * writes. When `pid` is the calling process the remote ranges live in our
+ * own address space, so the bytes copied out of them are checked as fetches.
+ */
+ptrdiff_t __asan_double_fetch_process_vm_readv(pid_t pid,
+                                               const struct iovec *local_iov,
+                                               unsigned long liovcnt,
+                                               const struct iovec *remote_iov,
+                                               unsigned long riovcnt,
+                                               unsigned long flags);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPK))
+/**
+ * Tags a watched region with the write-detection protection key.
+ *
+ * `addr` and `len` must cover whole pages. Returns 0 on success and -1 if
+ * protection keys are unsupported, the region is trapped by
+ * `mprotect_trap`, or `pkey_mprotect` fails.
+ */
+int __asan_mpk_watch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPK))
+/**
+ * Returns a region to the default protection key
+ */
+int __asan_mpk_unwatch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPK))
+/**
+ * Disables writes to watched regions on the calling thread. Only needed for
+ * threads that already existed when the first region was watched.
+ *
+ * Such threads start out with the kernel's default PKRU, which denies all
+ * access to a freshly allocated key, so reads are re-enabled here as well.
+ */
+void __asan_mpk_enter_thread(void);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPROTECT_TRAP))
+/**
+ * Watches a region in trap mode, protecting all of its pages.
+ *
+ * The region must be page aligned or share its pages only with memory that
+ * is itself safe to trap on. Returns 0 on success, -1 if its pages aren't
+ * all mapped, it is tagged by the `mpk` mode, or `mprotect` fails.
+ */
+int __asan_trap_watch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPROTECT_TRAP))
+/**
+ * Stops watching the trap-mode region containing `addr` and restores the
+ * protection its pages had before
+ */
+int __asan_trap_unwatch_region(uintptr_t addr);
+#endif
+
+#if (defined(__linux__) && defined(__x86_64__) && defined(ASAN_DOUBLE_FETCH_MPROTECT_TRAP))
+/**
+ * Forgets all trapped fetches, starting a new detection window
+ */
+void __asan_trap_reset_window(void);
+#endif
+
+/**
+ * Mutates double-fetched bytes with `hook` instead of the built-in mutator,
+ * or with the built-in one again if `hook` is null. Planned mutations, see
+ * the `mutation_plan` option, are still replayed as planned.
+ */
+void __asan_double_fetch_set_mutation_hook(void (*hook)(uintptr_t addr, uint8_t *data, size_t len));
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Sends the runtime's output to `hook` instead of stdout, or back to stdout
+ * if `hook` is null
+ */
+void __asan_double_fetch_set_print_hook(void (*hook)(const char *line, size_t len));
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_PROMETHEUS)
+/**
+ * Writes the current metrics to the file at `path`. Returns 0, or -1 on
+ * failure.
+ *
+ * # Safety
+ *
+ * `path` must be a valid NUL-terminated string.
+ */
+int __asan_double_fetch_write_metrics(const char *path);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_QEMU)
+/**
+ * Watches `[gpa, gpa + len)` in guest-physical memory
+ */
+void __asan_qemu_watch_gpa(uint64_t gpa, uint64_t len);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_QEMU)
+/**
+ * Stops watching the guest-physical region containing `gpa`
+ */
+void __asan_qemu_unwatch_gpa(uint64_t gpa);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_QEMU)
+/**
+ * Forgets all fetches in every guest region, starting a new window
+ */
+void __asan_qemu_reset_window(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_QEMU)
+/**
+ * Records a guest memory access made by `vcpu` at guest virtual `pc`.
+ *
+ * Returns 1 if the access was a double fetch, 0 otherwise.
+ */
+int __asan_qemu_mem_access(uint32_t vcpu, uint64_t gpa, uint32_t len, int is_write, uint64_t pc);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Names the watched region containing `addr` `name` in reports, in place of
+ * `region<N>`. Returns 0, or -1 if `name` is invalid or empty or no watched
+ * region contains `addr`.
+ *
+ * # Safety
+ *
+ * `name` must be a valid NUL-terminated string.
+ */
+int __asan_double_fetch_name_region(uintptr_t addr, const char *name);
+#endif
+
+/**
+ * Prints all pending detections and returns how many there were. Call this
+ * periodically from a context that may allocate and block.
+ */
+size_t __asan_double_fetch_drain_reports(void);
+
+#if defined(ASAN_DOUBLE_FETCH_ALLOCATOR_API)
+/**
+ * Routes the runtime's allocations to `alloc`/`free`, e.g. an arena kept
+ * apart from the target's heap. Must be called before
+ * `__asan_shared_memory_region_init`; returns 0 on success and -1 if the
+ * allocator was already fixed.
+ */
+int __asan_double_fetch_set_allocator(asan_double_fetch_alloc_fn_t alloc,
+                                      asan_double_fetch_free_fn_t free);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_SANCOV)
+/**
+ * Returns the fetch-site pair counters and stores their number to `len`,
+ * for fuzzers that don't pick up `__libfuzzer_extra_counters`
+ *
+ * # Safety
+ *
+ * `len` must be null or valid for writes.
+ */
+const uint8_t *__asan_double_fetch_fetch_pair_counters(size_t *len);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * [`__asan_double_fetch_check`](crate::__asan_double_fetch_check) for use
+ * inside signal handlers
+ */
+bool __asan_double_fetch_check_signal_safe(uintptr_t addr, size_t len, bool is_write);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Copies the `len` bytes at `addr`, as they were when first fetched, to
+ * `out`. Returns 0, or -1 if `out` is null, the bytes aren't all in one
+ * watched region and fetched from it before, or the `reporting` option is
+ * `strict`, which keeps no copies.
+ *
+ * Not to be called from a mutation hook, which runs with the region's
+ * tracker locked.
+ *
+ * # Safety
+ *
+ * `out` must be null or valid for writing `len` bytes.
+ */
+int __asan_double_fetch_first_fetched(uintptr_t addr, size_t len, uint8_t *out);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Fills in `*out` with the current statistics. Returns 0, or -1 if `out` is
+ * null.
+ *
+ * # Safety
+ *
+ * `out` must be null or valid for writing a `Stats`.
+ */
+int __asan_double_fetch_get_stats(struct asan_double_fetch_stats_t *out);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Fills in `*out` with the statistics of the watched region containing
+ * `addr`. Returns 0, or -1 if `out` is null or no watched region contains
+ * `addr`.
+ *
+ * # Safety
+ *
+ * `out` must be null or valid for writing a `RegionStats`.
+ */
+int __asan_double_fetch_get_region_stats(uintptr_t addr,
+                                         struct asan_double_fetch_region_stats_t *out);
+#endif
+
+#if !defined(ASAN_DOUBLE_FETCH_NO_STD)
+/**
+ * Zeroes the counters, the global ones and those of every watched region.
+ * What is currently tracked is left alone.
+ */
+void __asan_double_fetch_reset_stats(void);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_USERFAULTFD))
+/**
+ * Opens the userfaultfd and starts the fault handler thread.
+ *
+ * Returns 0 on success and -1 if userfaultfd is unavailable (e.g. the
+ * `vm.unprivileged_userfaultfd` sysctl forbids it).
+ */
+int __asan_uffd_init(void);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_USERFAULTFD))
+/**
+ * Watches a shmem-backed region through userfaultfd
+ */
+int __asan_uffd_watch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_USERFAULTFD))
+/**
+ * Stops watching a region previously passed to [`__asan_uffd_watch_region`]
+ */
+int __asan_uffd_unwatch_region(uintptr_t addr, size_t len);
+#endif
+
+#if (defined(__linux__) && defined(ASAN_DOUBLE_FETCH_USERFAULTFD))
+/**
+ * Forgets all page fetches and re-arms every watched page, starting a new
+ * detection window
+ */
+int __asan_uffd_reset_window(void);
+#endif
+
+#if defined(ASAN_DOUBLE_FETCH_VALGRIND)
+/**
+ * Handles a client request, returning `default` for anything that isn't
+ * ours, exactly like running natively without Valgrind. Watching and
+ * unwatching return 0, or `usize::MAX` (-1) on failure; checks return
+ * whether the access was to a watched region.
+ */
+size_t __asan_valgrind_client_request(size_t default_,
+                                      size_t request,
+                                      size_t arg1,
+                                      size_t arg2,
+                                      size_t arg3,
+                                      size_t _arg4,
+                                      size_t _arg5);
+#endif
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+
+#if defined(ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS) && defined(ASAN_DOUBLE_FETCH_NO_ASAN_ALIASES)
+#undef __asan_dbi_abi_version
+#undef __asan_dbi_check_pc
+#undef __asan_dbi_init
+#undef __asan_dbi_set_report_callback
+#undef __asan_dbi_unwatch
+#undef __asan_dbi_watch
+#undef __asan_double_fetch_abi_version
+#undef __asan_double_fetch_after_fork
+#undef __asan_double_fetch_canary_scan
+#undef __asan_double_fetch_check
+#undef __asan_double_fetch_check1
+#undef __asan_double_fetch_check16
+#undef __asan_double_fetch_check2
+#undef __asan_double_fetch_check4
+#undef __asan_double_fetch_check8
+#undef __asan_double_fetch_check_signal_safe
+#undef __asan_double_fetch_debug_dump
+#undef __asan_double_fetch_drain_reports
+#undef __asan_double_fetch_fetch_pair_counters
+#undef __asan_double_fetch_first_fetched
+#undef __asan_double_fetch_get_region_stats
+#undef __asan_double_fetch_get_stats
+#undef __asan_double_fetch_group_add
+#undef __asan_double_fetch_group_reset
+#undef __asan_double_fetch_memcpy
+#undef __asan_double_fetch_memmove
+#undef __asan_double_fetch_memset
+#undef __asan_double_fetch_name_region
+#undef __asan_double_fetch_pread
+#undef __asan_double_fetch_prepare_exec
+#undef __asan_double_fetch_print_heatmap
+#undef __asan_double_fetch_process_vm_readv
+#undef __asan_double_fetch_read
+#undef __asan_double_fetch_region_coverage
+#undef __asan_double_fetch_reset_stats
+#undef __asan_double_fetch_set_access_hook
+#undef __asan_double_fetch_set_allocator
+#undef __asan_double_fetch_set_mutation_hook
+#undef __asan_double_fetch_set_print_hook
+#undef __asan_double_fetch_strcpy
+#undef __asan_double_fetch_strlen
+#undef __asan_double_fetch_strnlen
+#undef __asan_double_fetch_write_graph
+#undef __asan_double_fetch_write_metrics
+#undef __asan_frida_check
+#undef __asan_frida_init
+#undef __asan_frida_unwatch
+#undef __asan_frida_watch
+#undef __asan_hw_unwatch_region
+#undef __asan_hw_watch_region
+#undef __asan_hw_window_end
+#undef __asan_mpk_enter_thread
+#undef __asan_mpk_unwatch_region
+#undef __asan_mpk_watch_region
+#undef __asan_qemu_mem_access
+#undef __asan_qemu_reset_window
+#undef __asan_qemu_unwatch_gpa
+#undef __asan_qemu_watch_gpa
+#undef __asan_shared_memory_region_init
+#undef __asan_shared_memory_region_shutdown
+#undef __asan_trap_reset_window
+#undef __asan_trap_unwatch_region
+#undef __asan_trap_watch_region
+#undef __asan_uffd_init
+#undef __asan_uffd_reset_window
+#undef __asan_uffd_unwatch_region
+#undef __asan_uffd_watch_region
+#undef __asan_unwatch_shared_memory_region
+#undef __asan_valgrind_client_request
+#undef __asan_watch_shared_memory_region
+#undef __asan_watch_shared_memory_region_granular
+#undef asan_register_shmat
+#undef asan_remember_shm_id
+#endif
+
+#endif  /* ASAN_DOUBLE_FETCH_H */
@@ -0,0 +1,105 @@
+/*
+ * asan-double-fetch: C++ RAII wrappers
+ *
+ * Header-only scoping over the entry points in asan_double_fetch.h, so a
+ * harness doesn't need hand-written cleanup on every path out of a test:
+ *
+ *     {
+ *         df::Window window;
+ *         df::WatchGuard region(shm, shm_len);
+ *         run_target(shm);
+ *     } // unwatched, reports flushed, detection counts reset
+ *
+ * Requires C++11. The entry points never throw, and neither do these.
+ */
+
+#ifndef ASAN_DOUBLE_FETCH_HPP
+#define ASAN_DOUBLE_FETCH_HPP
+
+#include <cstddef>
+#include <cstdint>
+
+#include "asan_double_fetch.h"
+
+#if defined(ASAN_DOUBLE_FETCH_PREFIXED_SYMBOLS) && defined(ASAN_DOUBLE_FETCH_NO_ASAN_ALIASES)
+#error "asan_double_fetch.hpp calls the runtime by its __asan_ names, don't define ASAN_DOUBLE_FETCH_NO_ASAN_ALIASES"
+#endif
+
+namespace df {
+
+/* Watches a region for as long as it lives. Moving it hands the region
+ * over; release() keeps it watched past the guard. */
+class WatchGuard {
+public:
+    /* Watches [addr, addr + len), in granules of `granularity` bytes, which
+     * must be a power of two; 0 uses the `granularity` option */
+    WatchGuard(std::uintptr_t addr, std::size_t len, std::size_t granularity = 0) noexcept
+        : addr_(addr), watched_(true) {
+        __asan_watch_shared_memory_region_granular(addr, len, granularity);
+    }
+
+    WatchGuard(const volatile void *addr, std::size_t len, std::size_t granularity = 0) noexcept
+        : WatchGuard(reinterpret_cast<std::uintptr_t>(addr), len, granularity) {}
+
+    ~WatchGuard() { reset(); }
+
+    WatchGuard(const WatchGuard &) = delete;
+    WatchGuard &operator=(const WatchGuard &) = delete;
+
+    WatchGuard(WatchGuard &&other) noexcept : addr_(other.addr_), watched_(other.watched_) {
+        other.watched_ = false;
+    }
+
+    WatchGuard &operator=(WatchGuard &&other) noexcept {
+        if (this != &other) {
+            reset();
+            addr_ = other.addr_;
+            watched_ = other.watched_;
+            other.watched_ = false;
+        }
+        return *this;
+    }
+
+    /* Start of the watched region */
+    std::uintptr_t addr() const noexcept { return addr_; }
+
+    /* Whether the guard still unwatches the region when it goes away */
+    bool watched() const noexcept { return watched_; }
+
+    /* Stops watching the region now */
+    void reset() noexcept {
+        if (watched_) {
+            __asan_unwatch_shared_memory_region(addr_);
+            watched_ = false;
+        }
+    }
+
+    /* Leaves the region watched when the guard goes away, returning its start */
+    std::uintptr_t release() noexcept {
+        watched_ = false;
+        return addr_;
+    }
+
+private:
+    std::uintptr_t addr_;
+    bool watched_;
+};
+
+/* One detection window, e.g. a fuzzing iteration. Initializes the runtime
+ * if it isn't already, and on the way out flushes pending reports, prints
+ * the summary and forgets every watched region and detection count, see
+ * __asan_shared_memory_region_shutdown. Declare guards inside the window so
+ * they unwatch before it closes. */
+class Window {
+public:
+    Window() noexcept { __asan_shared_memory_region_init(); }
+
+    ~Window() { __asan_shared_memory_region_shutdown(); }
+
+    Window(const Window &) = delete;
+    Window &operator=(const Window &) = delete;
+};
+
+} // namespace df
+
+#endif /* ASAN_DOUBLE_FETCH_HPP */
@@ -0,0 +1,22 @@
+/*
+ * asan-double-fetch: entry points for dynamic binary instrumentation clients
+ *
+ * Link against the runtime built with `--features dbi`. All functions are
+ * thread safe and may be called before any explicit initialization. They
+ * are declared, along with asan_dbi_report_t and the report callback, in
+ * asan_double_fetch.h.
+ */
+
+#ifndef ASAN_DOUBLE_FETCH_DBI_H
+#define ASAN_DOUBLE_FETCH_DBI_H
+
+#ifndef ASAN_DOUBLE_FETCH_DBI
+#define ASAN_DOUBLE_FETCH_DBI 1
+#endif
+
+#include "asan_double_fetch.h"
+
+/* Version of the DBI part of the contract */
+#define ASAN_DBI_ABI_VERSION 1
+
+#endif /* ASAN_DOUBLE_FETCH_DBI_H */
@@ -0,0 +1,53 @@
+/*
+ * asan-double-fetch: kernel copy-from-user hooks
+ *
+ * Include this after <linux/uaccess.h> in the translation units to be
+ * checked (or force-include it with `-include`) when linking against the
+ * runtime built with `--features linux_kasan`. Every copy_from_user,
+ * __copy_from_user_inatomic, get_user, strnlen_user and strncpy_from_user
+ * then tracks the user range for the rest of the current task's syscall and
+ * reports bytes the task copies twice, as well as string copies whose length
+ * no longer matches an earlier strnlen_user.
+ *
+ * Call __asan_double_fetch_syscall_exit() from the syscall exit path so the
+ * task's fetches are forgotten before it returns to user mode.
+ */
+
+#ifndef ASAN_DOUBLE_FETCH_UACCESS_H
+#define ASAN_DOUBLE_FETCH_UACCESS_H
+
+#include <linux/compiler.h>
+#include <linux/types.h>
+
+unsigned long __asan_double_fetch_copy_from_user(void *to, const void __user *from,
+                                                 unsigned long n);
+unsigned long __asan_double_fetch_copy_from_user_inatomic(void *to, const void __user *from,
+                                                          unsigned long n);
+int __asan_double_fetch_get_user(void *dst, const void __user *from, size_t size);
+long __asan_double_fetch_strnlen_user(const char __user *str, long n);
+long __asan_double_fetch_strncpy_from_user(char *dst, const char __user *src, long count);
+void __asan_double_fetch_syscall_exit(void);
+
+#undef copy_from_user
+#define copy_from_user(to, from, n) __asan_double_fetch_copy_from_user((to), (from), (n))
+
+#undef __copy_from_user_inatomic
+#define __copy_from_user_inatomic(to, from, n) \
+    __asan_double_fetch_copy_from_user_inatomic((to), (from), (n))
+
+#undef get_user
+#define get_user(x, ptr)                                                            \
+    ({                                                                              \
+        __typeof__(*(ptr)) __df_val;                                                \
+        int __df_ret = __asan_double_fetch_get_user(&__df_val, (ptr), sizeof(*(ptr))); \
+        (x) = __df_ret ? (__typeof__(*(ptr)))0 : __df_val;                          \
+        __df_ret;                                                                   \
+    })
+
+#undef strnlen_user
+#define strnlen_user(str, n) __asan_double_fetch_strnlen_user((str), (n))
+
+#undef strncpy_from_user
+#define strncpy_from_user(dst, src, count) __asan_double_fetch_strncpy_from_user((dst), (src), (count))
+
+#endif /* ASAN_DOUBLE_FETCH_UACCESS_H */
@@ -0,0 +1,93 @@
+/*
+ * asan-double-fetch: Valgrind-style client requests
+ *
+ * Annotate targets with the DF_* macros below. By default they are built on
+ * the real <valgrind/valgrind.h>, where they are no-ops both natively and
+ * under Valgrind. Define ASAN_DOUBLE_FETCH_CLIENT_REQUESTS before including
+ * this header (and link the runtime built with `--features valgrind`) to route
+ * every client request, including ones from existing Valgrind annotations,
+ * to the asan-double-fetch runtime instead.
+ */
+
+#ifndef ASAN_DOUBLE_FETCH_VALGRIND_H
+#define ASAN_DOUBLE_FETCH_VALGRIND_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef ASAN_DOUBLE_FETCH_CLIENT_REQUESTS
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+uintptr_t __asan_valgrind_client_request(uintptr_t default_value, uintptr_t request,
+                                         uintptr_t arg1, uintptr_t arg2, uintptr_t arg3,
+                                         uintptr_t arg4, uintptr_t arg5);
+
+#ifdef __cplusplus
+}
+#endif
+
+#define VG_USERREQ_TOOL_BASE(a, b) ((unsigned int)(((a)&0xff) << 24 | ((b)&0xff) << 16))
+
+#define VALGRIND_DO_CLIENT_REQUEST_EXPR(_zzq_default, _zzq_request, _zzq_arg1, _zzq_arg2, \
+                                        _zzq_arg3, _zzq_arg4, _zzq_arg5)                    \
+    __asan_valgrind_client_request(                                                        \
+        (uintptr_t)(_zzq_default), (uintptr_t)(_zzq_request), (uintptr_t)(_zzq_arg1),       \
+        (uintptr_t)(_zzq_arg2), (uintptr_t)(_zzq_arg3), (uintptr_t)(_zzq_arg4),             \
+        (uintptr_t)(_zzq_arg5))
+
+#define VALGRIND_DO_CLIENT_REQUEST(_zzq_rlval, _zzq_default, _zzq_request, _zzq_arg1,      \
+                                   _zzq_arg2, _zzq_arg3, _zzq_arg4, _zzq_arg5)             \
+    do {                                                                                   \
+        (_zzq_rlval) = VALGRIND_DO_CLIENT_REQUEST_EXPR((_zzq_default), (_zzq_request),      \
+                                                       (_zzq_arg1), (_zzq_arg2),            \
+                                                       (_zzq_arg3), (_zzq_arg4),            \
+                                                       (_zzq_arg5));                        \
+    } while (0)
+
+#define VALGRIND_DO_CLIENT_REQUEST_STMT(_zzq_request, _zzq_arg1, _zzq_arg2, _zzq_arg3,     \
+                                        _zzq_arg4, _zzq_arg5)                               \
+    do {                                                                                   \
+        (void)VALGRIND_DO_CLIENT_REQUEST_EXPR(0, (_zzq_request), (_zzq_arg1), (_zzq_arg2),  \
+                                              (_zzq_arg3), (_zzq_arg4), (_zzq_arg5));       \
+    } while (0)
+
+/* we're never Valgrind */
+#define RUNNING_ON_VALGRIND 0
+
+#else
+
+#include <valgrind/valgrind.h>
+
+#endif /* ASAN_DOUBLE_FETCH_CLIENT_REQUESTS */
+
+typedef enum {
+    DF_USERREQ__WATCH = VG_USERREQ_TOOL_BASE('D', 'F'),
+    DF_USERREQ__UNWATCH,
+    DF_USERREQ__CHECK,
+} df_client_request_t;
+
+/*
+ * Watches [addr, addr + len) for double fetches; evaluates to 0, or
+ * (uintptr_t)-1 if the region isn't watched
+ */
+#define DF_WATCH_REGION(addr, len) \
+    VALGRIND_DO_CLIENT_REQUEST_EXPR(0, DF_USERREQ__WATCH, (addr), (len), 0, 0, 0)
+
+/*
+ * Stops watching the region containing addr; evaluates to 0, or (uintptr_t)-1
+ * if no watched region contains addr
+ */
+#define DF_UNWATCH_REGION(addr) \
+    VALGRIND_DO_CLIENT_REQUEST_EXPR(0, DF_USERREQ__UNWATCH, (addr), 0, 0, 0, 0)
+
+/*
+ * Explicitly checks an access; evaluates to nonzero if it hit a watched region.
+ * All three evaluate to 0 without the runtime, natively or under Valgrind.
+ */
+#define DF_CHECK(addr, len, is_write) \
+    VALGRIND_DO_CLIENT_REQUEST_EXPR(0, DF_USERREQ__CHECK, (addr), (len), (is_write), 0, 0)
+
+#endif /* ASAN_DOUBLE_FETCH_VALGRIND_H */
@@ -0,0 +1,65 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+/*
+ * asan-double-fetch: tracepoints
+ *
+ * Every report is also emitted as the asan_double_fetch:df_report tracepoint,
+ * so collectors can stream findings off the box through perf or a BPF ring
+ * buffer instead of scraping dmesg, see examples/bpf. Unlike the dmesg report
+ * the tracepoint isn't rate-limited. `nr` is the syscall the report happened
+ * in, -1 outside one, and `ioctl_cmd` the command if it was an ioctl.
+ */
+
+#undef TRACE_SYSTEM
+#define TRACE_SYSTEM asan_double_fetch
+
+#if !defined(_ASAN_DOUBLE_FETCH_TRACE_H) || defined(TRACE_HEADER_MULTI_READ)
+#define _ASAN_DOUBLE_FETCH_TRACE_H
+
+#include <linux/sched.h>
+#include <linux/tracepoint.h>
+
+/* Longest bug type recorded, including the terminator */
+#define ASAN_DOUBLE_FETCH_BUG_LEN 24
+
+TRACE_EVENT(df_report,
+
+    TP_PROTO(const char *bug, size_t bug_len, unsigned long addr, size_t len,
+             unsigned long pc, long nr, unsigned long ioctl_cmd),
+
+    TP_ARGS(bug, bug_len, addr, len, pc, nr, ioctl_cmd),
+
+    TP_STRUCT__entry(
+        __array(char, bug, ASAN_DOUBLE_FETCH_BUG_LEN)
+        __field(unsigned long, addr)
+        __field(size_t, len)
+        __field(unsigned long, pc)
+        __field(pid_t, pid)
+        __field(long, nr)
+        __field(unsigned long, ioctl_cmd)
+    ),
+
+    TP_fast_assign(
+        /* the runtime's bug types aren't NUL-terminated */
+        bug_len = min_t(size_t, bug_len, ASAN_DOUBLE_FETCH_BUG_LEN - 1);
+        memcpy(__entry->bug, bug, bug_len);
+        __entry->bug[bug_len] = '\0';
+        __entry->addr = addr;
+        __entry->len = len;
+        __entry->pc = pc;
+        __entry->pid = current->pid;
+        __entry->nr = nr;
+        __entry->ioctl_cmd = ioctl_cmd;
+    ),
+
+    TP_printk("%s addr=0x%lx len=%zu pc=0x%lx pid=%d nr=%ld ioctl_cmd=0x%lx", __entry->bug,
+              __entry->addr, __entry->len, __entry->pc, __entry->pid, __entry->nr,
+              __entry->ioctl_cmd)
+);
+
+#endif /* _ASAN_DOUBLE_FETCH_TRACE_H */
+
+#undef TRACE_INCLUDE_PATH
+#define TRACE_INCLUDE_PATH .
+#undef TRACE_INCLUDE_FILE
+#define TRACE_INCLUDE_FILE asan_double_fetch_trace
+#include <trace/define_trace.h>
@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * asan-double-fetch: tracepoint definitions for the kernel runtime
+ *
+ * The Rust staticlib can't expand TRACE_EVENT, so it reports through the
+ * wrapper below.
+ */
+
+#include <linux/types.h>
+
+#define CREATE_TRACE_POINTS
+#include "asan_double_fetch_trace.h"
+
+/* `pc` is 0 if unknown, `nr` -1 outside a syscall */
+void __asan_double_fetch_trace_report(const char *bug, size_t bug_len, unsigned long addr,
+                                      size_t len, unsigned long pc, long nr,
+                                      unsigned long ioctl_cmd)
+{
+    trace_df_report(bug, bug_len, addr, len, pc, nr, ioctl_cmd);
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * asan-double-fetch: symbol exports for the kernel runtime
+ *
+ * The Rust staticlib can't emit __ksymtab entries itself, so the entry points
+ * modules may call are exported from here.
+ */
+
+#include <linux/export.h>
+#include <linux/types.h>
+
+void __asan_shared_memory_region_init(void);
+int __asan_watch_shared_memory_region(uintptr_t addr, size_t len);
+int __asan_unwatch_shared_memory_region(uintptr_t addr);
+bool __asan_double_fetch_check(uintptr_t addr, size_t len, bool is_write);
+bool __asan_double_fetch_check1(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check2(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check4(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check8(uintptr_t addr, bool is_write);
+bool __asan_double_fetch_check16(uintptr_t addr, bool is_write);
+
+void *__asan_double_fetch_memcpy(void *dst, const void *src, size_t n);
+void *__asan_double_fetch_memmove(void *dst, const void *src, size_t n);
+void *__asan_double_fetch_memset(void *dst, int c, size_t n);
+size_t __asan_double_fetch_strlen(const char *s);
+size_t __asan_double_fetch_strnlen(const char *s, size_t maxlen);
+char *__asan_double_fetch_strcpy(char *dst, const char *src);
+
+unsigned long __asan_double_fetch_copy_from_user(void *to, const void __user *from,
+                                                 unsigned long n);
+unsigned long __asan_double_fetch_copy_from_user_inatomic(void *to, const void __user *from,
+                                                          unsigned long n);
+int __asan_double_fetch_get_user(void *dst, const void __user *from, size_t size);
+long __asan_double_fetch_strnlen_user(const char __user *str, long n);
+long __asan_double_fetch_strncpy_from_user(char *dst, const char __user *src, long count);
+void __asan_double_fetch_kprobe_fetch(unsigned long from, size_t n);
+void __asan_double_fetch_syscall_exit(void);
+
+EXPORT_SYMBOL_GPL(__asan_shared_memory_region_init);
+EXPORT_SYMBOL_GPL(__asan_watch_shared_memory_region);
+EXPORT_SYMBOL_GPL(__asan_unwatch_shared_memory_region);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check1);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check2);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check4);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check8);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_check16);
+
+EXPORT_SYMBOL_GPL(__asan_double_fetch_memcpy);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_memmove);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_memset);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_strlen);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_strnlen);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_strcpy);
+
+EXPORT_SYMBOL_GPL(__asan_double_fetch_copy_from_user);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_copy_from_user_inatomic);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_get_user);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_strnlen_user);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_strncpy_from_user);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_kprobe_fetch);
+EXPORT_SYMBOL_GPL(__asan_double_fetch_syscall_exit);
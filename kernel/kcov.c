@@ -0,0 +1,60 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * asan-double-fetch: the current task's KCOV state, for tagging kernel reports
+ *
+ * The kcov fields of task_struct only exist with CONFIG_KCOV and the Rust
+ * staticlib's bindings can't depend on it, so it asks through the helper
+ * below.
+ */
+
+#include <linux/compiler.h>
+#include <linux/hardirq.h>
+#include <linux/kcov.h>
+#include <linux/sched.h>
+#include <linux/types.h>
+
+/* Mirrors `Kcov` in src/kcov.rs */
+struct asan_double_fetch_kcov {
+    /* the kcov instance collecting, i.e. the descriptor it was enabled on */
+    unsigned long instance;
+    /* the common handle passed to KCOV_REMOTE_ENABLE, 0 if none */
+    u64 common_handle;
+    unsigned long area;
+    /* in words */
+    unsigned int size;
+    /* words of the area filled so far */
+    unsigned long pos;
+    unsigned int mode;
+    /* collecting for another task, inside kcov_remote_start() */
+    bool remote;
+};
+
+/* Fills `kcov` and returns true if KCOV is collecting coverage for the
+ * current task */
+bool __asan_double_fetch_current_kcov(struct asan_double_fetch_kcov *kcov)
+{
+#ifdef CONFIG_KCOV
+    struct task_struct *t = current;
+    /* KCOV_IN_CTXSW is set around context switches */
+    unsigned int mode = READ_ONCE(t->kcov_mode) & ~KCOV_IN_CTXSW;
+    unsigned long *area;
+
+    if (mode == KCOV_MODE_DISABLED || mode == KCOV_MODE_INIT || !t->kcov)
+        return false;
+    /* kcov_remote_start() doesn't apply to hard interrupts */
+    if (in_hardirq() || in_nmi())
+        return false;
+
+    area = t->kcov_area;
+    kcov->instance = (unsigned long)t->kcov;
+    kcov->common_handle = t->kcov_handle;
+    kcov->area = (unsigned long)area;
+    kcov->size = t->kcov_size;
+    kcov->pos = area ? READ_ONCE(area[0]) : 0;
+    kcov->mode = mode;
+    kcov->remote = (t->flags & PF_KTHREAD) || (in_serving_softirq() && t->kcov_softirq);
+    return true;
+#else
+    return false;
+#endif
+}
@@ -0,0 +1,168 @@
+//! The kernel functions, types and globals the runtime uses
+//!
+//! Names and signatures follow the bindings the Rust-for-Linux build
+//! generates, so the runtime compiles unchanged against either.
+
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sim;
+
+pub type gfp_t = c_uint;
+pub type pid_t = c_int;
+pub type rcu_callback_t = Option<unsafe extern "C" fn(head: *mut callback_head)>;
+
+pub const HZ: u32 = 250;
+pub const EFAULT: u32 = 14;
+pub const GFP_ATOMIC: gfp_t = 0x820;
+
+/// CPUs the simulated kernel has
+pub static mut nr_cpu_ids: c_uint = 4;
+
+/// Advanced by [`sim::advance_jiffies`]
+pub static mut jiffies: c_ulong = 0;
+
+/// An all-zero lock is unlocked, as in the kernel
+#[repr(C)]
+pub struct raw_spinlock_t {
+    pub(crate) locked: AtomicBool,
+}
+
+#[repr(C)]
+pub struct callback_head {
+    pub next: *mut callback_head,
+    pub func: rcu_callback_t,
+}
+
+/// The fields of `struct task_struct` the runtime reads
+#[repr(C)]
+pub struct task_struct {
+    pub pid: pid_t,
+    pub comm: [c_char; 16],
+    pub kasan_depth: c_uint,
+}
+
+/// Spins until `lock` is taken and counts interrupts as disabled until it's
+/// released. Returns the interrupt state to restore.
+pub unsafe fn _raw_spin_lock_irqsave(lock: *mut raw_spinlock_t) -> c_ulong {
+    let flags = sim::disable_irqs();
+    while (*lock)
+        .locked
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        std::hint::spin_loop();
+    }
+    flags
+}
+
+pub unsafe fn _raw_spin_unlock_irqrestore(lock: *mut raw_spinlock_t, flags: c_ulong) {
+    (*lock).locked.store(false, Ordering::Release);
+    sim::restore_irqs(flags);
+}
+
+pub unsafe fn rcu_read_lock() {
+    sim::rcu_read_lock();
+}
+
+pub unsafe fn rcu_read_unlock() {
+    sim::rcu_read_unlock();
+}
+
+/// Runs `func` on `head` once no RCU reader is left
+pub unsafe fn call_rcu(head: *mut callback_head, func: rcu_callback_t) {
+    (*head).func = func;
+    sim::call_rcu(head);
+}
+
+pub unsafe fn raw_smp_processor_id() -> c_int {
+    sim::cpu()
+}
+
+pub unsafe fn get_current() -> *mut task_struct {
+    sim::current()
+}
+
+pub unsafe fn msecs_to_jiffies(m: c_uint) -> c_ulong {
+    (c_ulong::from(m) * c_ulong::from(HZ)).div_ceil(1000)
+}
+
+/// Copies `n` bytes from "user" memory, which faults on the null page.
+/// Returns the number of bytes not copied.
+pub unsafe fn _copy_from_user(to: *mut c_void, from: *const c_void, n: c_ulong) -> c_ulong {
+    if sim::faults(from as usize, n as usize) {
+        return n;
+    }
+    let from = sim::user_memory(from as usize) as *const u8;
+    core::ptr::copy_nonoverlapping(from, to as *mut u8, n as usize);
+    0
+}
+
+pub unsafe fn __copy_from_user_inatomic(
+    to: *mut c_void,
+    from: *const c_void,
+    n: c_ulong,
+) -> c_ulong {
+    _copy_from_user(to, from, n)
+}
+
+/// Length of the user string at `s` including its terminator, `n + 1` if
+/// it's longer than `n`, or 0 on a fault
+pub unsafe fn strnlen_user(s: *const c_char, n: c_long) -> c_long {
+    if sim::faults(s as usize, 1) {
+        return 0;
+    }
+    let s = sim::user_memory(s as usize) as *const c_char;
+    let n = n.max(0) as usize;
+    let len = (0..n)
+        .position(|i| *s.add(i) == 0)
+        .map_or(n + 1, |nul| nul + 1);
+    len as c_long
+}
+
+/// Copies the user string at `src` into `dst`, at most `count` bytes.
+/// Returns its length without the terminator, `count` if it was truncated,
+/// or `-EFAULT`.
+pub unsafe fn strncpy_from_user(dst: *mut c_char, src: *const c_char, count: c_long) -> c_long {
+    if sim::faults(src as usize, 1) {
+        return -(EFAULT as c_long);
+    }
+    let src = sim::user_memory(src as usize) as *const c_char;
+    let count = count.max(0) as usize;
+    for i in 0..count {
+        *dst.add(i) = *src.add(i);
+        if *src.add(i) == 0 {
+            return i as c_long;
+        }
+    }
+    count as c_long
+}
+
+pub unsafe fn krealloc(p: *const c_void, new_size: usize, _flags: gfp_t) -> *mut c_void {
+    libc::realloc(p as *mut c_void, new_size)
+}
+
+pub unsafe fn kfree(p: *const c_void) {
+    libc::free(p as *mut c_void)
+}
+
+pub unsafe fn get_random_u32() -> u32 {
+    sim::random() as u32
+}
+
+pub unsafe fn get_random_u64() -> u64 {
+    sim::random()
+}
+
+pub unsafe fn get_random_bytes(buf: *mut c_void, len: usize) {
+    let buf = core::slice::from_raw_parts_mut(buf as *mut u8, len);
+    for chunk in buf.chunks_mut(8) {
+        let random = sim::random().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+}
+
+/// Logs the call trace header `dump_stack()` starts with
+pub unsafe fn dump_stack() {
+    sim::printk(4, format_args!("Call Trace:\n"));
+}
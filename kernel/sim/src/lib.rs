@@ -0,0 +1,44 @@
+//! Host-side stand-in for the Rust-for-Linux `kernel` crate
+//!
+//! The `kernel_sim` feature builds the `linux_kasan` configuration against
+//! this crate instead of the one in a kernel tree, so the kernel code paths
+//! can be tested with `cargo test` like the rest of the runtime:
+//!
+//! ```text
+//! cargo +nightly test -p asan_double_fetch --no-default-features --features kernel_sim
+//! ```
+//!
+//! [`bindings`] provides the few kernel functions the runtime calls, backed
+//! by userspace equivalents that behave the way the runtime relies on:
+//! spinlocks exclude each other and count as disabling interrupts, RCU
+//! callbacks run once no reader is left, and user copies fault on the null
+//! page. The C side of the module in `kernel/*.c` is simulated as well.
+//! [`sim`] sets up and inspects the simulated kernel: the current task, CPU
+//! and syscall, time, and what was printed or traced. Like their kernel
+//! counterparts, the current task, CPU and syscall are per thread, and so is
+//! the kernel log, so tests running in parallel don't see each other's.
+
+#![allow(
+    non_camel_case_types,
+    non_upper_case_globals,
+    clippy::missing_safety_doc
+)]
+
+pub mod bindings;
+pub mod sim;
+
+/// `pr_info!`, into the calling thread's [`sim::take_log`]
+#[macro_export]
+macro_rules! pr_info {
+    ($($arg:tt)*) => {
+        $crate::sim::printk(6, format_args!($($arg)*))
+    };
+}
+
+/// `pr_err!`, into the calling thread's [`sim::take_log`]
+#[macro_export]
+macro_rules! pr_err {
+    ($($arg:tt)*) => {
+        $crate::sim::printk(3, format_args!($($arg)*))
+    };
+}
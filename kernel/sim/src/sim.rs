@@ -0,0 +1,266 @@
+//! Setting up and inspecting the simulated kernel
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_int, c_long, c_ulong};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::bindings::{self, callback_head, task_struct};
+
+thread_local! {
+    static TASK: *mut task_struct = Box::into_raw(Box::new(task_struct {
+        pid: next_pid(),
+        comm: comm("test"),
+        kasan_depth: 0,
+    }));
+    static CPU: Cell<c_int> = const { Cell::new(0) };
+    static IRQS_DISABLED: Cell<c_ulong> = const { Cell::new(0) };
+    static SYSCALL: Cell<Option<Syscall>> = const { Cell::new(None) };
+    static LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static TRACE: RefCell<Vec<TraceReport>> = const { RefCell::new(Vec::new()) };
+}
+
+fn next_pid() -> c_int {
+    static PID: AtomicUsize = AtomicUsize::new(100);
+    PID.fetch_add(1, Ordering::Relaxed) as c_int
+}
+
+fn comm(name: &str) -> [c_char; 16] {
+    let mut comm = [0; 16];
+    for (c, b) in comm.iter_mut().zip(name.bytes().take(15)) {
+        *c = b as c_char;
+    }
+    comm
+}
+
+/// The calling thread's task
+pub fn current() -> *mut task_struct {
+    TASK.with(|task| *task)
+}
+
+/// Renames the calling thread's task
+pub fn set_comm(name: &str) {
+    unsafe { (*current()).comm = comm(name) };
+}
+
+/// The CPU the calling thread runs on, 0 until [`set_cpu`]
+pub fn cpu() -> c_int {
+    CPU.with(Cell::get)
+}
+
+/// Moves the calling thread to `cpu`
+pub fn set_cpu(cpu: c_int) {
+    CPU.with(|current| current.set(cpu));
+}
+
+pub(crate) fn disable_irqs() -> c_ulong {
+    IRQS_DISABLED.with(|depth| depth.replace(depth.get() + 1))
+}
+
+pub(crate) fn restore_irqs(flags: c_ulong) {
+    IRQS_DISABLED.with(|depth| depth.set(flags));
+}
+
+/// Whether the calling thread holds a spinlock, and so has interrupts
+/// disabled
+pub fn irqs_disabled() -> bool {
+    IRQS_DISABLED.with(Cell::get) > 0
+}
+
+/// Serializes the tests that advance time, or count on it standing still
+/// or on the global report rate limit
+pub fn serialize() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Advances `jiffies` by `ticks`
+pub fn advance_jiffies(ticks: c_ulong) {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    unsafe {
+        let now = core::ptr::addr_of_mut!(bindings::jiffies);
+        now.write_volatile(now.read_volatile().wrapping_add(ticks));
+    }
+}
+
+/// Set in the addresses [`user_addr`] hands out. They are non-canonical, so
+/// dereferencing one directly faults, like touching user memory from the
+/// kernel under SMAP.
+const USER_TAG: usize = 1 << 62;
+
+/// `ptr` as a user address, only readable through the user copy helpers
+pub fn user_addr<T>(ptr: *mut T) -> usize {
+    ptr as usize | USER_TAG
+}
+
+/// The memory backing user address `addr`
+pub(crate) fn user_memory(addr: usize) -> usize {
+    addr & !USER_TAG
+}
+
+/// Whether a user copy of `[addr, addr + len)` faults: it does on the null
+/// page
+pub(crate) fn faults(addr: usize, len: usize) -> bool {
+    len > 0 && addr < 0x1000
+}
+
+pub(crate) fn random() -> u64 {
+    // splitmix64
+    static STATE: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+    let mut z = STATE
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+struct Rcu {
+    /// Inside a read-side critical section, on any thread
+    readers: usize,
+    /// Waiting for the readers to leave, as addresses
+    callbacks: Vec<usize>,
+}
+
+/// A grace period ends whenever no reader is left. Readers entering later
+/// can't see what the queued callbacks free, it was unpublished before they
+/// were queued, so the count and the queue only need to agree at that point.
+static RCU: Mutex<Rcu> = Mutex::new(Rcu {
+    readers: 0,
+    callbacks: Vec::new(),
+});
+
+fn rcu() -> MutexGuard<'static, Rcu> {
+    RCU.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn rcu_read_lock() {
+    rcu().readers += 1;
+}
+
+pub(crate) fn rcu_read_unlock() {
+    let callbacks = {
+        let mut rcu = rcu();
+        rcu.readers -= 1;
+        if rcu.readers > 0 {
+            return;
+        }
+        core::mem::take(&mut rcu.callbacks)
+    };
+    run_rcu_callbacks(callbacks);
+}
+
+pub(crate) fn call_rcu(head: *mut callback_head) {
+    let callbacks = {
+        let mut rcu = rcu();
+        rcu.callbacks.push(head as usize);
+        if rcu.readers > 0 {
+            return;
+        }
+        core::mem::take(&mut rcu.callbacks)
+    };
+    run_rcu_callbacks(callbacks);
+}
+
+fn run_rcu_callbacks(callbacks: Vec<usize>) {
+    for head in callbacks {
+        let head = head as *mut callback_head;
+        if let Some(func) = unsafe { (*head).func } {
+            unsafe { func(head) };
+        }
+    }
+}
+
+/// RCU callbacks still waiting for a grace period
+pub fn pending_rcu_callbacks() -> usize {
+    rcu().callbacks.len()
+}
+
+/// Prints a line to the calling thread's log at `level`, as `<level>text`
+pub fn printk(level: u8, args: fmt::Arguments) {
+    let line = format!("<{}>{}", level, args);
+    let line = line.strip_suffix('\n').unwrap_or(&line).to_owned();
+    LOG.with(|log| log.borrow_mut().push(line));
+}
+
+/// Takes the lines the calling thread printed so far
+pub fn take_log() -> Vec<String> {
+    LOG.with(|log| log.take())
+}
+
+/// Mirrors `struct asan_double_fetch_syscall` in `kernel/syscall.c`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Syscall {
+    pub nr: c_long,
+    pub ioctl_cmd: c_ulong,
+    pub is_ioctl: bool,
+}
+
+/// Puts the calling thread's task in `syscall` until [`exit_syscall`]
+pub fn enter_syscall(syscall: Syscall) {
+    SYSCALL.with(|current| current.set(Some(syscall)));
+}
+
+pub fn exit_syscall() {
+    SYSCALL.with(|current| current.set(None));
+}
+
+/// What `kernel/syscall.c` tells the runtime
+#[no_mangle]
+pub unsafe extern "C" fn __asan_double_fetch_current_syscall(syscall: *mut Syscall) -> bool {
+    match SYSCALL.with(Cell::get) {
+        Some(current) => {
+            *syscall = current;
+            true
+        }
+        None => false,
+    }
+}
+
+/// KCOV never collects in the simulated kernel
+#[no_mangle]
+pub extern "C" fn __asan_double_fetch_current_kcov(_kcov: *mut core::ffi::c_void) -> bool {
+    false
+}
+
+/// A `df_report` tracepoint event
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceReport {
+    pub bug: String,
+    pub addr: usize,
+    pub len: usize,
+    pub pc: usize,
+    pub nr: c_long,
+    pub ioctl_cmd: c_ulong,
+}
+
+/// Fires the `df_report` tracepoint, recording it for [`take_trace`]
+#[no_mangle]
+pub unsafe extern "C" fn __asan_double_fetch_trace_report(
+    bug: *const c_char,
+    bug_len: usize,
+    addr: usize,
+    len: usize,
+    pc: usize,
+    nr: c_long,
+    ioctl_cmd: c_ulong,
+) {
+    let bug = core::slice::from_raw_parts(bug as *const u8, bug_len);
+    let report = TraceReport {
+        bug: String::from_utf8_lossy(bug).into_owned(),
+        addr,
+        len,
+        pc,
+        nr,
+        ioctl_cmd,
+    };
+    TRACE.with(|trace| trace.borrow_mut().push(report));
+}
+
+/// Takes the tracepoint events the calling thread fired so far
+pub fn take_trace() -> Vec<TraceReport> {
+    TRACE.with(|trace| trace.take())
+}
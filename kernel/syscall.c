@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * asan-double-fetch: the current task's syscall, for tagging kernel reports
+ *
+ * syscall_get_nr() and syscall_get_arguments() are arch-specific inlines the
+ * Rust staticlib's bindings don't cover, so it asks through the helper below.
+ */
+
+#include <linux/compat.h>
+#include <linux/sched.h>
+#include <linux/sched/task_stack.h>
+#include <linux/types.h>
+#include <linux/unistd.h>
+
+#include <asm/syscall.h>
+
+/* Mirrors `Syscall` in src/syscalls.rs */
+struct asan_double_fetch_syscall {
+    long nr;
+    /* the command of an ioctl, 0 for other syscalls */
+    unsigned long ioctl_cmd;
+    bool is_ioctl;
+};
+
+/* Fills `syscall` and returns true if the current task is in a syscall, as
+ * opposed to a kernel thread or interrupt */
+bool __asan_double_fetch_current_syscall(struct asan_double_fetch_syscall *syscall)
+{
+    struct pt_regs *regs;
+    unsigned long args[6];
+
+    if (!in_task() || (current->flags & PF_KTHREAD))
+        return false;
+
+    regs = task_pt_regs(current);
+    syscall->nr = syscall_get_nr(current, regs);
+    if (syscall->nr < 0)
+        return false;
+
+    /* compat syscalls are numbered differently */
+    syscall->is_ioctl = !in_compat_syscall() && syscall->nr == __NR_ioctl;
+    syscall->ioctl_cmd = 0;
+    if (syscall->is_ioctl) {
+        syscall_get_arguments(current, regs, args);
+        syscall->ioctl_cmd = args[1];
+    }
+    return true;
+}
@@ -0,0 +1,283 @@
+//! External double-fetch monitor
+//!
+//! Attaches to a running process with ptrace, finds its shared mappings in
+//! `/proc/<pid>/maps`, and watches them from the outside for targets that
+//! can't be rebuilt with instrumentation. Watched pages are made `PROT_NONE`
+//! through injected `mprotect` calls. A fault on them doesn't say whether the
+//! access was a read or a write, so the faulting instruction is single-stepped
+//! with its page made read-only first: if it faults again it's a write, and
+//! is stepped once more with the page's original protection and otherwise
+//! ignored. Reads are fed to a [`MemoryTracker`] and a re-fetch of an already
+//! fetched byte is reported with the faulting thread and instruction. The
+//! page is then protected again. Faults only give the first byte accessed,
+//! so that's the byte tracked.
+//!
+//! Usage: `df-monitor <pid> [--filter <path substring>] [--window-ms <ms>]`
+//!
+//! While one thread is being single-stepped over an unprotected page, other
+//! threads may touch that page without faulting, so a small number of
+//! fetches can go unobserved under heavy contention.
+
+use std::env;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use asan_double_fetch::memory_tracking::MemoryTracker;
+use asan_double_fetch::span::{Span, SpanRelation};
+
+mod maps;
+mod ptrace;
+
+use maps::Mapping;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_sig: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+struct Options {
+    pid: i32,
+    filter: Option<String>,
+    window: Option<Duration>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: df-monitor <pid> [--filter <path substring>] [--window-ms <ms>]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let pid = args
+        .next()
+        .and_then(|pid| pid.parse().ok())
+        .unwrap_or_else(|| usage());
+
+    let mut options = Options {
+        pid,
+        filter: None,
+        window: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--filter" => options.filter = Some(args.next().unwrap_or_else(|| usage())),
+            "--window-ms" => {
+                let ms = args
+                    .next()
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or_else(|| usage());
+                options.window = Some(Duration::from_millis(ms));
+            }
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+struct Monitor {
+    pid: i32,
+    gadget: usize,
+    page_size: usize,
+    regions: Vec<Mapping>,
+    tracker: MemoryTracker,
+}
+
+impl Monitor {
+    fn region_of(&self, addr: usize) -> Option<&Mapping> {
+        let target = Span::with_len(addr, 1);
+        self.regions
+            .iter()
+            .find(|m| target.relation(&m.span) != SpanRelation::None)
+    }
+
+    fn protect_all(&self, tid: i32, prot: i32) -> io::Result<()> {
+        self.regions
+            .iter()
+            .try_for_each(|m| ptrace::mprotect(tid, self.gadget, &m.span, prot))
+    }
+
+    fn original_prot(mapping: &Mapping) -> i32 {
+        if mapping.writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        }
+    }
+
+    /// Handles a `SIGSEGV` stop. Returns false if the fault wasn't ours and
+    /// the signal must be delivered to the tracee.
+    fn on_fault(&mut self, tid: i32) -> io::Result<bool> {
+        let info = ptrace::siginfo(tid)?;
+        let addr = ptrace::fault_addr(&info);
+
+        let (prot, writable) = match self.region_of(addr) {
+            Some(mapping) => (Self::original_prot(mapping), mapping.writable),
+            None => return Ok(false),
+        };
+
+        let pc = ptrace::regs(tid)?.rip;
+        let page = Span::with_len(addr & !(self.page_size - 1), self.page_size);
+        ptrace::mprotect(tid, self.gadget, &page, libc::PROT_READ)?;
+        let status = ptrace::single_step(tid)?;
+        if Self::faulted_in(tid, status, &page)? {
+            if !writable {
+                // a write to a read-only mapping, the tracee's own fault
+                ptrace::mprotect(tid, self.gadget, &page, libc::PROT_NONE)?;
+                return Ok(false);
+            }
+            // writes aren't fetches
+            ptrace::mprotect(tid, self.gadget, &page, prot)?;
+            ptrace::single_step(tid)?;
+        } else if self.tracker.check(addr, 1).is_err() {
+            println!(
+                "(monitor) double-fetch detected! tid {} re-fetched {:#X} at pc {:#X}",
+                tid, addr, pc
+            );
+        } else if let Err(e) = self.tracker.track_access(addr, 1) {
+            println!("(monitor) {}", e);
+        }
+
+        ptrace::mprotect(tid, self.gadget, &page, libc::PROT_NONE)?;
+
+        Ok(true)
+    }
+
+    /// Whether the wait `status` of a single step is a fault in `page`
+    fn faulted_in(tid: i32, status: i32, page: &Span) -> io::Result<bool> {
+        if !libc::WIFSTOPPED(status) || libc::WSTOPSIG(status) != libc::SIGSEGV {
+            return Ok(false);
+        }
+        Ok(page.contains(ptrace::fault_addr(&ptrace::siginfo(tid)?)))
+    }
+
+    /// Restores original protections and detaches from every thread
+    fn shutdown(&self) -> io::Result<()> {
+        ptrace::interrupt(self.pid, self.pid)?;
+        for mapping in &self.regions {
+            ptrace::mprotect(
+                self.pid,
+                self.gadget,
+                &mapping.span,
+                Self::original_prot(mapping),
+            )?;
+        }
+
+        for tid in ptrace::threads(self.pid)? {
+            if tid != self.pid {
+                let _ = ptrace::interrupt(self.pid, tid);
+            }
+            let _ = ptrace::detach(tid);
+        }
+
+        Ok(())
+    }
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let pid = options.pid;
+
+    let regions = maps::shared_mappings(pid, options.filter.as_deref())?;
+    if regions.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no matching shared mappings",
+        ));
+    }
+
+    let threads = ptrace::threads(pid)?;
+    for tid in &threads {
+        ptrace::attach(*tid)?;
+    }
+
+    let mut monitor = Monitor {
+        pid,
+        gadget: ptrace::find_syscall_gadget(pid, &maps::vdso(pid)?)?,
+        page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize,
+        regions,
+        tracker: MemoryTracker::default(),
+    };
+
+    for mapping in &monitor.regions {
+        println!(
+            "(monitor) watching {} {}",
+            mapping.span,
+            if mapping.path.is_empty() {
+                "[anon]"
+            } else {
+                &mapping.path
+            }
+        );
+    }
+    monitor.protect_all(pid, libc::PROT_NONE)?;
+
+    for tid in &threads {
+        ptrace::cont(*tid, 0)?;
+    }
+
+    let mut window_start = Instant::now();
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            println!("(monitor) detaching");
+            return monitor.shutdown();
+        }
+
+        if let Some(window) = options.window {
+            if window_start.elapsed() >= window {
+                monitor.tracker.clear();
+                window_start = Instant::now();
+            }
+        }
+
+        let mut status = 0;
+        let tid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+        if tid == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            if tid == pid {
+                println!("(monitor) target exited");
+                return Ok(());
+            }
+            continue;
+        }
+
+        if !libc::WIFSTOPPED(status) {
+            continue;
+        }
+
+        let signal = match libc::WSTOPSIG(status) {
+            // new threads start stopped, clone events are just notifications
+            libc::SIGSTOP | libc::SIGTRAP => 0,
+            libc::SIGSEGV if monitor.on_fault(tid)? => 0,
+            signal => signal,
+        };
+        ptrace::cont(tid, signal)?;
+    }
+}
+
+fn main() {
+    let options = parse_args();
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigint as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        // no SA_RESTART, so waitpid returns and we get to clean up
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+
+    if let Err(e) = run(options) {
+        eprintln!("(monitor) error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::io;
+
+use asan_double_fetch::span::Span;
+
+/// A single line of `/proc/<pid>/maps`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    pub span: Span,
+    pub readable: bool,
+    pub writable: bool,
+    pub shared: bool,
+    pub path: String,
+}
+
+impl Mapping {
+    /// Parses one `/proc/<pid>/maps` line, e.g.
+    /// `7f12a000-7f12b000 rw-s 00000000 00:01 32769  /SYSV00000000 (deleted)`
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+
+        let (start, end) = fields.next()?.split_once('-')?;
+        let perms = fields.next()?.as_bytes();
+        if perms.len() != 4 {
+            return None;
+        }
+
+        // offset, device, inode
+        let path = fields.skip(3).collect::<Vec<_>>().join(" ");
+
+        Some(Self {
+            span: Span::new(
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ),
+            readable: perms[0] == b'r',
+            writable: perms[1] == b'w',
+            shared: perms[3] == b's',
+            path,
+        })
+    }
+}
+
+/// Returns the readable shared mappings of `pid` whose path contains
+/// `filter`, if one is given
+pub fn shared_mappings(pid: i32, filter: Option<&str>) -> io::Result<Vec<Mapping>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+
+    Ok(maps
+        .lines()
+        .filter_map(Mapping::parse)
+        .filter(|m| m.shared && m.readable)
+        .filter(|m| filter.is_none_or(|filter| m.path.contains(filter)))
+        .collect())
+}
+
+/// Returns the span of the tracee's vDSO, which always contains a `syscall`
+/// instruction we can borrow for syscall injection
+pub fn vdso(pid: i32) -> io::Result<Span> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+
+    maps.lines()
+        .filter_map(Mapping::parse)
+        .find(|m| m.path == "[vdso]")
+        .map(|m| m.span)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracee has no vDSO"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sysv_shm() {
+        let m = Mapping::parse(
+            "7f12a000-7f12b000 rw-s 00000000 00:01 32769                      /SYSV00000000 (deleted)",
+        )
+        .unwrap();
+
+        assert_eq!(m.span, Span::new(0x7f12a000, 0x7f12b000));
+        assert!(m.readable);
+        assert!(m.writable);
+        assert!(m.shared);
+        assert_eq!(m.path, "/SYSV00000000 (deleted)");
+    }
+
+    #[test]
+    fn parse_anonymous_private() {
+        let m = Mapping::parse("7ffd1000-7ffd2000 rw-p 00000000 00:00 0").unwrap();
+
+        assert!(!m.shared);
+        assert_eq!(m.path, "");
+    }
+
+    #[test]
+    fn parse_garbage() {
+        assert_eq!(Mapping::parse("not a mapping"), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Thin wrappers over the ptrace operations the monitor needs
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+
+use asan_double_fetch::span::Span;
+
+/// `syscall` on x86_64
+const SYSCALL_INSN: [u8; 2] = [0x0f, 0x05];
+
+fn check(res: libc::c_long) -> io::Result<libc::c_long> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// Waits for `tid` to stop and returns its wait status
+pub fn wait(tid: i32) -> io::Result<i32> {
+    let mut status = 0;
+    check(unsafe { libc::waitpid(tid, &mut status, libc::__WALL) }.into())?;
+    Ok(status)
+}
+
+/// Attaches to a single thread and waits for it to stop
+pub fn attach(tid: i32) -> io::Result<()> {
+    check(unsafe { libc::ptrace(libc::PTRACE_ATTACH, tid, 0, 0) })?;
+    wait(tid)?;
+    check(unsafe { libc::ptrace(libc::PTRACE_SETOPTIONS, tid, 0, libc::PTRACE_O_TRACECLONE) })?;
+    Ok(())
+}
+
+pub fn detach(tid: i32) -> io::Result<()> {
+    check(unsafe { libc::ptrace(libc::PTRACE_DETACH, tid, 0, 0) }).map(drop)
+}
+
+/// Resumes a stopped thread, delivering `signal` if it isn't 0
+pub fn cont(tid: i32, signal: i32) -> io::Result<()> {
+    check(unsafe { libc::ptrace(libc::PTRACE_CONT, tid, 0, signal) }).map(drop)
+}
+
+/// Executes a single instruction in a stopped thread, waits for it and
+/// returns its wait status
+pub fn single_step(tid: i32) -> io::Result<i32> {
+    check(unsafe { libc::ptrace(libc::PTRACE_SINGLESTEP, tid, 0, 0) })?;
+    wait(tid)
+}
+
+pub fn regs(tid: i32) -> io::Result<libc::user_regs_struct> {
+    let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+    check(unsafe { libc::ptrace(libc::PTRACE_GETREGS, tid, 0, &mut regs) })?;
+    Ok(regs)
+}
+
+pub fn set_regs(tid: i32, regs: &libc::user_regs_struct) -> io::Result<()> {
+    check(unsafe { libc::ptrace(libc::PTRACE_SETREGS, tid, 0, regs) }).map(drop)
+}
+
+pub fn siginfo(tid: i32) -> io::Result<libc::siginfo_t> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    check(unsafe { libc::ptrace(libc::PTRACE_GETSIGINFO, tid, 0, &mut info) })?;
+    Ok(info)
+}
+
+/// Lists the thread ids of `pid`
+pub fn threads(pid: i32) -> io::Result<Vec<i32>> {
+    std::fs::read_dir(format!("/proc/{}/task", pid))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Ok)
+        .collect()
+}
+
+/// Finds the address of a `syscall` instruction inside `code` of process
+/// `pid`. Injected syscalls jump there instead of patching the tracee's text,
+/// so other running threads never observe modified code.
+pub fn find_syscall_gadget(pid: i32, code: &Span) -> io::Result<usize> {
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let mut buf = vec![0; code.len()];
+    mem.read_exact_at(&mut buf, code.start() as u64)?;
+
+    buf.windows(2)
+        .position(|w| w == SYSCALL_INSN)
+        .map(|off| code.start() + off)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no syscall instruction found"))
+}
+
+/// Makes a stopped thread execute a syscall and restores its registers
+pub fn inject_syscall(tid: i32, gadget: usize, nr: libc::c_long, args: &[u64]) -> io::Result<i64> {
+    let saved = regs(tid)?;
+
+    let mut regs = saved;
+    regs.rip = gadget as u64;
+    regs.rax = nr as u64;
+    let mut arg_regs = [
+        &mut regs.rdi,
+        &mut regs.rsi,
+        &mut regs.rdx,
+        &mut regs.r10,
+        &mut regs.r8,
+        &mut regs.r9,
+    ];
+    for (reg, arg) in arg_regs.iter_mut().zip(args) {
+        **reg = *arg;
+    }
+
+    set_regs(tid, &regs)?;
+    single_step(tid)?;
+    let ret = self::regs(tid)?.rax as i64;
+    set_regs(tid, &saved)?;
+
+    if ret < 0 {
+        Err(io::Error::from_raw_os_error(-ret as i32))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Changes protection of `span` in the tracee through an injected `mprotect`
+pub fn mprotect(tid: i32, gadget: usize, span: &Span, prot: i32) -> io::Result<()> {
+    inject_syscall(
+        tid,
+        gadget,
+        libc::SYS_mprotect,
+        &[span.start() as u64, span.len() as u64, prot as u64],
+    )
+    .map(drop)
+}
+
+/// Stops a running thread with `SIGSTOP` and waits until it's stopped
+pub fn interrupt(pid: i32, tid: i32) -> io::Result<()> {
+    check(unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, libc::SIGSTOP) })?;
+    wait(tid).map(drop)
+}
+
+/// Returns the faulting address of a `SIGSEGV` stop
+pub fn fault_addr(info: &libc::siginfo_t) -> usize {
+    unsafe { info.si_addr() as usize }
+}
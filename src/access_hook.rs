@@ -0,0 +1,157 @@
+//! Per-region access callbacks
+//!
+//! Detection only speaks up about re-fetches, but analyses built on top of
+//! the runtime, such as sequence models or protocol decoders, want to see
+//! every access to a region. A harness hands a region an [`AccessHook`] with
+//! [`__asan_double_fetch_set_access_hook`], which is then called for every
+//! checked access to the region, reads and writes alike, before the runtime
+//! looks for a double fetch. A re-fetch the runtime mutates is thus seen
+//! with the bytes as they were before the mutation.
+//!
+//! The hook stays with the region's tracker: merging regions keeps the hook
+//! of the first merged region that has one, and unwatching the region drops
+//! it. Until any region gets a hook, checks don't look for one.
+
+use core::ffi::{c_int, c_void};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::PoisonError;
+
+use crate::{Address, ThreadSafeMemoryTracker};
+
+/// Called with each access of `len` bytes at `addr` to a region it was set
+/// for, and the PC of the access, 0 if unknown
+pub type AccessHook = extern "C" fn(addr: Address, len: usize, is_write: bool, pc: Address);
+
+/// Set once any region got a hook, so checks skip looking for one until then
+static ANY_HOOK: AtomicBool = AtomicBool::new(false);
+
+/// A region's [`AccessHook`], null if it has none
+#[derive(Debug, Default)]
+pub(crate) struct HookSlot(AtomicPtr<c_void>);
+
+impl HookSlot {
+    pub fn get(&self) -> Option<AccessHook> {
+        let hook = self.0.load(Ordering::Acquire);
+        // only ever set from an `AccessHook`
+        (!hook.is_null()).then(|| unsafe { core::mem::transmute::<*mut c_void, AccessHook>(hook) })
+    }
+
+    pub fn set(&self, hook: Option<AccessHook>) {
+        let hook = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut c_void);
+        self.0.store(hook, Ordering::Release);
+    }
+}
+
+/// Calls `tracker`'s region's hook, if any, with an access to it. The
+/// tracker isn't locked during the call, so the hook may call back into the
+/// runtime.
+pub(crate) fn call(
+    tracker: &ThreadSafeMemoryTracker,
+    addr: Address,
+    len: usize,
+    is_write: bool,
+    pc: Option<Address>,
+) {
+    if !ANY_HOOK.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let hook = tracker
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .access_hook()
+        .get();
+    if let Some(hook) = hook {
+        hook(addr, len, is_write, pc.unwrap_or(0));
+    }
+}
+
+/// Calls `hook` with every access to the watched region containing `addr`
+/// from now on, or stops calling the region's hook if `hook` is null.
+/// Returns 0, or -1 if no watched region contains `addr`.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("set_access_hook"))]
+pub extern "C" fn __asan_double_fetch_set_access_hook(
+    addr: Address,
+    // spelled out, as the header generator can't see through the alias here
+    hook: Option<extern "C" fn(addr: Address, len: usize, is_write: bool, pc: Address)>,
+) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_set_access_hook", -1, || {
+        let (_region, tracker) = match crate::get_memory_tracker(addr, 1) {
+            Some(found) => found,
+            None => {
+                log::warn!("{:#X} isn't watched, not setting its access hook", addr);
+                return -1;
+            }
+        };
+
+        if hook.is_some() {
+            ANY_HOOK.store(true, Ordering::Relaxed);
+        }
+        tracker
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .access_hook()
+            .set(hook);
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Accesses seen by [`record`], as `(addr, len, is_write, pc)`
+    static SEEN: Mutex<Vec<(Address, usize, bool, Address)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(addr: Address, len: usize, is_write: bool, pc: Address) {
+        SEEN.lock().unwrap().push((addr, len, is_write, pc));
+    }
+
+    /// What [`record`] saw of the `len` bytes at `base`, as offsets
+    fn seen(base: Address, len: usize) -> Vec<(usize, usize, bool, Address)> {
+        SEEN.lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, ..)| (base..base + len).contains(addr))
+            .map(|&(addr, len, is_write, pc)| (addr - base, len, is_write, pc))
+            .collect()
+    }
+
+    #[test]
+    fn every_access() {
+        crate::ensure_initialized();
+
+        let data = Box::leak(Box::new([0u8; 32]));
+        let base = data.as_ptr() as Address;
+        assert_eq!(__asan_double_fetch_set_access_hook(base, Some(record)), -1);
+        crate::__asan_watch_shared_memory_region(base, 16);
+        assert_eq!(
+            __asan_double_fetch_set_access_hook(base + 8, Some(record)),
+            0
+        );
+
+        crate::check_access(base, 4, false, Some(0x1234));
+        crate::check_access(base, 4, false, None);
+        crate::check_access(base + 4, 2, true, None);
+        // outside the region
+        crate::check_access(base + 16, 4, false, None);
+        assert_eq!(
+            seen(base, 32),
+            [(0, 4, false, 0x1234), (0, 4, false, 0), (4, 2, true, 0)]
+        );
+
+        // merged regions keep the hook
+        crate::__asan_watch_shared_memory_region(base + 12, 8);
+        crate::check_access(base + 16, 4, false, None);
+        assert_eq!(seen(base, 32).len(), 4);
+
+        assert_eq!(__asan_double_fetch_set_access_hook(base, None), 0);
+        crate::check_access(base + 8, 4, false, None);
+        assert_eq!(seen(base, 32).len(), 4);
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+}
@@ -0,0 +1,54 @@
+use core::fmt;
+use core::hash::Hash;
+
+/// An unsigned integer type usable as an address in a [`Span`](crate::span::Span)
+/// or [`MemoryTracker`](crate::memory_tracking::MemoryTracker).
+///
+/// This lets the interval-tracking core be reused for address spaces other
+/// than the host's virtual address space, e.g. guest physical addresses,
+/// device offsets, or file offsets.
+pub trait AddressType:
+    Copy + Ord + Hash + fmt::Debug + fmt::LowerHex + fmt::UpperHex + Default
+{
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn saturating_add(self, rhs: Self) -> Self;
+    fn saturating_sub(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    /// Nearest `f64`, for ratios of address counts
+    fn as_f64(self) -> f64;
+}
+
+macro_rules! impl_address_type {
+    ($($ty:ty),*) => {
+        $(
+            impl AddressType for $ty {
+                const ZERO: Self = 0;
+                const ONE: Self = 1;
+
+                #[inline]
+                fn saturating_add(self, rhs: Self) -> Self {
+                    <$ty>::saturating_add(self, rhs)
+                }
+
+                #[inline]
+                fn saturating_sub(self, rhs: Self) -> Self {
+                    <$ty>::saturating_sub(self, rhs)
+                }
+
+                #[inline]
+                fn wrapping_sub(self, rhs: Self) -> Self {
+                    <$ty>::wrapping_sub(self, rhs)
+                }
+
+                #[inline]
+                fn as_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_address_type!(u8, u16, u32, u64, u128, usize);
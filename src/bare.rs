@@ -0,0 +1,83 @@
+//! Glue for bare-metal builds, `no_std` without `linux_kasan`
+//!
+//! Embedded targets share no randomness source the runtime could call, so
+//! mutations draw from a fixed-seed splitmix64 instead; a run's mutations are
+//! the same every time it is repeated. The critical section `once_cell` and
+//! [`crate::sync::SpinLock`] use comes from the platform.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use rand::{Error, RngCore};
+
+/// Shared, so that each check continues the sequence. Behind a critical
+/// section rather than in an `AtomicU64`, which 32-bit targets lack.
+static STATE: Mutex<Cell<u64>> = Mutex::new(Cell::new(0x853c_49e6_748f_ea9b));
+
+/// Randomness from a fixed-seed splitmix64
+pub(crate) struct BareRng;
+
+impl RngCore for BareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let state = critical_section::with(|cs| {
+            let state = STATE.borrow(cs);
+            state.set(state.get().wrapping_add(0x9e37_79b9_7f4a_7c15));
+            state.get()
+        });
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Stands in for the platform's critical section in host test builds: one
+/// process-wide lock, taken once per thread however deeply sections nest
+#[cfg(test)]
+mod host_critical_section {
+    use std::cell::RefCell;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    std::thread_local! {
+        /// Held by the thread's outermost section
+        static GUARD: RefCell<Option<MutexGuard<'static, ()>>> = const { RefCell::new(None) };
+    }
+
+    struct HostCriticalSection;
+    critical_section::set_impl!(HostCriticalSection);
+
+    unsafe impl critical_section::Impl for HostCriticalSection {
+        unsafe fn acquire() -> critical_section::RawRestoreState {
+            if GUARD.with(|guard| guard.borrow().is_some()) {
+                return 0;
+            }
+            let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            GUARD.with(|slot| *slot.borrow_mut() = Some(guard));
+            1
+        }
+
+        unsafe fn release(outermost: critical_section::RawRestoreState) {
+            if outermost == 1 {
+                GUARD.with(|guard| guard.borrow_mut().take());
+            }
+        }
+    }
+}
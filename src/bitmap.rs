@@ -0,0 +1,364 @@
+//! Shadow-bitmap tracker for small and medium regions
+//!
+//! [`MemoryTracker`](crate::memory_tracking::MemoryTracker) scales with the
+//! number of disjoint spans, which is what you want for huge or sparsely
+//! fetched regions, but dense access patterns keep splitting and merging
+//! tree nodes. [`BitmapTracker`] instead shadows a fixed region with one bit
+//! per granule, a byte by default, so tracking and checking an access only
+//! touches the words covering it and never allocates after construction.
+//! The cost is one bit of shadow per granule of region, paid up front.
+
+#[cfg(feature = "no_std")]
+use alloc::{vec, vec::Vec};
+
+use crate::span::Span;
+use crate::Address;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Tracks fetched bytes of a fixed region in a bitmap
+///
+/// Accesses are clipped to the region; bytes outside of it are never
+/// tracked. With a granularity above a byte, touching any byte of a granule
+/// marks the whole granule, i.e. the part of it inside the region, as
+/// fetched. Granules are aligned to their size.
+///
+/// # Examples
+///
+/// ```
+/// # use asan_double_fetch::bitmap::BitmapTracker;
+/// # use asan_double_fetch::Span;
+/// #
+/// let mut rz = BitmapTracker::new(Span::new(0x4000, 0x5000));
+///
+/// rz.track_access(0x4141, 8);
+///
+/// assert!(rz.check(0x5151, 1).is_ok());
+/// assert_eq!(rz.check(0x4144, 1), Err(0x4141));
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct BitmapTracker {
+    region: Span,
+    /// `region`'s start rounded down to a granule
+    base: Address,
+    /// log2 of the granularity
+    shift: u32,
+    bits: Vec<u64>,
+}
+
+impl BitmapTracker {
+    /// Tracker for `region` with nothing fetched yet, tracking single bytes
+    pub fn new(region: Span) -> Self {
+        Self::with_granularity(region, 1)
+    }
+
+    /// Tracker for `region` with nothing fetched yet, tracking
+    /// `granularity`-sized granules
+    ///
+    /// # Panics
+    ///
+    /// Panics if `granularity` isn't a power of two.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::bitmap::BitmapTracker;
+    /// # use asan_double_fetch::Span;
+    /// #
+    /// let mut rz = BitmapTracker::with_granularity(Span::new(0x4000, 0x5000), 0x40);
+    ///
+    /// rz.track_access(0x4141, 8);
+    ///
+    /// assert_eq!(rz.check(0x417f, 1), Err(0x4140));
+    /// assert!(rz.check(0x4180, 1).is_ok());
+    /// ```
+    pub fn with_granularity(region: Span, granularity: usize) -> Self {
+        assert!(
+            granularity.is_power_of_two(),
+            "granularity {:#x} is not a power of two",
+            granularity
+        );
+
+        let shift = granularity.trailing_zeros();
+        let base = region.start() & !(granularity - 1);
+        let granules = (region.end() - base).div_ceil(granularity);
+
+        Self {
+            region,
+            base,
+            shift,
+            bits: vec![0; granules.div_ceil(WORD_BITS)],
+        }
+    }
+
+    /// The region this tracker shadows
+    pub fn region(&self) -> &Span {
+        &self.region
+    }
+
+    /// Track an access, marking the bytes of `[a, a + sz)` inside the
+    /// region as fetched
+    pub fn track_access(&mut self, a: Address, sz: usize) {
+        if let Some((lo, hi)) = self.bit_range(a, sz) {
+            self.fill(lo, hi, true);
+        }
+    }
+
+    /// Forget an access, marking the bytes of `[a, a + sz)` inside the
+    /// region as not fetched
+    pub fn remove_access(&mut self, a: Address, sz: usize) {
+        if let Some((lo, hi)) = self.bit_range(a, sz) {
+            self.fill(lo, hi, false);
+        }
+    }
+
+    /// Returns true if no bytes are tracked
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    /// Forget all accesses
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Number of fetched bytes
+    pub fn occupied_len(&self) -> usize {
+        self.redzones().map(|(_start, len)| len).sum()
+    }
+
+    /// Fraction of `total_len` bytes that were fetched. 0 if `total_len` is
+    /// 0.
+    pub fn coverage(&self, total_len: usize) -> f64 {
+        if total_len == 0 {
+            return 0.0;
+        }
+
+        self.occupied_len() as f64 / total_len as f64
+    }
+
+    /// Iterate over runs of fetched bytes
+    ///
+    /// Iterates over `(start, len)` of each run, sorted by start, like
+    /// [`MemoryTracker::redzones`](crate::memory_tracking::MemoryTracker::redzones)
+    pub fn redzones(&self) -> impl Iterator<Item = (Address, usize)> + '_ {
+        self.runs(0, self.granules())
+            .map(|span| (span.start(), span.len()))
+    }
+
+    /// Check an access
+    ///
+    /// # Errors
+    ///
+    /// On re-fetching tracked bytes, `Err(fault)` is returned, where `fault`
+    /// is the start address of the _last_ offending run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::bitmap::BitmapTracker;
+    /// # use asan_double_fetch::Span;
+    /// #
+    /// let mut rz = BitmapTracker::new(Span::new(0x4000, 0x5000));
+    ///
+    /// rz.track_access(0x4141, 8);
+    /// rz.remove_access(0x4143, 4);
+    ///
+    /// assert_eq!(rz.check(0x4141, 8), Err(0x4147));
+    /// ```
+    pub fn check(&self, a: Address, sz: usize) -> Result<(), Address> {
+        let (lo, hi) = match self.bit_range(a, sz) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        match self.rfind(lo, hi, true) {
+            Some(last) => {
+                let start = self.rfind(0, last, false).map_or(0, |clear| clear + 1);
+                Err(self.address(start))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Every run of fetched bytes `[a, a + sz)` overlaps, in ascending order
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::bitmap::BitmapTracker;
+    /// # use asan_double_fetch::Span;
+    /// #
+    /// let mut rz = BitmapTracker::new(Span::new(0x4000, 0x5000));
+    ///
+    /// rz.track_access(0x4141, 8);
+    /// rz.remove_access(0x4143, 4);
+    ///
+    /// let conflicts: Vec<_> = rz.check_all(0x4140, 0x10).collect();
+    /// assert_eq!(conflicts, [Span::new(0x4141, 0x4143), Span::new(0x4147, 0x4149)]);
+    /// ```
+    pub fn check_all(&self, a: Address, sz: usize) -> impl Iterator<Item = Span> + '_ {
+        let (lo, hi) = self.bit_range(a, sz).unwrap_or((0, 0));
+
+        // a run starting before the access may still reach into it
+        let lo = match self.find(lo, hi, true) {
+            Some(first) => self.rfind(0, first, false).map_or(0, |clear| clear + 1),
+            None => hi,
+        };
+        self.runs(lo, hi)
+    }
+
+    /// Runs of set bits starting in `[lo, hi)`, as address spans
+    fn runs(&self, mut lo: usize, hi: usize) -> impl Iterator<Item = Span> + '_ {
+        core::iter::from_fn(move || {
+            let start = self.find(lo, hi, true)?;
+            let end = self
+                .find(start, self.granules(), false)
+                .unwrap_or_else(|| self.granules());
+            lo = end;
+
+            Some(Span::new(self.address(start), self.address(end)))
+        })
+    }
+
+    /// Number of granules the region touches
+    fn granules(&self) -> usize {
+        (self.region.end() - self.base).div_ceil(1 << self.shift)
+    }
+
+    /// Start of granule `bit`, clamped to the region
+    fn address(&self, bit: usize) -> Address {
+        (self.base + (bit << self.shift)).clamp(self.region.start(), self.region.end())
+    }
+
+    /// Indices of the granules the part of `[a, a + sz)` inside the region
+    /// touches
+    fn bit_range(&self, a: Address, sz: usize) -> Option<(usize, usize)> {
+        let clipped = Span::with_len(a, sz).intersect(&self.region)?;
+
+        Some((
+            (clipped.start() - self.base) >> self.shift,
+            (clipped.end() - self.base).div_ceil(1 << self.shift),
+        ))
+    }
+
+    /// Sets or clears bits `[lo, hi)`
+    fn fill(&mut self, lo: usize, hi: usize, set: bool) {
+        for (word, mask) in masks(lo, hi) {
+            if set {
+                self.bits[word] |= mask;
+            } else {
+                self.bits[word] &= !mask;
+            }
+        }
+    }
+
+    /// The first bit in `[lo, hi)` that is `set`
+    fn find(&self, lo: usize, hi: usize, set: bool) -> Option<usize> {
+        masks(lo, hi).find_map(|(word, mask)| {
+            let bits = if set {
+                self.bits[word]
+            } else {
+                !self.bits[word]
+            } & mask;
+            (bits != 0).then(|| word * WORD_BITS + bits.trailing_zeros() as usize)
+        })
+    }
+
+    /// The last bit in `[lo, hi)` that is `set`
+    fn rfind(&self, lo: usize, hi: usize, set: bool) -> Option<usize> {
+        masks(lo, hi).rev().find_map(|(word, mask)| {
+            let bits = if set {
+                self.bits[word]
+            } else {
+                !self.bits[word]
+            } & mask;
+            (bits != 0).then(|| word * WORD_BITS + WORD_BITS - 1 - bits.leading_zeros() as usize)
+        })
+    }
+}
+
+/// `(word, mask)` of each word overlapping bits `[lo, hi)`
+fn masks(lo: usize, hi: usize) -> impl DoubleEndedIterator<Item = (usize, u64)> {
+    let words = if lo < hi {
+        lo / WORD_BITS..(hi - 1) / WORD_BITS + 1
+    } else {
+        0..0
+    };
+
+    words.map(move |word| {
+        let first = word * WORD_BITS;
+        let from = lo.max(first) - first;
+        let to = hi.min(first + WORD_BITS) - first;
+
+        let mask = if to - from == WORD_BITS {
+            !0
+        } else {
+            ((1u64 << (to - from)) - 1) << from
+        };
+        (word, mask)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_and_check() {
+        let mut tracker = BitmapTracker::new(Span::new(0x1000, 0x1100));
+
+        tracker.track_access(0x1038, 0x10);
+        assert_eq!(tracker.check(0x1047, 1), Err(0x1038));
+        assert!(tracker.check(0x1048, 0x10).is_ok());
+        assert!(tracker.check(0x1030, 8).is_ok());
+        assert_eq!(tracker.occupied_len(), 0x10);
+
+        // merges with the adjacent run
+        tracker.track_access(0x1048, 0x40);
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x1038, 0x50)]);
+        assert_eq!(tracker.check(0x1080, 1), Err(0x1038));
+
+        tracker.remove_access(0x1040, 8);
+        assert_eq!(
+            tracker.redzones().collect::<Vec<_>>(),
+            [(0x1038, 8), (0x1048, 0x40)]
+        );
+        assert_eq!(
+            tracker.check_all(0x103c, 0x10).collect::<Vec<_>>(),
+            [Span::new(0x1038, 0x1040), Span::new(0x1048, 0x1088)]
+        );
+
+        tracker.clear();
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn clips_to_region() {
+        let mut tracker = BitmapTracker::new(Span::new(0x1000, 0x1003));
+
+        tracker.track_access(0xff0, 0x20);
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x1000, 3)]);
+        assert_eq!(tracker.check(0xff0, 0x11), Err(0x1000));
+        assert!(tracker.check(0x1003, 0x10).is_ok());
+        assert!(tracker.check(0x1000, 0).is_ok());
+        assert_eq!(tracker.coverage(3), 1.0);
+    }
+
+    #[test]
+    fn granularity() {
+        let mut tracker = BitmapTracker::with_granularity(Span::new(0x1004, 0x1100), 0x10);
+
+        tracker.track_access(0x1005, 1);
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x1004, 0xc)]);
+        assert_eq!(tracker.check(0x100f, 1), Err(0x1004));
+        assert!(tracker.check(0x1010, 0x10).is_ok());
+
+        tracker.track_access(0x10f8, 0x10);
+        assert_eq!(
+            tracker.check_all(0x10f0, 1).collect::<Vec<_>>(),
+            [Span::new(0x10f0, 0x1100)]
+        );
+        assert_eq!(tracker.occupied_len(), 0x1c);
+    }
+}
@@ -0,0 +1,245 @@
+//! Limits on what the runtime tracks
+//!
+//! A harness that watches a region per message, or a target that fetches
+//! a region a few bytes apart all over, can make the runtime grow without
+//! bound. The `max_regions` option caps the regions watched at once and
+//! `max_tracked_spans` the disjoint fetched spans of each region tracked in
+//! a tree; bitmaps are bounded by their region's size and per-page bitmaps
+//! by `chunk_memory_cap`. Past either limit, `budget_policy` decides:
+//!
+//! - `refuse`, the default, doesn't watch the new region or track the new
+//!   fetch
+//! - `evict` unwatches the region with the fewest fetches since it was
+//!   watched to make room, or forgets the region's fetch history and starts
+//!   it over with the new fetch
+//!
+//! Either way, the first time a limit is hit is logged, and how often each
+//! was hit is logged at shutdown.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::PoisonError;
+
+use crate::config::{self, BudgetPolicy};
+use crate::memory_tracking::TrackerError;
+use crate::span::Span;
+use crate::{Address, MemoryTracker, ThreadSafeMemoryTracker, TrackerAlloc};
+
+/// Regions not watched for being over `max_regions`
+static REFUSED_REGIONS: AtomicUsize = AtomicUsize::new(0);
+/// Regions unwatched to make room for others
+static EVICTED_REGIONS: AtomicUsize = AtomicUsize::new(0);
+/// Fetches not tracked for being over `max_tracked_spans`
+static REFUSED_FETCHES: AtomicUsize = AtomicUsize::new(0);
+/// Fetch histories forgotten to make room for a fetch
+static EVICTED_HISTORIES: AtomicUsize = AtomicUsize::new(0);
+
+/// What to do about a region about to be watched
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Admission {
+    Watch,
+    Refuse,
+    /// Watch it after unwatching the region at this index
+    Evict(usize),
+}
+
+/// Counts a limit being hit, logging `what` the first time
+fn hit(counter: &AtomicUsize, what: core::fmt::Arguments) {
+    if counter.fetch_add(1, Ordering::Relaxed) == 0 {
+        log::warn!("{}, counting further ones until shutdown", what);
+    }
+}
+
+/// Whether a region may be watched next to the watched `regions`
+pub(crate) fn admit_region(
+    regions: &[(Span, ThreadSafeMemoryTracker)],
+    addr: Address,
+) -> Admission {
+    let config = config::get();
+    let admission = admission(regions, config.max_regions, config.budget_policy);
+    match admission {
+        Admission::Watch => (),
+        Admission::Refuse => hit(
+            &REFUSED_REGIONS,
+            format_args!(
+                "{} regions watched, not watching {:#X}",
+                regions.len(),
+                addr
+            ),
+        ),
+        Admission::Evict(idx) => hit(
+            &EVICTED_REGIONS,
+            format_args!(
+                "{} regions watched, unwatching the least fetched one at {:#X} to watch {:#X}",
+                regions.len(),
+                regions[idx].0.start(),
+                addr
+            ),
+        ),
+    }
+    admission
+}
+
+fn admission(
+    regions: &[(Span, ThreadSafeMemoryTracker)],
+    max_regions: usize,
+    policy: BudgetPolicy,
+) -> Admission {
+    if max_regions == 0 || regions.len() < max_regions {
+        return Admission::Watch;
+    }
+
+    match policy {
+        BudgetPolicy::Refuse => Admission::Refuse,
+        BudgetPolicy::Evict => regions
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (_, tracker))| {
+                tracker
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .counters()
+                    .fetches
+                    .load(Ordering::Relaxed)
+            })
+            .map_or(Admission::Refuse, |(idx, _)| Admission::Evict(idx)),
+    }
+}
+
+/// Keeps `tracker`, which just tracked `[a, a + sz)`, within
+/// `max_tracked_spans`
+pub(crate) fn limit_spans(
+    tracker: &mut MemoryTracker<Address, TrackerAlloc>,
+    a: Address,
+    sz: usize,
+) -> Result<(), TrackerError> {
+    let config = config::get();
+    limit(
+        tracker,
+        a,
+        sz,
+        config.max_tracked_spans,
+        config.budget_policy,
+    )
+}
+
+fn limit(
+    tracker: &mut MemoryTracker<Address, TrackerAlloc>,
+    a: Address,
+    sz: usize,
+    max_spans: usize,
+    policy: BudgetPolicy,
+) -> Result<(), TrackerError> {
+    if max_spans == 0 || tracker.len() <= max_spans {
+        return Ok(());
+    }
+
+    // only a fetch next to no tracked span adds one, so it's the fetch's own
+    match policy {
+        BudgetPolicy::Refuse => {
+            hit(
+                &REFUSED_FETCHES,
+                format_args!(
+                    "{} spans tracked, not tracking the fetch at {:#X}",
+                    max_spans, a
+                ),
+            );
+            tracker.remove_access(a, sz)
+        }
+        BudgetPolicy::Evict => {
+            hit(
+                &EVICTED_HISTORIES,
+                format_args!(
+                    "{} spans tracked, forgetting earlier fetches to track the fetch at {:#X}",
+                    max_spans, a
+                ),
+            );
+            tracker.clear();
+            tracker.track_access(a, sz)
+        }
+    }
+}
+
+/// Logs how often the limits were hit since init or the last shutdown and
+/// starts counting over
+pub(crate) fn summarize() {
+    let counts = [
+        (&REFUSED_REGIONS, "regions not watched over max_regions"),
+        (&EVICTED_REGIONS, "regions evicted over max_regions"),
+        (
+            &REFUSED_FETCHES,
+            "fetches not tracked over max_tracked_spans",
+        ),
+        (
+            &EVICTED_HISTORIES,
+            "fetch histories forgotten over max_tracked_spans",
+        ),
+    ];
+    for (counter, what) in counts {
+        let count = counter.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            log::warn!("{} {}", count, what);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_regions() {
+        let regions: Vec<_> = (1..=3)
+            .map(|i| {
+                let span = Span::with_len(i * 0x1000, 0x100);
+                let tracker = crate::new_tracker(&span, 1);
+                for _ in 0..(4 - i) {
+                    tracker.read().unwrap().count_fetch(span.start(), 1);
+                }
+                (span, tracker)
+            })
+            .collect();
+
+        assert_eq!(
+            admission(&regions, 0, BudgetPolicy::Refuse),
+            Admission::Watch
+        );
+        assert_eq!(
+            admission(&regions, 4, BudgetPolicy::Refuse),
+            Admission::Watch
+        );
+        assert_eq!(
+            admission(&regions, 3, BudgetPolicy::Refuse),
+            Admission::Refuse
+        );
+        // the last region was fetched from least
+        assert_eq!(
+            admission(&regions, 3, BudgetPolicy::Evict),
+            Admission::Evict(2)
+        );
+    }
+
+    #[test]
+    fn limits_spans() {
+        for policy in [BudgetPolicy::Refuse, BudgetPolicy::Evict] {
+            let mut tracker = MemoryTracker::default();
+            for a in [0x10, 0x20, 0x30] {
+                tracker.track_access(a, 4).unwrap();
+                limit(&mut tracker, a, 4, 2, policy).unwrap();
+            }
+            tracker.track_access(0x14, 4).unwrap();
+            limit(&mut tracker, 0x14, 4, 2, policy).unwrap();
+
+            assert_eq!(tracker.len(), 2);
+            assert!(tracker.check(0x14, 4).is_err());
+            match policy {
+                // 0x30 was refused, 0x14 joined the span at 0x10
+                BudgetPolicy::Refuse => assert!(tracker.check(0x30, 4).is_ok()),
+                // 0x30 started over
+                BudgetPolicy::Evict => {
+                    assert!(tracker.check(0x10, 4).is_ok());
+                    assert!(tracker.check(0x30, 4).is_err());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Canary propagation
+//!
+//! With `mutation=canary`, every re-fetch is handed a recognizable pattern
+//! instead of a random value: `df ca` followed by the canary's 16-bit id,
+//! repeated to fill the fetch. Where that pattern turns up later shows
+//! where the stale value flowed, pinpointing the sink of the double fetch.
+//!
+//! Memory `memcpy` and `memmove` copy to through the interceptors is
+//! searched automatically; harnesses can search anything else, such as the
+//! structure a request was parsed into, with
+//! [`__asan_double_fetch_canary_scan`]. Fetches shorter than a whole
+//! pattern get a truncated one that can't be told apart from other data, so
+//! they aren't searched for.
+
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::{region_names, Address};
+
+const MAGIC: [u8; 2] = [0xdf, 0xca];
+const PATTERN_LEN: usize = 4;
+
+/// Canaries searched for at once, the oldest being forgotten
+const MAX_LIVE: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Canary {
+    id: u16,
+    /// Where it was planted
+    addr: Address,
+    len: usize,
+    /// `addr` relative to its region, see [`region_names`]
+    at: String,
+    /// PC of the re-fetch that got it
+    pc: Option<Address>,
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+static LIVE: Mutex<Vec<Canary>> = Mutex::new(Vec::new());
+
+/// `LIVE.len()`, so copies skip the lock while no canary is planted
+static LIVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn live() -> std::sync::MutexGuard<'static, Vec<Canary>> {
+    LIVE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn pattern(id: u16) -> [u8; PATTERN_LEN] {
+    let id = id.to_le_bytes();
+    [MAGIC[0], MAGIC[1], id[0], id[1]]
+}
+
+/// Fills `data`, re-fetched from `addr` in `region`, with a new canary's
+/// pattern
+pub(crate) fn plant(region: &Span, addr: Address, pc: Option<Address>, data: &mut [u8]) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    for (b, p) in data.iter_mut().zip(pattern(id).iter().cycle()) {
+        *b = *p;
+    }
+    let at = region_names::relative(region, addr);
+    log::warn!("planted canary {:#06x} at {:#X} ({})", id, addr, at);
+
+    if data.len() < PATTERN_LEN {
+        return;
+    }
+    let mut live = live();
+    if live.len() == MAX_LIVE {
+        live.remove(0);
+    }
+    live.push(Canary {
+        id,
+        addr,
+        len: data.len(),
+        at,
+        pc,
+    });
+    LIVE_COUNT.store(live.len(), Ordering::Relaxed);
+}
+
+/// Searches `data`, which lives at `addr`, for live canaries, reporting each
+/// one found outside the bytes it was planted in. `via` says how the data
+/// got there. Returns how many were found.
+pub(crate) fn scan(addr: Address, data: &[u8], via: &str) -> usize {
+    if LIVE_COUNT.load(Ordering::Relaxed) == 0 {
+        return 0;
+    }
+
+    let live = live();
+    let mut found = 0;
+    let mut offset = 0;
+    while offset + PATTERN_LEN <= data.len() {
+        let window = &data[offset..offset + PATTERN_LEN];
+        let at = addr + offset;
+        let canary = (window[..2] == MAGIC)
+            .then(|| u16::from_le_bytes([window[2], window[3]]))
+            .and_then(|id| live.iter().find(|canary| canary.id == id))
+            .filter(|canary| !(canary.addr..canary.addr + canary.len).contains(&at));
+
+        match canary {
+            Some(canary) => {
+                log::error!(
+                    "canary {:#06x} planted at {:#X} ({}){} reached {:#X} via {}",
+                    canary.id,
+                    canary.addr,
+                    canary.at,
+                    canary
+                        .pc
+                        .map_or_else(String::new, |pc| format!(" (pc {:#X})", pc)),
+                    at,
+                    via
+                );
+                found += 1;
+                // a pattern repeats, so report it once per copy
+                while offset + PATTERN_LEN <= data.len()
+                    && data[offset..offset + PATTERN_LEN] == pattern(canary.id)
+                {
+                    offset += PATTERN_LEN;
+                }
+            }
+            None => offset += 1,
+        }
+    }
+    found
+}
+
+/// Forgets every canary, for runtime shutdown
+pub(crate) fn clear() {
+    live().clear();
+    LIVE_COUNT.store(0, Ordering::Relaxed);
+}
+
+/// Searches `[addr, addr + len)` for canaries planted by `mutation=canary`,
+/// e.g. a structure parsed from a watched region, and reports where each one
+/// turned up. Returns how many were found.
+///
+/// # Safety
+///
+/// `[addr, addr + len)` must be readable.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("canary_scan"))]
+pub unsafe extern "C" fn __asan_double_fetch_canary_scan(addr: Address, len: usize) -> usize {
+    crate::ffi::guard("__asan_double_fetch_canary_scan", 0, || {
+        if addr == 0 || len == 0 {
+            return 0;
+        }
+        let data = core::slice::from_raw_parts(addr as *const u8, len);
+        scan(addr, data, "__asan_double_fetch_canary_scan")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_propagated_canaries() {
+        let mut fetched = [0u8; 8];
+        let addr = fetched.as_ptr() as Address;
+        plant(&Span::with_len(addr, 8), addr, Some(0x1234), &mut fetched);
+        let id = u16::from_le_bytes([fetched[2], fetched[3]]);
+        assert_eq!(fetched[..4], fetched[4..]);
+        assert_eq!(fetched[..2], MAGIC);
+
+        // not where it was planted
+        assert_eq!(scan(addr, &fetched, "test"), 0);
+
+        let mut sink = [0u8; 24];
+        sink[3..11].copy_from_slice(&fetched);
+        sink[16..20].copy_from_slice(&fetched[..4]);
+        assert_eq!(scan(sink.as_ptr() as Address, &sink, "test"), 2);
+
+        // too short to search for
+        let mut short = [0u8; 2];
+        plant(&Span::with_len(0x10, 2), 0x10, None, &mut short);
+        assert_eq!(short, MAGIC);
+        assert!(live().iter().all(|canary| canary.addr != 0x10));
+
+        assert!(live().iter().any(|canary| canary.id == id));
+    }
+}
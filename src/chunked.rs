@@ -0,0 +1,299 @@
+//! Lazily allocated, page-chunked shadow for huge regions
+//!
+//! A [`BitmapTracker`] over a multi-GB mapping costs its full shadow up
+//! front, and a [`MemoryTracker`](crate::memory_tracking::MemoryTracker)
+//! over one that gets fetched densely grows a node per disjoint span.
+//! [`ChunkedTracker`] splits the region into fixed-size chunks, typically
+//! pages, and only allocates a bitmap for a chunk once something in it is
+//! fetched. The number of live chunks is capped; past the cap, the chunk
+//! least recently tracked into is evicted, forgetting its history, so memory
+//! stays bounded no matter how much of the region is touched.
+
+#[cfg(feature = "no_std")]
+use alloc::collections::BTreeMap;
+#[cfg(not(feature = "no_std"))]
+use std::collections::BTreeMap;
+
+use crate::bitmap::BitmapTracker;
+use crate::span::Span;
+use crate::Address;
+
+/// Tracks fetched bytes of a huge region in lazily allocated chunks
+///
+/// Like [`BitmapTracker`], accesses are clipped to the region. Runs of
+/// fetched bytes are reported per chunk, so one crossing a chunk boundary
+/// shows up as two.
+///
+/// # Examples
+///
+/// ```
+/// # use asan_double_fetch::chunked::ChunkedTracker;
+/// # use asan_double_fetch::Span;
+/// #
+/// let mut rz = ChunkedTracker::new(Span::new(0, 1 << 40), 0x1000, 1, 2);
+///
+/// rz.track_access(0x4141, 8);
+/// rz.track_access(0x10_0000_4141, 8);
+/// assert_eq!(rz.len(), 2);
+/// assert_eq!(rz.check(0x4144, 1), Err(0x4141));
+///
+/// // evicts the chunk at 0x4000
+/// rz.track_access(0x20_0000_0000, 8);
+/// assert_eq!(rz.evictions(), 1);
+/// assert!(rz.check(0x4144, 1).is_ok());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChunkedTracker {
+    region: Span,
+    /// `region`'s start rounded down to a chunk
+    base: Address,
+    chunk_size: usize,
+    granularity: usize,
+    max_chunks: usize,
+    /// Live chunks, by index from `base`
+    chunks: BTreeMap<usize, Chunk>,
+    /// Live chunk indices, by when they were last tracked into
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+    evictions: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Chunk {
+    last_used: u64,
+    bitmap: BitmapTracker,
+}
+
+impl ChunkedTracker {
+    /// Tracker for `region` with nothing fetched yet, split into
+    /// `chunk_size`-sized aligned chunks of `granularity`-sized granules,
+    /// keeping at most `max_chunks` of them
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` or `granularity` isn't a power of two, if
+    /// `granularity` is larger than `chunk_size`, or if `max_chunks` is 0.
+    pub fn new(region: Span, chunk_size: usize, granularity: usize, max_chunks: usize) -> Self {
+        assert!(
+            chunk_size.is_power_of_two() && granularity.is_power_of_two(),
+            "chunk size {:#x} or granularity {:#x} is not a power of two",
+            chunk_size,
+            granularity
+        );
+        assert!(granularity <= chunk_size, "granularity larger than a chunk");
+        assert!(max_chunks > 0, "no chunks allowed");
+
+        Self {
+            base: region.start() & !(chunk_size - 1),
+            region,
+            chunk_size,
+            granularity,
+            max_chunks,
+            chunks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            evictions: 0,
+        }
+    }
+
+    /// The region this tracker shadows
+    pub fn region(&self) -> &Span {
+        &self.region
+    }
+
+    /// Number of chunks currently allocated
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns true if no chunk is allocated
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Number of chunks evicted, and their history lost, since creation or
+    /// the last [`clear`](Self::clear)
+    pub fn evictions(&self) -> usize {
+        self.evictions
+    }
+
+    /// Track an access, allocating the chunks it touches and evicting the
+    /// least recently used ones past the cap
+    pub fn track_access(&mut self, a: Address, sz: usize) {
+        let (first, last) = match self.chunk_range(a, sz) {
+            Some(range) => range,
+            None => return,
+        };
+
+        for idx in first..=last {
+            self.tick += 1;
+            let tick = self.tick;
+
+            if let Some(chunk) = self.chunks.get_mut(&idx) {
+                self.lru.remove(&chunk.last_used);
+                chunk.last_used = tick;
+            } else {
+                if self.chunks.len() == self.max_chunks {
+                    self.evict();
+                }
+                let bitmap =
+                    BitmapTracker::with_granularity(self.chunk_span(idx), self.granularity);
+                self.chunks.insert(
+                    idx,
+                    Chunk {
+                        last_used: tick,
+                        bitmap,
+                    },
+                );
+            }
+            self.lru.insert(tick, idx);
+
+            if let Some(chunk) = self.chunks.get_mut(&idx) {
+                chunk.bitmap.track_access(a, sz);
+            }
+        }
+    }
+
+    /// Forget an access. Chunks stay allocated.
+    pub fn remove_access(&mut self, a: Address, sz: usize) {
+        if let Some((first, last)) = self.chunk_range(a, sz) {
+            for (_idx, chunk) in self.chunks.range_mut(first..=last) {
+                chunk.bitmap.remove_access(a, sz);
+            }
+        }
+    }
+
+    /// Forget all accesses and free every chunk
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.lru.clear();
+        self.evictions = 0;
+    }
+
+    /// Number of fetched bytes in live chunks
+    pub fn occupied_len(&self) -> usize {
+        self.chunks
+            .values()
+            .map(|chunk| chunk.bitmap.occupied_len())
+            .sum()
+    }
+
+    /// Fraction of `total_len` bytes that were fetched, as far as live
+    /// chunks know. 0 if `total_len` is 0.
+    pub fn coverage(&self, total_len: usize) -> f64 {
+        if total_len == 0 {
+            return 0.0;
+        }
+
+        self.occupied_len() as f64 / total_len as f64
+    }
+
+    /// Check an access
+    ///
+    /// # Errors
+    ///
+    /// On re-fetching tracked bytes, `Err(fault)` is returned, where `fault`
+    /// is the start address of the _last_ offending run.
+    pub fn check(&self, a: Address, sz: usize) -> Result<(), Address> {
+        let (first, last) = match self.chunk_range(a, sz) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        match self
+            .chunks
+            .range(first..=last)
+            .rev()
+            .find_map(|(_idx, chunk)| chunk.bitmap.check(a, sz).err())
+        {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+
+    /// Every run of fetched bytes `[a, a + sz)` overlaps, in ascending order
+    pub fn check_all(&self, a: Address, sz: usize) -> impl Iterator<Item = Span> + '_ {
+        self.chunk_range(a, sz)
+            .into_iter()
+            .flat_map(move |(first, last)| self.chunks.range(first..=last))
+            .flat_map(move |(_idx, chunk)| chunk.bitmap.check_all(a, sz))
+    }
+
+    /// Drops the least recently used chunk
+    fn evict(&mut self) {
+        if let Some((_tick, idx)) = self.lru.pop_first() {
+            self.chunks.remove(&idx);
+            self.evictions += 1;
+        }
+    }
+
+    /// Indices of the first and last chunk the part of `[a, a + sz)` inside
+    /// the region touches
+    fn chunk_range(&self, a: Address, sz: usize) -> Option<(usize, usize)> {
+        let clipped = Span::with_len(a, sz).intersect(&self.region)?;
+
+        Some((
+            (clipped.start() - self.base) / self.chunk_size,
+            (clipped.end() - 1 - self.base) / self.chunk_size,
+        ))
+    }
+
+    /// The part of the region chunk `idx` covers
+    fn chunk_span(&self, idx: usize) -> Span {
+        let start = self.base + idx * self.chunk_size;
+        Span::new(
+            start.max(self.region.start()),
+            start.saturating_add(self.chunk_size).min(self.region.end()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lazy_chunks() {
+        let mut tracker = ChunkedTracker::new(Span::new(0x800, 0x10_0000), 0x1000, 1, 4);
+        assert!(tracker.is_empty());
+
+        // crosses into the second chunk
+        tracker.track_access(0xff8, 0x10);
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.check(0x1004, 1), Err(0x1000));
+        assert_eq!(tracker.check(0xff0, 0x20), Err(0x1000));
+        assert_eq!(
+            tracker.check_all(0xff0, 0x20).collect::<Vec<_>>(),
+            [Span::new(0xff8, 0x1000), Span::new(0x1000, 0x1008)]
+        );
+        assert_eq!(tracker.occupied_len(), 0x10);
+
+        // clipped to the region
+        tracker.track_access(0, 0x900);
+        assert_eq!(tracker.check(0x7ff, 2), Err(0x800));
+
+        tracker.remove_access(0xff8, 0x10);
+        assert!(tracker.check(0xff0, 0x20).is_ok());
+        assert_eq!(tracker.len(), 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_tracked() {
+        let mut tracker = ChunkedTracker::new(Span::new(0, 0x10_0000), 0x1000, 1, 2);
+
+        tracker.track_access(0x1000, 1);
+        tracker.track_access(0x2000, 1);
+        // refreshes the first chunk
+        tracker.track_access(0x1001, 1);
+        tracker.track_access(0x3000, 1);
+
+        assert_eq!(tracker.evictions(), 1);
+        assert_eq!(tracker.check(0x1000, 2), Err(0x1000));
+        assert!(tracker.check(0x2000, 1).is_ok());
+        assert_eq!(tracker.check(0x3000, 1), Err(0x3000));
+
+        tracker.clear();
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.evictions(), 0);
+    }
+}
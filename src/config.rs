@@ -0,0 +1,851 @@
+use once_cell::sync::OnceCell;
+
+/// Environment variable holding the runtime options string.
+///
+/// The format mirrors `ASAN_OPTIONS`: a list of `key=value` pairs separated by
+/// `:` or `,`, e.g. `ASAN_DOUBLE_FETCH_OPTIONS=endianness=big`. Kernel
+/// builds read it when they're built instead.
+#[cfg_attr(feature = "no_std", allow(dead_code))]
+pub const OPTIONS_ENV_VAR: &str = "ASAN_DOUBLE_FETCH_OPTIONS";
+
+/// Highest [`Config::verbosity`], logging every check of a watched region
+pub const MAX_VERBOSITY: u8 = 2;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Byte order of the target whose memory is being watched
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    pub const fn native() -> Self {
+        if cfg!(target_endian = "big") {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "native" => Some(Self::native()),
+            "little" | "le" => Some(Endianness::Little),
+            "big" | "be" => Some(Endianness::Big),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Endianness {
+    fn default() -> Self {
+        Self::native()
+    }
+}
+
+/// What a child process does with the regions it inherited from its parent
+///
+/// Only children with their own address space are affected; a
+/// `clone(CLONE_VM)` child shares the parent's region table just like a
+/// thread. Regions keep their addresses in the child, so a `MAP_SHARED`
+/// region still covers the pages both processes see, while the access
+/// history is the child's own copy from then on.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum ForkPolicy {
+    /// Keep watching them, along with their access history, so a child
+    /// fetching bytes its parent already fetched is reported
+    #[default]
+    Keep,
+    /// Keep watching them, but forget what was fetched before the fork
+    Reset,
+    /// Forget them; the child starts out watching nothing
+    Clear,
+}
+
+impl ForkPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "keep" => Some(ForkPolicy::Keep),
+            "reset" => Some(ForkPolicy::Reset),
+            "clear" => Some(ForkPolicy::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when a region to be watched overlaps watched ones
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum OverlapPolicy {
+    /// Replace them all with one region spanning them. In userspace builds
+    /// it keeps what was fetched from them.
+    #[default]
+    Merge,
+    /// Don't watch it, and fail the watch
+    Reject,
+}
+
+impl OverlapPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "merge" => Some(OverlapPolicy::Merge),
+            "reject" => Some(OverlapPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// What double-fetched bytes are replaced with
+#[cfg(not(feature = "no_std"))]
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum MutationPolicy {
+    /// Boundary values and random bytes, see [`mutate`](crate::mutation::mutate)
+    #[default]
+    Random,
+    /// A recognizable pattern on every re-fetch, searched for later to find
+    /// where the injected value ends up, see [`canary`](crate::canary)
+    Canary,
+}
+
+#[cfg(not(feature = "no_std"))]
+impl MutationPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "random" => Some(MutationPolicy::Random),
+            "canary" => Some(MutationPolicy::Canary),
+            _ => None,
+        }
+    }
+}
+
+/// Which re-fetches are reported
+#[cfg(not(feature = "no_std"))]
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum Reporting {
+    /// Every re-fetch
+    #[default]
+    Strict,
+    /// Only re-fetches of bytes that changed since they were first fetched,
+    /// see [`snapshot`](crate::snapshot)
+    ObservedChange,
+    /// Every re-fetch, those of changed bytes as errors and the rest as
+    /// warnings
+    Both,
+}
+
+#[cfg(not(feature = "no_std"))]
+impl Reporting {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "strict" => Some(Reporting::Strict),
+            "observed-change" => Some(Reporting::ObservedChange),
+            "both" => Some(Reporting::Both),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a region or fetch past `max_regions` or
+/// `max_tracked_spans`, see [`budget`](crate::budget)
+#[cfg(not(feature = "no_std"))]
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum BudgetPolicy {
+    /// The new region isn't watched, or the new fetch isn't tracked
+    #[default]
+    Refuse,
+    /// The region fetched from least since it was watched is unwatched to
+    /// make room, or the region's fetch history is forgotten
+    Evict,
+}
+
+#[cfg(not(feature = "no_std"))]
+impl BudgetPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "refuse" => Some(BudgetPolicy::Refuse),
+            "evict" => Some(BudgetPolicy::Evict),
+            _ => None,
+        }
+    }
+}
+
+/// How detections are reported
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum ReportStyle {
+    /// One log line per detection
+    #[default]
+    Runtime,
+    /// AddressSanitizer-style multi-line reports, with the stack of the
+    /// re-fetch and a shadow map of the fetched bytes around it
+    Asan,
+}
+
+impl ReportStyle {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "runtime" => Some(ReportStyle::Runtime),
+            "asan" => Some(ReportStyle::Asan),
+            _ => None,
+        }
+    }
+}
+
+/// Whether reports are colored
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum Color {
+    /// Only when writing to a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Color::Auto),
+            "always" => Some(Color::Always),
+            "never" => Some(Color::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a granularity: `byte`, `word`, `cacheline`, `page`, or a power of
+/// two number of bytes
+fn parse_granularity(value: &str) -> Option<usize> {
+    let granularity = match value {
+        "byte" => 1,
+        "word" => 8,
+        "cacheline" => 64,
+        "page" => page_size(),
+        _ => value.parse().ok()?,
+    };
+
+    if granularity.is_power_of_two() {
+        Some(granularity)
+    } else {
+        None
+    }
+}
+
+/// Parses a flag: `1`/`true` or `0`/`false`
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// The host's page size
+#[cfg(all(unix, not(feature = "no_std")))]
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The host's page size
+#[cfg(not(all(unix, not(feature = "no_std"))))]
+pub(crate) fn page_size() -> usize {
+    0x1000
+}
+
+/// Runtime configuration, parsed once from [`OPTIONS_ENV_VAR`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// Byte order used when mutating 2/4/8-byte fetches as integers. Set
+    /// this when the watched memory belongs to a foreign-endian target, e.g.
+    /// a big-endian guest's shared pages analyzed from a little-endian host.
+    pub endianness: Endianness,
+    /// Regions a forked child keeps watching
+    pub fork: ForkPolicy,
+    /// What happens when a region to be watched overlaps watched ones
+    pub overlap: OverlapPolicy,
+    /// How much is logged: 0 for detections and failures only, 1 to add
+    /// watched regions and the like, 2 to add every check of a watched
+    /// region. `quiet=1` is `verbosity=0`. Has no effect when the host
+    /// installed its own `log` logger.
+    pub verbosity: u8,
+    /// Never mutate double-fetched bytes, only detect and report, for
+    /// workloads whose shared memory mustn't be corrupted. Overrides
+    /// `mutation`, `mutation_plan` and the control socket's `mutate on`.
+    pub report_only: bool,
+    /// Format of detection reports
+    pub report_style: ReportStyle,
+    /// Count fetches per granule and print each region's heat map when it
+    /// stops being watched
+    pub heatmap: bool,
+    /// File a Graphviz graph of fetch sites and the region bytes they fetch
+    /// is written to at exit
+    #[cfg(not(feature = "no_std"))]
+    pub dot_file: Option<String>,
+    /// File an HTML report of all detections is written to at exit
+    #[cfg(not(feature = "no_std"))]
+    pub html_report: Option<String>,
+    /// Most bytes hexdumped around a detection, before and after mutating
+    /// them. 0 disables the dumps.
+    pub hexdump_width: usize,
+    /// Mutations printed if the target crashes after one, at most
+    /// [`MAX_HISTORY`](crate::crash::MAX_HISTORY). 0 prints none.
+    #[cfg(all(unix, not(feature = "no_std")))]
+    pub crash_history: usize,
+    /// Write out deferred reports on fatal signals before the target goes
+    /// down
+    #[cfg(all(unix, not(feature = "no_std")))]
+    pub handle_fatal_signals: bool,
+    /// File every byte changed by a mutation is logged to, in the format of
+    /// [`minimize`](crate::minimize)
+    #[cfg(not(feature = "no_std"))]
+    pub mutation_log: Option<String>,
+    /// What double-fetched bytes are replaced with
+    #[cfg(not(feature = "no_std"))]
+    pub mutation: MutationPolicy,
+    /// Which re-fetches are reported
+    #[cfg(not(feature = "no_std"))]
+    pub reporting: Reporting,
+    /// Mutation log whose bytes are set instead of random ones, on the
+    /// detections it lists only
+    #[cfg(not(feature = "no_std"))]
+    pub mutation_plan: Option<String>,
+    /// File every random draw deciding a mutation is logged to, in the
+    /// format of [`decisions`](crate::decisions)
+    #[cfg(not(feature = "no_std"))]
+    pub decision_log: Option<String>,
+    /// Decision log whose draws are replayed instead of drawing from the RNG
+    #[cfg(not(feature = "no_std"))]
+    pub decision_replay: Option<String>,
+    /// Coloring of `asan` style reports
+    pub color: Color,
+    /// Regions up to this many bytes long are tracked in a bitmap rather
+    /// than a tree, trading an eighth of their size in shadow memory for
+    /// constant-time checks. 0 always uses the tree.
+    pub bitmap_max_len: usize,
+    /// Size in bytes of the aligned granules accesses are rounded out to
+    /// before being tracked or checked, for regions that don't set their own.
+    /// Coarser granules mean smaller trees and faster checks at the cost of
+    /// reporting re-fetches of bytes next to ones that were fetched.
+    pub granularity: usize,
+    /// Regions at least this many bytes long are tracked in per-page
+    /// bitmaps allocated on first fetch rather than a tree. 0 never does.
+    pub chunked_min_len: usize,
+    /// Most shadow memory in bytes the per-page bitmaps of a single region
+    /// may take up; past it, the least recently fetched pages are forgotten
+    pub chunk_memory_cap: usize,
+    /// Most regions watched at once; past it, `budget_policy` decides. 0 is
+    /// no limit.
+    #[cfg(not(feature = "no_std"))]
+    pub max_regions: usize,
+    /// Most disjoint fetched spans a region tracked in a tree keeps; past it,
+    /// `budget_policy` decides. 0 is no limit.
+    #[cfg(not(feature = "no_std"))]
+    pub max_tracked_spans: usize,
+    /// What happens past `max_regions` or `max_tracked_spans`
+    #[cfg(not(feature = "no_std"))]
+    pub budget_policy: BudgetPolicy,
+    /// Prometheus textfile the runtime counters are periodically written to
+    #[cfg(feature = "prometheus")]
+    pub metrics_file: Option<String>,
+    /// Seconds between writes of `metrics_file`
+    #[cfg(feature = "prometheus")]
+    pub metrics_interval: u64,
+    /// File every watch, unwatch and check of a watched region is recorded
+    /// to, in the format of [`trace`](crate::trace)
+    #[cfg(feature = "trace_recorder")]
+    pub trace_file: Option<String>,
+    /// Unix socket the runtime takes requests on, see
+    /// [`control`](crate::control)
+    #[cfg(all(unix, feature = "control_socket"))]
+    pub control_socket: Option<String>,
+    /// Check accesses from the start. Without it, checking waits for a
+    /// `detect on` request on the `control_socket`.
+    #[cfg(all(unix, feature = "control_socket"))]
+    pub detect: bool,
+    /// Unix datagram socket every fetch from a watched region is sent to,
+    /// see [`feed`](crate::feed)
+    #[cfg(all(unix, feature = "fetch_feed"))]
+    pub fetch_feed: Option<String>,
+    /// Send detections to journald, or syslog without it, see
+    /// [`syslog`](crate::syslog)
+    #[cfg(all(unix, feature = "syslog"))]
+    pub syslog: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            endianness: Endianness::default(),
+            fork: ForkPolicy::default(),
+            overlap: OverlapPolicy::default(),
+            verbosity: 1,
+            report_only: false,
+            report_style: ReportStyle::default(),
+            hexdump_width: 64,
+            #[cfg(all(unix, not(feature = "no_std")))]
+            crash_history: 0,
+            #[cfg(all(unix, not(feature = "no_std")))]
+            handle_fatal_signals: false,
+            #[cfg(not(feature = "no_std"))]
+            mutation_log: None,
+            #[cfg(not(feature = "no_std"))]
+            mutation: MutationPolicy::default(),
+            #[cfg(not(feature = "no_std"))]
+            reporting: Reporting::default(),
+            #[cfg(not(feature = "no_std"))]
+            mutation_plan: None,
+            #[cfg(not(feature = "no_std"))]
+            decision_log: None,
+            #[cfg(not(feature = "no_std"))]
+            decision_replay: None,
+            heatmap: false,
+            #[cfg(not(feature = "no_std"))]
+            dot_file: None,
+            #[cfg(not(feature = "no_std"))]
+            html_report: None,
+            color: Color::default(),
+            bitmap_max_len: 0x10000,
+            granularity: 1,
+            chunked_min_len: 1 << 30,
+            chunk_memory_cap: 64 << 20,
+            #[cfg(not(feature = "no_std"))]
+            max_regions: 0,
+            #[cfg(not(feature = "no_std"))]
+            max_tracked_spans: 0,
+            #[cfg(not(feature = "no_std"))]
+            budget_policy: BudgetPolicy::default(),
+            #[cfg(feature = "prometheus")]
+            metrics_file: None,
+            #[cfg(feature = "prometheus")]
+            metrics_interval: 10,
+            #[cfg(feature = "trace_recorder")]
+            trace_file: None,
+            #[cfg(all(unix, feature = "control_socket"))]
+            control_socket: None,
+            #[cfg(all(unix, feature = "control_socket"))]
+            detect: true,
+            #[cfg(all(unix, feature = "fetch_feed"))]
+            fetch_feed: None,
+            #[cfg(all(unix, feature = "syslog"))]
+            syslog: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parses an options string, ignoring (and reporting) unknown keys and
+    /// malformed values so a typo never takes down the target.
+    pub fn parse(options: &str) -> Self {
+        let mut config = Self::default();
+
+        for option in options.split([':', ',']) {
+            let option = option.trim();
+            if option.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    log::warn!("ignoring malformed option {:?}", option);
+                    continue;
+                }
+            };
+
+            let ok = match key {
+                "endianness" => Endianness::parse(value)
+                    .map(|endianness| config.endianness = endianness)
+                    .is_some(),
+                "fork" => ForkPolicy::parse(value)
+                    .map(|fork| config.fork = fork)
+                    .is_some(),
+                "overlap" => OverlapPolicy::parse(value)
+                    .map(|overlap| config.overlap = overlap)
+                    .is_some(),
+                #[cfg(not(feature = "no_std"))]
+                "mutation" => MutationPolicy::parse(value)
+                    .map(|mutation| config.mutation = mutation)
+                    .is_some(),
+                #[cfg(not(feature = "no_std"))]
+                "reporting" => Reporting::parse(value)
+                    .map(|reporting| config.reporting = reporting)
+                    .is_some(),
+                "verbosity" => value
+                    .parse()
+                    .ok()
+                    .filter(|verbosity| *verbosity <= MAX_VERBOSITY)
+                    .map(|verbosity| config.verbosity = verbosity)
+                    .is_some(),
+                "quiet" => parse_bool(value)
+                    .map(|quiet| config.verbosity = if quiet { 0 } else { 1 })
+                    .is_some(),
+                "report_only" => parse_bool(value)
+                    .map(|report_only| config.report_only = report_only)
+                    .is_some(),
+                "report_style" => ReportStyle::parse(value)
+                    .map(|style| config.report_style = style)
+                    .is_some(),
+                "heatmap" => parse_bool(value)
+                    .map(|heatmap| config.heatmap = heatmap)
+                    .is_some(),
+                #[cfg(not(feature = "no_std"))]
+                "dot_file" => {
+                    config.dot_file = Some(value.to_owned());
+                    true
+                }
+                #[cfg(not(feature = "no_std"))]
+                "html_report" => {
+                    config.html_report = Some(value.to_owned());
+                    true
+                }
+                "hexdump_width" => value
+                    .parse()
+                    .map(|width| config.hexdump_width = width)
+                    .is_ok(),
+                #[cfg(all(unix, not(feature = "no_std")))]
+                "crash_history" => value
+                    .parse()
+                    .map(|history| config.crash_history = history)
+                    .is_ok(),
+                #[cfg(all(unix, not(feature = "no_std")))]
+                "handle_fatal_signals" => parse_bool(value)
+                    .map(|handle| config.handle_fatal_signals = handle)
+                    .is_some(),
+                #[cfg(not(feature = "no_std"))]
+                "mutation_log" => {
+                    config.mutation_log = Some(value.to_owned());
+                    true
+                }
+                #[cfg(not(feature = "no_std"))]
+                "mutation_plan" => {
+                    config.mutation_plan = Some(value.to_owned());
+                    true
+                }
+                #[cfg(not(feature = "no_std"))]
+                "decision_log" => {
+                    config.decision_log = Some(value.to_owned());
+                    true
+                }
+                #[cfg(not(feature = "no_std"))]
+                "decision_replay" => {
+                    config.decision_replay = Some(value.to_owned());
+                    true
+                }
+                "color" => Color::parse(value)
+                    .map(|color| config.color = color)
+                    .is_some(),
+                "granularity" => parse_granularity(value)
+                    .map(|granularity| config.granularity = granularity)
+                    .is_some(),
+                "bitmap_max_len" => value.parse().map(|len| config.bitmap_max_len = len).is_ok(),
+                "chunked_min_len" => value
+                    .parse()
+                    .map(|len| config.chunked_min_len = len)
+                    .is_ok(),
+                "chunk_memory_cap" => value
+                    .parse()
+                    .map(|cap| config.chunk_memory_cap = cap)
+                    .is_ok(),
+                #[cfg(not(feature = "no_std"))]
+                "max_regions" => value.parse().map(|max| config.max_regions = max).is_ok(),
+                #[cfg(not(feature = "no_std"))]
+                "max_tracked_spans" => value
+                    .parse()
+                    .map(|max| config.max_tracked_spans = max)
+                    .is_ok(),
+                #[cfg(not(feature = "no_std"))]
+                "budget_policy" => BudgetPolicy::parse(value)
+                    .map(|policy| config.budget_policy = policy)
+                    .is_some(),
+                #[cfg(feature = "prometheus")]
+                "metrics_file" => {
+                    config.metrics_file = Some(value.to_owned());
+                    true
+                }
+                #[cfg(feature = "prometheus")]
+                "metrics_interval" => value
+                    .parse()
+                    .ok()
+                    .filter(|interval| *interval > 0)
+                    .map(|interval| config.metrics_interval = interval)
+                    .is_some(),
+                #[cfg(feature = "trace_recorder")]
+                "trace_file" => {
+                    config.trace_file = Some(value.to_owned());
+                    true
+                }
+                #[cfg(all(unix, feature = "control_socket"))]
+                "control_socket" => {
+                    config.control_socket = Some(value.to_owned());
+                    true
+                }
+                #[cfg(all(unix, feature = "control_socket"))]
+                "detect" => parse_bool(value)
+                    .map(|detect| config.detect = detect)
+                    .is_some(),
+                #[cfg(all(unix, feature = "fetch_feed"))]
+                "fetch_feed" => {
+                    config.fetch_feed = Some(value.to_owned());
+                    true
+                }
+                #[cfg(all(unix, feature = "syslog"))]
+                "syslog" => parse_bool(value)
+                    .map(|syslog| config.syslog = syslog)
+                    .is_some(),
+                _ => {
+                    log::warn!("ignoring unknown option {:?}", key);
+                    continue;
+                }
+            };
+
+            if !ok {
+                log::warn!("ignoring invalid value {:?} for {:?}", value, key);
+            }
+        }
+
+        config
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    fn from_env() -> Self {
+        std::env::var(OPTIONS_ENV_VAR)
+            .map(|options| Self::parse(&options))
+            .unwrap_or_default()
+    }
+
+    /// Kernel builds have no environment to read at runtime, so the options
+    /// are taken from the build's
+    #[cfg(feature = "no_std")]
+    fn from_env() -> Self {
+        option_env!("ASAN_DOUBLE_FETCH_OPTIONS")
+            .map(Self::parse)
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the global runtime configuration, parsing it on first use
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        // before parsing, so problems with the options get logged
+        crate::printer::install();
+        let config = Config::from_env();
+        crate::printer::set_verbosity(config.verbosity);
+        config
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endianness() {
+        assert_eq!(Config::parse("endianness=big").endianness, Endianness::Big);
+        assert_eq!(
+            Config::parse("endianness=le").endianness,
+            Endianness::Little
+        );
+        assert_eq!(
+            Config::parse("endianness=native").endianness,
+            Endianness::native()
+        );
+    }
+
+    #[test]
+    fn parse_overlap() {
+        assert_eq!(Config::parse("").overlap, OverlapPolicy::Merge);
+        assert_eq!(
+            Config::parse("overlap=reject").overlap,
+            OverlapPolicy::Reject
+        );
+    }
+
+    #[test]
+    fn parse_fork() {
+        assert_eq!(Config::parse("").fork, ForkPolicy::Keep);
+        assert_eq!(Config::parse("fork=reset").fork, ForkPolicy::Reset);
+        assert_eq!(Config::parse("fork=clear").fork, ForkPolicy::Clear);
+        assert_eq!(Config::parse("fork=sometimes").fork, ForkPolicy::Keep);
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn parse_mutation() {
+        assert_eq!(Config::parse("").mutation, MutationPolicy::Random);
+        assert_eq!(
+            Config::parse("mutation=canary").mutation,
+            MutationPolicy::Canary
+        );
+        assert_eq!(
+            Config::parse("mutation=gently").mutation,
+            MutationPolicy::Random
+        );
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn parse_reporting() {
+        assert_eq!(Config::parse("").reporting, Reporting::Strict);
+        assert_eq!(
+            Config::parse("reporting=observed-change").reporting,
+            Reporting::ObservedChange
+        );
+        assert_eq!(Config::parse("reporting=both").reporting, Reporting::Both);
+        assert_eq!(Config::parse("reporting=loud").reporting, Reporting::Strict);
+    }
+
+    #[test]
+    fn parse_bitmap_max_len() {
+        assert_eq!(Config::parse("").bitmap_max_len, 0x10000);
+        assert_eq!(Config::parse("bitmap_max_len=0").bitmap_max_len, 0);
+        assert_eq!(
+            Config::parse("bitmap_max_len=-1").bitmap_max_len,
+            Config::default().bitmap_max_len
+        );
+    }
+
+    #[test]
+    fn parse_chunking() {
+        let config = Config::parse("chunked_min_len=4096,chunk_memory_cap=0x10");
+        assert_eq!(config.chunked_min_len, 4096);
+        assert_eq!(config.chunk_memory_cap, Config::default().chunk_memory_cap);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn parse_metrics() {
+        let config = Config::parse("metrics_file=/tmp/df.prom,metrics_interval=0");
+        assert_eq!(config.metrics_file.as_deref(), Some("/tmp/df.prom"));
+        assert_eq!(config.metrics_interval, 10);
+        assert_eq!(Config::parse("metrics_interval=60").metrics_interval, 60);
+    }
+
+    #[cfg(feature = "trace_recorder")]
+    #[test]
+    fn parse_trace_file() {
+        assert_eq!(Config::parse("").trace_file, None);
+        assert_eq!(
+            Config::parse("trace_file=/tmp/df.trace")
+                .trace_file
+                .as_deref(),
+            Some("/tmp/df.trace")
+        );
+    }
+
+    #[cfg(all(unix, feature = "control_socket"))]
+    #[test]
+    fn parse_control_socket() {
+        let config = Config::parse("");
+        assert_eq!(config.control_socket, None);
+        assert!(config.detect);
+        let config = Config::parse("control_socket=/run/df.sock,detect=false");
+        assert_eq!(config.control_socket.as_deref(), Some("/run/df.sock"));
+        assert!(!config.detect);
+    }
+
+    #[cfg(all(unix, feature = "fetch_feed"))]
+    #[test]
+    fn parse_fetch_feed() {
+        assert_eq!(Config::parse("").fetch_feed, None);
+        let config = Config::parse("fetch_feed=/tmp/df-feed.sock");
+        assert_eq!(config.fetch_feed.as_deref(), Some("/tmp/df-feed.sock"));
+    }
+
+    #[cfg(all(unix, feature = "syslog"))]
+    #[test]
+    fn parse_syslog() {
+        assert!(!Config::parse("").syslog);
+        assert!(Config::parse("syslog=1").syslog);
+    }
+
+    #[test]
+    fn parse_report_style() {
+        let config = Config::parse("report_style=asan,color=never");
+        assert_eq!(config.report_style, ReportStyle::Asan);
+        assert_eq!(config.color, Color::Never);
+
+        let config = Config::parse("report_style=ubsan,color=sometimes");
+        assert_eq!(config.report_style, ReportStyle::Runtime);
+        assert_eq!(config.color, Color::Auto);
+
+        assert_eq!(Config::parse("").hexdump_width, 64);
+        assert_eq!(Config::parse("hexdump_width=0").hexdump_width, 0);
+    }
+
+    #[cfg(all(unix, not(feature = "no_std")))]
+    #[test]
+    fn parse_userspace_outputs() {
+        assert_eq!(Config::parse("").crash_history, 0);
+        assert_eq!(Config::parse("crash_history=8").crash_history, 8);
+        assert!(!Config::parse("").handle_fatal_signals);
+        assert!(Config::parse("handle_fatal_signals=1").handle_fatal_signals);
+        let config = Config::parse("mutation_log=df.log,mutation_plan=df.plan");
+        assert_eq!(config.mutation_log.as_deref(), Some("df.log"));
+        assert_eq!(config.mutation_plan.as_deref(), Some("df.plan"));
+        let config = Config::parse("decision_log=df.rng,decision_replay=old.rng");
+        assert_eq!(config.decision_log.as_deref(), Some("df.rng"));
+        assert_eq!(config.decision_replay.as_deref(), Some("old.rng"));
+        assert_eq!(
+            Config::parse("html_report=/tmp/df.html")
+                .html_report
+                .as_deref(),
+            Some("/tmp/df.html")
+        );
+        assert_eq!(
+            Config::parse("dot_file=df.dot").dot_file.as_deref(),
+            Some("df.dot")
+        );
+    }
+
+    #[test]
+    fn parse_verbosity() {
+        assert_eq!(Config::parse("").verbosity, 1);
+        assert_eq!(Config::parse("verbosity=2").verbosity, 2);
+        assert_eq!(Config::parse("verbosity=3").verbosity, 1);
+        assert_eq!(Config::parse("quiet=1").verbosity, 0);
+        assert_eq!(Config::parse("quiet=false").verbosity, 1);
+        assert_eq!(Config::parse("quiet=yes").verbosity, 1);
+        assert!(Config::parse("heatmap=true").heatmap);
+    }
+
+    #[test]
+    fn parse_report_only() {
+        assert!(!Config::parse("").report_only);
+        assert!(Config::parse("report_only=1").report_only);
+        assert!(!Config::parse("report_only=0").report_only);
+    }
+
+    #[test]
+    fn parse_granularity() {
+        assert_eq!(Config::parse("").granularity, 1);
+        assert_eq!(Config::parse("granularity=cacheline").granularity, 64);
+        assert_eq!(Config::parse("granularity=16").granularity, 16);
+        assert_eq!(Config::parse("granularity=page").granularity, page_size());
+        assert_eq!(Config::parse("granularity=12").granularity, 1);
+        assert_eq!(Config::parse("granularity=0").granularity, 1);
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn parse_budget() {
+        let config = Config::parse("");
+        assert_eq!(config.max_regions, 0);
+        assert_eq!(config.budget_policy, BudgetPolicy::Refuse);
+
+        let config = Config::parse("max_regions=64,max_tracked_spans=1024,budget_policy=evict");
+        assert_eq!(config.max_regions, 64);
+        assert_eq!(config.max_tracked_spans, 1024);
+        assert_eq!(config.budget_policy, BudgetPolicy::Evict);
+        assert_eq!(
+            Config::parse("budget_policy=lru").budget_policy,
+            BudgetPolicy::Refuse
+        );
+    }
+
+    #[test]
+    fn parse_ignores_garbage() {
+        assert_eq!(
+            Config::parse(":bogus,endianness=middle,nokey"),
+            Config::default()
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Control socket for live processes
+//!
+//! With the `control_socket` option set to a path, the runtime listens on a
+//! Unix domain socket there, so an operator can look at a long-running
+//! service and switch checking on only for a test window, e.g. with
+//! `socat - UNIX-CONNECT:<path>`. Combined with `detect=false`, the service
+//! runs unchecked until told otherwise. A socket left at the path by an
+//! earlier run is replaced, but one that's still listened on, e.g. by
+//! another instance of the service, or anything else there is left alone
+//! and the runtime doesn't listen.
+//!
+//! Requests are single lines. Each reply is zero or more lines of output
+//! followed by `ok` or `error: <reason>`:
+//!
+//! ```text
+//! regions             watched regions and the bytes fetched from each
+//! stats               the counters of __asan_double_fetch_get_stats
+//! dump                the debug dump, also printed by the target
+//! detect on|off       start or stop checking accesses
+//! mutate on|off       start or stop mutating double-fetched bytes
+//! reset               forget every fetch, keeping the regions watched
+//! help                this list
+//! ```
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::PoisonError;
+
+use crate::{config, debug, stats, TRACKED_MEMORY_REGIONS};
+
+/// Whether accesses are checked at all
+static DETECTING: AtomicBool = AtomicBool::new(true);
+
+/// Whether double-fetched bytes are mutated
+static MUTATING: AtomicBool = AtomicBool::new(true);
+
+pub(crate) fn detecting() -> bool {
+    DETECTING.load(Ordering::Relaxed)
+}
+
+pub(crate) fn mutating() -> bool {
+    MUTATING.load(Ordering::Relaxed)
+}
+
+const HELP: &str = "regions\nstats\ndump\ndetect on|off\nmutate on|off\nreset\nhelp";
+
+fn switch(flag: &AtomicBool, value: Option<&str>) -> Result<String, String> {
+    match value {
+        Some("on") => flag.store(true, Ordering::Relaxed),
+        Some("off") => flag.store(false, Ordering::Relaxed),
+        _ => return Err("expected on or off".into()),
+    }
+    Ok(String::new())
+}
+
+fn regions() -> String {
+    let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+        Some(mem_regions) => mem_regions.read(),
+        None => return String::new(),
+    };
+    mem_regions
+        .iter()
+        .map(|(region, tracker)| {
+            let tracker = tracker.read().unwrap_or_else(PoisonError::into_inner);
+            format!(
+                "{} len={:#x} fetched={:#x}\n",
+                region,
+                region.len(),
+                tracker.occupied_len()
+            )
+        })
+        .collect()
+}
+
+fn reset() {
+    if let Some(mem_regions) = TRACKED_MEMORY_REGIONS.get() {
+        for (_, tracker) in mem_regions.read().iter() {
+            tracker
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+}
+
+/// Carries out one request, returning its output
+fn handle(request: &str) -> Result<String, String> {
+    let mut words = request.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let value = words.next();
+    if words.next().is_some() {
+        return Err("too many arguments".into());
+    }
+
+    match (command, value) {
+        ("regions", None) => Ok(regions()),
+        ("stats", None) => {
+            let stats = stats::get();
+            Ok(format!(
+                "checks {}\nregion_hits {}\ndetections {}\nmutations {}\nwatched_regions {}\ntracked_bytes {}\n",
+                stats.checks,
+                stats.region_hits,
+                stats.detections,
+                stats.mutations,
+                stats.watched_regions,
+                stats.tracked_bytes
+            ))
+        }
+        ("dump", None) => {
+            debug::__asan_double_fetch_debug_dump();
+            Ok(match TRACKED_MEMORY_REGIONS.get() {
+                Some(mem_regions) => debug::render(&mem_regions.read()) + "\n",
+                None => String::new(),
+            })
+        }
+        ("detect", value) => {
+            let output = switch(&DETECTING, value)?;
+            log::info!(
+                "checking switched {} over the control socket",
+                value.unwrap()
+            );
+            Ok(output)
+        }
+        ("mutate", value) => switch(&MUTATING, value),
+        ("reset", None) => {
+            reset();
+            Ok(String::new())
+        }
+        ("help", None) => Ok(format!("{}\n", HELP)),
+        ("", None) => Err("empty request".into()),
+        _ => Err(format!("unknown request {:?}, try help", request.trim())),
+    }
+}
+
+/// Answers the requests of one client until it hangs up
+fn serve(stream: UnixStream) -> io::Result<()> {
+    let mut reply = stream.try_clone()?;
+    for request in BufReader::new(stream).lines() {
+        match handle(&request?) {
+            Ok(output) => writeln!(reply, "{}ok", output)?,
+            Err(e) => writeln!(reply, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Removes the socket an earlier run left at `path`, which would make
+/// binding fail. A socket something still listens on, or anything else at
+/// `path`, is left alone and an error.
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "path is in use by another listener",
+            )),
+            Err(e) => Err(e),
+        },
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and isn't a socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the `detect` option and starts listening on the `control_socket`,
+/// if one is configured. Called once, from runtime init.
+pub(crate) fn init() {
+    let config = config::get();
+    DETECTING.store(config.detect, Ordering::Relaxed);
+    let path = match &config.control_socket {
+        Some(path) => path,
+        None => return,
+    };
+
+    if let Err(e) = remove_stale_socket(path) {
+        log::error!("not listening on control socket {}: {}", path, e);
+        return;
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("failed to listen on control socket {}: {}", path, e);
+            return;
+        }
+    };
+
+    let spawned = std::thread::Builder::new()
+        .name("asan-df-control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(serve) {
+                    log::warn!("control socket client failed: {}", e);
+                }
+            }
+        });
+    match spawned {
+        Ok(_) => log::info!("listening on control socket {}", path),
+        Err(e) => log::error!("failed to start control socket: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_requests() {
+        assert_eq!(handle("help").unwrap().lines().count(), 7);
+        assert!(handle("stats").unwrap().starts_with("checks "));
+        assert_eq!(
+            handle("mutate sometimes").unwrap_err(),
+            "expected on or off"
+        );
+        assert_eq!(
+            handle("stats now").unwrap_err(),
+            "unknown request \"stats now\", try help"
+        );
+        assert_eq!(handle("reset all of it").unwrap_err(), "too many arguments");
+        assert_eq!(handle(" ").unwrap_err(), "empty request");
+
+        // not the real switches, other tests rely on them
+        let flag = AtomicBool::new(true);
+        assert_eq!(switch(&flag, Some("off")), Ok(String::new()));
+        assert!(!flag.load(Ordering::Relaxed));
+        assert!(switch(&flag, None).is_err());
+        assert_eq!(switch(&flag, Some("on")), Ok(String::new()));
+        assert!(flag.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn serves_a_client() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(server));
+
+        let mut writer = client.try_clone().unwrap();
+        writer.write_all(b"help\nbogus\n").unwrap();
+        writer.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let replies: Vec<String> = BufReader::new(client).lines().map(Result::unwrap).collect();
+        assert_eq!(replies[..7].join("\n"), HELP);
+        assert_eq!(replies[7], "ok");
+        assert_eq!(replies[8], "error: unknown request \"bogus\", try help");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn removes_only_sockets() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            let path = dir.join(format!("asan-df-{}-{}", std::process::id(), name));
+            path.to_str().unwrap().to_owned()
+        };
+
+        let file = path("file");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+        std::fs::remove_file(&file).unwrap();
+
+        let socket = path("sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let err = remove_stale_socket(&socket).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(std::fs::symlink_metadata(&socket).is_ok());
+
+        drop(listener);
+        remove_stale_socket(&socket).unwrap();
+        assert!(std::fs::symlink_metadata(&socket).is_err());
+        // nothing to remove
+        remove_stale_socket(&socket).unwrap();
+    }
+}
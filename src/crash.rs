@@ -0,0 +1,372 @@
+//! Fatal signal handling
+//!
+//! A mutation of double-fetched bytes often crashes the target right away,
+//! before anything deferred reaches its sink. With the `handle_fatal_signals`
+//! option on, the runtime handles `SIGSEGV`, `SIGBUS`, `SIGILL`, `SIGFPE` and
+//! `SIGABRT` by writing out the reports still deferred in the report queue
+//! and a summary of what it detected.
+//!
+//! With the `crash_history` option set to N, the runtime also keeps its most
+//! recent mutations and prints the last N first, so the crash can be pinned
+//! on the injected perturbation that caused it:
+//!
+//! ```text
+//! (runtime) signal 11 after 3 mutations, most recent last:
+//! (runtime)   #2 region 0x7f3a2c001000 +0x10 len=4 pc 0x55d0c2a1b3f4: 10 00 00 00 -> ff ff ff 7f
+//! ```
+//!
+//! The crash may well have happened inside `malloc` or with a lock held, so
+//! the handler sticks to async-signal-safe work: mutations are formatted when
+//! recorded, numbers are written out by hand, and everything goes to stderr
+//! with `write(2)`. The HTML report, fetch graph, trace and metrics written at
+//! exit are not written on a crash. The handler runs on an alternate stack,
+//! so stack overflows are reported as well, then passes the signal on to
+//! the handler it replaced, or lets the default action take its course.
+//!
+//! It is installed before the trap modes install theirs, so faults those
+//! handle never get here. A handler of the target's own, say a JIT's,
+//! is only called after this one, so every signal it handles is written out
+//! as a crash first; leave `handle_fatal_signals` and `crash_history` off
+//! for such targets.
+
+use core::fmt::{self, Write};
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::os::raw::c_int;
+use std::sync::{Mutex, Once, PoisonError};
+
+use crate::sigchain::Chain;
+use crate::signal_safe::{self, StackWriter};
+use crate::span::Span;
+use crate::{config, report_queue, stats, Address, DETECTIONS};
+
+/// Most mutations kept
+pub const MAX_HISTORY: usize = 64;
+/// Bytes kept of each mutation's data
+const MAX_BYTES: usize = 16;
+
+/// The first bytes of some data
+#[derive(Clone, Copy)]
+pub(crate) struct Bytes {
+    buf: [u8; MAX_BYTES],
+    len: usize,
+}
+
+impl Bytes {
+    const EMPTY: Self = Self {
+        buf: [0; MAX_BYTES],
+        len: 0,
+    };
+
+    pub fn of(data: &[u8]) -> Self {
+        let mut bytes = Self::EMPTY;
+        bytes.len = data.len().min(MAX_BYTES);
+        bytes.buf[..bytes.len].copy_from_slice(&data[..bytes.len]);
+        bytes
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.buf[..self.len].iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Mutation {
+    region: Address,
+    offset: usize,
+    len: usize,
+    pc: Option<Address>,
+    old: Bytes,
+    new: Bytes,
+}
+
+/// Ring of the most recent mutations, formatted as they are recorded
+struct History {
+    lines: [StackWriter; MAX_HISTORY],
+    /// Mutations ever recorded
+    count: usize,
+}
+
+impl History {
+    fn push(&mut self, mutation: &Mutation) {
+        let mut line = StackWriter::new();
+        let _ = write_mutation(&mut line, self.count, mutation);
+        self.lines[self.count % MAX_HISTORY] = line;
+        self.count += 1;
+    }
+
+    /// The last `n` mutations, oldest first
+    fn last(&self, n: usize) -> impl Iterator<Item = &StackWriter> + '_ {
+        let n = n.min(self.count).min(MAX_HISTORY);
+        (self.count - n..self.count).map(move |seq| &self.lines[seq % MAX_HISTORY])
+    }
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    lines: [StackWriter::new(); MAX_HISTORY],
+    count: 0,
+});
+
+/// Records that `[addr, addr + new.len())` in `region`, fetched by the
+/// instruction at `pc`, was mutated from `old` to `new`. Does nothing
+/// unless the `crash_history` option is set.
+pub(crate) fn record(region: &Span, addr: Address, pc: Option<Address>, old: &Bytes, new: &[u8]) {
+    if config::get().crash_history == 0 {
+        return;
+    }
+
+    HISTORY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(&Mutation {
+            region: region.start(),
+            offset: addr.wrapping_sub(region.start()),
+            len: new.len(),
+            pc,
+            old: *old,
+            new: Bytes::of(new),
+        });
+}
+
+fn write_mutation<W: Write>(w: &mut W, seq: usize, mutation: &Mutation) -> fmt::Result {
+    write!(
+        w,
+        "(runtime)   #{} region {:#x} +{:#x} len={}",
+        seq, mutation.region, mutation.offset, mutation.len
+    )?;
+    if let Some(pc) = mutation.pc {
+        write!(w, " pc {:#x}", pc)?;
+    }
+    write!(w, ": {} -> {}", mutation.old, mutation.new)?;
+    if mutation.len > MAX_BYTES {
+        w.write_str(" ...")?;
+    }
+    w.write_str("\n")
+}
+
+const SIGNALS: [c_int; 5] = [
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGILL,
+    libc::SIGFPE,
+    libc::SIGABRT,
+];
+
+/// The handlers replaced for each of [`SIGNALS`]
+static CHAINS: [Chain; SIGNALS.len()] = [const { Chain::new() }; SIGNALS.len()];
+
+/// Bytes of the stack the handler runs on
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+/// Set once a crash is being handled, so a second fatal signal while
+/// writing doesn't write again
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+fn write_history(sig: c_int, n: usize) {
+    let mut w = StackWriter::new();
+    // `try_lock` never blocks; a crash while recording a
+    // mutation leaves the lock held
+    let history = match signal_safe::try_lock(HISTORY.try_lock()) {
+        Some(history) => history,
+        None => {
+            w.push(b"(runtime) signal ");
+            w.push_dec(sig as usize);
+            w.push(b" while recording a mutation, history unavailable\n");
+            w.flush();
+            return;
+        }
+    };
+    if history.count == 0 {
+        return;
+    }
+
+    w.push(b"(runtime) signal ");
+    w.push_dec(sig as usize);
+    w.push(b" after ");
+    w.push_dec(history.count);
+    w.push(b" mutations, most recent last:\n");
+    w.flush();
+    for line in history.last(n) {
+        signal_safe::write_stderr(line.as_bytes());
+    }
+}
+
+/// Writes out the deferred reports that would otherwise be lost with the
+/// process, and a summary
+fn write_pending(sig: c_int) {
+    let mut w = StackWriter::new();
+    while let Some(report) = report_queue::take() {
+        w.push(b"(runtime) double-fetch detected! addr ");
+        w.push_hex(report.addr);
+        w.push(b" len ");
+        w.push_dec(report.len);
+        if let Some(pc) = report.pc {
+            w.push(b" pc ");
+            w.push_hex(pc);
+        }
+        w.push(b"\n");
+        w.flush();
+    }
+
+    w.push(b"(runtime) signal ");
+    w.push_dec(sig as usize);
+    w.push(b": ");
+    w.push_dec(DETECTIONS.load(Ordering::Relaxed));
+    w.push(b" double-fetches detected, ");
+    w.push_dec(stats::MUTATIONS.load(Ordering::Relaxed));
+    w.push(b" mutated\n");
+    w.flush();
+}
+
+extern "C" fn on_crash(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    if !CRASHED.swap(true, Ordering::SeqCst) {
+        // initialized before the handler was installed
+        let config = config::get();
+        if config.crash_history > 0 {
+            write_history(sig, config.crash_history);
+        }
+        if config.handle_fatal_signals {
+            write_pending(sig);
+        }
+    }
+
+    if let Some(idx) = SIGNALS.iter().position(|&signal| signal == sig) {
+        CHAINS[idx].pass_on(sig, info, ctx);
+    }
+}
+
+/// Gives the calling thread a stack to handle signals on, unless it already
+/// has one. Other threads handle them on the stack they set up, if any.
+fn install_alt_stack() {
+    unsafe {
+        let mut current: libc::stack_t = mem::zeroed();
+        if libc::sigaltstack(ptr::null(), &mut current) != 0
+            || current.ss_flags & libc::SS_DISABLE == 0
+        {
+            return;
+        }
+
+        let size = ALT_STACK_SIZE.max(libc::SIGSTKSZ);
+        let stack = libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if stack == libc::MAP_FAILED {
+            log::error!("failed to map a signal stack");
+            return;
+        }
+        let stack = libc::stack_t {
+            ss_sp: stack,
+            ss_flags: 0,
+            ss_size: size,
+        };
+        if libc::sigaltstack(&stack, ptr::null_mut()) != 0 {
+            log::error!("failed to install a signal stack");
+            libc::munmap(stack.ss_sp, size);
+        }
+    }
+}
+
+/// Installs the fatal signal handlers, if `handle_fatal_signals` is on or
+/// `crash_history` is set. Called from runtime init, and before the trap
+/// modes install their handlers; only the first call installs.
+pub(crate) fn init() {
+    let config = config::get();
+    if !config.handle_fatal_signals && config.crash_history == 0 {
+        return;
+    }
+
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        install_alt_stack();
+        for (signal, chain) in SIGNALS.iter().zip(&CHAINS) {
+            chain.install(*signal, on_crash);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mutation(offset: usize, old: &[u8], new: &[u8]) -> Mutation {
+        Mutation {
+            region: 0x1000,
+            offset,
+            len: new.len(),
+            pc: Some(0x4141),
+            old: Bytes::of(old),
+            new: Bytes::of(new),
+        }
+    }
+
+    #[test]
+    fn keeps_the_latest() {
+        let mut history = History {
+            lines: [StackWriter::new(); MAX_HISTORY],
+            count: 0,
+        };
+        for offset in 0..MAX_HISTORY + 3 {
+            history.push(&mutation(offset, &[0], &[1]));
+        }
+
+        let last: Vec<&[u8]> = history.last(2).map(StackWriter::as_bytes).collect();
+        assert_eq!(
+            last,
+            [
+                format!(
+                    "(runtime)   #{0} region 0x1000 +{0:#x} len=1 pc 0x4141: 00 -> 01\n",
+                    MAX_HISTORY + 1
+                )
+                .as_bytes(),
+                format!(
+                    "(runtime)   #{0} region 0x1000 +{0:#x} len=1 pc 0x4141: 00 -> 01\n",
+                    MAX_HISTORY + 2
+                )
+                .as_bytes(),
+            ]
+        );
+        assert_eq!(history.last(1000).count(), MAX_HISTORY);
+        assert!(history
+            .last(1000)
+            .next()
+            .unwrap()
+            .as_bytes()
+            .starts_with(b"(runtime)   #3 "));
+    }
+
+    #[test]
+    fn formats_mutations() {
+        let mut out = String::new();
+        write_mutation(
+            &mut out,
+            2,
+            &mutation(0x10, &[0x10, 0, 0, 0], &[0xff, 0xff, 0xff, 0x7f]),
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            "(runtime)   #2 region 0x1000 +0x10 len=4 pc 0x4141: 10 00 00 00 -> ff ff ff 7f\n"
+        );
+
+        let mut out = String::new();
+        let mut long = mutation(0, &[0xaa; 20], &[0xbb; 20]);
+        long.pc = None;
+        write_mutation(&mut out, 0, &long).unwrap();
+        assert!(out.starts_with("(runtime)   #0 region 0x1000 +0x0 len=20: aa aa"));
+        assert!(out.ends_with("bb bb ...\n"));
+    }
+}
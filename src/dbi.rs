@@ -0,0 +1,160 @@
+//! ABI-stable entry points for dynamic binary instrumentation clients
+//!
+//! DynamoRIO, Pin, and similar frameworks see the effective address and PC of
+//! every memory access, so they can drive the runtime directly without the
+//! compiler pass. The contract is declared in `include/asan_double_fetch.h`
+//! under `ASAN_DOUBLE_FETCH_DBI`, which `include/asan_double_fetch_dbi.h`
+//! defines before including it; bump [`DBI_ABI_VERSION`] whenever a
+//! signature or [`DbiReport`] changes incompatibly. An example DynamoRIO
+//! client lives in `examples/dynamorio/`.
+
+use std::ffi::c_void;
+use std::os::raw::c_int;
+use std::sync::{PoisonError, RwLock};
+
+use crate::span::Span;
+use crate::Address;
+
+/// Version of the DBI entry point contract
+pub const DBI_ABI_VERSION: u32 = 1;
+
+/// Detection details passed to the report callback
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct DbiReport {
+    /// `size_of::<DbiReport>()`, lets newer clients detect appended fields
+    pub size: u32,
+    pub addr: Address,
+    pub len: usize,
+    /// PC of the re-fetch, 0 if unknown
+    pub pc: Address,
+    pub region_start: Address,
+    pub region_len: usize,
+}
+
+pub type DbiReportCallback = extern "C" fn(report: *const DbiReport, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    func: DbiReportCallback,
+    user_data: *mut c_void,
+}
+
+// the user data pointer is owned by the client, which promises it can be used
+// from any thread
+unsafe impl Send for Callback {}
+unsafe impl Sync for Callback {}
+
+static REPORT_CALLBACK: RwLock<Option<Callback>> = RwLock::new(None);
+
+/// Invokes the registered report callback, if any
+pub(crate) fn report(addr: Address, len: usize, pc: Option<Address>, region: &Span) {
+    // not called under the lock, so it can replace itself
+    let callback = match *REPORT_CALLBACK
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+    {
+        Some(callback) => callback,
+        None => return,
+    };
+    let report = DbiReport {
+        size: std::mem::size_of::<DbiReport>() as u32,
+        addr,
+        len,
+        pc: pc.unwrap_or(0),
+        region_start: region.start(),
+        region_len: region.len(),
+    };
+    (callback.func)(&report, callback.user_data);
+}
+
+/// Returns [`DBI_ABI_VERSION`]; clients should refuse to run on a mismatch
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_abi_version"))]
+pub extern "C" fn __asan_dbi_abi_version() -> u32 {
+    DBI_ABI_VERSION
+}
+
+/// Initializes the runtime if it isn't already. Returns 0, or -1 if that
+/// failed.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_init"))]
+pub extern "C" fn __asan_dbi_init() -> c_int {
+    crate::ffi::guard("__asan_dbi_init", -1, || {
+        crate::ensure_initialized();
+        0
+    })
+}
+
+/// Watches `[addr, addr + len)`. Returns 0, or -1 if the region isn't
+/// watched, as [`crate::__asan_watch_shared_memory_region`] does.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_watch"))]
+pub extern "C" fn __asan_dbi_watch(addr: Address, len: usize) -> c_int {
+    __asan_dbi_init();
+    crate::__asan_watch_shared_memory_region(addr, len)
+}
+
+/// Stops watching the region containing `addr`. Returns 0, or -1 if no
+/// watched region contains it.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_unwatch"))]
+pub extern "C" fn __asan_dbi_unwatch(addr: Address) -> c_int {
+    __asan_dbi_init();
+    crate::__asan_unwatch_shared_memory_region(addr)
+}
+
+/// Checks an access made by the instruction at `pc`
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_check_pc"))]
+pub extern "C" fn __asan_dbi_check_pc(
+    addr: Address,
+    len: usize,
+    is_write: c_int,
+    pc: Address,
+) -> c_int {
+    __asan_dbi_init();
+    crate::check_access(addr, len, is_write != 0, Some(pc)) as c_int
+}
+
+/// Registers `callback` to be invoked for every detection, replacing any
+/// previous one. Pass a null callback to unregister.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("dbi_set_report_callback"))]
+pub extern "C" fn __asan_dbi_set_report_callback(
+    // spelled out, as the header generator can't see through the alias here
+    callback: Option<extern "C" fn(report: *const DbiReport, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) {
+    crate::ffi::guard("__asan_dbi_set_report_callback", (), || {
+        *REPORT_CALLBACK
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) =
+            callback.map(|func| Callback { func, user_data });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn unregisters_itself(_report: *const DbiReport, _user_data: *mut c_void) {
+        REPORTED.fetch_add(1, Ordering::SeqCst);
+        __asan_dbi_set_report_callback(None, ptr::null_mut());
+    }
+
+    #[test]
+    fn callback_can_unregister_itself() {
+        __asan_dbi_set_report_callback(Some(unregisters_itself), ptr::null_mut());
+        report(0x1004, 4, None, &Span::with_len(0x1000, 0x10));
+
+        // other tests' detections may have got to it first
+        assert_ne!(REPORTED.load(Ordering::SeqCst), 0);
+        assert!(REPORT_CALLBACK.read().unwrap().is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Dump of the region table for debuggers
+//!
+//! [`__asan_double_fetch_debug_dump`] is meant to be called from a debugger
+//! attached to a stopped target, e.g. `call __asan_double_fetch_debug_dump()`
+//! in gdb. It never blocks on a lock a stopped thread may hold: the region
+//! table is read from its lock-free snapshot, and regions whose tracker is
+//! locked are listed without their spans.
+//!
+//! Core files can't run code, so `gdb/asan_double_fetch.py` walks the same
+//! data structures from the debugger instead.
+
+use std::fmt::Write as _;
+
+use crate::printer::printer;
+use crate::span::Span;
+use crate::{signal_safe, ThreadSafeMemoryTracker, TRACKED_MEMORY_REGIONS};
+
+/// Fetched spans listed per region
+const MAX_SPANS: usize = 64;
+
+/// The dump of `regions`
+pub(crate) fn render(regions: &[(Span, ThreadSafeMemoryTracker)]) -> String {
+    let mut out = format!(
+        "{} watched region{}",
+        regions.len(),
+        if regions.len() == 1 { "" } else { "s" }
+    );
+
+    for (n, (region, tracker)) in regions.iter().enumerate() {
+        let _ = write!(out, "\n#{} {} len={:#x}", n, region, region.len());
+
+        let tracker = match signal_safe::try_lock(tracker.try_read()) {
+            Some(tracker) => tracker,
+            None => {
+                out.push_str(", tracker locked");
+                continue;
+            }
+        };
+        let spans: Vec<Span> = tracker.check_all(region.start(), region.len()).collect();
+        let _ = write!(
+            out,
+            ", {} backend, granularity {:#x}, {:#x} bytes fetched in {} span{}",
+            tracker.backend_name(),
+            tracker.granularity(),
+            tracker.occupied_len(),
+            spans.len(),
+            if spans.len() == 1 { "" } else { "s" }
+        );
+
+        for span in spans.iter().take(MAX_SPANS) {
+            let _ = write!(
+                out,
+                "\n    {} +{:#x}",
+                span,
+                span.start().wrapping_sub(region.start())
+            );
+        }
+        if spans.len() > MAX_SPANS {
+            let _ = write!(out, "\n    ... {} more", spans.len() - MAX_SPANS);
+        }
+    }
+    out
+}
+
+/// Prints every watched region with its backend and fetched spans, for
+/// calling from a debugger. Output bypasses the `verbosity` option and any
+/// logger the host installed.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("debug_dump"))]
+pub extern "C" fn __asan_double_fetch_debug_dump() {
+    crate::ffi::guard("__asan_double_fetch_debug_dump", (), || {
+        let dump = match TRACKED_MEMORY_REGIONS.get() {
+            Some(mem_regions) => render(&mem_regions.read()),
+            None => "runtime not initialized".into(),
+        };
+
+        for line in dump.lines() {
+            printer().print(format_args!("(runtime) {}", line));
+        }
+        printer().flush();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_regions() {
+        let region = Span::with_len(0x1000, 0x100);
+        let tracker = crate::new_tracker(&region, 1);
+        {
+            let mut tracker = tracker.write().unwrap();
+            tracker.track_access(0x1000, 4).unwrap();
+            tracker.track_access(0x1010, 2).unwrap();
+        }
+        let locked = crate::new_tracker(&region, 1);
+        let _guard = locked.write().unwrap();
+
+        let dump = render(&[(region.clone(), tracker), (region, locked.clone())]);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "2 watched regions");
+        assert_eq!(
+            lines[1],
+            "#0 0x0000000000001000..0x0000000000001100 len=0x100, bitmap backend, granularity 0x1, 0x6 bytes fetched in 2 spans"
+        );
+        assert_eq!(lines[2], "    0x0000000000001000..0x0000000000001004 +0x0");
+        assert_eq!(lines[3], "    0x0000000000001010..0x0000000000001012 +0x10");
+        assert_eq!(
+            lines[4],
+            "#1 0x0000000000001000..0x0000000000001100 len=0x100, tracker locked"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Recording and replaying random decisions
+//!
+//! Mutations are chosen by drawing from an RNG. With the `decision_log`
+//! option set to a path, every draw is appended to that file as it is made,
+//! numbered in the order the runtime made them, with the decision it was
+//! made for, its kind and its value in hex:
+//!
+//! ```text
+//! # seq decision draw value
+//! 0 mutate u32 9e3779b9
+//! 1 value u32 00000004
+//! ```
+//!
+//! With the `decision_replay` option set to such a file, the runtime takes
+//! its draws from the file, in order, instead of the RNG, so a run whose
+//! detections come in the same order gets bit-exact the same mutations. If a
+//! draw doesn't match the file, because the runs diverged or the file ran
+//! out, the runtime says so once and goes back to the RNG.
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+
+use crate::config;
+
+/// What a draw decides
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Decision {
+    /// Whether to mutate a detection's bytes
+    Mutate,
+    /// The bytes to mutate them to
+    Value,
+}
+
+impl Decision {
+    fn name(self) -> &'static str {
+        match self {
+            Decision::Mutate => "mutate",
+            Decision::Value => "value",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "mutate" => Some(Decision::Mutate),
+            "value" => Some(Decision::Value),
+            _ => None,
+        }
+    }
+}
+
+/// One draw from the RNG
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    U32(u32),
+    U64(u64),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Whether `self` was drawn the way `other` is being drawn
+    fn same_kind(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::U32(_), Value::U32(_)) | (Value::U64(_), Value::U64(_)) => true,
+            (Value::Bytes(a), Value::Bytes(b)) => a.len() == b.len(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::U32(value) => write!(f, "u32 {:08x}", value),
+            Value::U64(value) => write!(f, "u64 {:016x}", value),
+            Value::Bytes(bytes) => {
+                f.write_str("bytes ")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Draw {
+    decision: Decision,
+    value: Value,
+}
+
+/// Parses a decision log, skipping blank and `#` lines. Lines are expected in
+/// sequence order.
+fn parse(text: &str) -> Result<Vec<Draw>, String> {
+    let mut draws = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let draw = match fields[..] {
+            [seq, decision, kind, value] if seq.parse() == Ok(draws.len()) => {
+                let value = match kind {
+                    "u32" => u32::from_str_radix(value, 16).ok().map(Value::U32),
+                    "u64" => u64::from_str_radix(value, 16).ok().map(Value::U64),
+                    "bytes" if value.len() % 2 == 0 => (0..value.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+                        .collect::<Option<_>>()
+                        .map(Value::Bytes),
+                    _ => None,
+                };
+                Decision::parse(decision)
+                    .zip(value)
+                    .map(|(decision, value)| Draw { decision, value })
+            }
+            _ => None,
+        };
+        draws.push(draw.ok_or_else(|| format!("line {}: malformed draw {:?}", n + 1, line))?);
+    }
+    Ok(draws)
+}
+
+/// Draws made, numbering them
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+static LOG: OnceCell<Option<Mutex<File>>> = OnceCell::new();
+
+static REPLAY: OnceCell<Option<Vec<Draw>>> = OnceCell::new();
+
+/// Set once a draw didn't match the replayed file
+static DIVERGED: AtomicBool = AtomicBool::new(false);
+
+fn log_file() -> Option<&'static Mutex<File>> {
+    LOG.get_or_init(|| {
+        let path = config::get().decision_log.as_ref()?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(b"# seq decision draw value\n")?;
+                Ok(file)
+            });
+        match file {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                log::error!("failed to create decision log {}: {}", path, e);
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+fn replayed() -> Option<&'static [Draw]> {
+    REPLAY
+        .get_or_init(|| {
+            let path = config::get().decision_replay.as_ref()?;
+            match std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse(&text))
+            {
+                Ok(draws) => {
+                    log::info!("replaying {} decisions from {}", draws.len(), path);
+                    Some(draws)
+                }
+                Err(e) => {
+                    log::error!("failed to read decision replay {}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_deref()
+}
+
+/// An RNG whose draws are recorded to `decision_log` and replayed from
+/// `decision_replay`, when those are set
+pub(crate) struct Rng<R> {
+    inner: R,
+    decision: Decision,
+}
+
+impl<R: RngCore> Rng<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decision: Decision::Mutate,
+        }
+    }
+
+    /// Labels the draws that follow as made for `decision`
+    pub fn deciding(&mut self, decision: Decision) -> &mut Self {
+        self.decision = decision;
+        self
+    }
+
+    /// Takes the next draw from the replayed file if it matches `drawn`, the
+    /// value from the RNG, and records the outcome
+    fn draw(&mut self, drawn: Value) -> Value {
+        let log = log_file();
+        let replay = replayed();
+        if log.is_none() && replay.is_none() {
+            return drawn;
+        }
+
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let value = match replay.map(|draws| draws.get(seq)) {
+            Some(Some(draw)) if draw.decision == self.decision && draw.value.same_kind(&drawn) => {
+                draw.value.clone()
+            }
+            Some(draw) => {
+                if !DIVERGED.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "decision #{} ({}, {}) {}, using the RNG from here on",
+                        seq,
+                        self.decision.name(),
+                        drawn,
+                        match draw {
+                            Some(_) => "doesn't match the replayed one",
+                            None => "is past the end of the replay",
+                        }
+                    );
+                }
+                drawn
+            }
+            None => drawn,
+        };
+
+        if let Some(log) = log {
+            let mut line = String::new();
+            let _ = writeln!(line, "{} {} {}", seq, self.decision.name(), value);
+            let written = log
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_all(line.as_bytes());
+            if let Err(e) = written {
+                log::error!("failed to log decision: {}", e);
+            }
+        }
+        value
+    }
+}
+
+impl<R: RngCore> RngCore for Rng<R> {
+    fn next_u32(&mut self) -> u32 {
+        let drawn = Value::U32(self.inner.next_u32());
+        match self.draw(drawn) {
+            Value::U32(value) => value,
+            _ => unreachable!("draws are replayed as their own kind"),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let drawn = Value::U64(self.inner.next_u64());
+        match self.draw(drawn) {
+            Value::U64(value) => value,
+            _ => unreachable!("draws are replayed as their own kind"),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest);
+        if let Value::Bytes(bytes) = self.draw(Value::Bytes(dest.to_vec())) {
+            dest.copy_from_slice(&bytes);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_draws() {
+        let draws = parse("# seq decision draw value\n0 mutate u32 9e3779b9\n\n1 value bytes 00ff\n2 value u64 1\n").unwrap();
+        assert_eq!(
+            draws,
+            [
+                Draw {
+                    decision: Decision::Mutate,
+                    value: Value::U32(0x9e37_79b9)
+                },
+                Draw {
+                    decision: Decision::Value,
+                    value: Value::Bytes(vec![0, 0xff])
+                },
+                Draw {
+                    decision: Decision::Value,
+                    value: Value::U64(1)
+                },
+            ]
+        );
+        assert_eq!(draws[1].value.to_string(), "bytes 00ff");
+        assert_eq!(draws[2].value.to_string(), "u64 0000000000000001");
+
+        // out of sequence
+        assert!(parse("1 mutate u32 0\n").is_err());
+        assert!(parse("0 mutate u16 0\n").is_err());
+        assert!(parse("0 value bytes 0f0\n").is_err());
+        assert_eq!(
+            parse("0 flip u32 0\n").unwrap_err(),
+            "line 1: malformed draw \"0 flip u32 0\""
+        );
+    }
+
+    #[test]
+    fn matches_kinds() {
+        assert!(Value::U32(1).same_kind(&Value::U32(2)));
+        assert!(!Value::U32(1).same_kind(&Value::U64(1)));
+        assert!(Value::Bytes(vec![1, 2]).same_kind(&Value::Bytes(vec![3, 4])));
+        assert!(!Value::Bytes(vec![1]).same_kind(&Value::Bytes(vec![3, 4])));
+    }
+}
@@ -0,0 +1,314 @@
+//! Graphviz export of which fetch sites touch which parts of a region
+//!
+//! With the `dot_file` option set to a path, every fetch from a watched
+//! region is recorded as an edge from its site to the bytes of the region it
+//! read, and a DOT graph of them is written to that path at exit, or on
+//! demand with [`__asan_double_fetch_write_graph`]. Render it with e.g.
+//! `dot -Tsvg`. Sites are PCs when the caller passed one and otherwise the
+//! first frame of the fetch's stack outside the runtime, which is costly to
+//! resolve, so this is meant for analysis runs rather than fuzzing.
+//!
+//! Each region is a cluster of the offset ranges fetched from it. Edges are
+//! labelled with how often the site fetched that range; edges that
+//! double-fetched are drawn in bold red.
+//!
+//! At most [`MAX_EDGES`] distinct edges are kept; fetches along new edges
+//! past that are only counted.
+
+use core::ffi::{c_char, c_int};
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::{config, report, Address};
+
+/// Distinct edges kept for the graph
+pub const MAX_EDGES: usize = 10_000;
+
+/// Where a fetch came from
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Site {
+    Pc(Address),
+    /// A stack frame, as `symbol file:line`
+    Frame(String),
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Site::Pc(pc) => write!(f, "{:#x}", pc),
+            Site::Frame(frame) => f.write_str(frame),
+        }
+    }
+}
+
+/// Bytes `[offset, offset + len)` of the region starting at `region`
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Target {
+    region: Address,
+    offset: usize,
+    len: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Edge {
+    fetches: u64,
+    double_fetches: u64,
+}
+
+#[derive(Default)]
+struct Graph {
+    edges: BTreeMap<(Site, Target), Edge>,
+    dropped: u64,
+}
+
+static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+    edges: BTreeMap::new(),
+    dropped: 0,
+});
+
+impl Graph {
+    fn add(&mut self, site: Site, target: Target, double_fetch: bool) {
+        let key = (site, target);
+        if !self.edges.contains_key(&key) && self.edges.len() >= MAX_EDGES {
+            self.dropped += 1;
+            return;
+        }
+
+        let edge = self.edges.entry(key).or_default();
+        edge.fetches += 1;
+        if double_fetch {
+            edge.double_fetches += 1;
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut sites: BTreeMap<&Site, usize> = BTreeMap::new();
+        let mut regions: BTreeMap<Address, BTreeMap<Target, usize>> = BTreeMap::new();
+        let mut targets = 0;
+        for (site, target) in self.edges.keys() {
+            let next = sites.len();
+            sites.entry(site).or_insert(next);
+            regions
+                .entry(target.region)
+                .or_default()
+                .entry(*target)
+                .or_insert_with(|| {
+                    targets += 1;
+                    targets - 1
+                });
+        }
+
+        let mut out = String::from(
+            "digraph double_fetch {\n  rankdir=LR;\n  node [fontname=\"monospace\"];\n",
+        );
+        if self.dropped > 0 {
+            let _ = writeln!(
+                out,
+                "  label=\"{} fetches along further edges not shown\";",
+                self.dropped
+            );
+        }
+
+        for (site, id) in &sites {
+            let _ = writeln!(
+                out,
+                "  s{} [shape=box, label=\"{}\"];",
+                id,
+                escape(&site.to_string())
+            );
+        }
+        for (n, (region, targets)) in regions.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "  subgraph cluster_{} {{\n    label=\"region {:#x}\";",
+                n, region
+            );
+            for (target, id) in targets {
+                let _ = writeln!(
+                    out,
+                    "    t{} [label=\"+{:#x} ({} bytes)\"];",
+                    id, target.offset, target.len
+                );
+            }
+            let _ = writeln!(out, "  }}");
+        }
+
+        for ((site, target), edge) in &self.edges {
+            let _ = write!(
+                out,
+                "  s{} -> t{} [label=\"{}",
+                sites[site], regions[&target.region][target], edge.fetches
+            );
+            if edge.double_fetches > 0 {
+                let _ = write!(
+                    out,
+                    " ({} double)\", color=red, fontcolor=red, penwidth=2",
+                    edge.double_fetches
+                );
+            } else {
+                out.push('"');
+            }
+            let _ = writeln!(out, "];");
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// `text` escaped for a quoted DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Records a fetch of `[addr, addr + len)` from `region` by the instruction
+/// at `pc`, or by the caller if that's unknown. Does nothing unless a
+/// `dot_file` is configured.
+pub(crate) fn record(
+    pc: Option<Address>,
+    region: &Span,
+    addr: Address,
+    len: usize,
+    double_fetch: bool,
+) {
+    if config::get().dot_file.is_none() {
+        return;
+    }
+    let fetched = match Span::with_len(addr, len).intersect(region) {
+        Some(fetched) => fetched,
+        None => return,
+    };
+
+    let site = match pc {
+        Some(pc) => Site::Pc(pc),
+        None => {
+            let frames = report::frames(&Backtrace::force_capture().to_string());
+            Site::Frame(
+                frames
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| "<unknown>".into()),
+            )
+        }
+    };
+    let target = Target {
+        region: region.start(),
+        offset: fetched.start() - region.start(),
+        len: fetched.len(),
+    };
+
+    GRAPH
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .add(site, target, double_fetch);
+}
+
+/// Writes the graph of everything recorded so far to `path`
+fn write(path: &Path) -> io::Result<()> {
+    let graph = GRAPH.lock().unwrap_or_else(PoisonError::into_inner);
+    std::fs::write(path, graph.render())
+}
+
+extern "C" fn write_at_exit() {
+    crate::ffi::guard("write_at_exit", (), || {
+        if let Some(path) = &config::get().dot_file {
+            if let Err(e) = write(Path::new(path)) {
+                log::error!("failed to write fetch graph to {}: {}", path, e);
+            }
+        }
+    })
+}
+
+/// Registers the exit hook writing the graph, if a `dot_file` is
+/// configured. Called once, from runtime init.
+pub(crate) fn init() {
+    if config::get().dot_file.is_some() && unsafe { libc::atexit(write_at_exit) } != 0 {
+        log::error!("failed to register the fetch graph exit hook");
+    }
+}
+
+/// Writes the fetch graph recorded so far to the file at `path`. Returns 0,
+/// or -1 on failure. Nothing is recorded unless the `dot_file` option is
+/// set.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("write_graph"))]
+pub unsafe extern "C" fn __asan_double_fetch_write_graph(path: *const c_char) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_write_graph", -1, || {
+        if path.is_null() {
+            return -1;
+        }
+
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(_) => return -1,
+        };
+
+        match write(Path::new(path)) {
+            Ok(()) => 0,
+            Err(e) => {
+                log::error!("failed to write fetch graph to {}: {}", path, e);
+                -1
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(offset: usize, len: usize) -> Target {
+        Target {
+            region: 0x1000,
+            offset,
+            len,
+        }
+    }
+
+    #[test]
+    fn renders_edges() {
+        let mut graph = Graph::default();
+        let parse = Site::Frame("target::parse \"hdr\" ./src/lib.rs:3:5".into());
+
+        graph.add(Site::Pc(0x4141), target(0, 4), false);
+        graph.add(parse.clone(), target(0, 4), false);
+        graph.add(parse.clone(), target(0, 4), true);
+        graph.add(parse, target(8, 8), false);
+
+        let dot = graph.render();
+        assert!(dot.starts_with("digraph double_fetch {"));
+        assert!(dot.contains("  s0 [shape=box, label=\"0x4141\"];"));
+        assert!(dot.contains("label=\"target::parse \\\"hdr\\\" ./src/lib.rs:3:5\""));
+        assert!(dot.contains("  subgraph cluster_0 {\n    label=\"region 0x1000\";"));
+        assert!(dot.contains("    t1 [label=\"+0x8 (8 bytes)\"];"));
+        assert!(dot.contains("  s0 -> t0 [label=\"1\"];"));
+        assert!(dot.contains(
+            "  s1 -> t0 [label=\"2 (1 double)\", color=red, fontcolor=red, penwidth=2];"
+        ));
+        assert!(dot.contains("  s1 -> t1 [label=\"1\"];"));
+    }
+
+    #[test]
+    fn caps_edges() {
+        let mut graph = Graph::default();
+        for offset in 0..MAX_EDGES + 2 {
+            graph.add(Site::Pc(0), target(offset, 1), false);
+        }
+        // existing edges still count
+        graph.add(Site::Pc(0), target(0, 1), true);
+
+        assert_eq!(graph.edges.len(), MAX_EDGES);
+        assert_eq!(graph.dropped, 2);
+        assert_eq!(graph.edges[&(Site::Pc(0), target(0, 1))].double_fetches, 1);
+    }
+}
@@ -0,0 +1,260 @@
+//! Detections as ETW events
+//!
+//! With the `etw` feature on Windows, the runtime registers the
+//! `AsanDoubleFetch` TraceLogging provider and writes a `Detection` event
+//! for every detection, so WPR, WPA and traceview capture findings alongside
+//! the rest of a test run's telemetry:
+//!
+//! ```text
+//! tracelog -start adf -f adf.etl -guid #e86fc00b-4a49-5d37-52b6-38c034131743
+//! ```
+//!
+//! The provider GUID is the one EventSource derives from its name, so tools
+//! that take `*AsanDoubleFetch` find it as well. Events are self-describing,
+//! with the fields `Addr`, `Len`, `Pc`, `Region`, `Offset`, `RegionStart`,
+//! `RegionLen` and `Changed`; `Pc` and `Changed` are left out when unknown.
+//! `Offset` is signed, negative for accesses starting before the region.
+//! Detections of bytes that changed since first fetched are logged at error
+//! level, the rest at warning level, and nothing is formatted unless a
+//! session enabled the provider at that level.
+
+use once_cell::sync::OnceCell;
+use windows_sys::core::GUID;
+use windows_sys::Win32::System::Diagnostics::Etw::{
+    EventEnabled, EventProviderSetTraits, EventRegister, EventSetInformation, EventWriteTransfer,
+    EVENT_DATA_DESCRIPTOR, EVENT_DATA_DESCRIPTOR_0, EVENT_DATA_DESCRIPTOR_0_0,
+    EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA, EVENT_DATA_DESCRIPTOR_TYPE_NONE,
+    EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA, EVENT_DESCRIPTOR, REGHANDLE, TRACE_LEVEL_ERROR,
+    TRACE_LEVEL_WARNING,
+};
+
+use crate::span::Span;
+use crate::{region_names, Address};
+
+/// `{e86fc00b-4a49-5d37-52b6-38c034131743}`, hashed from `AsanDoubleFetch`
+const PROVIDER_ID: GUID = GUID::from_u128(0xe86fc00b_4a49_5d37_52b6_38c034131743);
+
+/// The provider's TraceLogging traits: their size, then its name
+const PROVIDER_TRAITS: &[u8] = b"\x12\0AsanDoubleFetch\0";
+
+/// Channel marking events as TraceLogging ones to older consumers
+const TRACELOGGING_CHANNEL: u8 = 11;
+
+// TraceLogging field types
+const IN_ANSISTRING: u8 = 2;
+const IN_UINT8: u8 = 4;
+const IN_INT64: u8 = 9;
+const IN_UINT64: u8 = 10;
+const IN_HEXINT64: u8 = 21;
+/// Marks an input type as followed by an output type
+const CHAIN: u8 = 0x80;
+const OUT_BOOLEAN: u8 = 3;
+const OUT_UTF8: u8 = 35;
+
+/// Registered for the life of the process, `None` if registration failed
+static PROVIDER: OnceCell<Option<REGHANDLE>> = OnceCell::new();
+
+fn provider() -> Option<REGHANDLE> {
+    *PROVIDER.get_or_init(|| {
+        let mut handle = 0;
+        let status = unsafe { EventRegister(&PROVIDER_ID, None, core::ptr::null(), &mut handle) };
+        if status != 0 {
+            log::error!("failed to register ETW provider: {}", status);
+            return None;
+        }
+        unsafe {
+            EventSetInformation(
+                handle,
+                EventProviderSetTraits,
+                PROVIDER_TRAITS.as_ptr().cast(),
+                PROVIDER_TRAITS.len() as u32,
+            )
+        };
+        Some(handle)
+    })
+}
+
+/// A TraceLogging event's metadata and the payload it describes
+struct Event {
+    metadata: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl Event {
+    fn new(name: &str) -> Self {
+        // metadata size, filled in by `finish`, and no tags
+        let mut metadata = vec![0, 0, 0];
+        metadata.extend_from_slice(name.as_bytes());
+        metadata.push(0);
+        Self {
+            metadata,
+            payload: Vec::new(),
+        }
+    }
+
+    fn field(&mut self, name: &str, types: &[u8], value: &[u8]) {
+        self.metadata.extend_from_slice(name.as_bytes());
+        self.metadata.push(0);
+        self.metadata.extend_from_slice(types);
+        self.payload.extend_from_slice(value);
+    }
+
+    fn hex(&mut self, name: &str, value: usize) {
+        self.field(name, &[IN_HEXINT64], &(value as u64).to_le_bytes());
+    }
+
+    fn int(&mut self, name: &str, value: usize) {
+        self.field(name, &[IN_UINT64], &(value as u64).to_le_bytes());
+    }
+
+    fn signed(&mut self, name: &str, value: isize) {
+        self.field(name, &[IN_INT64], &(value as i64).to_le_bytes());
+    }
+
+    fn string(&mut self, name: &str, value: &str) {
+        // region names come from the harness
+        let mut value = value.replace('\0', "").into_bytes();
+        value.push(0);
+        self.field(name, &[IN_ANSISTRING | CHAIN, OUT_UTF8], &value);
+    }
+
+    fn boolean(&mut self, name: &str, value: bool) {
+        self.field(name, &[IN_UINT8 | CHAIN, OUT_BOOLEAN], &[u8::from(value)]);
+    }
+
+    fn finish(mut self) -> Self {
+        let len = self.metadata.len() as u16;
+        self.metadata[..2].copy_from_slice(&len.to_le_bytes());
+        self
+    }
+}
+
+/// The `Detection` event of `len` bytes at `addr` in `region`
+fn detection(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    changed: Option<bool>,
+) -> Event {
+    let mut event = Event::new("Detection");
+    event.hex("Addr", addr);
+    event.int("Len", len);
+    if let Some(pc) = pc {
+        event.hex("Pc", pc);
+    }
+    event.string("Region", &region_names::name(region));
+    // accesses may start before the region
+    event.signed("Offset", addr.wrapping_sub(region.start()) as isize);
+    event.hex("RegionStart", region.start());
+    event.int("RegionLen", region.len());
+    if let Some(changed) = changed {
+        event.boolean("Changed", changed);
+    }
+    event.finish()
+}
+
+fn data_descriptor(data: &[u8], kind: u32) -> EVENT_DATA_DESCRIPTOR {
+    EVENT_DATA_DESCRIPTOR {
+        Ptr: data.as_ptr() as u64,
+        Size: data.len() as u32,
+        Anonymous: EVENT_DATA_DESCRIPTOR_0 {
+            Anonymous: EVENT_DATA_DESCRIPTOR_0_0 {
+                Type: kind as u8,
+                ..Default::default()
+            },
+        },
+    }
+}
+
+/// Writes the detection of `len` bytes at `addr` in `region` as an ETW
+/// event, if a session is listening. `changed` says whether the bytes
+/// changed since first fetched, if known; changed ones are errors.
+pub(crate) fn report(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    changed: Option<bool>,
+) {
+    let handle = match provider() {
+        Some(handle) => handle,
+        None => return,
+    };
+    let level = if changed == Some(true) {
+        TRACE_LEVEL_ERROR
+    } else {
+        TRACE_LEVEL_WARNING
+    };
+    let descriptor = EVENT_DESCRIPTOR {
+        Id: 0,
+        Version: 0,
+        Channel: TRACELOGGING_CHANNEL,
+        Level: level as u8,
+        Opcode: 0,
+        Task: 0,
+        Keyword: 0,
+    };
+    if !unsafe { EventEnabled(handle, &descriptor) } {
+        return;
+    }
+
+    let event = detection(addr, len, pc, region, changed);
+    let data = [
+        data_descriptor(
+            PROVIDER_TRAITS,
+            EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA,
+        ),
+        data_descriptor(&event.metadata, EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA),
+        data_descriptor(&event.payload, EVENT_DATA_DESCRIPTOR_TYPE_NONE),
+    ];
+    let status = unsafe {
+        EventWriteTransfer(
+            handle,
+            &descriptor,
+            core::ptr::null(),
+            core::ptr::null(),
+            data.len() as u32,
+            data.as_ptr(),
+        )
+    };
+    if status != 0 {
+        log::error!("failed to write detection to ETW: {}", status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_detections() {
+        assert_eq!(PROVIDER_TRAITS.len(), usize::from(PROVIDER_TRAITS[0]));
+
+        let region = Span::with_len(0x1000, 0x100);
+        let event = detection(0x1008, 4, None, &region, Some(true));
+        let metadata = &event.metadata;
+        assert_eq!(
+            usize::from(u16::from_le_bytes([metadata[0], metadata[1]])),
+            metadata.len()
+        );
+        assert!(metadata[3..].starts_with(b"Detection\0Addr\0\x15Len\0\x0aRegion\0\x82\x23"));
+        assert!(metadata.ends_with(b"Changed\0\x84\x03"));
+        // Addr, Len, then Region
+        assert_eq!(&event.payload[..8], &0x1008u64.to_le_bytes());
+        assert_eq!(&event.payload[16..23], b"region\0");
+        assert_eq!(event.payload.last(), Some(&1));
+    }
+
+    #[test]
+    fn encodes_offsets_before_the_region() {
+        let region = Span::with_len(0x1000, 0x100);
+        let event = detection(0x0ffc, 8, None, &region, None);
+        assert!(event
+            .metadata
+            .windows(8)
+            .any(|field| field == b"Offset\0\x09"));
+        // Addr, Len, Region, then Offset
+        assert_eq!(&event.payload[23..31], &(-4i64).to_le_bytes());
+    }
+}
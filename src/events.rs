@@ -0,0 +1,110 @@
+//! Detection events for Rust embedders
+//!
+//! Rust harnesses linking the runtime as a crate can [`subscribe`] to
+//! detections instead of registering a C callback or parsing the log. Each
+//! subscriber gets its own channel, and every detection is sent to all of
+//! them as a [`Detection`] from the detecting thread without blocking it;
+//! receivers read them whenever they like, e.g. from a thread of their own.
+//! Dropping a receiver unsubscribes it. Builds with `no_alloc_hot_path`
+//! send none.
+//!
+//! ```no_run
+//! let detections = asan_double_fetch::events::subscribe();
+//! std::thread::spawn(move || {
+//!     for detection in detections {
+//!         eprintln!("double fetch at {}", detection.location);
+//!     }
+//! });
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::{region_names, Address, Tracker};
+
+/// A double fetch, as sent to subscribers
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Detection {
+    pub addr: Address,
+    pub len: usize,
+    /// PC of the re-fetch, `None` if unknown
+    pub pc: Option<Address>,
+    /// The watched region it happened in
+    pub region: Span,
+    /// `addr` as `<name>+<offset>` into `region`, as in reports
+    pub location: String,
+    /// The earlier fetches it re-fetched
+    pub earlier_fetches: Vec<Span>,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<Detection>>> = Mutex::new(Vec::new());
+
+/// `SUBSCRIBERS.len()`, so detections skip building events nobody receives
+static SUBSCRIBER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a receiver for every detection from now on
+pub fn subscribe() -> Receiver<Detection> {
+    let (sender, receiver) = mpsc::channel();
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(PoisonError::into_inner);
+    subscribers.push(sender);
+    SUBSCRIBER_COUNT.store(subscribers.len(), Ordering::Relaxed);
+    receiver
+}
+
+/// Sends the detection of `len` bytes at `addr` to every subscriber,
+/// dropping those whose receiver is gone
+pub(crate) fn publish(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    tracker: &Tracker,
+) {
+    if SUBSCRIBER_COUNT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let detection = Detection {
+        addr,
+        len,
+        pc,
+        region: region.clone(),
+        location: region_names::at(region, addr),
+        earlier_fetches: tracker.check_all(addr, len).collect(),
+    };
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(PoisonError::into_inner);
+    subscribers.retain(|subscriber| subscriber.send(detection.clone()).is_ok());
+    SUBSCRIBER_COUNT.store(subscribers.len(), Ordering::Relaxed);
+}
+
+#[cfg(all(test, not(feature = "no_alloc_hot_path")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_detections_to_subscribers() {
+        let detections = subscribe();
+        let dropped = subscribe();
+        drop(dropped);
+
+        let buf = Box::leak(Box::new([0u8; 16]));
+        let addr = buf.as_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(addr, 16);
+        crate::__asan_double_fetch_check(addr + 4, 4, false);
+        crate::__asan_double_fetch_check(addr + 4, 4, false);
+        crate::__asan_unwatch_shared_memory_region(addr);
+
+        // other tests detect concurrently
+        let detection = detections
+            .try_iter()
+            .find(|detection| detection.addr == addr + 4)
+            .unwrap();
+        assert_eq!(detection.len, 4);
+        assert_eq!(detection.region, Span::with_len(addr, 16));
+        assert!(detection.location.ends_with("+0x4"));
+        assert_eq!(detection.earlier_fetches, [Span::with_len(addr + 4, 4)]);
+    }
+}
@@ -0,0 +1,195 @@
+//! Carrying shm registrations across `exec`
+//!
+//! Multi-stage harnesses often create the shared memory in a launcher that
+//! then execs the actual target, which loses every `shmget()` the runtime
+//! remembered. Calling [`__asan_double_fetch_prepare_exec`] right before the
+//! exec writes the remembered segments to a memfd that survives it, and the
+//! launcher passes the memfd's number in [`HANDOFF_FD_ENV_VAR`] in the
+//! environment it execs with; runtime init in the new image reads them back,
+//! so its `shmat()`s of those segments are watched as usual. The runtime
+//! never sets the variable itself, as changing the environment of a running
+//! target races with its own `getenv()`s.
+//!
+//! The variable outlives the hand-off in the new image's environment, so an
+//! image further down the line may find it pointing at an fd that is closed
+//! or that the target opened for something else. The fd is only taken over
+//! if it is a memfd made by [`__asan_double_fetch_prepare_exec`].
+//!
+//! Only SysV segments from `shmget()` are handed off, as ids and sizes.
+//! Addresses are meaningless in the new image, which has to map the segments
+//! again itself. Nothing else about the watched regions survives the exec:
+//! regions watched directly, e.g. memfd or POSIX shm mappings, have to be
+//! watched again by the new image, and fetch history and statistics start
+//! over.
+
+use core::ffi::c_int;
+use core::mem;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Mutex, PoisonError};
+
+use crate::SHMGET_IDS;
+
+/// Environment variable holding the hand-off memfd's number in the new image
+pub const HANDOFF_FD_ENV_VAR: &str = "ASAN_DOUBLE_FETCH_HANDOFF_FD";
+
+/// Name of the hand-off memfd, as the new image checks for it
+const MEMFD_NAME: &str = "asan_double_fetch_handoff";
+
+/// The hand-off memfd, rewritten by every later hand-off
+static HANDOFF: Mutex<Option<File>> = Mutex::new(None);
+
+/// One `<id> <size>` line per segment
+fn serialize(ids: &[(c_int, usize)]) -> String {
+    ids.iter()
+        .map(|(id, size)| format!("{} {}\n", id, size))
+        .collect()
+}
+
+/// Parses [`serialize`]'s output, skipping malformed lines
+fn parse(table: &str) -> Vec<(c_int, usize)> {
+    table
+        .lines()
+        .filter_map(|line| {
+            let (id, size) = line.split_once(' ')?;
+            Some((id.parse().ok()?, size.parse().ok()?))
+        })
+        .collect()
+}
+
+fn prepare_exec() -> io::Result<c_int> {
+    let ids = match SHMGET_IDS.get() {
+        Some(ids) => serialize(&ids.lock().unwrap_or_else(PoisonError::into_inner)),
+        None => String::new(),
+    };
+
+    let mut handoff = HANDOFF.lock().unwrap_or_else(PoisonError::into_inner);
+    if handoff.is_none() {
+        let name = format!("{}\0", MEMFD_NAME);
+        // no MFD_CLOEXEC, the whole point is for the fd to survive the exec
+        let fd = unsafe { libc::memfd_create(name.as_ptr().cast(), 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *handoff = Some(unsafe { File::from_raw_fd(fd) });
+    }
+
+    let file = handoff.as_mut().expect("hand-off memfd was just created");
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(ids.as_bytes())?;
+    Ok(file.as_raw_fd())
+}
+
+/// Hands the SysV shm segments remembered from `shmget()` off to the image
+/// about to be exec'd; other watched regions aren't handed off and have to
+/// be watched again after the exec. Returns the memfd holding them, to be
+/// passed in
+/// `ASAN_DOUBLE_FETCH_HANDOFF_FD` in the environment the launcher execs
+/// with, or -1 on failure. Later calls reuse the same memfd.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("prepare_exec"))]
+pub extern "C" fn __asan_double_fetch_prepare_exec() -> c_int {
+    crate::ffi::guard(
+        "__asan_double_fetch_prepare_exec",
+        -1,
+        || match prepare_exec() {
+            Ok(fd) => fd,
+            Err(e) => {
+                log::error!("failed to prepare exec hand-off: {}", e);
+                -1
+            }
+        },
+    )
+}
+
+/// Takes over `fd` if it is a hand-off memfd, leaving anything else alone
+fn handoff_file(fd: c_int) -> Option<File> {
+    // a memfd is a regular file without a link anywhere
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0
+        || stat.st_mode & libc::S_IFMT != libc::S_IFREG
+        || stat.st_nlink != 0
+    {
+        return None;
+    }
+
+    let link = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    if link.to_str()? != format!("/memfd:{} (deleted)", MEMFD_NAME) {
+        return None;
+    }
+    Some(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Takes over the segments handed off by the previous image, if any. Called
+/// once, from runtime init.
+pub(crate) fn attach() {
+    let fd = match std::env::var(HANDOFF_FD_ENV_VAR) {
+        Ok(fd) => fd,
+        Err(_) => return,
+    };
+
+    let file = fd.parse().ok().and_then(handoff_file);
+    let mut file = match file {
+        Some(file) => file,
+        None => {
+            log::warn!("{} isn't a hand-off memfd, ignoring it", fd);
+            return;
+        }
+    };
+    let mut table = String::new();
+    if let Err(e) = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut table))
+    {
+        log::error!("failed to read exec hand-off: {}", e);
+        return;
+    }
+
+    let handed_off = parse(&table);
+    log::info!(
+        "re-attached {} shm segments from the previous image",
+        handed_off.len()
+    );
+    SHMGET_IDS
+        .get()
+        .expect("SHMGET_IDS not initialized")
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .extend(handed_off);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_roundtrip() {
+        let ids = [(0x1234, 0x1000), (-1, 0)];
+
+        assert_eq!(parse(&serialize(&ids)), ids);
+        assert_eq!(parse("7 16\ngarbage\n8\n9 x\n"), [(7, 16)]);
+    }
+
+    #[test]
+    fn takes_over_only_handoff_memfds() {
+        let fd = __asan_double_fetch_prepare_exec();
+        assert!(fd >= 0);
+        assert_eq!(__asan_double_fetch_prepare_exec(), fd);
+
+        // left open for the launcher's exec
+        let dup = unsafe { libc::dup(fd) };
+        let mut file = handoff_file(dup).unwrap();
+        let mut table = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut table).unwrap();
+        assert_eq!(parse(&table), []);
+
+        let other = File::open("/dev/null").unwrap();
+        assert!(handoff_file(other.as_raw_fd()).is_none());
+        assert!(handoff_file(-1).is_none());
+        // still open
+        assert_ne!(unsafe { libc::fcntl(other.as_raw_fd(), libc::F_GETFD) }, -1);
+    }
+}
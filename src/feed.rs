@@ -0,0 +1,136 @@
+//! Fetch events for an external attacker process
+//!
+//! In-process mutation only changes bytes the moment they are re-fetched.
+//! To race real writes against the target instead, a separate process that
+//! maps the same shared memory can listen for fetches: with the `fetch_feed`
+//! option set to the path of a Unix datagram socket, every fetch from a
+//! watched region is sent there as a [`FetchEvent`], right before the
+//! runtime checks it, e.g. so the attacker can flip a length the target just
+//! validated.
+//!
+//! Events are sent without blocking and dropped if nobody is listening or
+//! the listener falls behind; [`dropped`] counts them.
+
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::span::Span;
+use crate::{config, Address};
+
+/// One fetch, as sent to the `fetch_feed` socket in native byte order.
+/// Offsets are relative to the region, which the attacker maps at an
+/// address of its own.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FetchEvent {
+    /// Size of the event in bytes, lets listeners detect fields appended later
+    pub size: u32,
+    /// Process that made the fetch
+    pub pid: u32,
+    /// Start of the region in the target's address space, identifying it
+    pub region_start: u64,
+    pub region_len: u64,
+    /// Offset of the fetch into the region
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl FetchEvent {
+    /// Size of an event on the wire
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        // a `repr(C)` struct of integers without padding
+        unsafe { core::mem::transmute(*self) }
+    }
+
+    /// Reads an event from a datagram, `None` if it is too short or not an
+    /// event
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+        let event: Self = unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast()) };
+        (event.size as usize >= Self::SIZE).then_some(event)
+    }
+}
+
+/// Events that couldn't be sent
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+static SOCKET: OnceCell<Option<(UnixDatagram, String)>> = OnceCell::new();
+
+/// Events dropped since start, because nobody was listening or the
+/// listener fell behind
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+fn socket() -> Option<&'static (UnixDatagram, String)> {
+    SOCKET
+        .get_or_init(|| {
+            let path = config::get().fetch_feed.as_ref()?;
+            let socket = UnixDatagram::unbound().and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            });
+            match socket {
+                Ok(socket) => Some((socket, path.clone())),
+                Err(e) => {
+                    log::error!("failed to create fetch feed socket: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Publishes a fetch of `len` bytes at `addr` in `region`, if a `fetch_feed`
+/// is configured
+pub(crate) fn publish(region: &Span, addr: Address, len: usize) {
+    let (socket, path) = match socket() {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    let event = FetchEvent {
+        size: FetchEvent::SIZE as u32,
+        pid: std::process::id(),
+        region_start: region.start() as u64,
+        region_len: region.len() as u64,
+        offset: addr.wrapping_sub(region.start()) as u64,
+        len: len as u64,
+    };
+    if socket.send_to(&event.to_bytes(), path).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_round_trip() {
+        let event = FetchEvent {
+            size: FetchEvent::SIZE as u32,
+            pid: 42,
+            region_start: 0x1000,
+            region_len: 0x100,
+            offset: 0x8,
+            len: 4,
+        };
+        assert_eq!(FetchEvent::SIZE, 40);
+
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        sender.send(&event.to_bytes()).unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(FetchEvent::from_bytes(&buf[..n]), Some(event));
+
+        assert_eq!(FetchEvent::from_bytes(&buf[..n - 1]), None);
+        assert_eq!(FetchEvent::from_bytes(&[0; FetchEvent::SIZE]), None);
+    }
+}
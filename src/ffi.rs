@@ -0,0 +1,121 @@
+//! Panic containment at the C boundary
+//!
+//! Instrumented code calls into the runtime from everywhere, and a panic
+//! unwinding out of an `extern "C"` function takes the whole target down
+//! with it (older compilers even made it undefined behavior). Every entry
+//! point therefore runs its body through [`guard`], which catches a panic,
+//! logs which entry point it escaped from and hands the caller a fallback
+//! value, typically "not a double fetch" or an error code.
+//!
+//! Kernel builds can't unwind, a panic there is fatal regardless, so `guard`
+//! just runs the body.
+//!
+//! The entry points are declared for C in `include/asan_double_fetch.h`,
+//! which build.rs generates from the sources. Bump [`ABI_VERSION`] whenever
+//! an entry point or struct there changes incompatibly. C++ harnesses can
+//! scope watches and detection windows with the RAII wrappers in
+//! `include/asan_double_fetch.hpp` instead of calling them directly.
+//!
+//! Built with the `prefixed_symbols` feature, entry points are exported as
+//! `adf_<name>_v<ABI_VERSION>` instead, see `symbol!`, so the runtime can be
+//! linked into the same binary as a real AddressSanitizer runtime.
+
+/// Version of the contract described in `include/asan_double_fetch.h`
+pub const ABI_VERSION: u32 = 1;
+
+/// Returns [`ABI_VERSION`]; callers built against a header with another
+/// `ASAN_DOUBLE_FETCH_ABI_VERSION` should refuse to run
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("abi_version"))]
+pub extern "C" fn __asan_double_fetch_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// Runs `body`, returning `fallback` if it panics
+#[cfg(not(feature = "no_std"))]
+pub(crate) fn guard<R>(entry: &str, fallback: R, body: impl FnOnce() -> R) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(ret) => ret,
+        Err(_) => {
+            log::error!("panic in {}, continuing", entry);
+            fallback
+        }
+    }
+}
+
+/// Runs `body`, returning `fallback` if it panics
+#[cfg(feature = "no_std")]
+pub(crate) fn guard<R>(_entry: &str, _fallback: R, body: impl FnOnce() -> R) -> R {
+    body()
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "no_std"))]
+    use super::*;
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn panic_returns_fallback() {
+        assert_eq!(guard("test", -1, || 0), 0);
+        assert_eq!(guard("test", -1, || panic!("boom")), -1);
+    }
+
+    // the committed header is the userspace build's
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn header_is_current() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/asan_double_fetch.h"));
+        let committed = include_str!("../include/asan_double_fetch.h");
+        assert!(
+            generated == committed,
+            "include/asan_double_fetch.h is out of date, copy it from {}/asan_double_fetch.h",
+            env!("OUT_DIR")
+        );
+        assert!(committed.contains(&format!(
+            "#define ASAN_DOUBLE_FETCH_ABI_VERSION {}\n",
+            ABI_VERSION
+        )));
+        assert!(committed.contains(&format!(
+            "#define __asan_double_fetch_check adf_check_v{}\n",
+            ABI_VERSION
+        )));
+    }
+
+    /// Every entry point has a prefixed name, the one the header expects
+    #[test]
+    fn prefixed_names_follow_the_scheme() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        let mut entry_points = 0;
+        for file in std::fs::read_dir(dir).unwrap() {
+            let source = std::fs::read_to_string(file.unwrap().path()).unwrap();
+            let lines: Vec<&str> = source.lines().map(str::trim).collect();
+            for (i, _) in lines
+                .iter()
+                .enumerate()
+                .filter(|(_, l)| **l == "#[no_mangle]")
+            {
+                entry_points += 1;
+                let stem = lines[i + 1]
+                    .strip_prefix(
+                        "#[cfg_attr(feature = \"prefixed_symbols\", export_name = symbol!(",
+                    )
+                    .and_then(|rest| rest.strip_suffix("))]"))
+                    .unwrap_or_else(|| panic!("no prefixed name after {:?}", lines[i..].first()));
+                if stem.starts_with('$') {
+                    continue;
+                }
+                let name = lines[i + 2..]
+                    .iter()
+                    .find_map(|line| line.split("fn ").nth(1)?.split('(').next())
+                    .unwrap();
+                let expected = ["__asan_double_fetch_", "__asan_", "asan_"]
+                    .iter()
+                    .find_map(|prefix| name.strip_prefix(prefix))
+                    .unwrap();
+                assert_eq!(stem, format!("{:?}", expected), "{}", name);
+            }
+        }
+        assert!(entry_points > 60);
+    }
+}
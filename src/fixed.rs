@@ -0,0 +1,382 @@
+//! Allocation-free, fixed-capacity containers
+//!
+//! With the `heapless` feature, the region list and every region's tracker
+//! live in statically sized arrays instead of `Vec`s and `BTreeSet`s, so the
+//! runtime works before the kernel's allocator is up and on embedded targets
+//! without one. Capacities are const generics; running out is reported to the
+//! caller rather than growing.
+
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::address::AddressType;
+use crate::span::Span;
+use crate::Address;
+
+/// A fixed-capacity container ran out of room
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CapacityError;
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fixed capacity exhausted")
+    }
+}
+
+/// A `Vec` backed by an inline array of `N` elements
+pub struct FixedVec<T, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> FixedVec<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+
+    /// Appends `item`, handing it back if the vector is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(item);
+        }
+
+        self.items[self.len].write(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Inserts `item` at `idx`, shifting the rest up, or hands it back if the
+    /// vector is full
+    pub fn insert(&mut self, idx: usize, item: T) -> Result<(), T> {
+        assert!(idx <= self.len, "insert index {} out of bounds", idx);
+        if self.len == N {
+            return Err(item);
+        }
+
+        self.items[self.len].write(item);
+        self.len += 1;
+        self.items[idx..self.len].rotate_right(1);
+        Ok(())
+    }
+
+    /// Removes and returns the element at `idx`, shifting the rest down
+    pub fn remove(&mut self, idx: usize) -> T {
+        assert!(idx < self.len, "remove index {} out of bounds", idx);
+
+        let item = unsafe { self.items[idx].assume_init_read() };
+        self.items[idx..self.len].rotate_left(1);
+        self.len -= 1;
+        item
+    }
+
+    pub fn clear(&mut self) {
+        while self.len > 0 {
+            self.len -= 1;
+            unsafe { self.items[self.len].assume_init_drop() };
+        }
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Deref for FixedVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.items.as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> DerefMut for FixedVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.items.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> Drop for FixedVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// [`MemoryTracker`](crate::memory_tracking::MemoryTracker) over a sorted
+/// array of at most `N` disjoint, non-adjacent spans
+///
+/// Operations are linear in the number of tracked spans, which is fine for
+/// the small capacities this is meant for.
+#[derive(Clone, Debug)]
+pub struct FixedMemoryTracker<A: AddressType = Address, const N: usize = 64> {
+    /// `(start, end)` of each span, sorted by start
+    spans: [(A, A); N],
+    len: usize,
+}
+
+impl<A: AddressType, const N: usize> Default for FixedMemoryTracker<A, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: AddressType, const N: usize> FixedMemoryTracker<A, N> {
+    pub const fn new() -> Self {
+        Self {
+            spans: [(A::ZERO, A::ZERO); N],
+            len: 0,
+        }
+    }
+
+    fn spans(&self) -> &[(A, A)] {
+        &self.spans[..self.len]
+    }
+
+    fn insert_at(&mut self, idx: usize, span: (A, A)) {
+        self.spans[idx..=self.len].rotate_right(1);
+        self.spans[idx] = span;
+        self.len += 1;
+    }
+
+    fn remove_range(&mut self, from: usize, to: usize) {
+        self.spans[from..self.len].rotate_left(to - from);
+        self.len -= to - from;
+    }
+
+    /// Marks `[a, a + sz)` as fetched, merging with overlapping and adjacent
+    /// spans. Fails without changing anything if a new span is needed and the
+    /// tracker is full.
+    pub fn track_access(&mut self, a: A, sz: A) -> Result<(), CapacityError> {
+        let new = Span::with_len(a, sz);
+        if new.is_empty() {
+            return Ok(());
+        }
+        let (mut start, mut end) = (new.start(), new.end());
+
+        // the first span that ends at or after our start and the first one
+        // that starts after our end bound the spans to merge with
+        let first = self.spans().partition_point(|&(_, e)| e < start);
+        let last = self.spans().partition_point(|&(s, _)| s <= end);
+
+        if first == last {
+            if self.len == N {
+                return Err(CapacityError);
+            }
+            self.insert_at(first, (start, end));
+            return Ok(());
+        }
+
+        start = start.min(self.spans[first].0);
+        end = end.max(self.spans[last - 1].1);
+        self.remove_range(first + 1, last);
+        self.spans[first] = (start, end);
+        Ok(())
+    }
+
+    /// Forgets `[a, a + sz)`, splitting spans it falls in the middle of.
+    /// Fails without changing anything if that split needs room the tracker
+    /// doesn't have.
+    pub fn remove_access(&mut self, a: A, sz: A) -> Result<(), CapacityError> {
+        let clear = Span::with_len(a, sz);
+        if clear.is_empty() {
+            return Ok(());
+        }
+        let (start, end) = (clear.start(), clear.end());
+
+        let first = self.spans().partition_point(|&(_, e)| e <= start);
+        let last = self.spans().partition_point(|&(s, _)| s < end);
+        if first >= last {
+            return Ok(());
+        }
+
+        let head = (self.spans[first].0, start);
+        let tail = (end, self.spans[last - 1].1);
+        let keep_head = head.0 < head.1;
+        let keep_tail = tail.0 < tail.1;
+
+        let needed = keep_head as usize + keep_tail as usize;
+        if self.len - (last - first) + needed > N {
+            return Err(CapacityError);
+        }
+
+        self.remove_range(first, last);
+        if keep_tail {
+            self.insert_at(first, tail);
+        }
+        if keep_head {
+            self.insert_at(first, head);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Iterates over `(start, len)` of each span, sorted by start
+    pub fn redzones(&self) -> impl DoubleEndedIterator<Item = (A, A)> + ExactSizeIterator + '_ {
+        self.spans()
+            .iter()
+            .map(|&(start, end)| (start, end.wrapping_sub(start)))
+    }
+
+    /// Checks `[a, a + sz)` against the tracked spans, returning the start of
+    /// the last overlapping span on a hit, like
+    /// [`MemoryTracker::check`](crate::memory_tracking::MemoryTracker::check)
+    pub fn check(&self, a: A, sz: A) -> Result<(), A> {
+        if sz == A::ZERO {
+            return Ok(());
+        }
+
+        let end = a.saturating_add(sz);
+        let last = self.spans().partition_point(|&(s, _)| s < end);
+
+        match last.checked_sub(1).map(|idx| self.spans[idx]) {
+            Some((start, span_end)) if a < span_end => Err(start),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Statically allocated pool of `N` values handed out as `&'static T`
+///
+/// Values are never freed, only marked unused on release, so a reference
+/// that outlives its release still points at a valid, if recycled, value.
+pub struct Pool<T: 'static, const N: usize> {
+    items: [T; N],
+    in_use: [AtomicBool; N],
+}
+
+impl<T: 'static, const N: usize> Pool<T, N> {
+    pub const fn new(items: [T; N]) -> Self {
+        Self {
+            items,
+            in_use: [const { AtomicBool::new(false) }; N],
+        }
+    }
+
+    /// Claims an unused value, or `None` if all are taken
+    pub fn claim(&'static self) -> Option<&'static T> {
+        self.in_use
+            .iter()
+            .zip(&self.items)
+            .find_map(|(in_use, item)| {
+                in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .ok()
+                    .map(|_| item)
+            })
+    }
+
+    /// Returns `item`, previously claimed from this pool, to it
+    pub fn release(&'static self, item: &'static T) {
+        if let Some(idx) = self.items.iter().position(|i| core::ptr::eq(i, item)) {
+            self.in_use[idx].store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_vec() {
+        let mut v: FixedVec<u32, 3> = FixedVec::new();
+
+        assert_eq!(v.push(1), Ok(()));
+        assert_eq!(v.push(2), Ok(()));
+        assert_eq!(v.push(3), Ok(()));
+        assert_eq!(v.push(4), Err(4));
+        assert_eq!(v.remove(0), 1);
+        assert_eq!(&v[..], &[2, 3]);
+        assert_eq!(v.push(4), Ok(()));
+        assert_eq!(&v[..], &[2, 3, 4]);
+        assert_eq!(v.insert(0, 5), Err(5));
+        assert_eq!(v.remove(1), 3);
+        assert_eq!(v.insert(1, 5), Ok(()));
+        assert_eq!(&v[..], &[2, 5, 4]);
+    }
+
+    #[test]
+    fn track_merges() {
+        let mut tracker: FixedMemoryTracker<usize, 4> = FixedMemoryTracker::new();
+
+        tracker.track_access(0x10, 4).unwrap();
+        tracker.track_access(0x20, 4).unwrap();
+        tracker.track_access(0x14, 4).unwrap();
+        assert_eq!(
+            tracker.redzones().collect::<Vec<_>>(),
+            [(0x10, 8), (0x20, 4)]
+        );
+
+        // bridges both
+        tracker.track_access(0x16, 0x10).unwrap();
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x10, 0x16)]);
+
+        assert_eq!(tracker.check(0x23, 4), Err(0x10));
+        assert!(tracker.check(0x26, 4).is_ok());
+        assert!(tracker.check(0x8, 8).is_ok());
+
+        tracker.clear();
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn full_tracker_refuses() {
+        let mut tracker: FixedMemoryTracker<usize, 2> = FixedMemoryTracker::new();
+
+        tracker.track_access(0x10, 4).unwrap();
+        tracker.track_access(0x20, 4).unwrap();
+        assert_eq!(tracker.track_access(0x30, 4), Err(CapacityError));
+        // merging needs no room
+        assert_eq!(tracker.track_access(0x12, 4), Ok(()));
+
+        // splitting does
+        assert_eq!(tracker.remove_access(0x21, 2), Err(CapacityError));
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.remove_access(0x20, 2), Ok(()));
+        assert_eq!(
+            tracker.redzones().collect::<Vec<_>>(),
+            [(0x10, 6), (0x22, 2)]
+        );
+    }
+
+    #[test]
+    fn remove_splits() {
+        let mut tracker: FixedMemoryTracker<usize, 4> = FixedMemoryTracker::new();
+
+        tracker.track_access(0x4141, 8).unwrap();
+        tracker.remove_access(0x4143, 4).unwrap();
+
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.check(0x4141, 8), Err(0x4147));
+    }
+
+    #[test]
+    fn pool() {
+        static POOL: Pool<u32, 2> = Pool::new([0, 1]);
+
+        let a = POOL.claim().unwrap();
+        let b = POOL.claim().unwrap();
+        assert!(POOL.claim().is_none());
+
+        POOL.release(a);
+        assert!(core::ptr::eq(POOL.claim().unwrap(), a));
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,151 @@
+//! Keeping the runtime usable in children of `fork()`
+//!
+//! `fork()` only copies the calling thread, so a lock that another thread
+//! held at that moment stays locked forever in the child, and the child's
+//! first check deadlocks on it. Forking servers are the most common users of
+//! shared memory, so [`init`] registers `pthread_atfork` handlers that take
+//! every lock of the region table right before the fork and release them on
+//! both sides once it's done. The child thus starts out with fresh, unheld
+//! locks over a consistent table.
+//!
+//! What the child then does with the regions it inherited is up to the
+//! `fork` option, see [`ForkPolicy`]: `keep` (the default) keeps watching
+//! them, access history included, `reset` keeps watching them with a clean
+//! history, and `clear` forgets them.
+//!
+//! Children created with a raw `clone` syscall bypass the atfork handlers.
+//! Those with their own address space should call
+//! [`__asan_double_fetch_after_fork`] first thing; `CLONE_VM` children share
+//! the parent's table and must not.
+
+use std::cell::RefCell;
+use std::sync::{MutexGuard, PoisonError, RwLockWriteGuard};
+
+use core::ffi::c_int;
+
+use crate::config::{self, ForkPolicy};
+use crate::swap::SwapWriteGuard;
+use crate::{Lock, RegionVec, Tracker, SHMGET_IDS, TRACKED_MEMORY_REGIONS};
+
+/// Locks held by the forking thread for the duration of `fork()`
+struct HeldLocks {
+    // fields drop in declaration order, so the trackers are released before
+    // the list that keeps them alive
+    _trackers: Vec<RwLockWriteGuard<'static, Tracker>>,
+    _regions: SwapWriteGuard<'static, RegionVec>,
+    _shm_ids: Option<MutexGuard<'static, Vec<(c_int, usize)>>>,
+}
+
+thread_local! {
+    static HELD_LOCKS: RefCell<Option<HeldLocks>> = const { RefCell::new(None) };
+}
+
+/// Registers the fork handlers. Called once, from runtime init.
+pub(crate) fn init() {
+    let ret = unsafe { libc::pthread_atfork(Some(prepare), Some(parent), Some(child)) };
+    if ret != 0 {
+        log::error!("pthread_atfork failed: {}", ret);
+    }
+}
+
+/// Takes the shm id lock, then the region list's, then every tracker's,
+/// matching the order the rest of the runtime nests them in
+unsafe extern "C" fn prepare() {
+    crate::ffi::guard("prepare", (), || {
+        let regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(regions) => regions,
+            None => return,
+        };
+
+        let shm_ids = SHMGET_IDS
+            .get()
+            .map(|ids| ids.lock().unwrap_or_else(PoisonError::into_inner));
+        let regions = regions.write();
+
+        // the trackers can't go away while we hold the list they live in
+        let trackers = regions
+            .iter()
+            .map(|(_span, tracker)| {
+                let tracker: &'static Lock<Tracker> = &*(&**tracker as *const Lock<Tracker>);
+                tracker.write().unwrap_or_else(PoisonError::into_inner)
+            })
+            .collect();
+
+        HELD_LOCKS.with(|held| {
+            *held.borrow_mut() = Some(HeldLocks {
+                _trackers: trackers,
+                _regions: regions,
+                _shm_ids: shm_ids,
+            })
+        });
+    })
+}
+
+fn release() {
+    HELD_LOCKS.with(|held| held.borrow_mut().take());
+}
+
+unsafe extern "C" fn parent() {
+    crate::ffi::guard("parent", (), || {
+        release();
+    })
+}
+
+unsafe extern "C" fn child() {
+    crate::ffi::guard("child", (), || {
+        release();
+        __asan_double_fetch_after_fork();
+    })
+}
+
+/// Applies the `fork` option to the regions inherited from the parent. Runs
+/// automatically in children of `fork()`; call it from children created by
+/// a raw `clone` without `CLONE_VM`.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("after_fork"))]
+pub extern "C" fn __asan_double_fetch_after_fork() {
+    crate::ffi::guard("__asan_double_fetch_after_fork", (), || {
+        let regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(regions) => regions,
+            None => return,
+        };
+
+        match config::get().fork {
+            ForkPolicy::Keep => (),
+            ForkPolicy::Reset => {
+                for (_span, tracker) in regions.read().iter() {
+                    tracker
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clear();
+                }
+            }
+            ForkPolicy::Clear => {
+                crate::clear_regions(regions);
+                if let Some(ids) = SHMGET_IDS.get() {
+                    ids.lock().unwrap_or_else(PoisonError::into_inner).clear();
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_released_after_prepare() {
+        crate::ensure_initialized();
+
+        unsafe { prepare() };
+        assert!(HELD_LOCKS.with(|held| held.borrow().is_some()));
+        unsafe { parent() };
+        assert!(HELD_LOCKS.with(|held| held.borrow().is_none()));
+
+        // would deadlock if the region list were still locked
+        let data = [0u8; 8];
+        crate::__asan_watch_shared_memory_region(data.as_ptr() as usize, data.len());
+        crate::__asan_unwatch_shared_memory_region(data.as_ptr() as usize);
+    }
+}
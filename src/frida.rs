@@ -0,0 +1,75 @@
+//! Entry points for Frida agents
+//!
+//! Frida's `NativeFunction` marshals JavaScript booleans and numbers most
+//! reliably as `int`, and agents usually load the runtime into a process that
+//! never called `__asan_shared_memory_region_init`, so these wrappers take
+//! `c_int` flags and initialize the runtime on demand. See
+//! `frida/double_fetch.js` for an agent that routes every memory access of
+//! selected functions through `__asan_frida_check` using Stalker.
+
+use std::os::raw::c_int;
+
+use crate::Address;
+
+/// Initializes the runtime if it isn't already. Safe to call any number of
+/// times; returns 0, or -1 if initialization failed.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("frida_init"))]
+pub extern "C" fn __asan_frida_init() -> c_int {
+    crate::ffi::guard("__asan_frida_init", -1, || {
+        crate::ensure_initialized();
+        0
+    })
+}
+
+/// Watches `[addr, addr + len)`. Returns 0, or -1 if the region isn't
+/// watched, as [`crate::__asan_watch_shared_memory_region`] does.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("frida_watch"))]
+pub extern "C" fn __asan_frida_watch(addr: Address, len: usize) -> c_int {
+    __asan_frida_init();
+    crate::__asan_watch_shared_memory_region(addr, len)
+}
+
+/// Stops watching the region containing `addr`. Returns 0, or -1 if no
+/// watched region contains it.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("frida_unwatch"))]
+pub extern "C" fn __asan_frida_unwatch(addr: Address) -> c_int {
+    __asan_frida_init();
+    crate::__asan_unwatch_shared_memory_region(addr)
+}
+
+/// Stalker callout target. Returns 1 if the access was to a watched region,
+/// so an agent can stop instrumenting code that never touches one.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("frida_check"))]
+pub extern "C" fn __asan_frida_check(addr: Address, len: usize, is_write: c_int) -> c_int {
+    __asan_frida_init();
+    let watched = crate::in_watched_region(addr, len);
+    crate::__asan_double_fetch_check(addr, len, is_write != 0);
+    watched as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_reports_watched_accesses() {
+        let data = [0u8; 16];
+        let base = data.as_ptr() as Address;
+
+        assert_eq!(__asan_frida_check(base, 4, 0), 0);
+        assert_eq!(__asan_frida_watch(base, 8), 0);
+        assert_eq!(__asan_frida_watch(0, 8), -1);
+        assert_eq!(__asan_frida_check(base, 4, 0), 1);
+        assert_eq!(__asan_frida_check(base + 4, 4, 1), 1);
+        // straddling the end of the region
+        assert_eq!(__asan_frida_check(base + 6, 4, 0), 1);
+        assert_eq!(__asan_frida_check(base + 8, 4, 0), 0);
+        assert_eq!(__asan_frida_unwatch(base), 0);
+        assert_eq!(__asan_frida_unwatch(base), -1);
+        assert_eq!(__asan_frida_check(base, 4, 0), 0);
+    }
+}
@@ -0,0 +1,209 @@
+//! Region groups
+//!
+//! Real protocols often span several segments, e.g. a request ring and the
+//! arena its descriptors point into. Grouping such regions under a name
+//! makes them one unit: resetting the group forgets every fetch from all of
+//! them at once, ending a transaction such as the handling of one request,
+//! and detections in any member name the group and its other members.
+//!
+//! A region belongs to at most one group and leaves it when unwatched.
+
+use core::ffi::{c_char, c_int};
+use std::ffi::CStr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::{find_region, Address, TRACKED_MEMORY_REGIONS};
+
+struct Group {
+    name: String,
+    /// Sorted by start address
+    members: Vec<Span>,
+}
+
+static GROUPS: Mutex<Vec<Group>> = Mutex::new(Vec::new());
+
+fn groups() -> std::sync::MutexGuard<'static, Vec<Group>> {
+    GROUPS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The group `region` belongs to, as `"name" [start,end) ...` listing every
+/// member
+pub(crate) fn describe(region: &Span) -> Option<String> {
+    let groups = groups();
+    let group = groups.iter().find(|group| group.members.contains(region))?;
+    let mut out = format!("{:?}", group.name);
+    for member in &group.members {
+        out.push_str(&format!(" [{:#x},{:#x})", member.start(), member.end()));
+    }
+    Some(out)
+}
+
+/// Removes `region` from its group, if any, dropping the group once empty
+pub(crate) fn forget(region: &Span) {
+    groups().retain_mut(|group| {
+        group.members.retain(|member| member != region);
+        !group.members.is_empty()
+    });
+}
+
+/// Drops every group, once all regions are unwatched
+pub(crate) fn clear() {
+    groups().clear();
+}
+
+/// Adds `region` to the group called `name`, creating it if needed and
+/// taking the region out of any other group
+fn add(name: &str, region: Span) {
+    forget(&region);
+
+    let mut groups = groups();
+    let idx = match groups.iter().position(|group| group.name == name) {
+        Some(idx) => idx,
+        None => {
+            groups.push(Group {
+                name: name.to_owned(),
+                members: Vec::new(),
+            });
+            groups.len() - 1
+        }
+    };
+    let members = &mut groups[idx].members;
+    let at = members.partition_point(|member| member.start() < region.start());
+    members.insert(at, region);
+}
+
+/// Forgets every fetch from the regions of the group called `name`,
+/// returning how many there are, or `None` if there's no such group
+fn reset(name: &str) -> Option<usize> {
+    let members = groups()
+        .iter()
+        .find(|group| group.name == name)?
+        .members
+        .clone();
+
+    if let Some(mem_regions) = TRACKED_MEMORY_REGIONS.get() {
+        for (region, tracker) in mem_regions.read().iter() {
+            if members.contains(region) {
+                tracker
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clear();
+            }
+        }
+    }
+    Some(members.len())
+}
+
+/// The group name at `name`, `None` if it's null or not UTF-8
+unsafe fn name<'a>(name: *const c_char) -> Option<&'a str> {
+    if name.is_null() {
+        return None;
+    }
+    CStr::from_ptr(name).to_str().ok()
+}
+
+/// Adds the watched region containing `addr` to the group called `name`,
+/// creating the group if needed. A region is in one group at most, so this
+/// moves it out of any other. Returns 0, or -1 if `name` is invalid or no
+/// watched region contains `addr`.
+///
+/// # Safety
+///
+/// `name` must be a valid NUL-terminated string.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("group_add"))]
+pub unsafe extern "C" fn __asan_double_fetch_group_add(
+    name: *const c_char,
+    addr: Address,
+) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_group_add", -1, || {
+        let name = match self::name(name) {
+            Some(name) => name,
+            None => return -1,
+        };
+        let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(mem_regions) => mem_regions.read(),
+            None => return -1,
+        };
+        let region = match find_region(&mem_regions, &Span::with_len(addr, 1)) {
+            Some(idx) => mem_regions[idx].0.clone(),
+            None => {
+                log::warn!(
+                    "{:#X} isn't watched, not adding it to group {:?}",
+                    addr,
+                    name
+                );
+                return -1;
+            }
+        };
+
+        log::info!("adding region {} to group {:?}", region, name);
+        add(name, region);
+        0
+    })
+}
+
+/// Forgets every fetch from the regions in the group called `name`, keeping
+/// them watched, e.g. once the request spanning them has been handled.
+/// Returns how many regions were reset, or -1 if there's no such group.
+///
+/// # Safety
+///
+/// `name` must be a valid NUL-terminated string.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("group_reset"))]
+pub unsafe extern "C" fn __asan_double_fetch_group_reset(name: *const c_char) -> c_int {
+    crate::ffi::guard(
+        "__asan_double_fetch_group_reset",
+        -1,
+        || match self::name(name).and_then(reset) {
+            Some(reset) => reset as c_int,
+            None => -1,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetched(addr: Address) -> usize {
+        let (_, tracker) = crate::get_memory_tracker(addr, 1).unwrap();
+        let fetched = tracker.read().unwrap_or_else(PoisonError::into_inner);
+        fetched.occupied_len()
+    }
+
+    #[test]
+    fn reset_together() {
+        let ring = Box::leak(Box::new([0u8; 64])).as_ptr() as Address;
+        let arena = Box::leak(Box::new([0u8; 256])).as_ptr() as Address;
+        let outside = Box::leak(Box::new([0u8; 16])).as_ptr() as Address;
+        for (addr, len) in [(ring, 64), (arena, 256), (outside, 16)] {
+            crate::__asan_watch_shared_memory_region(addr, len);
+            crate::__asan_double_fetch_check(addr, 8, false);
+        }
+
+        let name = "test_ring\0".as_ptr().cast();
+        unsafe {
+            assert_eq!(__asan_double_fetch_group_reset(name), -1);
+            assert_eq!(__asan_double_fetch_group_add(name, ring + 4), 0);
+            assert_eq!(__asan_double_fetch_group_add(name, arena), 0);
+            assert_eq!(__asan_double_fetch_group_add(name, 0x10), -1);
+            assert_eq!(__asan_double_fetch_group_reset(name), 2);
+        }
+        assert_eq!(fetched(ring), 0);
+        assert_eq!(fetched(arena), 0);
+        assert_eq!(fetched(outside), 8);
+
+        let described = describe(&Span::with_len(arena, 256)).unwrap();
+        assert!(described.starts_with("\"test_ring\" ["));
+        assert!(described.contains(&format!("[{:#x},{:#x})", ring, ring + 64)));
+        assert_eq!(describe(&Span::with_len(outside, 16)), None);
+
+        for addr in [ring, arena, outside] {
+            crate::__asan_unwatch_shared_memory_region(addr);
+        }
+        assert_eq!(unsafe { __asan_double_fetch_group_reset(name) }, -1);
+    }
+}
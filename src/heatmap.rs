@@ -0,0 +1,160 @@
+//! Per-region fetch counts, rendered as ASCII heat maps
+//!
+//! With the `heatmap` option, every watched region counts the fetches of
+//! each of its granules, so developers can eyeball which fields of a shared
+//! struct get re-read. The map is printed when the region is unwatched or
+//! the runtime shut down, and on demand with
+//! [`__asan_double_fetch_print_heatmap`].
+//!
+//! Each character is one granule, or, for regions of more than 4096
+//! granules, a run of them counted together:
+//!
+//! ```text
+//! heat map of 0x7f3a2c001000 len=0x40, 0x1 byte per cell, ' ' unfetched, '.' once, ':' twice, up to '@' 9+ times:
+//!   +0x0000 |........::::@@@@                                                |
+//! ```
+
+use core::ffi::c_int;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::fmt::Write as _;
+
+use crate::span::Span;
+use crate::Address;
+
+/// Most cells a map has
+pub(crate) const MAX_CELLS: usize = 4096;
+/// Cells per printed row
+const ROW: usize = 64;
+/// Characters for 0, 1, 2, ... 9+ fetches
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+/// Fetch counts of a region's cells
+#[derive(Debug)]
+pub(crate) struct HeatMap {
+    region: Span,
+    /// Bytes per cell, a multiple of the region's granularity
+    cell_len: usize,
+    /// Counted with atomics, as fetches are checked under a shared lock
+    counts: Box<[AtomicU32]>,
+}
+
+impl HeatMap {
+    /// Empty map of `region`, tracked in `granularity`-sized granules
+    pub fn new(region: &Span, granularity: usize) -> Self {
+        let granules = region.len().div_ceil(granularity).max(1);
+        let cell_len = granules.div_ceil(MAX_CELLS) * granularity;
+        let cells = region.len().div_ceil(cell_len).max(1);
+
+        Self {
+            region: region.clone(),
+            cell_len,
+            counts: (0..cells).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Counts a fetch of `[a, a + sz)`, clipped to the region
+    pub fn record(&self, a: Address, sz: usize) {
+        let fetched = match Span::with_len(a, sz).intersect(&self.region) {
+            Some(fetched) => fetched,
+            None => return,
+        };
+
+        let first = (fetched.start() - self.region.start()) / self.cell_len;
+        let last = (fetched.end() - 1 - self.region.start()) / self.cell_len;
+        for count in &self.counts[first..=last] {
+            // saturates rather than wrapping back to "unfetched"
+            let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1));
+        }
+    }
+
+    pub fn clear(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// The map as text, a header line followed by one line per row of cells
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "heat map of {:#x} len={:#x}, {:#x} byte{} per cell, ' ' unfetched, '.' once, ':' twice, up to '@' {}+ times:",
+            self.region.start(),
+            self.region.len(),
+            self.cell_len,
+            if self.cell_len == 1 { "" } else { "s" },
+            RAMP.len() - 1
+        );
+
+        for (row, counts) in self.counts.chunks(ROW).enumerate() {
+            let _ = write!(out, "\n  +{:#06x} |", row * ROW * self.cell_len);
+            for count in counts {
+                let count = count.load(Ordering::Relaxed) as usize;
+                out.push(RAMP[count.min(RAMP.len() - 1)] as char);
+            }
+            let _ = write!(out, "{:1$}|", "", ROW - counts.len());
+        }
+        out
+    }
+}
+
+/// Prints the heat map of the watched region containing `addr`. Returns 0,
+/// or -1 if `addr` isn't in a watched region or heat maps are disabled.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("print_heatmap"))]
+pub extern "C" fn __asan_double_fetch_print_heatmap(addr: Address) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_print_heatmap", -1, || {
+        let (_region, tracker) = match crate::get_memory_tracker(addr, 1) {
+            Some(found) => found,
+            None => return -1,
+        };
+
+        let tracker = tracker
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        match tracker.heat_map() {
+            Some(heat_map) => {
+                log::info!("{}", heat_map.render());
+                0
+            }
+            None => -1,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_cell() {
+        let heat_map = HeatMap::new(&Span::with_len(0x1000, 0x50), 4);
+        heat_map.record(0x1000, 4);
+        heat_map.record(0x1004, 8);
+        heat_map.record(0x1006, 1);
+        for _ in 0..20 {
+            heat_map.record(0x104c, 4);
+        }
+        // clipped
+        heat_map.record(0xff0, 0x14);
+
+        let rendered = heat_map.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines[0].starts_with("heat map of 0x1000 len=0x50, 0x4 bytes per cell"));
+        assert_eq!(lines[1], format!("  +0x0000 |::. {:15}@{:44}|", "", ""));
+
+        heat_map.clear();
+        assert_eq!(
+            heat_map.render().lines().nth(1),
+            Some(format!("  +0x0000 |{:20}{:44}|", "", "").as_str())
+        );
+    }
+
+    #[test]
+    fn buckets_large_regions() {
+        let heat_map = HeatMap::new(&Span::with_len(0, MAX_CELLS * 0x1000 + 1), 1);
+        assert_eq!(heat_map.cell_len, 0x1001);
+        assert!(heat_map.counts.len() <= MAX_CELLS);
+
+        heat_map.record(MAX_CELLS * 0x1000, 1);
+        assert_eq!(heat_map.counts.last().unwrap().load(Ordering::Relaxed), 1);
+    }
+}
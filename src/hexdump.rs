@@ -0,0 +1,203 @@
+//! Hexdumps of the bytes around a detection
+//!
+//! Rows are 16 bytes, aligned to absolute addresses, and labelled with both
+//! their offset into the region and their address, so a dump can be lined
+//! up with the layout of the structure in the region. Rows holding the
+//! access are marked with `=>`.
+//!
+//! At most `hexdump_width` bytes are shown: the access with a row of context
+//! on either side if that fits, otherwise the start of the access alone. The
+//! window never leaves the region or the pages the access touches, so
+//! dumping can't fault on memory the target didn't map.
+
+use core::fmt;
+
+use crate::config;
+use crate::span::Span;
+use crate::Address;
+
+/// Bytes per row
+const ROW: usize = 16;
+
+fn round_up(a: Address, to: usize) -> Address {
+    a.saturating_add(to - 1) & !(to - 1)
+}
+
+/// The bytes of `region` to dump for an access of `[addr, addr + len)`
+fn window(region: &Span, addr: Address, len: usize, width: usize) -> Option<Span> {
+    if width == 0 {
+        return None;
+    }
+
+    let page = config::page_size();
+    let access = Span::with_len(addr, len.max(1));
+    let bounds = Span::new(addr & !(page - 1), round_up(access.end(), page)).intersect(region)?;
+
+    let context = Span::new(
+        (addr & !(ROW - 1)).saturating_sub(ROW),
+        round_up(access.end(), ROW).saturating_add(ROW),
+    )
+    .intersect(&bounds)?;
+    if context.len() <= width {
+        return Some(context);
+    }
+
+    Span::with_len(addr, width)
+        .intersect(&access)?
+        .intersect(&bounds)
+}
+
+/// One row of a dump
+struct Row<'a> {
+    region: &'a Span,
+    window: &'a Span,
+    access: &'a Span,
+    /// Address of the row's first byte
+    start: Address,
+}
+
+impl Row<'_> {
+    /// The byte at `a`, if it is in the window
+    fn byte(&self, a: Address) -> Option<u8> {
+        if self.window.contains(a) {
+            Some(unsafe { core::ptr::read_volatile(a as *const u8) })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Row<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let row = Span::with_len(self.start, ROW);
+        let marker = if row.intersect(self.access).is_some() {
+            "=>"
+        } else {
+            "  "
+        };
+        // the first row may start before the region
+        let (sign, offset) = if self.start >= self.region.start() {
+            ('+', self.start - self.region.start())
+        } else {
+            ('-', self.region.start() - self.start)
+        };
+        write!(f, "{} {}{:#06x} {:#x}:", marker, sign, offset, self.start)?;
+
+        for a in self.start..self.start + ROW {
+            match self.byte(a) {
+                Some(byte) => write!(f, " {:02x}", byte)?,
+                None => write!(f, "   ")?,
+            }
+        }
+
+        write!(f, "  |")?;
+        for a in self.start..self.start + ROW {
+            let c = match self.byte(a) {
+                Some(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                Some(_) => '.',
+                None => ' ',
+            };
+            write!(f, "{}", c)?;
+        }
+        write!(f, "|")
+    }
+}
+
+/// Logs a dump of the bytes around `[addr, addr + len)` in `region`,
+/// headed by `label`
+pub(crate) fn dump(label: &str, region: &Span, addr: Address, len: usize) {
+    let window = match window(region, addr, len, config::get().hexdump_width) {
+        Some(window) => window,
+        None => return,
+    };
+    let access = Span::with_len(addr, len);
+
+    log::warn!("{}:", label);
+    let mut start = window.start() & !(ROW - 1);
+    while start < window.end() {
+        log::warn!(
+            "{}",
+            Row {
+                region,
+                window: &window,
+                access: &access,
+                start,
+            }
+        );
+        start += ROW;
+    }
+
+    if access.end() > window.end() {
+        log::warn!("... {:#x} more bytes", access.end() - window.end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(64))]
+    struct Page([u8; 64]);
+
+    fn rows(region: &Span, addr: Address, len: usize, width: usize) -> Vec<String> {
+        let window = window(region, addr, len, width).unwrap();
+        let access = Span::with_len(addr, len);
+        let mut start = window.start() & !(ROW - 1);
+        let mut rows = Vec::new();
+        while start < window.end() {
+            let row = Row {
+                region,
+                window: &window,
+                access: &access,
+                start,
+            };
+            rows.push(format!("{}", row));
+            start += ROW;
+        }
+        rows
+    }
+
+    #[test]
+    fn dumps_with_context() {
+        let mut page = Page([0; 64]);
+        page.0[..16].copy_from_slice(b"0123456789abcdef");
+        page.0[0x20] = 0xff;
+        let base = page.0.as_ptr() as Address;
+        // starts 4 bytes into the page
+        let region = Span::new(base + 4, base + 64);
+
+        let rows = rows(&region, base + 0x1e, 4, 64);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0],
+            format!(
+                "   -0x0004 {:#x}:             34 35 36 37 38 39 61 62 63 64 65 66  |    456789abcdef|",
+                base
+            )
+        );
+        assert!(rows[1].starts_with(&format!("=> +0x000c {:#x}: 00", base + 0x10)));
+        assert!(rows[2].starts_with(&format!("=> +0x001c {:#x}: ff 00", base + 0x20)));
+        assert!(rows[2].ends_with("|................|"));
+        assert!(rows[3].starts_with(&format!("   +0x002c {:#x}: 00", base + 0x30)));
+    }
+
+    #[test]
+    fn narrow_window() {
+        let page = Page([0x41; 64]);
+        let base = page.0.as_ptr() as Address;
+        let region = Span::with_len(base, 64);
+
+        // no room for context, so just the start of the access
+        assert_eq!(
+            window(&region, base + 0x18, 0x20, 8),
+            Some(Span::with_len(base + 0x18, 8))
+        );
+        assert_eq!(rows(&region, base + 0x18, 0x20, 8).len(), 1);
+        assert_eq!(window(&region, base, 4, 0), None);
+        // context clipped to the region
+        assert_eq!(
+            window(&region, base + 0x30, 4, 0x100),
+            Some(Span::new(base + 0x20, base + 64))
+        );
+    }
+}
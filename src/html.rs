@@ -0,0 +1,319 @@
+//! HTML report of a run's detections
+//!
+//! With the `html_report` option set to a path, every detection is recorded
+//! along with the stack of the re-fetch, the shadow map of the fetched bytes
+//! around it and, if it was mutated, the bytes before and after. When the
+//! process exits, a self-contained HTML page of them is written to that
+//! path, for sharing findings with developers who won't read the console
+//! output. Stacks and maps are collapsible, so the page stays skimmable.
+//!
+//! At most [`MAX_DETECTIONS`] detections are kept, and [`MAX_BYTES`] bytes of
+//! each; later ones are only counted.
+
+use core::ffi::c_int;
+use std::backtrace::Backtrace;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::{config, region_names, report, Address, Tracker};
+
+/// Detections kept for the report
+pub const MAX_DETECTIONS: usize = 1000;
+/// Bytes of each detection's data kept for its mutation log
+pub const MAX_BYTES: usize = 256;
+
+/// A recorded detection
+#[derive(Clone, Debug)]
+pub(crate) struct Detection {
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: Span,
+    /// See [`region_names`]
+    region_name: String,
+    shm_id: Option<c_int>,
+    earlier: Vec<Span>,
+    stack: Vec<String>,
+    shadow: String,
+    before: Vec<u8>,
+    /// The data after mutating it, if it was
+    after: Option<Vec<u8>>,
+}
+
+impl Detection {
+    /// `a` as `<name>+<offset>` into the region
+    fn relative(&self, a: Address) -> String {
+        format!("{}{}", self.region_name, report::offset(&self.region, a))
+    }
+
+    /// Records that the detection's data was mutated to `data`
+    pub fn mutated(&mut self, data: &[u8]) {
+        self.after = Some(data[..data.len().min(MAX_BYTES)].to_vec());
+    }
+}
+
+struct Log {
+    detections: Vec<Detection>,
+    dropped: usize,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    detections: Vec::new(),
+    dropped: 0,
+});
+
+/// Starts recording a re-fetch of `[addr, addr + len)` in `region`, from the
+/// re-fetch itself, before its data is mutated. `None` unless an
+/// `html_report` is configured.
+pub(crate) fn begin(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    tracker: &Tracker,
+) -> Option<Detection> {
+    config::get().html_report.as_ref()?;
+
+    let data = unsafe { core::slice::from_raw_parts(addr as *const u8, len.min(MAX_BYTES)) };
+    Some(Detection {
+        addr,
+        len,
+        pc,
+        region: region.clone(),
+        region_name: region_names::name(region),
+        shm_id: region_names::shm_id(region),
+        earlier: tracker.check_all(addr, len).collect(),
+        stack: report::frames(&Backtrace::force_capture().to_string()),
+        shadow: report::shadow_map(addr, len, region, tracker),
+        before: data.to_vec(),
+        after: None,
+    })
+}
+
+/// Adds a detection started with [`begin`] to the report
+pub(crate) fn record(detection: Option<Detection>) {
+    if let Some(detection) = detection {
+        let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+        if log.detections.len() < MAX_DETECTIONS {
+            log.detections.push(detection);
+        } else {
+            log.dropped += 1;
+        }
+    }
+}
+
+/// `text` with the characters HTML treats specially escaped
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            out.push(if i % 16 == 0 { '\n' } else { ' ' });
+        }
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; font-family: monospace; }
+section { border-top: 1px solid #ccc; margin-top: 1.5em; }
+h2 { font-family: monospace; font-size: 1.1em; color: #b00; }
+summary { cursor: pointer; font-weight: bold; }
+pre { background: #f6f6f6; padding: 0.6em; overflow-x: auto; }";
+
+/// The report page for `detections`, noting `dropped` ones that weren't
+/// kept
+fn render(detections: &[Detection], dropped: usize) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Double-fetch report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>Double-fetch report</h1>",
+        STYLE
+    );
+    let _ = writeln!(
+        out,
+        "<p>{} detections in process {}.</p>",
+        detections.len() + dropped,
+        std::process::id()
+    );
+    if dropped > 0 {
+        let _ = writeln!(
+            out,
+            "<p>Only the first {} are detailed below; {} more were not kept.</p>",
+            detections.len(),
+            dropped
+        );
+    }
+
+    let _ = writeln!(out, "<table>\n<tr><th>#</th><th>Address</th><th>Size</th><th>Region</th><th>Offset</th><th>PC</th><th>Mutated</th></tr>");
+    for (i, detection) in detections.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"#d{0}\">{0}</a></td><td>{1:#x}</td><td>{2}</td><td>{3:#x}</td><td>{4}</td><td>{5}</td><td>{6}</td></tr>",
+            i,
+            detection.addr,
+            detection.len,
+            detection.region.start(),
+            escape(&detection.relative(detection.addr)),
+            detection.pc.map_or("".into(), |pc| format!("{:#x}", pc)),
+            if detection.after.is_some() { "yes" } else { "no" }
+        );
+    }
+    let _ = writeln!(out, "</table>");
+
+    for (i, detection) in detections.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "<section id=\"d{}\">\n<h2>#{}: READ of size {} at {:#x} in region {:#x} ({})</h2>",
+            i,
+            i,
+            detection.len,
+            detection.addr,
+            detection.region.start(),
+            escape(&region_names::format_at(
+                &detection.region_name,
+                &report::offset(&detection.region, detection.addr),
+                detection.shm_id
+            ))
+        );
+
+        let _ = writeln!(
+            out,
+            "<details>\n<summary>Stack of the re-fetch ({} frames)</summary>\n<pre>",
+            detection.stack.len()
+        );
+        for (n, frame) in detection.stack.iter().enumerate() {
+            let _ = writeln!(out, "#{} {}", n, escape(frame));
+        }
+        let _ = writeln!(out, "</pre>\n</details>");
+
+        let _ = writeln!(
+            out,
+            "<details>\n<summary>Region map</summary>\n<pre>{:#x}-byte region [{:#x},{:#x})",
+            detection.region.len(),
+            detection.region.start(),
+            detection.region.end()
+        );
+        for earlier in &detection.earlier {
+            let _ = writeln!(
+                out,
+                "previously fetched [{:#x},{:#x}) ({})",
+                earlier.start(),
+                earlier.end(),
+                escape(&detection.relative(earlier.start()))
+            );
+        }
+        let _ = writeln!(out, "\n{}</pre>\n</details>", escape(&detection.shadow));
+
+        match &detection.after {
+            Some(after) => {
+                let _ = writeln!(
+                    out,
+                    "<details>\n<summary>Mutation</summary>\n<pre>before:\n{}\nafter:\n{}</pre>\n</details>",
+                    hex(&detection.before),
+                    hex(after)
+                );
+            }
+            None => {
+                let _ = writeln!(
+                    out,
+                    "<details>\n<summary>Not mutated</summary>\n<pre>{}</pre>\n</details>",
+                    hex(&detection.before)
+                );
+            }
+        }
+        let _ = writeln!(out, "</section>");
+    }
+
+    let _ = writeln!(out, "</body>\n</html>");
+    out
+}
+
+/// Writes the report of everything recorded so far to `path`
+fn write(path: &Path) -> io::Result<()> {
+    let log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    std::fs::write(path, render(&log.detections, log.dropped))
+}
+
+extern "C" fn write_at_exit() {
+    crate::ffi::guard("write_at_exit", (), || {
+        if let Some(path) = &config::get().html_report {
+            match write(Path::new(path)) {
+                Ok(()) => log::info!("wrote HTML report to {}", path),
+                Err(e) => log::error!("failed to write HTML report to {}: {}", path, e),
+            }
+        }
+    })
+}
+
+/// Registers the exit hook writing the report, if one is configured. Called
+/// once, from runtime init.
+pub(crate) fn init() {
+    if config::get().html_report.is_some() && unsafe { libc::atexit(write_at_exit) } != 0 {
+        log::error!("failed to register the HTML report exit hook");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_detections() {
+        let region = Span::with_len(0x1000, 0x100);
+        let detection = Detection {
+            addr: 0x1010,
+            len: 2,
+            pc: Some(0x4141),
+            region: region.clone(),
+            region_name: "<ring>".into(),
+            shm_id: Some(0x8001),
+            earlier: vec![Span::with_len(0x1010, 1)],
+            stack: vec!["<target::Header as core::fmt::Debug>::fmt".into()],
+            shadow: "=>0x1010: F".into(),
+            before: vec![0xaa, 0xbb],
+            after: Some(vec![0xcc, 0xbb]),
+        };
+        let untouched = Detection {
+            after: None,
+            ..detection.clone()
+        };
+
+        let page = render(&[detection, untouched], 3);
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<p>5 detections in process"));
+        assert!(page.contains("3 more were not kept"));
+        assert!(page.contains(
+            "<td>0x1010</td><td>2</td><td>0x1000</td><td>&lt;ring&gt;+0x10</td><td>0x4141</td><td>yes</td>"
+        ));
+        assert!(page.contains("<h2>#1: READ of size 2 at 0x1010 in region 0x1000 (&lt;ring&gt;+0x10, shm id 0x8001)</h2>"));
+        assert!(page.contains("#0 &lt;target::Header as core::fmt::Debug&gt;::fmt"));
+        assert!(page.contains("previously fetched [0x1010,0x1011) (&lt;ring&gt;+0x10)"));
+        assert!(page.contains("=&gt;0x1010: F"));
+        assert!(page.contains("before:\naa bb\nafter:\ncc bb"));
+        assert!(page.contains("<summary>Not mutated</summary>"));
+    }
+
+    #[test]
+    fn hex_rows() {
+        assert_eq!(hex(&[0; 17]), format!("{}\n00", ["00"; 16].join(" ")));
+    }
+}
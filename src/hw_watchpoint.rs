@@ -0,0 +1,325 @@
+//! Hardware watchpoint backend
+//!
+//! Small, hot control structures (a ring's head/tail indices, a length field)
+//! can be watched with debug registers through `perf_event_open` breakpoint
+//! events. The hardware counts every access, so the target pays nothing per
+//! access in software; at the end of a window the counters are read and any
+//! watched word read more than once is reported.
+//!
+//! Debug registers can't trigger on reads alone, so each word is watched by
+//! a pair of breakpoints, one on reads and writes and one on writes only,
+//! and its reads are the difference of the two counts. Only a handful of
+//! debug registers exist (4 on x86), each covering an aligned 1, 2, 4, or 8
+//! byte range, so regions are limited to a couple of words.
+//! Events are opened for the calling thread and inherited by threads it
+//! creates afterwards.
+
+use std::io;
+use std::mem;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+
+use crate::span::Span;
+use crate::Address;
+
+/// Number of hardware breakpoint slots we're willing to use
+pub const MAX_WATCHPOINTS: usize = 4;
+
+/// Breakpoints watching each word
+const BREAKPOINTS_PER_WORD: usize = 2;
+
+const PERF_TYPE_BREAKPOINT: u32 = 5;
+const HW_BREAKPOINT_W: u32 = 2;
+const HW_BREAKPOINT_RW: u32 = 3;
+
+const PERF_ATTR_FLAG_DISABLED: u64 = 1 << 0;
+const PERF_ATTR_FLAG_INHERIT: u64 = 1 << 1;
+const PERF_ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const PERF_ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    bp_addr: u64,
+    bp_len: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+struct Watchpoint {
+    /// Counts reads and writes
+    accesses: c_int,
+    /// Counts writes only
+    writes: c_int,
+    span: Span,
+}
+
+impl Watchpoint {
+    fn open(span: Span) -> io::Result<Self> {
+        let accesses = open_breakpoint(&span, HW_BREAKPOINT_RW)?;
+        match open_breakpoint(&span, HW_BREAKPOINT_W) {
+            Ok(writes) => Ok(Self {
+                accesses,
+                writes,
+                span,
+            }),
+            Err(e) => {
+                unsafe { libc::close(accesses) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads of the word since the counters were last reset
+    fn reads(&self) -> io::Result<u64> {
+        Ok(read_count(self.accesses)?.saturating_sub(read_count(self.writes)?))
+    }
+
+    fn reset(&self) {
+        unsafe {
+            libc::ioctl(self.accesses, PERF_EVENT_IOC_RESET, 0);
+            libc::ioctl(self.writes, PERF_EVENT_IOC_RESET, 0);
+        }
+    }
+
+    fn close(&self) {
+        unsafe {
+            libc::close(self.accesses);
+            libc::close(self.writes);
+        }
+    }
+}
+
+static WATCHPOINTS: Mutex<Vec<Watchpoint>> = Mutex::new(Vec::new());
+
+/// Splits a region into the naturally aligned 1/2/4/8-byte pieces a debug
+/// register can cover, or `None` if it needs more than `slots` registers
+pub fn split_into_watchpoints(addr: Address, len: usize, slots: usize) -> Option<Vec<Span>> {
+    let end = addr.checked_add(len)?;
+    let mut pieces = Vec::new();
+    let mut cur = addr;
+
+    while cur < end {
+        let size = [8, 4, 2, 1]
+            .iter()
+            .copied()
+            .find(|size| cur.is_multiple_of(*size) && cur + size <= end)
+            .unwrap_or(1);
+        pieces.push(Span::with_len(cur, size));
+        cur += size;
+    }
+
+    if pieces.len() > slots {
+        None
+    } else {
+        Some(pieces)
+    }
+}
+
+fn open_breakpoint(span: &Span, bp_type: u32) -> io::Result<c_int> {
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_BREAKPOINT,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        flags: PERF_ATTR_FLAG_DISABLED
+            | PERF_ATTR_FLAG_INHERIT
+            | PERF_ATTR_FLAG_EXCLUDE_KERNEL
+            | PERF_ATTR_FLAG_EXCLUDE_HV,
+        bp_type,
+        bp_addr: span.start() as u64,
+        bp_len: span.len() as u64,
+        ..Default::default()
+    };
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            0,
+            -1,
+            -1,
+            0,
+        )
+    } as c_int;
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) } == -1 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+
+    Ok(fd)
+}
+
+fn read_count(fd: c_int) -> io::Result<u64> {
+    let mut count = 0u64;
+    let read = unsafe { libc::read(fd, (&mut count as *mut u64).cast(), mem::size_of::<u64>()) };
+    if read != mem::size_of::<u64>() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(count)
+}
+
+/// Watches a small region with hardware breakpoints.
+///
+/// Returns 0 on success and -1 if the region needs more debug registers than
+/// are free or the kernel refuses the breakpoint.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("hw_watch_region"))]
+pub extern "C" fn __asan_hw_watch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_hw_watch_region", -1, || {
+        let mut watchpoints = WATCHPOINTS.lock().unwrap();
+        let free = MAX_WATCHPOINTS - watchpoints.len() * BREAKPOINTS_PER_WORD;
+
+        let pieces = match split_into_watchpoints(addr, len, free / BREAKPOINTS_PER_WORD) {
+            Some(pieces) => pieces,
+            None => {
+                log::warn!(
+                    "region {:#X} len={:#X} needs more than {} free hardware watchpoints",
+                    addr,
+                    len,
+                    free
+                );
+                return -1;
+            }
+        };
+
+        let mut opened = Vec::with_capacity(pieces.len());
+        for span in pieces {
+            match Watchpoint::open(span.clone()) {
+                Ok(wp) => opened.push(wp),
+                Err(e) => {
+                    log::error!("failed to set hardware watchpoint on {}: {}", span, e);
+                    opened.iter().for_each(Watchpoint::close);
+                    return -1;
+                }
+            }
+        }
+
+        watchpoints.extend(opened);
+        0
+    })
+}
+
+/// Removes all hardware watchpoints covering `[addr, addr + len)`
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("hw_unwatch_region"))]
+pub extern "C" fn __asan_hw_unwatch_region(addr: Address, len: usize) {
+    crate::ffi::guard("__asan_hw_unwatch_region", (), || {
+        let region = Span::with_len(addr, len);
+        WATCHPOINTS.lock().unwrap().retain(|wp| {
+            let covered = wp.span.start() >= region.start() && wp.span.end() <= region.end();
+            if covered {
+                wp.close();
+            }
+            !covered
+        });
+    })
+}
+
+/// Ends the current window: reports every watched word that was read more
+/// than once since the last call, then resets the counters. Writes don't
+/// count, so a word written and then read once isn't reported.
+///
+/// Returns the number of double fetches found.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("hw_window_end"))]
+pub extern "C" fn __asan_hw_window_end() -> usize {
+    crate::ffi::guard("__asan_hw_window_end", 0, || {
+        let watchpoints = WATCHPOINTS.lock().unwrap();
+        let mut detections = 0;
+
+        for wp in watchpoints.iter() {
+            match wp.reads() {
+                Ok(reads) if reads > 1 => {
+                    detections += 1;
+                    log::warn!(
+                        "double-fetch detected! (hw watchpoint) {} read {} times",
+                        wp.span,
+                        reads
+                    );
+                }
+                Ok(_) => (),
+                Err(e) => log::error!("failed to read watchpoint {}: {}", wp.span, e),
+            }
+
+            wp.reset();
+        }
+
+        detections
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_word() {
+        assert_eq!(
+            split_into_watchpoints(0x1000, 8, 4),
+            Some(vec![Span::new(0x1000, 0x1008)])
+        );
+    }
+
+    #[test]
+    fn unaligned_region() {
+        assert_eq!(
+            split_into_watchpoints(0x1003, 7, 4),
+            Some(vec![
+                Span::new(0x1003, 0x1004),
+                Span::new(0x1004, 0x1008),
+                Span::new(0x1008, 0x100a),
+            ])
+        );
+    }
+
+    #[test]
+    fn writes_are_not_fetches() {
+        let word = std::hint::black_box(Box::new(0u64));
+        let ptr = &*word as *const u64 as *mut u64;
+        if __asan_hw_watch_region(ptr as Address, 8) != 0 {
+            // no debug registers available, e.g. in a VM
+            return;
+        }
+
+        unsafe {
+            ptr.write_volatile(1);
+            ptr.read_volatile();
+        }
+        assert_eq!(__asan_hw_window_end(), 0);
+
+        unsafe {
+            ptr.read_volatile();
+            ptr.read_volatile();
+        }
+        assert_eq!(__asan_hw_window_end(), 1);
+
+        __asan_hw_unwatch_region(ptr as Address, 8);
+    }
+
+    #[test]
+    fn too_large() {
+        assert_eq!(split_into_watchpoints(0x1000, 0x28, 4), None);
+    }
+}
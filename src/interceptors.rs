@@ -0,0 +1,361 @@
+//! Checked replacements for libc memory and string functions
+//!
+//! Instrumentation only sees loads in code it compiled, so a target calling
+//! into uninstrumented libc to copy out of a watched region would otherwise go
+//! unnoticed. The compiler pass rewrites calls to these functions into calls
+//! to the `__asan_double_fetch_*` helpers below, which record the whole call
+//! as a single fetch (or write) of the bytes it touches and then perform the
+//! operation.
+//!
+//! Copies whose length was double fetched on the same thread are reported as
+//! well, see [`stale_length`](crate::stale_length), and the copied bytes are
+//! searched for [`canary`](crate::canary) patterns.
+//!
+//! With the `syscall_interceptors` feature, `read`, `pread` and
+//! `process_vm_readv` get the same treatment so data pulled into or out of a
+//! watched region by the kernel is attributed to the region.
+
+use core::ffi::{c_char, c_void, CStr};
+use core::ptr;
+
+use crate::{check_access, Address};
+
+/// `memcpy` that checks `src` as one fetch of `n` bytes and `dst` as a write
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("memcpy"))]
+pub unsafe extern "C" fn __asan_double_fetch_memcpy(
+    dst: *mut c_void,
+    src: *const c_void,
+    n: usize,
+) -> *mut c_void {
+    if n > 0 {
+        check_access(src as Address, n, false, None);
+        check_access(dst as Address, n, true, None);
+        #[cfg(not(feature = "no_std"))]
+        crate::stale_length::check_copy("memcpy", src as Address, dst as Address, n);
+        ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, n);
+        #[cfg(not(feature = "no_std"))]
+        crate::canary::scan(
+            dst as Address,
+            core::slice::from_raw_parts(dst as *const u8, n),
+            "memcpy",
+        );
+    }
+    dst
+}
+
+/// `memmove` that checks `src` as one fetch of `n` bytes and `dst` as a write
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("memmove"))]
+pub unsafe extern "C" fn __asan_double_fetch_memmove(
+    dst: *mut c_void,
+    src: *const c_void,
+    n: usize,
+) -> *mut c_void {
+    if n > 0 {
+        check_access(src as Address, n, false, None);
+        check_access(dst as Address, n, true, None);
+        #[cfg(not(feature = "no_std"))]
+        crate::stale_length::check_copy("memmove", src as Address, dst as Address, n);
+        ptr::copy(src as *const u8, dst as *mut u8, n);
+        #[cfg(not(feature = "no_std"))]
+        crate::canary::scan(
+            dst as Address,
+            core::slice::from_raw_parts(dst as *const u8, n),
+            "memmove",
+        );
+    }
+    dst
+}
+
+/// `memset` that checks `dst` as a write of `n` bytes
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("memset"))]
+pub unsafe extern "C" fn __asan_double_fetch_memset(
+    dst: *mut c_void,
+    c: i32,
+    n: usize,
+) -> *mut c_void {
+    if n > 0 {
+        check_access(dst as Address, n, true, None);
+        ptr::write_bytes(dst as *mut u8, c as u8, n);
+    }
+    dst
+}
+
+/// Length of the string at `s`, scanning at most `max` bytes
+unsafe fn scan_len(s: *const c_char, max: usize) -> usize {
+    let mut len = 0;
+    while len < max && *s.add(len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+/// `strlen` that checks the scanned bytes, including the terminator, as one
+/// fetch. A later copy of the same string is then a re-fetch, catching the
+/// canonical length-then-copy pattern where the string grows in between.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("strlen"))]
+pub unsafe extern "C" fn __asan_double_fetch_strlen(s: *const c_char) -> usize {
+    let len = CStr::from_ptr(s).to_bytes().len();
+    check_access(s as Address, len + 1, false, None);
+    len
+}
+
+/// `strnlen` that checks the scanned bytes as one fetch. The terminator is
+/// only part of the fetch if it was found within `maxlen` bytes.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("strnlen"))]
+pub unsafe extern "C" fn __asan_double_fetch_strnlen(s: *const c_char, maxlen: usize) -> usize {
+    let len = scan_len(s, maxlen);
+    let scanned = if len < maxlen { len + 1 } else { len };
+    if scanned > 0 {
+        check_access(s as Address, scanned, false, None);
+    }
+    len
+}
+
+/// `strcpy` that checks `src`, including the terminator, as one fetch and
+/// the bytes written to `dst` as a write
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("strcpy"))]
+pub unsafe extern "C" fn __asan_double_fetch_strcpy(
+    dst: *mut c_char,
+    src: *const c_char,
+) -> *mut c_char {
+    let n = CStr::from_ptr(src).to_bytes().len() + 1;
+    check_access(src as Address, n, false, None);
+    check_access(dst as Address, n, true, None);
+    ptr::copy_nonoverlapping(src, dst, n);
+    dst
+}
+
+/// Checks the first `total` bytes spread across `iov`, in order, as accesses
+#[cfg(all(target_os = "linux", feature = "syscall_interceptors"))]
+unsafe fn check_iovecs(iov: *const libc::iovec, iovcnt: usize, mut total: usize, is_write: bool) {
+    for i in 0..iovcnt {
+        if total == 0 {
+            break;
+        }
+
+        let iov = &*iov.add(i);
+        let len = iov.iov_len.min(total);
+        if len > 0 {
+            check_access(iov.iov_base as Address, len, is_write, None);
+        }
+        total -= len;
+    }
+}
+
+/// `read` that checks the bytes the kernel stored into `buf` as a write
+#[cfg(all(unix, feature = "syscall_interceptors"))]
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("read"))]
+pub unsafe extern "C" fn __asan_double_fetch_read(
+    fd: libc::c_int,
+    buf: *mut c_void,
+    count: usize,
+) -> isize {
+    let ret = libc::read(fd, buf, count);
+    if ret > 0 {
+        check_access(buf as Address, ret as usize, true, None);
+    }
+    ret
+}
+
+/// `pread` that checks the bytes the kernel stored into `buf` as a write
+#[cfg(all(unix, feature = "syscall_interceptors"))]
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("pread"))]
+pub unsafe extern "C" fn __asan_double_fetch_pread(
+    fd: libc::c_int,
+    buf: *mut c_void,
+    count: usize,
+    offset: libc::off_t,
+) -> isize {
+    let ret = libc::pread(fd, buf, count, offset);
+    if ret > 0 {
+        check_access(buf as Address, ret as usize, true, None);
+    }
+    ret
+}
+
+/// `process_vm_readv` that checks the transferred bytes of `local_iov` as
+/// writes. When `pid` is the calling process the remote ranges live in our
+/// own address space, so the bytes copied out of them are checked as fetches.
+#[cfg(all(target_os = "linux", feature = "syscall_interceptors"))]
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("process_vm_readv"))]
+pub unsafe extern "C" fn __asan_double_fetch_process_vm_readv(
+    pid: libc::pid_t,
+    local_iov: *const libc::iovec,
+    liovcnt: libc::c_ulong,
+    remote_iov: *const libc::iovec,
+    riovcnt: libc::c_ulong,
+    flags: libc::c_ulong,
+) -> isize {
+    let ret = libc::process_vm_readv(pid, local_iov, liovcnt, remote_iov, riovcnt, flags);
+    if ret > 0 {
+        if pid == libc::getpid() {
+            check_iovecs(remote_iov, riovcnt as usize, ret as usize, false);
+        }
+        check_iovecs(local_iov, liovcnt as usize, ret as usize, true);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn memcpy_is_one_fetch() {
+        crate::ensure_initialized();
+
+        let src = [0x41u8; 32];
+        let mut dst = [0u8; 32];
+        crate::__asan_watch_shared_memory_region(src.as_ptr() as Address, src.len());
+
+        unsafe {
+            __asan_double_fetch_memcpy(dst.as_mut_ptr().cast(), src.as_ptr().cast(), src.len());
+        }
+        assert_eq!(dst, src);
+
+        let (_, tracker) = crate::get_memory_tracker(src.as_ptr() as Address, 1).unwrap();
+        assert_eq!(
+            tracker
+                .read()
+                .unwrap()
+                .check(src.as_ptr() as Address, src.len()),
+            Err(src.as_ptr() as Address)
+        );
+        assert_eq!(
+            tracker
+                .read()
+                .unwrap()
+                .check_all(src.as_ptr() as Address, src.len())
+                .count(),
+            1
+        );
+
+        crate::__asan_unwatch_shared_memory_region(src.as_ptr() as Address);
+    }
+
+    #[test]
+    fn memset_and_memmove() {
+        let mut buf = [0u8; 16];
+
+        unsafe {
+            __asan_double_fetch_memset(buf.as_mut_ptr().cast(), 0x42, 8);
+            __asan_double_fetch_memmove(buf.as_mut_ptr().add(4).cast(), buf.as_ptr().cast(), 8);
+        }
+        assert_eq!(buf[..12], [0x42; 12]);
+        assert_eq!(buf[12..], [0; 4]);
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn strlen_then_strcpy_is_double_fetch() {
+        crate::ensure_initialized();
+
+        let mut src = *b"hello\0\0\0";
+        let mut dst = [0u8; 8];
+        let base = src.as_mut_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(base, src.len());
+
+        let len = unsafe { __asan_double_fetch_strlen(base as *const c_char) };
+        assert_eq!(len, 5);
+
+        let (_, tracker) = crate::get_memory_tracker(base, 1).unwrap();
+        // the terminator was scanned too, the bytes past it were not
+        assert_eq!(tracker.read().unwrap().check(base + 5, 1), Err(base));
+        assert!(tracker.read().unwrap().check(base + 6, 2).is_ok());
+
+        unsafe {
+            __asan_double_fetch_strcpy(dst.as_mut_ptr().cast(), base as *const c_char);
+        }
+        // the re-fetch may have mutated `src`, but whatever it holds now was copied
+        assert_eq!(dst[..6], src[..6]);
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+
+    #[test]
+    fn strnlen_stops_at_maxlen() {
+        let s = b"abcdef\0";
+
+        unsafe {
+            assert_eq!(__asan_double_fetch_strnlen(s.as_ptr().cast(), 3), 3);
+            assert_eq!(__asan_double_fetch_strnlen(s.as_ptr().cast(), 16), 6);
+            assert_eq!(__asan_double_fetch_strnlen(s.as_ptr().cast(), 0), 0);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "syscall_interceptors"))]
+    #[test]
+    fn read_is_tracked_as_write() {
+        crate::ensure_initialized();
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(
+            unsafe { libc::write(fds[1], b"abcd".as_ptr().cast(), 4) },
+            4
+        );
+
+        let mut buf = [0u8; 16];
+        let base = buf.as_mut_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(base, buf.len());
+
+        let ret = unsafe { __asan_double_fetch_read(fds[0], base as *mut c_void, buf.len()) };
+        assert_eq!(ret, 4);
+        assert_eq!(&buf[..4], b"abcd");
+
+        // only the bytes actually read were touched
+        let (_, tracker) = crate::get_memory_tracker(base, 1).unwrap();
+        assert!(tracker.read().unwrap().check(base, 4).is_err());
+        assert!(tracker.read().unwrap().check(base + 4, 12).is_ok());
+
+        crate::__asan_unwatch_shared_memory_region(base);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "syscall_interceptors"))]
+    #[test]
+    fn process_vm_readv_from_self_is_fetch() {
+        crate::ensure_initialized();
+
+        let mut src = [0x41u8; 8];
+        let mut dst = [0u8; 8];
+        let base = src.as_mut_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(base, src.len());
+
+        let local = libc::iovec {
+            iov_base: dst.as_mut_ptr().cast(),
+            iov_len: dst.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: base as *mut c_void,
+            iov_len: src.len(),
+        };
+        let ret = unsafe {
+            __asan_double_fetch_process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0)
+        };
+        if ret < 0 {
+            // process_vm_readv may be blocked by seccomp in the sandbox
+            crate::__asan_unwatch_shared_memory_region(base);
+            return;
+        }
+        assert_eq!(ret, 8);
+
+        let (_, tracker) = crate::get_memory_tracker(base, 1).unwrap();
+        assert!(tracker.read().unwrap().check(base, 8).is_err());
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+}
@@ -0,0 +1,251 @@
+//! KASAN-style reporting for kernel builds
+//!
+//! Detections are printed in the same shape as `kasan_report()` output so
+//! existing dmesg tooling (syzkaller's report parser in particular) picks them
+//! up, are rate-limited like `printk_ratelimited`, and are dropped while the
+//! current task has KASAN reporting disabled via `kasan_disable_current()`.
+//! Each report is also emitted as the `asan_double_fetch:df_report`
+//! tracepoint, defined in `kernel/events.c`, for collectors that stream
+//! findings through perf or BPF; that one isn't rate-limited. Reports are
+//! tagged with the syscall they happened in, see [`syscalls`], and with the
+//! KCOV state of the task, see [`kcov`](crate::kcov).
+
+use core::ffi::{c_char, c_long, c_ulong};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use kernel::bindings;
+use kernel::pr_err;
+
+use crate::kcov::Kcov;
+use crate::syscalls::{self, Syscall};
+use crate::Address;
+
+extern "C" {
+    /// Fires the `df_report` tracepoint; `pc` is 0 if unknown, `nr` -1
+    /// outside a syscall
+    fn __asan_double_fetch_trace_report(
+        bug: *const c_char,
+        bug_len: usize,
+        addr: Address,
+        len: usize,
+        pc: Address,
+        nr: c_long,
+        ioctl_cmd: c_ulong,
+    );
+}
+
+/// Reports allowed per [`RATELIMIT_INTERVAL_SECS`], matching
+/// `DEFAULT_RATELIMIT_BURST`
+const RATELIMIT_BURST: usize = 10;
+/// Matches `DEFAULT_RATELIMIT_INTERVAL`
+const RATELIMIT_INTERVAL_SECS: u64 = 5;
+
+/// Start of the current rate-limit interval, in jiffies
+static INTERVAL_START: AtomicU64 = AtomicU64::new(0);
+static INTERVAL_REPORTS: AtomicUsize = AtomicUsize::new(0);
+static SUPPRESSED_REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the current task has KASAN reports disabled
+fn reports_disabled() -> bool {
+    unsafe { (*bindings::get_current()).kasan_depth != 0 }
+}
+
+/// Returns whether a report may be printed now, starting a new interval and
+/// noting how many reports the previous one swallowed when it has elapsed
+fn ratelimit() -> bool {
+    let now = unsafe { bindings::jiffies };
+    let start = INTERVAL_START.load(Ordering::Relaxed);
+
+    if now.wrapping_sub(start) >= RATELIMIT_INTERVAL_SECS * bindings::HZ as u64
+        && INTERVAL_START
+            .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        INTERVAL_REPORTS.store(0, Ordering::Relaxed);
+        let suppressed = SUPPRESSED_REPORTS.swap(0, Ordering::Relaxed);
+        if suppressed > 0 {
+            pr_err!("asan-double-fetch: {} reports suppressed\n", suppressed);
+        }
+    }
+
+    if INTERVAL_REPORTS.fetch_add(1, Ordering::Relaxed) < RATELIMIT_BURST {
+        true
+    } else {
+        SUPPRESSED_REPORTS.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// The current task's `comm`, up to its terminator
+pub(crate) fn current_comm(comm: &[c_char]) -> &str {
+    let comm = unsafe { core::slice::from_raw_parts(comm.as_ptr() as *const u8, comm.len()) };
+    let len = comm.iter().position(|&b| b == 0).unwrap_or(comm.len());
+    core::str::from_utf8(&comm[..len]).unwrap_or("?")
+}
+
+/// Fires the `df_report` tracepoint and prints a `BUG: KASAN: <bug>` report
+/// for `len` bytes at `addr`, followed by the current stack. Both name the
+/// syscall the current task is in, which is also counted for the summary,
+/// and the printed report its KCOV state.
+pub(crate) fn report(bug: &str, addr: Address, len: usize, pc: Option<Address>) {
+    if reports_disabled() {
+        return;
+    }
+    let syscall = Syscall::current();
+    // before printing, which adds coverage of its own
+    let kcov = Kcov::current();
+    if let Some(syscall) = syscall {
+        syscalls::count(syscall);
+    }
+    unsafe {
+        __asan_double_fetch_trace_report(
+            bug.as_ptr().cast(),
+            bug.len(),
+            addr,
+            len,
+            pc.unwrap_or(0),
+            syscall.map_or(-1, |syscall| syscall.nr),
+            syscall.map_or(0, |syscall| syscall.ioctl_cmd),
+        )
+    };
+    if !ratelimit() {
+        return;
+    }
+
+    let task = unsafe { &*bindings::get_current() };
+    let cpu = unsafe { bindings::raw_smp_processor_id() };
+
+    pr_err!("==================================================================\n");
+    match pc {
+        Some(pc) => pr_err!("BUG: KASAN: {} at pc {:#x}\n", bug, pc),
+        None => pr_err!("BUG: KASAN: {}\n", bug),
+    }
+    pr_err!(
+        "Read of size {} at addr {:#x} by task {}/{} on cpu {}\n",
+        len,
+        addr,
+        current_comm(&task.comm),
+        task.pid,
+        cpu
+    );
+    if let Some(syscall) = syscall {
+        pr_err!("In {}\n", syscall);
+    }
+    if let Some(kcov) = kcov {
+        pr_err!("KCOV: {}\n", kcov);
+    }
+    pr_err!("\n");
+    unsafe { bindings::dump_stack() };
+    pr_err!("==================================================================\n");
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use kernel::sim;
+
+    use super::*;
+
+    const INTERVAL: c_ulong = RATELIMIT_INTERVAL_SECS * bindings::HZ as c_ulong;
+
+    /// Starts a fresh rate-limit interval with nothing counted, printed or
+    /// traced. Callers hold [`sim::serialize`].
+    pub(crate) fn reset() {
+        INTERVAL_START.store(unsafe { bindings::jiffies }, Ordering::Relaxed);
+        INTERVAL_REPORTS.store(0, Ordering::Relaxed);
+        SUPPRESSED_REPORTS.store(0, Ordering::Relaxed);
+        syscalls::summarize();
+        sim::take_log();
+        sim::take_trace();
+    }
+
+    #[test]
+    fn report_format() {
+        let _serial = sim::serialize();
+        reset();
+        sim::set_comm("fuzzer");
+        let pid = unsafe { (*bindings::get_current()).pid };
+
+        sim::enter_syscall(sim::Syscall {
+            nr: 16,
+            ioctl_cmd: 0xc008_6401,
+            is_ioctl: true,
+        });
+        report("double-fetch", 0x1000, 4, Some(0xffff_ffff_8100_0000));
+        sim::exit_syscall();
+
+        let rule = "<3>==================================================================";
+        assert_eq!(
+            sim::take_log(),
+            [
+                rule.to_owned(),
+                "<3>BUG: KASAN: double-fetch at pc 0xffffffff81000000".to_owned(),
+                format!(
+                    "<3>Read of size 4 at addr 0x1000 by task fuzzer/{} on cpu 0",
+                    pid
+                ),
+                "<3>In ioctl 0xc0086401".to_owned(),
+                "<3>".to_owned(),
+                "<4>Call Trace:".to_owned(),
+                rule.to_owned(),
+            ]
+        );
+        assert_eq!(
+            sim::take_trace(),
+            [sim::TraceReport {
+                bug: "double-fetch".to_owned(),
+                addr: 0x1000,
+                len: 4,
+                pc: 0xffff_ffff_8100_0000,
+                nr: 16,
+                ioctl_cmd: 0xc008_6401,
+            }]
+        );
+
+        syscalls::summarize();
+        assert_eq!(
+            sim::take_log(),
+            [format!(
+                "<3>asan-double-fetch: 1 reports in ioctl 0xc0086401, last by task fuzzer/{}",
+                pid
+            )]
+        );
+    }
+
+    #[test]
+    fn ratelimited() {
+        let _serial = sim::serialize();
+        reset();
+
+        for _ in 0..RATELIMIT_BURST + 2 {
+            report("double-fetch", 0x2000, 1, None);
+        }
+        let printed = sim::take_log()
+            .iter()
+            .filter(|line| line.starts_with("<3>BUG: KASAN"))
+            .count();
+        assert_eq!(printed, RATELIMIT_BURST);
+        // the tracepoint isn't rate-limited
+        let traced = sim::take_trace();
+        assert_eq!(traced.len(), RATELIMIT_BURST + 2);
+        assert_eq!(traced[0].nr, -1);
+
+        sim::advance_jiffies(INTERVAL);
+        report("double-fetch", 0x2000, 1, None);
+        let log = sim::take_log();
+        assert_eq!(log[0], "<3>asan-double-fetch: 2 reports suppressed");
+        assert_eq!(log[2], "<3>BUG: KASAN: double-fetch");
+    }
+
+    #[test]
+    fn disabled_for_current() {
+        let _serial = sim::serialize();
+        reset();
+
+        unsafe { (*bindings::get_current()).kasan_depth = 1 };
+        report("double-fetch", 0x3000, 8, None);
+        unsafe { (*bindings::get_current()).kasan_depth = 0 };
+
+        assert!(sim::take_log().is_empty());
+        assert!(sim::take_trace().is_empty());
+    }
+}
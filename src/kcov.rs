@@ -0,0 +1,60 @@
+//! KCOV correlation for kernel builds
+//!
+//! When KCOV is collecting coverage for the task a double fetch is detected
+//! in, the report names the kcov instance and the common handle the task
+//! enabled it with, which syzkaller derives from the executor process, plus
+//! how far into the coverage area the program had got. Detections inside
+//! `kcov_remote_start()` sections, such as USB or vhost workers, are marked
+//! remote: their coverage, and so their finding, belongs to whichever
+//! program handed the work off. `kernel/kcov.c` reads the task's state.
+
+use core::ffi::{c_uint, c_ulong};
+use core::fmt;
+
+/// Mirrors `struct asan_double_fetch_kcov` in `kernel/kcov.c`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Kcov {
+    /// The kcov instance collecting, i.e. the descriptor it was enabled on
+    pub instance: c_ulong,
+    /// The common handle passed to `KCOV_REMOTE_ENABLE`, 0 if none
+    pub common_handle: u64,
+    pub area: c_ulong,
+    /// In words
+    pub size: c_uint,
+    /// Words of the area filled so far
+    pub pos: c_ulong,
+    pub mode: c_uint,
+    /// Collecting for another task, inside `kcov_remote_start()`
+    pub remote: bool,
+}
+
+extern "C" {
+    fn __asan_double_fetch_current_kcov(kcov: *mut Kcov) -> bool;
+}
+
+impl Kcov {
+    /// The current task's KCOV state, `None` if it isn't collecting coverage
+    pub(crate) fn current() -> Option<Self> {
+        let mut kcov = Self::default();
+        unsafe { __asan_double_fetch_current_kcov(&mut kcov) }.then_some(kcov)
+    }
+}
+
+impl fmt::Display for Kcov {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kcov {:#x}", self.instance)?;
+        if self.common_handle != 0 {
+            write!(f, ", common handle {:#x}", self.common_handle)?;
+        }
+        write!(
+            f,
+            ", area {:#x} at {}/{} words",
+            self.area, self.pos, self.size
+        )?;
+        if self.remote {
+            write!(f, ", remote")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Kernel-side glue for linking the runtime into a module or the kernel image
+//!
+//! Provides what the userspace dependencies would otherwise supply: the
+//! critical section `once_cell` uses for its globals and a randomness source
+//! for mutations. `kernel/` holds the Kbuild files and the
+//! `EXPORT_SYMBOL_GPL` wrappers for the staticlib.
+
+use core::cell::UnsafeCell;
+
+use kernel::bindings;
+use rand::{CryptoRng, RngCore};
+
+struct GlobalSpinlock(UnsafeCell<bindings::raw_spinlock_t>);
+
+// SAFETY: only ever accessed through the spinlock API
+unsafe impl Sync for GlobalSpinlock {}
+
+/// Serializes every critical section; an all-zero spinlock is unlocked. The
+/// guard-based [`crate::sync::SpinLock`] doesn't fit `critical_section`'s
+/// split acquire/release, hence the raw lock.
+static CRITICAL_SECTION_LOCK: GlobalSpinlock =
+    GlobalSpinlock(UnsafeCell::new(unsafe { core::mem::zeroed() }));
+
+struct KernelCriticalSection;
+critical_section::set_impl!(KernelCriticalSection);
+
+unsafe impl critical_section::Impl for KernelCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        bindings::_raw_spin_lock_irqsave(CRITICAL_SECTION_LOCK.0.get()) as usize
+    }
+
+    unsafe fn release(flags: critical_section::RawRestoreState) {
+        bindings::_raw_spin_unlock_irqrestore(CRITICAL_SECTION_LOCK.0.get(), flags as _);
+    }
+}
+
+/// Randomness from the kernel's CRNG
+pub(crate) struct KernelRng;
+
+impl RngCore for KernelRng {
+    fn next_u32(&mut self) -> u32 {
+        unsafe { bindings::get_random_u32() }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        unsafe { bindings::get_random_u64() }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        unsafe { bindings::get_random_bytes(dest.as_mut_ptr().cast(), dest.len() as _) }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for KernelRng {}
+
+/// `kmalloc(GFP_ATOMIC)`, usable from the atomic contexts the check path runs
+/// in
+#[cfg(feature = "allocator_api")]
+pub(crate) struct AtomicKmalloc;
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for AtomicKmalloc {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        // power-of-two kmalloc sizes are naturally aligned
+        let size = layout.pad_to_align().size().max(layout.align());
+        let ptr = unsafe { bindings::krealloc(core::ptr::null(), size, bindings::GFP_ATOMIC) };
+
+        core::ptr::NonNull::new(ptr as *mut u8)
+            .map(|ptr| core::ptr::NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(core::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, _layout: core::alloc::Layout) {
+        bindings::kfree(ptr.as_ptr().cast());
+    }
+}
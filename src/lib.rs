@@ -1,191 +1,1381 @@
-#![cfg_attr(feature = "no_std", no_std)]
-#![cfg_attr(feature = "no_std", feature(alloc, allocator_api))]
+//! Runtime for detecting double fetches from shared memory
+//!
+//! Instrumented targets call the `extern "C"` entry points to watch regions
+//! and report their accesses; re-reading bytes that were already fetched is
+//! reported as a double fetch, and the re-read bytes may be mutated to shake
+//! out the bug.
+//!
+//! The interval-set machinery underneath is usable on its own:
+//! [`MemoryTracker`] records accessed [`Span`]s, merging overlapping and
+//! adjacent ones, and answers whether a new access touches any of them.
+//!
+//! ```
+//! use asan_double_fetch::{MemoryTracker, Span};
+//!
+//! let mut tracker: MemoryTracker = MemoryTracker::default();
+//! tracker.track_access(0x1000, 8).unwrap();
+//! tracker.track_access(0x1008, 8).unwrap();
+//!
+//! assert_eq!(tracker.len(), 1);
+//! assert_eq!(tracker.check(0x100c, 4), Err(0x1000));
+//! assert!(tracker.check(0x1010, 4).is_ok());
+//!
+//! let fetched: Span = Span::with_len(0x1000, 0x10);
+//! assert!(fetched.contains(0x100f));
+//! ```
 
-mod memory_tracking;
-mod span;
+// tests of kernel builds run on the host, on the standard test harness
+#![cfg_attr(all(feature = "no_std", not(test)), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api, btreemap_alloc))]
+// entry points keep `#[no_mangle]` for the header generator, `export_name`
+// takes precedence when it's set
+#![cfg_attr(feature = "prefixed_symbols", allow(unused_attributes))]
 
+/// Exported name of an entry point in `prefixed_symbols` builds: its name
+/// without the `__asan_`, `__asan_double_fetch_` or `asan_` prefix, as
+/// `adf_<name>_v<ABI version>`. Keeps a runtime linked next to a real
+/// AddressSanitizer runtime out of its namespace.
+#[cfg(feature = "prefixed_symbols")]
+macro_rules! symbol {
+    ($name:literal) => {
+        concat!("adf_", $name, "_v1")
+    };
+}
+
+#[cfg(feature = "no_std")]
+extern crate alloc;
+
+#[cfg(not(feature = "no_std"))]
+mod access_hook;
+pub mod address;
+#[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+mod bare;
+pub mod bitmap;
+#[cfg(not(feature = "no_std"))]
+mod budget;
+#[cfg(not(feature = "no_std"))]
+mod canary;
+pub mod chunked;
+mod config;
+#[cfg(all(unix, feature = "control_socket"))]
+mod control;
+#[cfg(all(unix, not(feature = "no_std")))]
+mod crash;
+#[cfg(feature = "dbi")]
+mod dbi;
+#[cfg(not(feature = "no_std"))]
+mod debug;
+#[cfg(not(feature = "no_std"))]
+mod decisions;
+#[cfg(not(feature = "no_std"))]
+pub mod dot;
+#[cfg(all(windows, feature = "etw"))]
+mod etw;
+#[cfg(not(feature = "no_std"))]
+pub mod events;
+#[cfg(all(target_os = "linux", not(feature = "no_std")))]
+mod exec_handoff;
+#[cfg(all(unix, feature = "fetch_feed"))]
+pub mod feed;
+mod ffi;
+#[cfg(any(test, feature = "heapless"))]
+// mirrors the containers it replaces, not all of which the runtime uses
+#[allow(dead_code)]
+mod fixed;
+#[cfg(all(unix, not(feature = "no_std")))]
+mod fork;
+#[cfg(feature = "frida")]
+mod frida;
+#[cfg(not(feature = "no_std"))]
+mod groups;
+#[cfg(not(feature = "no_std"))]
+pub mod heatmap;
+mod hexdump;
+#[cfg(not(feature = "no_std"))]
+mod html;
+#[cfg(all(target_os = "linux", feature = "hw_watchpoint"))]
+mod hw_watchpoint;
+mod interceptors;
+#[cfg(feature = "linux_kasan")]
+mod kasan;
+#[cfg(feature = "linux_kasan")]
+mod kcov;
+#[cfg(feature = "linux_kasan")]
+mod kmod;
+pub mod memory_tracking;
+#[cfg(not(feature = "no_std"))]
+pub mod minimize;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+mod mpk;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mprotect_trap"))]
+mod mprotect_trap;
+mod mutation;
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
+mod percpu;
+mod printer;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "qemu")]
+mod qemu;
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
+mod rcu;
+#[cfg(not(feature = "no_std"))]
+mod region_names;
+#[cfg(not(feature = "no_std"))]
+mod region_tracker;
+#[cfg(not(feature = "no_std"))]
+mod report;
+mod report_queue;
+#[cfg(feature = "allocator_api")]
+pub mod runtime_alloc;
+#[cfg(feature = "sancov")]
+mod sancov;
+#[cfg(all(unix, not(feature = "no_std")))]
+mod sigchain;
+#[cfg(not(feature = "no_std"))]
+mod signal_safe;
+#[cfg(not(feature = "no_std"))]
+mod snapshot;
+pub mod span;
+#[cfg(not(feature = "no_std"))]
+mod stale_length;
+#[cfg(not(feature = "no_std"))]
+pub mod stats;
+#[cfg(not(feature = "no_std"))]
+mod swap;
 #[cfg(feature = "no_std")]
+mod sync;
+#[cfg(feature = "linux_kasan")]
+mod syscalls;
+#[cfg(all(unix, feature = "syslog"))]
+mod syslog;
+#[cfg(feature = "tracing")]
+mod telemetry;
+#[cfg(feature = "trace_recorder")]
+pub mod trace;
+#[cfg(feature = "linux_kasan")]
+mod uaccess;
+#[cfg(all(target_os = "linux", feature = "userfaultfd"))]
+mod userfaultfd;
+#[cfg(feature = "valgrind")]
+mod valgrind;
+
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
 use alloc::sync::Arc;
-use memory_tracking::MemoryTracker;
+use core::ffi::c_int;
+#[cfg(not(feature = "no_std"))]
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+pub use memory_tracking::{MemoryTracker, TrackerError};
 use once_cell::sync::OnceCell;
 use rand::Rng;
-use span::Span;
-use span::SpanRelation;
-use std::ffi::c_void;
-use std::os::raw::c_int;
+pub use span::{Span, SpanRelation};
 #[cfg(not(feature = "no_std"))]
 use std::sync::Arc;
+#[cfg(not(feature = "no_std"))]
+use std::sync::PoisonError;
 
+// the header generator can't tell these apart
 #[cfg(feature = "no_std")]
-type Lock<T> = kernel::sync::Mutex<T>;
+/// cbindgen:ignore
+type Lock<T> = sync::SpinLock<T>;
 #[cfg(not(feature = "no_std"))]
+/// cbindgen:ignore
 type Lock<T> = std::sync::RwLock<T>;
-type Address = usize;
+pub type Address = usize;
 
-type ThreadSafeMemoryTracker = Arc<Lock<MemoryTracker>>;
+/// Allocator backing the runtime's trackers and region list
+#[cfg(feature = "allocator_api")]
+type TrackerAlloc = runtime_alloc::RuntimeAlloc;
+#[cfg(all(not(feature = "allocator_api"), not(feature = "heapless")))]
+type TrackerAlloc = memory_tracking::Global;
+
+/// A region's access history
+#[cfg(not(feature = "no_std"))]
+type Tracker = region_tracker::RegionTracker;
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
+type Tracker = MemoryTracker<Address, TrackerAlloc>;
 
-/// Global list of memory regions being tracked
-static TRACKED_MEMORY_REGIONS: OnceCell<Lock<Vec<(crate::span::Span, ThreadSafeMemoryTracker)>>> =
-    OnceCell::new();
+#[cfg(all(not(feature = "no_std"), not(feature = "allocator_api")))]
+type ThreadSafeMemoryTracker = Arc<Lock<Tracker>>;
+#[cfg(all(not(feature = "no_std"), feature = "allocator_api"))]
+type ThreadSafeMemoryTracker = Arc<Lock<Tracker>, TrackerAlloc>;
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
+type ThreadSafeMemoryTracker = Arc<percpu::PerCpuTracker>;
+#[cfg(feature = "heapless")]
+type ThreadSafeMemoryTracker = &'static Lock<fixed::FixedMemoryTracker<Address, MAX_TRACKED_SPANS>>;
 
-/// Global list of pending memory regions that were created with `shmget()`
+#[cfg(all(not(feature = "no_std"), not(feature = "allocator_api")))]
+type RegionVec = Vec<(Span, ThreadSafeMemoryTracker)>;
+#[cfg(all(not(feature = "no_std"), feature = "allocator_api"))]
+type RegionVec = Vec<(Span, ThreadSafeMemoryTracker), TrackerAlloc>;
+/// Looked up on every access but rarely modified, so userspace builds swap
+/// snapshots and kernel builds use RCU
+#[cfg(not(feature = "no_std"))]
+type RegionList = swap::SwapList<RegionVec>;
+#[cfg(all(feature = "no_std", not(feature = "heapless")))]
+type RegionList = rcu::RcuVec<(Span, ThreadSafeMemoryTracker)>;
+#[cfg(feature = "heapless")]
+type RegionList = Lock<fixed::FixedVec<(Span, ThreadSafeMemoryTracker), MAX_REGIONS>>;
+
+/// Most regions that can be watched at once in `heapless` builds
+#[cfg(feature = "heapless")]
+pub const MAX_REGIONS: usize = 64;
+/// Most disjoint fetched spans each region tracks in `heapless` builds
+#[cfg(feature = "heapless")]
+pub const MAX_TRACKED_SPANS: usize = 256;
+
+/// Backing storage for the trackers of `heapless` builds
+#[cfg(feature = "heapless")]
+static TRACKER_POOL: fixed::Pool<
+    Lock<fixed::FixedMemoryTracker<Address, MAX_TRACKED_SPANS>>,
+    MAX_REGIONS,
+> = fixed::Pool::new([const { Lock::new(fixed::FixedMemoryTracker::new()) }; MAX_REGIONS]);
+
+/// Global list of memory regions being tracked, sorted by start address so
+/// lookups are a binary search
+static TRACKED_MEMORY_REGIONS: OnceCell<RegionList> = OnceCell::new();
+
+/// Double-fetches detected since init or the last shutdown
+static DETECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sizes of the memory regions created with `shmget()`, by id. Entries stay
+/// after the first `shmat()` so every mapping of a segment is watched,
+/// including ones made by a later image after an exec hand-off.
+#[cfg(not(feature = "no_std"))]
 static SHMGET_IDS: OnceCell<std::sync::Mutex<Vec<(c_int, usize)>>> = OnceCell::new();
 
+#[cfg(not(feature = "no_std"))]
 #[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("remember_shm_id"))]
 pub extern "C" fn asan_remember_shm_id(id: c_int, size: usize) {
-    println!("(runtime) got shm with id {:#x} and len {:#x}", id, size);
-    let ids = SHMGET_IDS.get().expect("SHMGET_IDS not initialized");
-    let mut ids = ids.lock().unwrap();
-    ids.push((id, size));
+    crate::ffi::guard("asan_remember_shm_id", (), || {
+        log::debug!("got shm with id {:#x} and len {:#x}", id, size);
+        ensure_initialized();
+
+        let ids = SHMGET_IDS.get().expect("SHMGET_IDS not initialized");
+        let mut ids = ids
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        ids.push((id, size));
+    })
 }
 
+#[cfg(not(feature = "no_std"))]
 #[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("register_shmat"))]
 pub extern "C" fn asan_register_shmat(id: c_int, addr: *mut c_void) {
-    println!("(runtime) got shmat with id {:#x} and addr {:p}", id, addr);
-    let ids = SHMGET_IDS.get().expect("SHMGET_IDS not initialized");
-    let mut ids = ids.lock().unwrap();
-    if let Some(idx) = ids.iter().position(|(list_id, size)| *list_id == id) {
-        println!("(runtime) found match for shmat");
+    crate::ffi::guard("asan_register_shmat", (), || {
+        log::debug!("got shmat with id {:#x} and addr {:p}", id, addr);
+        // no ids can have been remembered before init
+        let ids = match SHMGET_IDS.get() {
+            Some(ids) => ids,
+            None => return,
+        };
+        let ids = ids
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(&(_, size)) = ids.iter().find(|(list_id, _size)| *list_id == id) {
+            log::debug!("found match for shmat");
 
-        let (_, size) = ids.remove(idx);
-        __asan_watch_shared_memory_region(addr as Address, size);
-    }
+            __asan_watch_shared_memory_region(addr as Address, size);
+            #[cfg(not(feature = "no_std"))]
+            region_names::set_shm_id(addr as Address, id);
+        }
+    })
 }
 
+/// Initializes the runtime. Calling this again, including after
+/// [`__asan_shared_memory_region_shutdown`], does nothing. Entry points
+/// reached before this was called either initialize the runtime themselves
+/// (watching a region) or do nothing (checks, unwatching), since nothing can
+/// be tracked yet.
 #[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("shared_memory_region_init"))]
 pub extern "C" fn __asan_shared_memory_region_init() {
-    #[cfg(not(feature = "no_std"))]
-    let global = Default::default();
-    #[cfg(feature = "no_std")]
-    let global = {
-        let mut mutex = Lock::new(Default::default());
-        kernel::mutex_init!(Pin::new(&mut mutex), "asan_tracked_memory_regions");
-    };
+    crate::ffi::guard("__asan_shared_memory_region_init", (), || {
+        ensure_initialized();
+    })
+}
 
+fn init() {
     TRACKED_MEMORY_REGIONS
-        .set(global)
-        .expect("failed to init shared memory region global");
+        .set(new_region_list())
+        .unwrap_or_else(|_| panic!("failed to init shared memory region global"));
 
+    #[cfg(not(feature = "no_std"))]
     SHMGET_IDS
         .set(Default::default())
         .expect("failed to SHMGET_IDS");
 
-    println!("(runtime) shared_mem runtime initialized");
+    #[cfg(feature = "linux_kasan")]
+    uaccess::init();
+
+    #[cfg(all(unix, not(feature = "no_std")))]
+    fork::init();
+
+    #[cfg(all(target_os = "linux", not(feature = "no_std")))]
+    exec_handoff::attach();
+
+    #[cfg(feature = "prometheus")]
+    prometheus::init();
+    #[cfg(all(unix, feature = "control_socket"))]
+    control::init();
+
+    #[cfg(not(feature = "no_std"))]
+    html::init();
+    #[cfg(not(feature = "no_std"))]
+    dot::init();
+    #[cfg(feature = "trace_recorder")]
+    trace::init();
+    #[cfg(all(unix, not(feature = "no_std")))]
+    crash::init();
+
+    let config = config::get();
+
+    log::info!("shared_mem runtime initialized with {:?}", config);
 }
 
-/// Creates a new memory tracker for the given address + its size
+/// Flushes pending reports and output, prints a summary, and forgets all
+/// watched regions, remembered shm ids and detection counts so the runtime
+/// can be cycled between fuzzing iterations. The runtime stays initialized,
+/// so regions may be watched again right away.
 #[no_mangle]
-pub extern "C" fn __asan_watch_shared_memory_region(addr: Address, len: usize) {
-    println!(
-        "(runtime) watching memory region at {:#X}, len={:#X}",
-        addr, len
-    );
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("shared_memory_region_shutdown"))]
+pub extern "C" fn __asan_shared_memory_region_shutdown() {
+    crate::ffi::guard("__asan_shared_memory_region_shutdown", (), || {
+        let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(mem_regions) => mem_regions,
+            None => return,
+        };
 
-    let span = Span::with_len(addr, len);
-    let mem_regions = TRACKED_MEMORY_REGIONS
-        .get()
-        .expect("tracked memory regions is not initialized");
+        report_queue::__asan_double_fetch_drain_reports();
 
-    #[cfg(not(feature = "no_std"))]
-    let mut mem_regions = mem_regions.write().unwrap();
-    #[cfg(feature = "linux_kasan")]
+        let watched = clear_regions(mem_regions);
+
+        #[cfg(not(feature = "no_std"))]
+        if let Some(ids) = SHMGET_IDS.get() {
+            ids.lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .clear();
+        }
+
+        #[cfg(feature = "linux_kasan")]
+        uaccess::reset();
+        #[cfg(feature = "linux_kasan")]
+        syscalls::summarize();
+        #[cfg(not(feature = "no_std"))]
+        canary::clear();
+        #[cfg(not(feature = "no_std"))]
+        budget::summarize();
+
+        // last look at this iteration's counters before they're reset
+        #[cfg(feature = "prometheus")]
+        prometheus::write_configured();
+
+        #[cfg(feature = "trace_recorder")]
+        {
+            trace::record(trace::Event::Shutdown);
+            trace::flush();
+        }
+
+        log::info!(
+            "shutting down: {} double-fetches detected, {} regions still watched",
+            DETECTIONS.swap(0, Ordering::Relaxed),
+            watched
+        );
+        log::logger().flush();
+    })
+}
+
+/// Stops watching every region in `mem_regions`, returning how many there
+/// were
+fn clear_regions(mem_regions: &RegionList) -> usize {
+    #[cfg(not(feature = "heapless"))]
+    let mut mem_regions = mem_regions.write();
+    #[cfg(feature = "heapless")]
     let mut mem_regions = mem_regions.lock();
 
-    mem_regions.push((span, Default::default()))
+    let watched = mem_regions.len();
+    for idx in (0..watched).rev() {
+        let (_span, _tracker) = mem_regions.remove(idx);
+        #[cfg(not(feature = "no_std"))]
+        stats::summarize(
+            &_span,
+            &_tracker.read().unwrap_or_else(PoisonError::into_inner),
+        );
+        #[cfg(not(feature = "no_std"))]
+        print_heat_map(&_tracker);
+
+        #[cfg(feature = "heapless")]
+        TRACKER_POOL.release(_tracker);
+
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+        mpk::__asan_mpk_unwatch_region(_span.start(), _span.len());
+    }
+    #[cfg(not(feature = "no_std"))]
+    groups::clear();
+    #[cfg(not(feature = "no_std"))]
+    region_names::clear();
+    watched
 }
 
-/// Destroys the memory tracker corresponding to the given address + its size
+/// Initializes the runtime unless that already happened, for entry points
+/// that may be reached before the harness called
+/// `__asan_shared_memory_region_init`
+fn ensure_initialized() {
+    static INIT: OnceCell<()> = OnceCell::new();
+
+    INIT.get_or_init(init);
+}
+
+/// Creates a new memory tracker for the given address + its size
+///
+/// A region overlapping watched ones is merged with them into one region,
+/// or not watched with the `overlap=reject` option. Returns 0, or -1 if the
+/// region isn't watched, including when `addr` is null, `len` is 0 or the
+/// region would wrap around the address space.
 #[no_mangle]
-pub extern "C" fn __asan_unwatch_shared_memory_region(addr: Address) {
-    let target_span = Span::with_len(addr, 1);
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("watch_shared_memory_region"))]
+pub extern "C" fn __asan_watch_shared_memory_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_watch_shared_memory_region", -1, || {
+        watch_region(addr, len, config::get().granularity)
+    })
+}
+
+/// Like [`__asan_watch_shared_memory_region`], but tracks the region in
+/// `granularity`-sized aligned granules instead of the `granularity` option.
+/// `granularity` must be a power of two; 0 uses the option. Kernel builds
+/// always track single bytes.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("watch_shared_memory_region_granular"))]
+pub extern "C" fn __asan_watch_shared_memory_region_granular(
+    addr: Address,
+    len: usize,
+    granularity: usize,
+) -> c_int {
+    crate::ffi::guard("__asan_watch_shared_memory_region_granular", -1, || {
+        let granularity = match granularity {
+            0 => config::get().granularity,
+            granularity if granularity.is_power_of_two() => granularity,
+            _ => {
+                log::warn!(
+                    "granularity {:#X} is not a power of two, not watching {:#X}",
+                    granularity,
+                    addr
+                );
+                return -1;
+            }
+        };
+
+        watch_region(addr, len, granularity)
+    })
+}
+
+/// Why `[addr, addr + len)` can't be watched, if it can't
+fn invalid_region(addr: Address, len: usize) -> Option<&'static str> {
+    if addr == 0 {
+        Some("null address")
+    } else if len == 0 {
+        Some("empty region")
+    } else if addr.checked_add(len).is_none() {
+        Some("region wraps around the address space")
+    } else {
+        None
+    }
+}
+
+/// Watches `[addr, addr + len)`, tracking it in `granularity`-sized granules.
+/// Returns 0, or -1 if it isn't watched.
+fn watch_region(addr: Address, len: usize, _granularity: usize) -> c_int {
+    if let Some(why) = invalid_region(addr, len) {
+        log::warn!(
+            "not watching memory region at {:#X}, len={:#X}: {}",
+            addr,
+            len,
+            why
+        );
+        return -1;
+    }
+    log::info!("watching memory region at {:#X}, len={:#X}", addr, len);
+
+    ensure_initialized();
+
+    let mut span = Span::with_len(addr, len);
+    #[cfg(feature = "tracing")]
+    telemetry::watch(&span, _granularity);
+    #[cfg(feature = "trace_recorder")]
+    trace::record(trace::Event::Watch {
+        addr,
+        len,
+        granularity: _granularity,
+    });
     let mem_regions = TRACKED_MEMORY_REGIONS
         .get()
         .expect("tracked memory regions is not initialized");
 
-    #[cfg(not(feature = "no_std"))]
-    let mut mem_regions = mem_regions.write().unwrap();
-    #[cfg(feature = "linux_kasan")]
+    #[cfg(not(feature = "heapless"))]
+    let mut mem_regions = mem_regions.write();
+    #[cfg(feature = "heapless")]
     let mut mem_regions = mem_regions.lock();
 
-    if let Some(idx) = mem_regions
-        .iter()
-        .position(|(va_range, _tracker)| target_span.relation(&va_range) != SpanRelation::None)
-    {
-        mem_regions.remove(idx);
+    let overlapping = overlapping(&mem_regions, &span);
+    if !overlapping.is_empty() {
+        if config::get().overlap == config::OverlapPolicy::Reject {
+            log::warn!(
+                "{} overlaps {} watched regions, not watching it",
+                span,
+                overlapping.len()
+            );
+            return -1;
+        }
+
+        let first = &mem_regions[overlapping.start].0;
+        let last = &mem_regions[overlapping.end - 1].0;
+        span = Span::new(span.start().min(first.start()), span.end().max(last.end()));
+        log::info!(
+            "merging {} overlapping watched regions into {}",
+            overlapping.len(),
+            span
+        );
+    }
+    #[cfg(not(feature = "no_std"))]
+    let name = overlapping
+        .clone()
+        .next()
+        .and_then(|idx| region_names::take(&mem_regions[idx].0));
+    #[cfg(not(feature = "heapless"))]
+    let tracker = new_tracker(&span, _granularity);
+    for _ in overlapping.clone() {
+        let (merged, old) = mem_regions.remove(overlapping.start);
+        #[cfg(not(feature = "no_std"))]
+        carry_over(&merged, &old, &tracker);
+        unwatched(&merged, old);
+    }
+
+    // merging leaves fewer regions, so only new ones can go over budget
+    #[cfg(not(feature = "no_std"))]
+    match budget::admit_region(&mem_regions, addr) {
+        budget::Admission::Watch => (),
+        budget::Admission::Refuse => return -1,
+        budget::Admission::Evict(idx) => {
+            let (evicted, tracker) = mem_regions.remove(idx);
+            log::info!(
+                "evicting memory region at {:#X}, len={:#X}",
+                evicted.start(),
+                evicted.len()
+            );
+            unwatched(&evicted, tracker);
+        }
+    }
+
+    let idx = mem_regions.partition_point(|(region, _)| region.start() <= span.start());
+    #[cfg(not(feature = "heapless"))]
+    mem_regions.insert(idx, (span.clone(), tracker));
+    #[cfg(not(feature = "no_std"))]
+    match name {
+        Some(name) => region_names::restore(&span, name),
+        None => region_names::watched(&span),
+    }
+    #[cfg(feature = "heapless")]
+    match TRACKER_POOL.claim() {
+        Some(tracker) => {
+            tracker.lock().clear();
+            if let Err((_, tracker)) = mem_regions.insert(idx, (span.clone(), tracker)) {
+                TRACKER_POOL.release(tracker);
+                log::warn!("region list full, not watching {:#X}", addr);
+                return -1;
+            }
+        }
+        None => {
+            log::warn!("tracker pool empty, not watching {:#X}", addr);
+            return -1;
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+    mpk::__asan_mpk_watch_region(span.start(), span.len());
+    0
+}
+
+/// Indices of the regions in the sorted, disjoint `regions` that share bytes
+/// with `span`
+fn overlapping<T>(regions: &[(Span, T)], span: &Span) -> core::ops::Range<usize> {
+    if span.is_empty() {
+        return 0..0;
+    }
+
+    let start = regions.partition_point(|(region, _)| region.end() <= span.start());
+    let end = regions.partition_point(|(region, _)| region.start() < span.end());
+    start..end.max(start)
+}
+
+/// Carries what was fetched from `merged`, how often, and its access hook
+/// unless an earlier merged region had one, over to `tracker` of the region
+/// it is merged into
+#[cfg(not(feature = "no_std"))]
+fn carry_over(merged: &Span, old: &ThreadSafeMemoryTracker, tracker: &ThreadSafeMemoryTracker) {
+    let old = old.read().unwrap_or_else(PoisonError::into_inner);
+    let mut tracker = tracker.write().unwrap_or_else(PoisonError::into_inner);
+    for fetched in old.check_all(merged.start(), merged.len()) {
+        log_tracker_error(tracker.track_access(fetched.start(), fetched.len()));
+    }
+    if let (Some(old), Some(snapshot)) = (old.snapshot(), tracker.snapshot()) {
+        snapshot.take_over(old, merged.start(), merged.len());
+    }
+    tracker.counters().add(old.counters());
+    if tracker.access_hook().get().is_none() {
+        tracker.access_hook().set(old.access_hook().get());
     }
 }
 
+/// Destroys the memory tracker of the watched region containing `addr`.
+/// Returns 0, or -1 if no watched region contains `addr`.
 #[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("unwatch_shared_memory_region"))]
+pub extern "C" fn __asan_unwatch_shared_memory_region(addr: Address) -> c_int {
+    crate::ffi::guard("__asan_unwatch_shared_memory_region", -1, || {
+        let target_span = Span::with_len(addr, 1);
+        // nothing can be watched before init
+        let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(mem_regions) => mem_regions,
+            None => return -1,
+        };
+
+        #[cfg(not(feature = "heapless"))]
+        let mut mem_regions = mem_regions.write();
+        #[cfg(feature = "heapless")]
+        let mut mem_regions = mem_regions.lock();
+
+        if let Some(idx) = find_region(&mem_regions, &target_span) {
+            let (span, tracker) = mem_regions.remove(idx);
+            log::info!(
+                "unwatching memory region at {:#X}, len={:#X}",
+                span.start(),
+                span.len()
+            );
+            unwatched(&span, tracker);
+            0
+        } else {
+            log::warn!("{:#X} isn't watched, not unwatching it", addr);
+            -1
+        }
+    })
+}
+
+/// Lets go of `span`, just removed from the watched regions, and of its
+/// tracker
+#[cfg_attr(feature = "no_std", allow(unused_variables))]
+fn unwatched(span: &Span, _tracker: ThreadSafeMemoryTracker) {
+    #[cfg(feature = "tracing")]
+    telemetry::unwatch(span);
+    #[cfg(feature = "trace_recorder")]
+    trace::record(trace::Event::Unwatch { addr: span.start() });
+    #[cfg(not(feature = "no_std"))]
+    groups::forget(span);
+    #[cfg(not(feature = "no_std"))]
+    region_names::unwatched(span);
+    #[cfg(not(feature = "no_std"))]
+    print_heat_map(&_tracker);
+
+    #[cfg(feature = "heapless")]
+    TRACKER_POOL.release(_tracker);
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+    mpk::__asan_mpk_unwatch_region(span.start(), span.len());
+}
+
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("check"))]
 pub extern "C" fn __asan_double_fetch_check(addr: Address, len: usize, is_write: bool) -> bool {
-    let memory_tracker = get_memory_tracker(addr, len);
-    if memory_tracker.is_none() {
+    check_access(addr, len, is_write, None)
+}
+
+/// Fraction of the watched region containing `addr` that has been fetched
+/// since it was watched, or -1 if `addr` isn't in a watched region
+#[cfg(not(feature = "no_std"))]
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("region_coverage"))]
+pub extern "C" fn __asan_double_fetch_region_coverage(addr: Address) -> f64 {
+    ffi::guard(
+        "__asan_double_fetch_region_coverage",
+        -1.0,
+        || match get_memory_tracker(addr, 1) {
+            Some((region, tracker)) => tracker
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .coverage(region.len()),
+            None => -1.0,
+        },
+    )
+}
+
+macro_rules! sized_checks {
+    ($($name:ident => $stem:literal, $size:literal),* $(,)?) => {
+        $(
+            /// Fast path for fixed-size accesses, mirroring ASAN's
+            /// `__asan_loadN`/`__asan_storeN` callbacks. An access outside
+            /// every watched region, as most are, returns right after
+            /// looking up the region list, without entering a check or
+            /// being counted in the `checks` statistic.
+            #[no_mangle]
+            #[cfg_attr(feature = "prefixed_symbols", export_name = symbol!($stem))]
+            pub extern "C" fn $name(addr: Address, is_write: bool) -> bool {
+                if !in_watched_region(addr, $size) {
+                    return false;
+                }
+                check_access(addr, $size, is_write, None)
+            }
+        )*
+    };
+}
+
+sized_checks! {
+    __asan_double_fetch_check1 => "check1", 1,
+    __asan_double_fetch_check2 => "check2", 2,
+    __asan_double_fetch_check4 => "check4", 4,
+    __asan_double_fetch_check8 => "check8", 8,
+    __asan_double_fetch_check16 => "check16", 16,
+}
+
+/// Whether `[addr, addr + len)` shares bytes with a watched region. Takes no
+/// lock in userspace, where the region list is swapped out whole.
+#[inline(always)]
+fn in_watched_region(addr: Address, len: usize) -> bool {
+    let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+        Some(mem_regions) => mem_regions,
+        None => return false,
+    };
+    #[cfg(not(feature = "heapless"))]
+    let mem_regions = mem_regions.read();
+    #[cfg(feature = "heapless")]
+    let mem_regions = mem_regions.lock();
+
+    find_region(&mem_regions, &Span::with_len(addr, len)).is_some()
+}
+
+/// Checks an access, optionally attributed to the instruction at `pc`.
+/// Contains panics, as every entry point and interceptor ends up here.
+fn check_access(addr: Address, len: usize, is_write: bool, pc: Option<Address>) -> bool {
+    ffi::guard("check_access", false, || {
+        check_access_impl(addr, len, is_write, pc)
+    })
+}
+
+/// Nothing is formatted or printed on this path unless the access turns out
+/// to be a double fetch, or `verbosity=2` logs every check of a watched
+/// region, as it runs for every instrumented access. Opt-in
+/// outputs fed by every fetch, like `dot_file` or `fetch_feed`, return right
+/// away when they're off.
+#[inline(always)]
+fn check_access_impl(addr: Address, len: usize, is_write: bool, pc: Option<Address>) -> bool {
+    // an empty copy reads nothing, so it isn't a fetch, let alone a double
+    // one, whatever region it points into
+    if len == 0 {
         return false;
     }
 
-    println!(
-        "(runtime) fetch check addr: {:#X}, len: {:#X}, is_write: {:?}",
-        addr, len, is_write
-    );
+    #[cfg(not(feature = "no_std"))]
+    let _guard = match signal_safe::enter() {
+        Some(guard) => guard,
+        // a signal handler interrupted a check on this thread
+        None => return signal_safe::check(addr, len, is_write, pc),
+    };
+    #[cfg(not(feature = "no_std"))]
+    signal_safe::apply_pending();
+
+    #[cfg(all(unix, feature = "control_socket"))]
+    if !control::detecting() {
+        return false;
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    stats::CHECKS.fetch_add(1, Ordering::Relaxed);
+
+    let (region, memory_tracker) = match get_memory_tracker(addr, len) {
+        Some(found) => found,
+        None => return false,
+    };
+    check_tracked(&region, memory_tracker, false, addr, len, is_write, pc)
+}
+
+/// Checks an access to `_region`, whose fetches `memory_tracker` tracks.
+/// With `user_memory`, `_region` is in user memory, which is reported on but
+/// never read or written here.
+#[inline(always)]
+fn check_tracked(
+    _region: &Span,
+    memory_tracker: ThreadSafeMemoryTracker,
+    user_memory: bool,
+    addr: Address,
+    len: usize,
+    is_write: bool,
+    pc: Option<Address>,
+) -> bool {
+    #[cfg(not(feature = "no_std"))]
+    stats::REGION_HITS.fetch_add(1, Ordering::Relaxed);
+    #[cfg(not(feature = "no_std"))]
+    access_hook::call(&memory_tracker, addr, len, is_write, pc);
+    #[cfg(feature = "tracing")]
+    let _check_span = telemetry::check(_region, addr, len, is_write);
+
+    if !cfg!(feature = "no_alloc_hot_path") {
+        log::trace!(
+            "fetch check addr: {:#X}, len: {:#X}, is_write: {:?}",
+            addr,
+            len,
+            is_write
+        );
+    }
 
-    let memory_tracker = memory_tracker.unwrap();
     #[cfg(feature = "no_std")]
-    let memory_tracker = memory_tracker.lock();
+    let mut memory_tracker = memory_tracker.lock();
+
+    #[cfg(feature = "trace_recorder")]
+    if is_write {
+        trace::record(trace::Event::Check {
+            addr,
+            len,
+            pc,
+            is_write,
+            double_fetch: false,
+        });
+    }
 
     if !is_write {
+        #[cfg(all(unix, feature = "fetch_feed"))]
+        feed::publish(_region, addr, len);
+        #[cfg(feature = "sancov")]
+        sancov::fetched(pc);
+
         #[cfg(not(feature = "no_std"))]
-        let memory_tracker = memory_tracker.read().unwrap();
+        let memory_tracker = memory_tracker
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        #[cfg(not(feature = "no_std"))]
+        memory_tracker.count_fetch(addr, len);
+
+        let double_fetch = memory_tracker.check(addr, len).is_err();
+        #[cfg(not(feature = "no_std"))]
+        let changed = if double_fetch {
+            memory_tracker.changed(addr, len)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "no_std"))]
+        let double_fetch = double_fetch
+            && !(config::get().reporting == config::Reporting::ObservedChange
+                && changed == Some(false));
+        #[cfg(feature = "no_std")]
+        let changed = None;
+        #[cfg(not(feature = "no_std"))]
+        if !cfg!(feature = "no_alloc_hot_path") {
+            dot::record(pc, _region, addr, len, double_fetch);
+        }
+        #[cfg(feature = "trace_recorder")]
+        trace::record(trace::Event::Check {
+            addr,
+            len,
+            pc,
+            is_write,
+            double_fetch,
+        });
 
-        if memory_tracker.check(addr, len).is_err() {
+        if double_fetch {
             // this is a double-fetch
-            println!("(runtime) double-fetch detected!");
-            let data: &mut [u8] =
-                unsafe { std::slice::from_raw_parts_mut(std::mem::transmute(addr), len) };
-            if len <= 16 {
-                println!("(runtime) existing bytes: {:X?}", data);
+            DETECTIONS.fetch_add(1, Ordering::Relaxed);
+            #[cfg(not(feature = "no_std"))]
+            memory_tracker
+                .counters()
+                .detections
+                .fetch_add(1, Ordering::Relaxed);
+            let asan_style = config::get().report_style == config::ReportStyle::Asan;
+            if cfg!(feature = "no_alloc_hot_path") {
+                report_queue::defer(report_queue::Report { addr, len, pc });
+            } else if asan_style {
+                #[cfg(not(feature = "no_std"))]
+                report::emit(addr, len, pc, _region, &memory_tracker, changed, true);
+            } else {
+                report_detection(addr, len, pc, changed);
+            }
+            #[cfg(feature = "tracing")]
+            telemetry::detection(_region, addr, len, pc);
+            // the asan style report lists them itself
+            #[cfg(not(feature = "heapless"))]
+            if !cfg!(feature = "no_alloc_hot_path") && !asan_style {
+                for fetched in memory_tracker.check_all(addr, len) {
+                    #[cfg(not(feature = "no_std"))]
+                    log::warn!(
+                        "re-fetches earlier fetch of {} ({})",
+                        fetched,
+                        region_names::relative(_region, fetched.start())
+                    );
+                    #[cfg(feature = "no_std")]
+                    log::warn!("re-fetches earlier fetch of {}", fetched);
+                }
+                #[cfg(not(feature = "no_std"))]
+                if let Some(group) = groups::describe(_region) {
+                    log::warn!("region is in group {}", group);
+                }
+                #[cfg(feature = "sancov")]
+                if let Some(edge) = sancov::edge() {
+                    log::warn!("re-fetched after coverage edge {}", edge);
+                }
+            }
+            #[cfg(feature = "dbi")]
+            dbi::report(addr, len, pc, _region);
+            #[cfg(feature = "python")]
+            python::report(addr, len, pc, _region);
+            #[cfg(not(feature = "no_std"))]
+            if !cfg!(feature = "no_alloc_hot_path") {
+                events::publish(addr, len, pc, _region, &memory_tracker);
+            }
+            #[cfg(all(unix, feature = "syslog"))]
+            if !cfg!(feature = "no_alloc_hot_path") {
+                syslog::report(addr, len, pc, _region, changed);
+            }
+            #[cfg(all(windows, feature = "etw"))]
+            if !cfg!(feature = "no_alloc_hot_path") {
+                etw::report(addr, len, pc, _region, changed);
+            }
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+            if !cfg!(feature = "no_alloc_hot_path") {
+                match mpk::was_written(addr, len) {
+                    Some(true) => log::warn!("data was written since the first fetch"),
+                    Some(false) => {
+                        log::warn!("data was not written since the first fetch")
+                    }
+                    None => (),
+                }
+            }
+
+            // the kernel can't touch user memory directly, and mutating it
+            // after the copy wouldn't change what the kernel uses anyway
+            if user_memory {
+                return false;
             }
 
-            let mut rng = rand::thread_rng();
-            if rng.gen() {
-                data.iter_mut().for_each(|b| *b = rng.gen());
-                if len <= 16 {
-                    println!("(runtime) new bytes: {:X?}", data);
+            #[cfg(not(feature = "no_std"))]
+            let mut html_detection = if cfg!(feature = "no_alloc_hot_path") {
+                None
+            } else {
+                html::begin(addr, len, pc, _region, &memory_tracker)
+            };
+
+            let data: &mut [u8] = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+            let dump_bytes = !cfg!(feature = "no_alloc_hot_path");
+            if dump_bytes {
+                hexdump::dump("existing bytes", _region, addr, len);
+            }
+            #[cfg(not(feature = "no_std"))]
+            let stale = stale_length::value(data);
+
+            #[cfg(not(feature = "no_std"))]
+            let mut rng = decisions::Rng::new(rand::thread_rng());
+            #[cfg(feature = "linux_kasan")]
+            let mut rng = kmod::KernelRng;
+            #[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+            let mut rng = bare::BareRng;
+            #[cfg(not(feature = "no_std"))]
+            let planned = minimize::begin();
+            #[cfg(not(feature = "no_std"))]
+            let mutate = planned.decide(|| rng.deciding(decisions::Decision::Mutate).gen());
+            #[cfg(not(feature = "no_std"))]
+            let canary = config::get().mutation == config::MutationPolicy::Canary;
+            // every re-fetch gets a canary, so none of them goes untraced
+            #[cfg(not(feature = "no_std"))]
+            let mutate = mutate || canary;
+            #[cfg(feature = "no_std")]
+            let mutate = rng.gen();
+            #[cfg(all(unix, feature = "control_socket"))]
+            let mutate = mutate && control::mutating();
+            let mutate = mutate && !config::get().report_only;
+            if mutate {
+                #[cfg(not(feature = "no_std"))]
+                stats::MUTATIONS.fetch_add(1, Ordering::Relaxed);
+                #[cfg(not(feature = "no_std"))]
+                memory_tracker
+                    .counters()
+                    .mutations
+                    .fetch_add(1, Ordering::Relaxed);
+                #[cfg(all(unix, not(feature = "no_std")))]
+                let old = crash::Bytes::of(data);
+                let mut mutate_data = |data: &mut [u8]| {
+                    #[cfg(not(feature = "no_std"))]
+                    planned.mutate(_region, addr, data, |data| {
+                        if canary {
+                            return canary::plant(_region, addr, pc, data);
+                        }
+                        let rng = rng.deciding(decisions::Decision::Value);
+                        mutation::mutate_fetched(addr, data, config::get().endianness, rng)
+                    });
+                    #[cfg(feature = "no_std")]
+                    mutation::mutate_fetched(addr, data, config::get().endianness, &mut rng);
+                };
+                #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+                mpk::with_writes_allowed(|| mutate_data(data));
+                #[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "mpk")))]
+                mutate_data(data);
+                // so the runtime's own writes don't count as changes
+                #[cfg(not(feature = "no_std"))]
+                if let Some(snapshot) = memory_tracker.snapshot() {
+                    snapshot.store(addr, data);
+                }
+                if dump_bytes {
+                    hexdump::dump("new bytes", _region, addr, len);
+                }
+                #[cfg(all(unix, not(feature = "no_std")))]
+                crash::record(_region, addr, pc, &old, data);
+                #[cfg(not(feature = "no_std"))]
+                if let Some(detection) = &mut html_detection {
+                    detection.mutated(data);
                 }
             }
+            #[cfg(not(feature = "no_std"))]
+            stale_length::record(addr, pc, stale, stale_length::value(data));
+            #[cfg(not(feature = "no_std"))]
+            html::record(html_detection);
             return false;
         }
     }
 
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "mpk"))]
+    if !is_write {
+        mpk::clear_written(addr, len);
+    }
+
     #[cfg(not(feature = "no_std"))]
-    let mut memory_tracker = memory_tracker.write().unwrap();
-    memory_tracker.track_access(addr, len);
+    let mut memory_tracker = memory_tracker
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    // only bytes fetched for the first time are first-fetched ones
+    #[cfg(not(feature = "no_std"))]
+    log_tracker_error(if is_write {
+        memory_tracker.track_access(addr, len)
+    } else {
+        memory_tracker.track_fetch(addr, len)
+    });
+    #[cfg(all(feature = "no_std", not(feature = "heapless")))]
+    log_tracker_error(memory_tracker.track_access(addr, len));
+    #[cfg(feature = "heapless")]
+    if memory_tracker.track_access(addr, len).is_err() {
+        log::warn!("tracker full, fetch at {:#X} not tracked", addr);
+    }
 
     false
 }
 
-fn get_memory_tracker(addr: Address, len: usize) -> Option<Arc<Lock<MemoryTracker>>> {
-    let target_span = Span::with_len(addr, len);
-    let mem_regions = TRACKED_MEMORY_REGIONS
-        .get()
-        .expect("tracked memory regions is not initialized");
+fn new_region_list() -> RegionList {
+    #[cfg(all(not(feature = "no_std"), not(feature = "allocator_api")))]
+    return swap::SwapList::new(Vec::new());
+    #[cfg(all(not(feature = "no_std"), feature = "allocator_api"))]
+    return swap::SwapList::new(Vec::new_in(TrackerAlloc::default()));
+    #[cfg(feature = "no_std")]
+    return Default::default();
+}
+
+#[cfg(not(feature = "heapless"))]
+fn new_tracker(_region: &Span, _granularity: usize) -> ThreadSafeMemoryTracker {
+    #[cfg(all(not(feature = "no_std"), not(feature = "allocator_api")))]
+    return Arc::new(Lock::new(Tracker::new(_region, _granularity)));
+    #[cfg(all(not(feature = "no_std"), feature = "allocator_api"))]
+    return Arc::new_in(
+        Lock::new(Tracker::new(_region, _granularity)),
+        TrackerAlloc::default(),
+    );
+    #[cfg(feature = "no_std")]
+    return Default::default();
+}
+
+/// Prints the heat map of a region that stops being watched, if it has one
+#[cfg(not(feature = "no_std"))]
+fn print_heat_map(tracker: &ThreadSafeMemoryTracker) {
+    let tracker = tracker.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some(heat_map) = tracker.heat_map() {
+        log::info!("{}", heat_map.render());
+    }
+}
 
+/// Logs a failed tracker operation. The tracker stays consistent, so
+/// detection carries on without that one update.
+#[cfg(not(feature = "heapless"))]
+fn log_tracker_error<A: address::AddressType>(
+    result: Result<(), memory_tracking::TrackerError<A>>,
+) {
+    if let Err(e) = result {
+        log::error!("{}", e);
+    }
+}
+
+/// Prints a detection, either right away or when draining deferred reports.
+/// `changed` says whether the re-fetched bytes changed since they were first
+/// fetched, if known; changed ones are reported as errors.
+fn report_detection(addr: Address, len: usize, pc: Option<Address>, changed: Option<bool>) {
     #[cfg(not(feature = "no_std"))]
-    let mem_regions = mem_regions.write().unwrap();
+    if config::get().report_style == config::ReportStyle::Asan {
+        if let Some((region, tracker)) = get_memory_tracker(addr, len) {
+            let tracker = tracker.read().unwrap_or_else(PoisonError::into_inner);
+            report::emit(addr, len, pc, &region, &tracker, changed, false);
+            return;
+        }
+    }
+
     #[cfg(feature = "linux_kasan")]
+    {
+        // kernel builds don't snapshot fetched bytes
+        let _ = changed;
+        kasan::report("double-fetch", addr, len, pc);
+    }
+    #[cfg(not(feature = "no_std"))]
+    let at = region_names::locate(addr).map_or_else(String::new, |at| format!(" ({})", at));
+    #[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+    let at = "";
+    #[cfg(not(feature = "linux_kasan"))]
+    let (level, change) = match changed {
+        Some(true) => (log::Level::Error, ", changed since first fetch"),
+        Some(false) => (log::Level::Warn, ", unchanged since first fetch"),
+        None => (log::Level::Warn, ""),
+    };
+    #[cfg(not(feature = "linux_kasan"))]
+    match pc {
+        Some(pc) => log::log!(
+            level,
+            "double-fetch detected! addr: {:#X}{}, len: {:#X}, pc: {:#X}{}",
+            addr,
+            at,
+            len,
+            pc,
+            change
+        ),
+        None => log::log!(
+            level,
+            "double-fetch detected! addr: {:#X}{}, len: {:#X}{}",
+            addr,
+            at,
+            len,
+            change
+        ),
+    }
+}
+
+fn get_memory_tracker(addr: Address, len: usize) -> Option<(Span, ThreadSafeMemoryTracker)> {
+    let target_span = Span::with_len(addr, len);
+    let mem_regions = TRACKED_MEMORY_REGIONS.get()?;
+
+    #[cfg(not(feature = "heapless"))]
+    let mem_regions = mem_regions.read();
+    #[cfg(feature = "heapless")]
     let mem_regions = mem_regions.lock();
 
-    mem_regions.iter().find_map(|(va_range, tracker)| {
-        if target_span.relation(&va_range) == SpanRelation::None {
-            None
-        } else {
-            Some(Arc::clone(tracker))
-        }
-    })
+    let (va_range, tracker) = &mem_regions[find_region(&mem_regions, &target_span)?];
+    Some((va_range.clone(), ThreadSafeMemoryTracker::clone(tracker)))
+}
+
+/// Index of the region in the sorted `regions` that `span` shares bytes
+/// with: the one it starts in, or else the first one starting inside it.
+/// Merely adjacent regions don't count.
+fn find_region<A: address::AddressType, T>(
+    regions: &[(Span<A>, T)],
+    span: &Span<A>,
+) -> Option<usize> {
+    let idx = regions.partition_point(|(region, _)| region.start() <= span.start());
+
+    let overlaps = |idx: usize| match regions.get(idx) {
+        Some((region, _)) => span.intersect(region).is_some(),
+        None => false,
+    };
+
+    match idx.checked_sub(1) {
+        Some(before) if overlaps(before) => Some(before),
+        _ if overlaps(idx) => Some(idx),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn find_region() {
+        let regions: [(Span, ()); 3] = [
+            (Span::new(0x1000, 0x2000), ()),
+            (Span::new(0x2000, 0x2100), ()),
+            (Span::new(0x3000, 0x4000), ()),
+        ];
+
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0xfff, 0x1000)),
+            None
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0xfff, 0x1001)),
+            Some(0)
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0x1fff, 0x2001)),
+            Some(0)
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0x2000, 0x2001)),
+            Some(1)
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0x2100, 0x3000)),
+            None
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0x2100, 0x3001)),
+            Some(2)
+        );
+        assert_eq!(
+            super::find_region(&regions, &Span::new(0x3800, 0x3800)),
+            None
+        );
+        assert_eq!(
+            super::find_region(&[] as &[(Span, ())], &Span::new(0, 1)),
+            None
+        );
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn sized_checks() {
+        ensure_initialized();
+
+        let data = Box::leak(Box::new([0u8; 16]));
+        let base = data.as_ptr() as Address;
+        assert!(!in_watched_region(base, 8));
+        assert!(!__asan_double_fetch_check8(base, false));
+
+        __asan_watch_shared_memory_region(base, data.len());
+        assert!(in_watched_region(base + 15, 8));
+        assert!(!in_watched_region(base + 16, 8));
+        __asan_double_fetch_check4(base, false);
+        __asan_double_fetch_check2(base + 2, false);
+        let (_region, tracker) = get_memory_tracker(base, 1).unwrap();
+        let tracker = tracker.read().unwrap();
+        assert_eq!(tracker.counters().detections.load(Ordering::Relaxed), 1);
+        drop(tracker);
+
+        __asan_unwatch_shared_memory_region(base);
+    }
+
+    #[test]
+    fn overlapping() {
+        let regions = [
+            (Span::new(0x1000, 0x2000), ()),
+            (Span::new(0x2000, 0x2100), ()),
+            (Span::new(0x3000, 0x4000), ()),
+        ];
+
+        assert_eq!(
+            super::overlapping(&regions, &Span::new(0x800, 0x1000)),
+            0..0
+        );
+        assert_eq!(
+            super::overlapping(&regions, &Span::new(0x1fff, 0x2001)),
+            0..2
+        );
+        assert_eq!(
+            super::overlapping(&regions, &Span::new(0x2100, 0x3000)),
+            2..2
+        );
+        assert_eq!(super::overlapping(&regions, &Span::new(0x0, 0x5000)), 0..3);
+        assert_eq!(
+            super::overlapping(&regions, &Span::new(0x3800, 0x3800)),
+            0..0
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regions() {
+        let data = [0u8; 8];
+        let base = data.as_ptr() as Address;
+
+        assert_eq!(__asan_watch_shared_memory_region(0, 8), -1);
+        assert_eq!(__asan_watch_shared_memory_region(base, 0), -1);
+        assert_eq!(__asan_watch_shared_memory_region(usize::MAX - 3, 8), -1);
+        assert_eq!(__asan_watch_shared_memory_region_granular(base, 8, 3), -1);
+        assert!(get_memory_tracker(base, 8).is_none());
+        assert_eq!(__asan_unwatch_shared_memory_region(base), -1);
+
+        assert_eq!(__asan_watch_shared_memory_region(base, 8), 0);
+        assert_eq!(__asan_unwatch_shared_memory_region(base + 7), 0);
+        assert_eq!(__asan_unwatch_shared_memory_region(base), -1);
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn merges_overlapping_regions() {
+        let data = Box::leak(Box::new([0u8; 32]));
+        let base = data.as_ptr() as Address;
+        assert_eq!(__asan_watch_shared_memory_region(base, 8), 0);
+        assert_eq!(__asan_watch_shared_memory_region(base + 16, 8), 0);
+        __asan_double_fetch_check(base, 4, false);
+
+        assert_eq!(__asan_watch_shared_memory_region(base + 4, 16), 0);
+        let (region, _tracker) = get_memory_tracker(base + 20, 1).unwrap();
+        assert_eq!(region, Span::with_len(base, 24));
+        assert!(get_memory_tracker(base + 24, 1).is_none());
+        // what was fetched before the merge still counts
+        assert!(!__asan_double_fetch_check(base, 4, false));
+        let mut stats = stats::RegionStats::default();
+        unsafe { stats::__asan_double_fetch_get_region_stats(base, &mut stats) };
+        assert_eq!(stats.detections, 1);
+
+        __asan_unwatch_shared_memory_region(base);
+        assert!(get_memory_tracker(base, 24).is_none());
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn region_coverage() {
+        ensure_initialized();
+
+        let data = [0u8; 16];
+        let base = data.as_ptr() as Address;
+        assert_eq!(__asan_double_fetch_region_coverage(base), -1.0);
+
+        __asan_watch_shared_memory_region(base, data.len());
+        __asan_double_fetch_check(base, 4, false);
+        assert_eq!(__asan_double_fetch_region_coverage(base), 0.25);
+
+        __asan_unwatch_shared_memory_region(base);
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    #[test]
+    fn survives_poisoned_tracker() {
+        ensure_initialized();
+
+        let data = [0u8; 8];
+        let base = data.as_ptr() as Address;
+        __asan_watch_shared_memory_region(base, data.len());
+
+        let (_region, tracker) = get_memory_tracker(base, 4).unwrap();
+        let _ = std::thread::spawn(move || {
+            let _guard = tracker.write().unwrap();
+            panic!("target panicked with the tracker locked");
+        })
+        .join();
+
+        __asan_double_fetch_check(base, 4, false);
+        let (_region, tracker) = get_memory_tracker(base, 4).unwrap();
+        assert!(tracker.is_poisoned());
+        assert!(tracker
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .check(base, 4)
+            .is_err());
+
+        __asan_unwatch_shared_memory_region(base);
+    }
 }
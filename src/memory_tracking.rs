@@ -1,10 +1,24 @@
+#[cfg(all(feature = "no_std", feature = "allocator_api"))]
+pub use alloc::alloc::Global;
 #[cfg(feature = "no_std")]
 use alloc::collections::BTreeSet;
-#[cfg(not(feature = "no_std"))]
-use std::collections::BTreeSet;
+#[cfg(feature = "no_std")]
+use alloc::vec::Vec;
+#[cfg(feature = "allocator_api")]
+use core::alloc::Allocator;
 use core::fmt;
+use core::hash::{Hash, Hasher};
+#[cfg(not(feature = "allocator_api"))]
+use core::marker::PhantomData;
 use core::ops::Bound::{Excluded, Included};
+#[cfg(not(feature = "allocator_api"))]
+use core::ops::{Deref, DerefMut};
+#[cfg(all(not(feature = "no_std"), feature = "allocator_api"))]
+pub use std::alloc::Global;
+#[cfg(not(feature = "no_std"))]
+use std::collections::BTreeSet;
 
+use crate::address::AddressType;
 use crate::span::{Span, SpanRelation};
 use crate::Address;
 
@@ -54,62 +68,186 @@ use crate::Address;
 ///   This example is with a BTreeMap, however the concept can also be applied
 ///   to BTreeSets if one extends ranges to be comparable, which is exactly
 ///   what this code does.
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
-pub struct MemoryTracker(BTreeSet<Span>);
+///
+/// An access is a set of bytes, so empty accesses, such as a zero-length
+/// copy, are no-ops: they're never tracked, never cleared and never conflict
+/// with a redzone, even one they start inside. The tracker also keeps which
+/// bytes were accessed, not how often, so tracking a span again leaves a
+/// single redzone, and a single `remove_access` clears it however many times
+/// it was tracked.
+///
+/// The tracker is generic over its address type so that it can be used for
+/// address spaces other than the host's, e.g. `MemoryTracker<u64>` for guest
+/// physical addresses on a 32-bit host.
+///
+/// With the `allocator_api` feature (nightly), the tracker's nodes can be
+/// placed in a caller-supplied allocator with [`MemoryTracker::new_in`].
+#[derive(Clone, Debug)]
+pub struct MemoryTracker<A: AddressType = Address, Alloc: TrackerAllocator = Global>(
+    SpanSet<A, Alloc>,
+);
+
+/// A tracker operation met an existing span in a relation to the requested
+/// one that the interval logic has no case for
+///
+/// This indicates a bug in the merging logic rather than bad input. The
+/// tracker stays usable: spans already merged are kept merged and the
+/// offending span is kept as is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TrackerError<A: AddressType = Address> {
+    /// Tracking `new` ran into `existing`
+    Merge { new: Span<A>, existing: Span<A> },
+    /// Clearing `clear` ran into `existing`
+    Clear { clear: Span<A>, existing: Span<A> },
+}
+
+impl<A: AddressType> fmt::Display for TrackerError<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Merge { new, existing } => write!(
+                f,
+                "error merging span: requested merge of {} overlapping with {}",
+                new, existing
+            ),
+            TrackerError::Clear { clear, existing } => write!(
+                f,
+                "error clearing span: requested clear of {} overlapping with {}",
+                clear, existing
+            ),
+        }
+    }
+}
+
+#[cfg(not(feature = "no_std"))]
+impl<A: AddressType> std::error::Error for TrackerError<A> {}
+
+/// Allocators a [`MemoryTracker`] can be placed in
+#[cfg(feature = "allocator_api")]
+pub trait TrackerAllocator: Allocator + Clone {}
+#[cfg(feature = "allocator_api")]
+impl<T: Allocator + Clone> TrackerAllocator for T {}
+
+/// Allocators a [`MemoryTracker`] can be placed in. Without the
+/// `allocator_api` feature that is only the global allocator.
+#[cfg(not(feature = "allocator_api"))]
+pub trait TrackerAllocator: Clone {}
+#[cfg(not(feature = "allocator_api"))]
+impl TrackerAllocator for Global {}
+
+/// Stand-in for `std::alloc::Global` without the `allocator_api` feature
+#[cfg(not(feature = "allocator_api"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
 
-impl Default for MemoryTracker {
+#[cfg(feature = "allocator_api")]
+type SpanSet<A, Alloc> = BTreeSet<Span<A>, Alloc>;
+
+/// `BTreeSet` carrying an allocator type it can't use on stable
+#[cfg(not(feature = "allocator_api"))]
+#[derive(Clone, Debug)]
+struct SpanSet<A: AddressType, Alloc>(BTreeSet<Span<A>>, PhantomData<Alloc>);
+
+#[cfg(not(feature = "allocator_api"))]
+impl<A: AddressType, Alloc> SpanSet<A, Alloc> {
+    fn new_in(_alloc: Alloc) -> Self {
+        Self(BTreeSet::new(), PhantomData)
+    }
+}
+
+#[cfg(not(feature = "allocator_api"))]
+impl<A: AddressType, Alloc> Deref for SpanSet<A, Alloc> {
+    type Target = BTreeSet<Span<A>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(not(feature = "allocator_api"))]
+impl<A: AddressType, Alloc> DerefMut for SpanSet<A, Alloc> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<A: AddressType, Alloc: TrackerAllocator + Default> Default for MemoryTracker<A, Alloc> {
     fn default() -> Self {
-        Self(BTreeSet::new())
+        Self::new_in(Alloc::default())
+    }
+}
+
+impl<A: AddressType, Alloc: TrackerAllocator> PartialEq for MemoryTracker<A, Alloc> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.iter().eq(other.0.iter())
     }
 }
 
-impl fmt::Display for MemoryTracker {
+impl<A: AddressType, Alloc: TrackerAllocator> Eq for MemoryTracker<A, Alloc> {}
+
+impl<A: AddressType, Alloc: TrackerAllocator> Hash for MemoryTracker<A, Alloc> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.0.len());
+        for span in self.0.iter() {
+            span.hash(state);
+        }
+    }
+}
+
+impl<A: AddressType, Alloc: TrackerAllocator> fmt::Display for MemoryTracker<A, Alloc> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{{")?;
-        for span in &self.0 {
+        for span in self.0.iter() {
             writeln!(f, "\t{}", span)?;
         }
         writeln!(f, "}}")
     }
 }
 
-impl MemoryTracker {
-    /// New redzone span
+impl<A: AddressType, Alloc: TrackerAllocator> MemoryTracker<A, Alloc> {
+    /// Empty tracker whose nodes are allocated with `alloc`
+    pub fn new_in(alloc: Alloc) -> Self {
+        Self(SpanSet::new_in(alloc))
+    }
+
+    /// Track an access
     ///
     /// Takes a base address and size, and creates a redzone for it. If the
     /// new redzone overlaps with any existing redzones, they are merged.
     ///
+    /// # Errors
+    ///
+    /// Returns a [`TrackerError`] if an existing span can't be merged.
+    ///
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     ///
     /// assert!(rz.check(0x5151, 1).is_ok());
     /// assert!(rz.check(0x4144, 1).is_err());
     /// ```
-    pub fn track_access(&mut self, a: Address, sz: usize) {
+    pub fn track_access(&mut self, a: A, sz: A) -> Result<(), TrackerError<A>> {
         let new = Span::with_len(a, sz);
+        if new.is_empty() {
+            return Ok(());
+        }
 
-        // we want to merge with adjacent spans, so we need to broaden the range
-        // by 1 byte on each side to make us overlap
-        let overlapped: Vec<Span> = self
-            .lookup_range(a.saturating_sub(1), sz.saturating_add(1))
-            .cloned()
-            .collect();
-
-        let mut start: Option<Address> = None;
-        let mut end: Option<Address> = None;
+        let mut start: Option<A> = None;
+        let mut end: Option<A> = None;
 
-        for span in overlapped {
+        // we want to merge with adjacent spans too, so look for spans that
+        // merely touch ours. Spans are taken out one at a time rather than
+        // collected first to keep this allocation-free.
+        while let Some(span) = self.first_touching(&new) {
             self.0.remove(&span);
 
             match new.relation(&span) {
                 SpanRelation::Break => (),
-                SpanRelation::Engulf => {
+                SpanRelation::Equal | SpanRelation::Engulf => {
                     start = Some(span.start());
                     end = Some(span.end());
                 }
@@ -117,10 +255,17 @@ impl MemoryTracker {
                     start = Some(span.start())
                 }
                 SpanRelation::AdjacentEnd | SpanRelation::OverlapEnd => end = Some(span.end()),
-                SpanRelation::None => panic!(format!(
-                    "error merging span: requested merge of {} overlapping with {}",
-                    new, span
-                )),
+                SpanRelation::None => {
+                    self.0.insert(span.clone());
+                    self.0.insert(Span::new(
+                        start.unwrap_or_else(|| new.start()),
+                        end.unwrap_or_else(|| new.end()),
+                    ));
+                    return Err(TrackerError::Merge {
+                        new,
+                        existing: span,
+                    });
+                }
             }
         }
 
@@ -128,41 +273,101 @@ impl MemoryTracker {
         let new_end = end.unwrap_or_else(|| new.end());
 
         self.0.insert(Span::new(new_start, new_end));
+        Ok(())
+    }
+
+    /// Track many accesses at once
+    ///
+    /// The `(address, size)` pairs are sorted and merged among themselves
+    /// first, so overlapping and adjacent ones, as in a replayed trace, cost
+    /// a single tree update each rather than one per access.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TrackerError`] if an existing span can't be merged. The
+    /// accesses before it have been tracked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::MemoryTracker;
+    /// #
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
+    ///
+    /// rz.track_accesses([(0x4145, 4), (0x4141, 4), (0x5151, 8)]).unwrap();
+    ///
+    /// assert_eq!(rz.redzones().collect::<Vec<_>>(), [(0x4141, 8), (0x5151, 8)]);
+    /// ```
+    pub fn track_accesses(
+        &mut self,
+        spans: impl IntoIterator<Item = (A, A)>,
+    ) -> Result<(), TrackerError<A>> {
+        let mut spans: Vec<Span<A>> = spans
+            .into_iter()
+            .map(|(a, sz)| Span::with_len(a, sz))
+            .filter(|span| !span.is_empty())
+            .collect();
+        spans.sort_unstable();
+
+        let mut merged: Option<Span<A>> = None;
+        for span in spans {
+            merged = match merged.as_ref().and_then(|merged| merged.union(&span)) {
+                Some(union) => Some(union),
+                None => {
+                    if let Some(done) = merged {
+                        self.track_access(done.start(), done.len())?;
+                    }
+                    Some(span)
+                }
+            };
+        }
+
+        match merged {
+            Some(done) => self.track_access(done.start(), done.len()),
+            None => Ok(()),
+        }
     }
 
-    /// Clear redzone span
+    /// Forget an access
     ///
-    /// Clears the red from existing span. If the address and sz and size are
+    /// Clears `[a, a + sz)` from the existing redzones. If those bytes are
     /// not currently red, this is a no-op.
     ///
+    /// # Errors
+    ///
+    /// Returns a [`TrackerError`] if an existing span can't be split.
+    ///
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     /// // redzone is from [0x4141..0x4149)
     ///
     /// assert!(rz.check(0x4141, 1).is_err());
     ///
-    /// rz.clear_span(0x4141, 1);
+    /// rz.remove_access(0x4141, 1).unwrap();
     /// // redzone is from [0x4142..0x4149)
     ///
     /// assert!(rz.check(0x4141, 1).is_ok());
     /// assert!(rz.check(0x4142, 1).is_err());
     /// ```
-    pub fn remove_access(&mut self, a: Address, sz: usize) {
-        let overlap: Vec<Span> = self.lookup_range(a, sz).cloned().collect();
-
+    pub fn remove_access(&mut self, a: A, sz: A) -> Result<(), TrackerError<A>> {
         let clear = Span::with_len(a, sz);
+        if clear.is_empty() {
+            return Ok(());
+        }
 
-        for span in overlap {
+        // any pieces put back below lie outside the cleared range, so this
+        // terminates once nothing overlaps it anymore
+        while let Some(span) = self.first_in_range(a, sz) {
             self.0.remove(&span);
 
             match clear.relation(&span) {
-                SpanRelation::Break => (),
+                SpanRelation::Equal | SpanRelation::Break => (),
                 SpanRelation::OverlapEnd => {
                     self.0.insert(Span::new(clear.end(), span.end()));
                 }
@@ -173,22 +378,26 @@ impl MemoryTracker {
                     let a = Span::new(span.start(), clear.start());
                     let b = Span::new(clear.end(), span.end());
 
-                    if a.len() > 0 {
+                    if a.len() > A::ZERO {
                         self.0.insert(a);
                     }
-                    if b.len() > 0 {
+                    if b.len() > A::ZERO {
                         self.0.insert(b);
                     }
                 }
-                _ => panic!(format!(
-                    "error clearing span: requested clear of {} overlapping with {}",
-                    clear, span
-                )),
+                _ => {
+                    self.0.insert(span.clone());
+                    return Err(TrackerError::Clear {
+                        clear,
+                        existing: span,
+                    });
+                }
             }
         }
+        Ok(())
     }
 
-    /// Spans in the redzone
+    /// Number of redzones
     ///
     /// The number of spans in the redzone. Note: due to merging and splitting
     /// this may be greater or less than the number the user inserted.
@@ -196,16 +405,16 @@ impl MemoryTracker {
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     /// // redzone is from [0x4141..0x4149)
     ///
     /// assert_eq!(rz.len(), 1);
     ///
-    /// rz.clear_span(0x4145, 2);
+    /// rz.remove_access(0x4145, 2).unwrap();
     /// // redzones are from:
     /// //   [0x4141..0x4145)
     /// //   [0x4147..0x4149)
@@ -216,18 +425,18 @@ impl MemoryTracker {
         self.0.len()
     }
 
-    /// Returns true if the Redzone is empty
+    /// Returns true if no redzones are tracked
     ///
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
     /// assert_eq!(rz.is_empty(), true);
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     ///
     /// assert_eq!(rz.is_empty(), false);
     /// ```
@@ -240,11 +449,11 @@ impl MemoryTracker {
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     ///
     /// assert!(rz.check(0x4144, 1).is_err());
     ///
@@ -258,34 +467,127 @@ impl MemoryTracker {
 
     /// Iterate over redzones
     ///
-    /// Iterate over a tuples of (redzone, size), sorted by the redzone base
+    /// Iterates over `(start, len)` of each redzone, sorted by start
     ///
     /// # Examples
     ///
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
-    /// rz.red_span(0x5151, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
+    /// rz.track_access(0x5151, 8).unwrap();
     ///
     /// let mut ii = rz.redzones();
     ///
     /// assert_eq!(ii.next(), Some((0x4141, 8)));
     /// assert_eq!(ii.next(), Some((0x5151, 8)));
     /// assert_eq!(ii.next(), None);
+    ///
+    /// assert_eq!(rz.redzones().next_back(), Some((0x5151, 8)));
     /// ```
-    pub fn redzones(&self) -> impl Iterator<Item = (Address, usize)> {
+    pub fn redzones(&self) -> impl DoubleEndedIterator<Item = (A, A)> + ExactSizeIterator + '_ {
+        self.0.iter().map(|span| (span.start(), span.len()))
+    }
+
+    /// Number of bytes covered by redzones
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::MemoryTracker;
+    /// #
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
+    ///
+    /// rz.track_access(0x4141, 8).unwrap();
+    /// rz.track_access(0x5151, 8).unwrap();
+    ///
+    /// assert_eq!(rz.occupied_len(), 16);
+    /// ```
+    pub fn occupied_len(&self) -> A {
         self.0
             .iter()
-            .map(|span| (span.start(), span.len()))
-            .collect::<Vec<(Address, usize)>>()
-            .into_iter()
+            .fold(A::ZERO, |total, span| total.saturating_add(span.len()))
+    }
+
+    /// Fraction of `total_len` bytes covered by redzones, e.g. how much of a
+    /// watched region has been fetched. 0 if `total_len` is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::MemoryTracker;
+    /// #
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
+    ///
+    /// rz.track_access(0x4000, 0x400).unwrap();
+    ///
+    /// assert_eq!(rz.coverage(0x1000), 0.25);
+    /// ```
+    pub fn coverage(&self, total_len: A) -> f64 {
+        if total_len == A::ZERO {
+            return 0.0;
+        }
+
+        self.occupied_len().as_f64() / total_len.as_f64()
+    }
+
+    /// Iterates over the parts of `range` not covered by any redzone, in
+    /// ascending order
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::{MemoryTracker, Span};
+    /// #
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
+    ///
+    /// rz.track_access(0x4000, 0x10).unwrap();
+    /// rz.track_access(0x4020, 0x10).unwrap();
+    ///
+    /// let gaps: Vec<_> = rz.gaps(Span::new(0x4008, 0x4040)).collect();
+    /// assert_eq!(gaps, [Span::new(0x4010, 0x4020), Span::new(0x4030, 0x4040)]);
+    /// ```
+    pub fn gaps(&self, range: Span<A>) -> impl Iterator<Item = Span<A>> + '_ {
+        let (mut cursor, end) = (range.start(), range.end());
+
+        // the span starting before the range may still reach into it
+        let before = self
+            .0
+            .range(..Span::new(cursor, A::ZERO))
+            .next_back()
+            .filter(|span| span.end() > cursor);
+        let mut spans = before.into_iter().chain(
+            self.0
+                .range(Span::new(cursor, A::ZERO)..Span::new(end, A::ZERO)),
+        );
+
+        core::iter::from_fn(move || loop {
+            if cursor >= end {
+                return None;
+            }
+
+            let gap_end = match spans.next() {
+                Some(span) => {
+                    let gap = Span::new(cursor, span.start().min(end));
+                    cursor = cursor.max(span.end());
+                    if gap.is_empty() {
+                        continue;
+                    }
+                    return Some(gap);
+                }
+                None => end,
+            };
+
+            let gap = Span::new(cursor, gap_end);
+            cursor = end;
+            return Some(gap);
+        })
     }
 
-    /// Check Address
+    /// Check an access
     ///
     /// Checks if any part of a given address and size overlap with an
     /// existing redzone.
@@ -298,40 +600,375 @@ impl MemoryTracker {
     /// # Examples
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
+    /// rz.track_access(0x4141, 8).unwrap();
     ///
     /// assert!(rz.check(0x5151, 1).is_ok());
     /// assert!(rz.check(0x4144, 1).is_err());
     /// ```
     ///
     /// ```
-    /// # use penumbra::Redzone;
+    /// # use asan_double_fetch::MemoryTracker;
     /// #
-    /// let mut rz = Redzone::default();
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
     ///
-    /// rz.red_span(0x4141, 8);
-    /// rz.clear_span(0x4143, 4);
+    /// rz.track_access(0x4141, 8).unwrap();
+    /// rz.remove_access(0x4143, 4).unwrap();
     ///
     /// assert_eq!(rz.check(0x4141, 8), Err(0x4147));
     /// ```
-    pub fn check(&self, a: Address, sz: usize) -> Result<(), Address> {
+    pub fn check(&self, a: A, sz: A) -> Result<(), A> {
+        if Span::with_len(a, sz).is_empty() {
+            return Ok(());
+        }
+
         match self.lookup_range(a, sz).next() {
             None => Ok(()),
             Some(span) => Err(span.start()),
         }
     }
 
-    fn lookup_range(&self, a: Address, sz: usize) -> impl Iterator<Item = &Span> {
+    /// Every redzone `[a, a + sz)` overlaps, in ascending order
+    ///
+    /// Unlike [`check`](Self::check), which stops at the first conflict,
+    /// this yields all previously tracked ranges an access re-fetches.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use asan_double_fetch::{MemoryTracker, Span};
+    /// #
+    /// let mut rz: MemoryTracker = MemoryTracker::default();
+    ///
+    /// rz.track_access(0x4141, 8).unwrap();
+    /// rz.remove_access(0x4143, 4).unwrap();
+    ///
+    /// let conflicts: Vec<_> = rz.check_all(0x4140, 0x10).cloned().collect();
+    /// assert_eq!(conflicts, [Span::new(0x4141, 0x4143), Span::new(0x4147, 0x4149)]);
+    /// assert_eq!(rz.check_all(0x4143, 4).count(), 0);
+    /// ```
+    pub fn check_all(&self, a: A, sz: A) -> impl Iterator<Item = &Span<A>> + '_ {
+        let access = Span::with_len(a, sz);
+
+        // the span starting before the access may still reach into it
+        let before = self
+            .0
+            .range(..Span::new(a, A::ZERO))
+            .next_back()
+            .filter(move |span| !access.is_empty() && span.end() > a);
+
+        before.into_iter().chain(
+            self.0
+                .range(Span::new(a, A::ZERO)..Span::new(a.saturating_add(sz), A::ZERO)),
+        )
+    }
+
+    /// The last span overlapping or adjacent to `span`
+    fn first_touching(&self, span: &Span<A>) -> Option<Span<A>> {
+        // spans only compare by start, so this includes one starting at
+        // `span`'s end
+        self.0
+            .range(..=Span::new(span.end(), A::ZERO))
+            .next_back()
+            .filter(|touching| touching.end() >= span.start())
+            .cloned()
+    }
+
+    fn first_in_range(&self, a: A, sz: A) -> Option<Span<A>> {
+        self.lookup_range(a, sz).next().cloned()
+    }
+
+    /// Spans overlapping `[a, a + sz)`, from the last one down. Borrows the
+    /// tree rather than collecting, so checks never allocate.
+    fn lookup_range(&self, a: A, sz: A) -> impl Iterator<Item = &Span<A>> + '_ {
         self.0
             .range((
-                Included(Span::new(0, 0)),
-                Excluded(Span::new(a.saturating_add(sz), 0)),
+                Included(Span::new(A::ZERO, A::ZERO)),
+                Excluded(Span::new(a.saturating_add(sz), A::ZERO)),
             ))
             .rev()
             .take_while(move |span| a < span.end())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn merge_adjacent() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        tracker.track_access(0x4141, 4).unwrap();
+        tracker.track_access(0x4145, 4).unwrap();
+        // adjacent to the start of an existing span
+        tracker.track_access(0x413d, 4).unwrap();
+
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.redzones().next(), Some((0x413d, 0xc)));
+        assert_eq!(tracker.check(0x4148, 1), Err(0x413d));
+        assert!(tracker.check(0x4149, 1).is_ok());
+    }
+
+    #[test]
+    fn split_on_remove() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        tracker.track_access(0x4141, 8).unwrap();
+        tracker.remove_access(0x4143, 4).unwrap();
+
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.check(0x4141, 8), Err(0x4147));
+    }
+
+    #[test]
+    fn identical_spans() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        tracker.track_access(0x4141, 4).unwrap();
+        tracker.track_access(0x4141, 4).unwrap();
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x4141, 4)]);
+
+        tracker.remove_access(0x4141, 4).unwrap();
+        assert!(tracker.is_empty());
+
+        // covering a tracked span and sharing its end
+        tracker.track_access(0x4145, 4).unwrap();
+        tracker.track_access(0x4141, 8).unwrap();
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x4141, 8)]);
+        tracker.track_access(0x4149, 4).unwrap();
+        tracker.remove_access(0x4145, 8).unwrap();
+        assert_eq!(tracker.redzones().collect::<Vec<_>>(), [(0x4141, 4)]);
+    }
+
+    #[test]
+    fn zero_length() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        tracker.track_access(0x4141, 0).unwrap();
+        assert!(tracker.is_empty());
+
+        tracker.track_access(0x4141, 8).unwrap();
+        assert!(tracker.check(0x4143, 0).is_ok());
+        assert_eq!(tracker.check_all(0x4143, 0).count(), 0);
+        tracker.remove_access(0x4143, 0).unwrap();
+        assert_eq!(tracker.len(), 1);
+
+        // clipped to nothing at the end of the address space
+        tracker.track_access(usize::MAX, 8).unwrap();
+        assert!(tracker.check(usize::MAX, 8).is_ok());
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[test]
+    fn batched() {
+        let mut batched: MemoryTracker = MemoryTracker::default();
+        let mut single: MemoryTracker = MemoryTracker::default();
+        let accesses = [
+            (0x30, 8),
+            (0x10, 4),
+            (0x14, 4),
+            (0x0, 0),
+            (0x12, 0x10),
+            (0x80, 1),
+        ];
+
+        single.track_access(0x38, 8).unwrap();
+        batched.track_access(0x38, 8).unwrap();
+        for &(a, sz) in &accesses {
+            single.track_access(a, sz).unwrap();
+        }
+        batched.track_accesses(accesses).unwrap();
+
+        assert_eq!(batched, single);
+        assert_eq!(
+            batched.redzones().collect::<Vec<_>>(),
+            [(0x10, 0x12), (0x30, 0x10), (0x80, 1)]
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        let region = Span::new(0x1000, 0x1100);
+        assert_eq!(
+            tracker.gaps(region.clone()).collect::<Vec<_>>(),
+            [Span::new(0x1000, 0x1100)]
+        );
+
+        tracker.track_access(0xff0, 0x20).unwrap();
+        tracker.track_access(0x1080, 0x80).unwrap();
+        assert_eq!(
+            tracker.gaps(region.clone()).collect::<Vec<_>>(),
+            [Span::new(0x1010, 0x1080)]
+        );
+        assert_eq!(tracker.occupied_len(), 0xa0);
+
+        tracker.track_access(0x1010, 0x70).unwrap();
+        assert_eq!(tracker.gaps(region).count(), 0);
+    }
+
+    #[test]
+    fn check_all() {
+        let mut tracker: MemoryTracker = MemoryTracker::default();
+
+        tracker.track_access(0x1000, 0x10).unwrap();
+        tracker.track_access(0x1020, 0x10).unwrap();
+        tracker.track_access(0x1040, 0x10).unwrap();
+
+        assert_eq!(
+            tracker.check_all(0x1008, 0x20).cloned().collect::<Vec<_>>(),
+            [Span::new(0x1000, 0x1010), Span::new(0x1020, 0x1030)]
+        );
+        // touching the end of a span isn't a conflict
+        assert_eq!(tracker.check_all(0x1010, 0x10).count(), 0);
+        assert_eq!(tracker.check_all(0x1030, 0x11).count(), 1);
+        assert_eq!(tracker.check_all(0x1008, 0).count(), 0);
+    }
+
+    #[test]
+    fn generic_address() {
+        let mut tracker: MemoryTracker<u64> = MemoryTracker::default();
+
+        tracker.track_access(0x1_0000_0000, 0x10).unwrap();
+
+        assert!(tracker.check(0x1_0000_0008, 4).is_err());
+        assert!(tracker.check(0x0_0000_0008, 4).is_ok());
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn custom_allocator() {
+        use core::alloc::{AllocError, Layout};
+        use core::ptr::NonNull;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Clone, Copy)]
+        struct Counting<'a>(&'a AtomicUsize);
+
+        unsafe impl Allocator for Counting<'_> {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+                Global.deallocate(ptr, layout)
+            }
+        }
+
+        let live = AtomicUsize::new(0);
+        let mut tracker = MemoryTracker::<usize, _>::new_in(Counting(&live));
+
+        tracker.track_access(0x4141, 8).unwrap();
+        assert!(tracker.check(0x4144, 1).is_err());
+        assert!(live.load(Ordering::Relaxed) > 0);
+
+        drop(tracker);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Track(u8, u8),
+        TrackMany(Vec<(u8, u8)>),
+        Remove(u8, u8),
+        Clear,
+    }
+
+    /// Tracker operations over `u8` addresses, so accesses often meet and
+    /// some are clipped at the end of the address space
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (any::<u8>(), 0u8..0x40).prop_map(|(a, sz)| Op::Track(a, sz)),
+            1 => proptest::collection::vec((any::<u8>(), 0u8..0x20), 0..8).prop_map(Op::TrackMany),
+            2 => (any::<u8>(), 0u8..0x40).prop_map(|(a, sz)| Op::Remove(a, sz)),
+            1 => Just(Op::Clear),
+        ]
+    }
+
+    /// A tracker keeping the set of tracked bytes
+    #[derive(Default)]
+    struct Reference(BTreeSet<u8>);
+
+    impl Reference {
+        fn set(&mut self, a: u8, sz: u8, tracked: bool) {
+            for byte in a..a.saturating_add(sz) {
+                if tracked {
+                    self.0.insert(byte);
+                } else {
+                    self.0.remove(&byte);
+                }
+            }
+        }
+
+        /// Runs of tracked bytes, as `(start, len)`
+        fn redzones(&self) -> Vec<(u8, u8)> {
+            let mut redzones: Vec<(u8, u8)> = Vec::new();
+            for &byte in &self.0 {
+                match redzones.last_mut() {
+                    Some((start, len)) if *start + *len == byte => *len += 1,
+                    _ => redzones.push((byte, 1)),
+                }
+            }
+            redzones
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_reference(
+            ops in proptest::collection::vec(op(), 0..32),
+            probe in (any::<u8>(), 0u8..0x40),
+        ) {
+            let mut tracker: MemoryTracker<u8> = MemoryTracker::default();
+            let mut reference = Reference::default();
+
+            for op in ops {
+                match op {
+                    Op::Track(a, sz) => {
+                        tracker.track_access(a, sz).unwrap();
+                        reference.set(a, sz, true);
+                    }
+                    Op::TrackMany(accesses) => {
+                        tracker.track_accesses(accesses.iter().copied()).unwrap();
+                        for (a, sz) in accesses {
+                            reference.set(a, sz, true);
+                        }
+                    }
+                    Op::Remove(a, sz) => {
+                        tracker.remove_access(a, sz).unwrap();
+                        reference.set(a, sz, false);
+                    }
+                    Op::Clear => {
+                        tracker.clear();
+                        reference.0.clear();
+                    }
+                }
+
+                let redzones = reference.redzones();
+                prop_assert_eq!(tracker.redzones().collect::<Vec<_>>(), redzones.clone());
+                prop_assert_eq!(tracker.len(), redzones.len());
+                prop_assert_eq!(usize::from(tracker.occupied_len()), reference.0.len());
+
+                // a re-fetch conflicts with every run it shares bytes with
+                let (a, sz) = probe;
+                let access = Span::with_len(a, sz);
+                let conflicts: Vec<_> = redzones
+                    .iter()
+                    .map(|&(start, len)| Span::with_len(start, len))
+                    .filter(|span| span.intersect(&access).is_some())
+                    .collect();
+                prop_assert_eq!(tracker.check_all(a, sz).cloned().collect::<Vec<_>>(), conflicts.clone());
+                prop_assert_eq!(tracker.check(a, sz), conflicts.last().map_or(Ok(()), |last| Err(last.start())));
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Logging and replaying mutated bytes, for minimizing crashes
+//!
+//! With the `mutation_log` option set to a path, every byte a mutation
+//! changes is appended to that file as soon as it is written, so the log
+//! survives the crash the mutation causes. Each line is the detection's
+//! number in the run, the byte's offset into its region, and the old and new
+//! value, all in hex:
+//!
+//! ```text
+//! # detection offset old new
+//! 3 0x8 07 ff
+//! ```
+//!
+//! With the `mutation_plan` option set to a file in the same format, the
+//! runtime mutates nothing but the bytes it lists, on the detections it
+//! lists, instead of consulting the RNG. Given the same inputs, a target
+//! sees the same detections in the same order, so `df-minimize` replays
+//! subsets of a crashing run's log to find the bytes the crash needs.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use once_cell::sync::OnceCell;
+
+use crate::span::Span;
+use crate::{config, Address};
+
+/// Bytes to set on each detection, by detection number
+type Plan = BTreeMap<usize, Vec<(usize, u8)>>;
+
+/// Detections seen, numbering them
+static DETECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+static PLAN: OnceCell<Option<Plan>> = OnceCell::new();
+
+static LOG: OnceCell<Option<Mutex<File>>> = OnceCell::new();
+
+/// One mutated byte
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// Number of the detection in its run, from 0
+    pub detection: usize,
+    /// Offset of the byte into its region
+    pub offset: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [detection, offset, old, new] => Some(Self {
+                detection: detection.parse().ok()?,
+                offset: usize::from_str_radix(offset.trim_start_matches("0x"), 16).ok()?,
+                old: u8::from_str_radix(old, 16).ok()?,
+                new: u8::from_str_radix(new, 16).ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The entry's line in a log or plan
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:#x} {:02x} {:02x}",
+            self.detection, self.offset, self.old, self.new
+        )
+    }
+}
+
+/// Parses a log or plan, skipping blank and `#` lines
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            Entry::parse(line).ok_or_else(|| format!("line {}: malformed entry {:?}", n + 1, line))
+        })
+        .collect()
+}
+
+fn plan() -> Option<&'static Plan> {
+    PLAN.get_or_init(|| {
+        let path = config::get().mutation_plan.as_ref()?;
+        match std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| parse(&text))
+        {
+            Ok(entries) => {
+                log::info!("replaying mutation plan {}", path);
+                let mut plan = Plan::new();
+                for entry in entries {
+                    plan.entry(entry.detection)
+                        .or_default()
+                        .push((entry.offset, entry.new));
+                }
+                Some(plan)
+            }
+            Err(e) => {
+                log::error!("failed to read mutation plan {}: {}", path, e);
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+fn log_file() -> Option<&'static Mutex<File>> {
+    LOG.get_or_init(|| {
+        let path = config::get().mutation_log.as_ref()?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(b"# detection offset old new\n")?;
+                Ok(file)
+            });
+        match file {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                log::error!("failed to create mutation log {}: {}", path, e);
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+/// How a detection's bytes are to be mutated
+pub(crate) struct Planned {
+    detection: usize,
+    /// Bytes to set if replaying a plan, as offsets into the region
+    bytes: Option<&'static [(usize, u8)]>,
+}
+
+/// Numbers a detection and looks up its bytes in the plan, if there is one
+pub(crate) fn begin() -> Planned {
+    let detection = DETECTIONS.fetch_add(1, Ordering::Relaxed);
+    Planned {
+        detection,
+        bytes: plan().map(|plan| plan.get(&detection).map_or(&[][..], Vec::as_slice)),
+    }
+}
+
+impl Planned {
+    /// Whether to mutate at all: if the plan lists bytes for this detection
+    /// when replaying one, otherwise what `random` says
+    pub fn decide(&self, random: impl FnOnce() -> bool) -> bool {
+        match self.bytes {
+            Some(bytes) => !bytes.is_empty(),
+            None => random(),
+        }
+    }
+
+    /// Mutates `data`, fetched from `addr` in `region`, with the planned
+    /// bytes or else with `random`, and logs the bytes that changed
+    pub fn mutate(
+        &self,
+        region: &Span,
+        addr: Address,
+        data: &mut [u8],
+        random: impl FnOnce(&mut [u8]),
+    ) {
+        let log = log_file();
+        let before = log.map(|_| data.to_vec());
+
+        match self.bytes {
+            Some(bytes) => {
+                for &(offset, new) in bytes {
+                    let i = (region.start() + offset).wrapping_sub(addr);
+                    if let Some(byte) = data.get_mut(i) {
+                        *byte = new;
+                    }
+                }
+            }
+            None => random(data),
+        }
+
+        if let (Some(log), Some(before)) = (log, before) {
+            let lines = self.log_lines(addr.wrapping_sub(region.start()), &before, data);
+            let written = log
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_all(lines.as_bytes());
+            if let Err(e) = written {
+                log::error!("failed to log mutation: {}", e);
+            }
+        }
+    }
+
+    /// Log entries for the bytes that differ between `before` and `after`,
+    /// which start at `offset` into the region
+    fn log_lines(&self, offset: usize, before: &[u8], after: &[u8]) -> String {
+        let mut lines = String::new();
+        for (i, (&old, &new)) in before.iter().zip(after).enumerate() {
+            if old != new {
+                let entry = Entry {
+                    detection: self.detection,
+                    offset: offset + i,
+                    old,
+                    new,
+                };
+                let _ = writeln!(lines, "{}", entry);
+            }
+        }
+        lines
+    }
+}
+
+/// Reads the entries of the mutation log or plan at `path`
+pub fn read(path: &str) -> io::Result<Vec<Entry>> {
+    parse(&std::fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes `entries` as a plan to `path`
+pub fn write(path: &str, entries: &[Entry]) -> io::Result<()> {
+    let mut text = String::from("# detection offset old new\n");
+    for entry in entries {
+        let _ = writeln!(text, "{}", entry);
+    }
+    std::fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plans() {
+        let entries = parse("# detection offset old new\n\n3 0x8 07 ff\n10 0 aa bb\n").unwrap();
+        assert_eq!(
+            entries,
+            [
+                Entry {
+                    detection: 3,
+                    offset: 8,
+                    old: 7,
+                    new: 0xff
+                },
+                Entry {
+                    detection: 10,
+                    offset: 0,
+                    old: 0xaa,
+                    new: 0xbb
+                }
+            ]
+        );
+        assert_eq!(entries[1].to_string(), "10 0x0 aa bb");
+
+        assert_eq!(
+            parse("3 0x8 07\n").unwrap_err(),
+            "line 1: malformed entry \"3 0x8 07\""
+        );
+        assert!(parse("3 0x8 07 zz\n").is_err());
+    }
+
+    #[test]
+    fn applies_and_logs() {
+        static BYTES: [(usize, u8); 2] = [(0x9, 0xff), (0x40, 0xff)];
+        let region = Span::with_len(0x1000, 0x100);
+        let planned = Planned {
+            detection: 3,
+            bytes: Some(&BYTES),
+        };
+        assert!(planned.decide(|| unreachable!()));
+
+        let mut data = [7; 4];
+        planned.mutate(&region, 0x1008, &mut data, |_| unreachable!());
+        // only bytes of the fetch are touched
+        assert_eq!(data, [7, 0xff, 7, 7]);
+
+        assert_eq!(planned.log_lines(0x8, &[7; 4], &data), "3 0x9 07 ff\n");
+
+        let unplanned = Planned {
+            detection: 4,
+            bytes: Some(&[]),
+        };
+        assert!(!unplanned.decide(|| true));
+    }
+}
@@ -0,0 +1,428 @@
+//! Memory protection key (Intel MPK) write detection
+//!
+//! A double fetch is only exploitable if the data can change between the two
+//! fetches. With this module enabled, watched regions are tagged with a
+//! protection key whose write permission is disabled in PKRU. Any write to the
+//! region raises a `SIGSEGV` with `SEGV_PKUERR`; the handler records the
+//! written bytes, lifts the write-disable bit in the interrupted thread's
+//! saved PKRU, and single-steps the store. The following `SIGTRAP` puts the
+//! write-disable bit back.
+//!
+//! The check path uses [`clear_written`] on a first fetch and
+//! [`was_written`] on a re-fetch to tell "data actually changed" apart from
+//! "data merely re-read".
+//!
+//! PKRU is per-thread: threads inherit it from their creator, so regions
+//! should be watched before worker threads are spawned, or each existing
+//! thread should call `__asan_mpk_enter_thread()`. Existing threads that
+//! don't are denied all access to the key, so their first access to a
+//! watched region faults and is let through as a read, with writes disabled
+//! from then on. Writes from other processes sharing the mapping are not
+//! visible to MPK.
+//!
+//! Faults and traps that aren't MPK's are passed on to the handlers installed
+//! before, so MPK works alongside the target's own `SIGSEGV` handling and the
+//! `mprotect_trap` mode. A region can't be both tagged and trapped, though:
+//! tagging maps it read/write again, so watching it with the other mode
+//! while it is watched with one fails.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::sync::{Mutex, PoisonError};
+
+use once_cell::sync::OnceCell;
+
+use crate::memory_tracking::MemoryTracker;
+use crate::sigchain::Chain;
+use crate::signal_safe;
+use crate::span::{Span, SpanRelation};
+use crate::swap::SwapList;
+use crate::Address;
+
+const PKEY_DISABLE_WRITE: u32 = 0x2;
+const SEGV_PKUERR: c_int = 4;
+
+/// XSAVE state component number of PKRU
+const XFEATURE_PKRU: u32 = 9;
+/// Offset of XSTATE_BV in the XSAVE header
+const XSTATE_BV_OFFSET: usize = 512;
+
+/// EFLAGS trap flag, raises `SIGTRAP` after the next instruction
+const TRAP_FLAG: libc::greg_t = 0x100;
+
+/// Writes are recorded with this granularity around the faulting address
+/// since the fault only reports where the store starts
+const WRITE_GRANULE: usize = 16;
+
+struct Mpk {
+    pkey: c_int,
+    pkru_offset: usize,
+    /// Lock-free to look up from the handlers
+    regions: SwapList<Vec<Span>>,
+    written: Mutex<MemoryTracker>,
+}
+
+static MPK: OnceCell<Option<Mpk>> = OnceCell::new();
+
+static SEGV_CHAIN: Chain = Chain::new();
+static TRAP_CHAIN: Chain = Chain::new();
+
+thread_local! {
+    /// Whether the current thread is single-stepping a store we let through
+    static STEPPING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// PKRU access-disable bit for `pkey`
+pub const fn access_disable_bit(pkey: c_int) -> u32 {
+    1 << (2 * pkey as u32)
+}
+
+/// PKRU write-disable bit for `pkey`
+pub const fn write_disable_bit(pkey: c_int) -> u32 {
+    1 << (2 * pkey as u32 + 1)
+}
+
+fn rdpkru() -> u32 {
+    let pkru: u32;
+    unsafe {
+        asm!("rdpkru", in("ecx") 0, out("eax") pkru, out("edx") _, options(nomem, nostack));
+    }
+    pkru
+}
+
+fn wrpkru(pkru: u32) {
+    unsafe {
+        asm!("wrpkru", in("eax") pkru, in("ecx") 0, in("edx") 0, options(nostack));
+    }
+}
+
+/// Runs `f` with writes to watched regions allowed on the current thread.
+///
+/// Used by the runtime itself, e.g. when mutating fetched bytes, so its own
+/// writes aren't attributed to the target.
+pub fn with_writes_allowed<T>(f: impl FnOnce() -> T) -> T {
+    let mpk = match MPK.get() {
+        Some(Some(mpk)) => mpk,
+        _ => return f(),
+    };
+
+    let pkru = rdpkru();
+    wrpkru(pkru & !write_disable_bit(mpk.pkey));
+    let res = f();
+    wrpkru(pkru);
+    res
+}
+
+/// Forgets any recorded writes to `[addr, addr + len)`
+pub fn clear_written(addr: Address, len: usize) {
+    if let Some(Some(mpk)) = MPK.get() {
+        crate::log_tracker_error(
+            mpk.written
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove_access(addr, len),
+        );
+    }
+}
+
+/// Returns `Some(true)` if `[addr, addr + len)` was written since the last
+/// [`clear_written`], or `None` if MPK isn't tracking writes
+pub fn was_written(addr: Address, len: usize) -> Option<bool> {
+    match MPK.get() {
+        Some(Some(mpk)) => Some(
+            mpk.written
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .check(addr, len)
+                .is_err(),
+        ),
+        _ => None,
+    }
+}
+
+/// Whether any of `span` is in a region tagged for write detection
+#[cfg(feature = "mprotect_trap")]
+pub(crate) fn tags(span: &Span) -> bool {
+    match MPK.get() {
+        Some(Some(mpk)) => mpk.is_watched(span),
+        _ => false,
+    }
+}
+
+impl Mpk {
+    fn is_watched(&self, target: &Span) -> bool {
+        self.regions
+            .read()
+            .iter()
+            .any(|region| target.relation(region) != SpanRelation::None)
+    }
+
+    /// Returns the saved PKRU in a signal frame's XSAVE area
+    unsafe fn saved_pkru(&self, ctx: &mut libc::ucontext_t) -> *mut u32 {
+        let xsave = ctx.uc_mcontext.fpregs as *mut u8;
+        // make sure the kernel restores PKRU from the frame rather than
+        // resetting it to its init value
+        *(xsave.add(XSTATE_BV_OFFSET) as *mut u64) |= 1 << XFEATURE_PKRU;
+        xsave.add(self.pkru_offset) as *mut u32
+    }
+}
+
+extern "C" fn segv_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    crate::ffi::guard("segv_handler", (), || {
+        let addr = unsafe { (*info).si_addr() } as Address;
+        let mpk = match MPK.get() {
+            Some(Some(mpk))
+                if unsafe { (*info).si_code } == SEGV_PKUERR
+                    && mpk.is_watched(&Span::with_len(addr, 1)) =>
+            {
+                mpk
+            }
+            _ => return SEGV_CHAIN.pass_on(sig, info, ctx),
+        };
+
+        let ctx = unsafe { &mut *(ctx as *mut libc::ucontext_t) };
+        let pkru = unsafe { mpk.saved_pkru(ctx) };
+        if unsafe { *pkru } & access_disable_bit(mpk.pkey) != 0 {
+            // a thread that predates the key, and may only be reading: let
+            // it read as `__asan_mpk_enter_thread` would, a write faults again
+            unsafe { *pkru = *pkru & !access_disable_bit(mpk.pkey) | write_disable_bit(mpk.pkey) };
+            return;
+        }
+
+        let granule = addr & !(WRITE_GRANULE - 1);
+        // a failed update leaves the tracker usable and can't be logged
+        // from the signal handler, so it is dropped, as is a write while
+        // another thread holds the tracker
+        if let Some(mut written) = signal_safe::try_lock(mpk.written.try_lock()) {
+            let _ = written.track_access(granule, WRITE_GRANULE);
+        }
+
+        unsafe { *pkru &= !write_disable_bit(mpk.pkey) };
+        STEPPING.with(|stepping| stepping.set(true));
+        ctx.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+    })
+}
+
+extern "C" fn trap_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    crate::ffi::guard("trap_handler", (), || {
+        let mpk = match MPK.get() {
+            Some(Some(mpk)) if STEPPING.with(|stepping| stepping.replace(false)) => mpk,
+            _ => return TRAP_CHAIN.pass_on(sig, info, ctx),
+        };
+
+        let ctx = unsafe { &mut *(ctx as *mut libc::ucontext_t) };
+        unsafe { *mpk.saved_pkru(ctx) |= write_disable_bit(mpk.pkey) };
+        ctx.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+    })
+}
+
+fn mpk() -> Option<&'static Mpk> {
+    MPK.get_or_init(|| {
+        let pkey = unsafe { libc::syscall(libc::SYS_pkey_alloc, 0, PKEY_DISABLE_WRITE) } as c_int;
+        if pkey == -1 {
+            log::warn!(
+                "protection keys unavailable: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+
+        let pkru_offset = __cpuid_count(0xd, XFEATURE_PKRU).ebx as usize;
+        // the crash handler has to come before ours in the chain, so that it
+        // never sees the faults we handle
+        crate::crash::init();
+
+        SEGV_CHAIN.install(libc::SIGSEGV, segv_handler);
+        TRAP_CHAIN.install(libc::SIGTRAP, trap_handler);
+
+        Some(Mpk {
+            pkey,
+            pkru_offset,
+            regions: SwapList::new(Vec::new()),
+            written: Default::default(),
+        })
+    })
+    .as_ref()
+}
+
+/// Tags a watched region with the write-detection protection key.
+///
+/// `addr` and `len` must cover whole pages. Returns 0 on success and -1 if
+/// protection keys are unsupported, the region is trapped by
+/// `mprotect_trap`, or `pkey_mprotect` fails.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("mpk_watch_region"))]
+pub extern "C" fn __asan_mpk_watch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_mpk_watch_region", -1, || {
+        let mpk = match mpk() {
+            Some(mpk) => mpk,
+            None => return -1,
+        };
+
+        #[cfg(feature = "mprotect_trap")]
+        if crate::mprotect_trap::traps(&Span::with_len(addr, len)) {
+            log::error!(
+                "{:#X} len={:#X} is trapped, not tagging it for write detection",
+                addr,
+                len
+            );
+            return -1;
+        }
+
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pkey_mprotect,
+                addr,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                mpk.pkey,
+            )
+        };
+        if res == -1 {
+            log::error!(
+                "pkey_mprotect of {:#X} len={:#X} failed: {}",
+                addr,
+                len,
+                std::io::Error::last_os_error()
+            );
+            return -1;
+        }
+
+        mpk.regions.write().push(Span::with_len(addr, len));
+        0
+    })
+}
+
+/// Returns a region to the default protection key
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("mpk_unwatch_region"))]
+pub extern "C" fn __asan_mpk_unwatch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_mpk_unwatch_region", -1, || {
+        let mpk = match mpk() {
+            Some(mpk) => mpk,
+            None => return -1,
+        };
+
+        let span = Span::with_len(addr, len);
+        mpk.regions.write().retain(|region| *region != span);
+        crate::log_tracker_error(
+            mpk.written
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove_access(addr, len),
+        );
+
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pkey_mprotect,
+                addr,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                0,
+            )
+        };
+        if res == -1 {
+            -1
+        } else {
+            0
+        }
+    })
+}
+
+/// Disables writes to watched regions on the calling thread. Only needed for
+/// threads that already existed when the first region was watched.
+///
+/// Such threads start out with the kernel's default PKRU, which denies all
+/// access to a freshly allocated key, so reads are re-enabled here as well.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("mpk_enter_thread"))]
+pub extern "C" fn __asan_mpk_enter_thread() {
+    crate::ffi::guard("__asan_mpk_enter_thread", (), || {
+        if let Some(mpk) = mpk() {
+            wrpkru(rdpkru() & !access_disable_bit(mpk.pkey) | write_disable_bit(mpk.pkey));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn pkru_bits() {
+        assert_eq!(write_disable_bit(0), 0b10);
+        assert_eq!(write_disable_bit(1), 0b1000);
+        assert_eq!(write_disable_bit(15), 1 << 31);
+        assert_eq!(access_disable_bit(1), 0b100);
+    }
+
+    #[test]
+    fn detects_writes() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        } as *mut u32;
+
+        if __asan_mpk_watch_region(page as Address, page_size) != 0 {
+            // no MPK on this machine
+            return;
+        }
+        // the key may have been allocated by another test's thread
+        __asan_mpk_enter_thread();
+
+        let field = unsafe { page.add(8) };
+        assert_eq!(was_written(field as Address, 4), Some(false));
+
+        unsafe { field.write_volatile(0x4141_4141) };
+        assert_eq!(unsafe { field.read_volatile() }, 0x4141_4141);
+        assert_eq!(was_written(field as Address, 4), Some(true));
+
+        clear_written(field as Address, 4);
+        with_writes_allowed(|| unsafe { field.write_volatile(0x4242_4242) });
+        assert_eq!(was_written(field as Address, 4), Some(false));
+
+        assert_eq!(__asan_mpk_unwatch_region(page as Address, page_size), 0);
+        unsafe { libc::munmap(page.cast(), page_size) };
+    }
+    #[test]
+    fn lets_threads_predating_the_key_read() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        } as *mut u32;
+
+        if __asan_mpk_watch_region(page as Address, page_size) != 0 {
+            // no MPK on this machine
+            return;
+        }
+        // the kernel's default for a key allocated after the thread started
+        let pkey = mpk().unwrap().pkey;
+        wrpkru(rdpkru() | access_disable_bit(pkey) | write_disable_bit(pkey));
+
+        assert_eq!(unsafe { page.read_volatile() }, 0);
+        assert_eq!(was_written(page as Address, 4), Some(false));
+        unsafe { page.write_volatile(0x4141_4141) };
+        assert_eq!(was_written(page as Address, 4), Some(true));
+
+        assert_eq!(__asan_mpk_unwatch_region(page as Address, page_size), 0);
+        unsafe { libc::munmap(page.cast(), page_size) };
+    }
+}
@@ -0,0 +1,396 @@
+//! mprotect-based trap mode
+//!
+//! Watched regions are mapped `PROT_NONE` so every fetch from them raises a
+//! `SIGSEGV`. The handler records the faulting address, reports a double
+//! fetch with the exact faulting instruction if that address was already
+//! fetched in the current window, then unprotects the page and single-steps
+//! the faulting instruction with the trap flag. The following `SIGTRAP`
+//! re-protects the page so the next fetch traps again.
+//!
+//! This gives instruction-precise fetch sites without any PC plumbing from
+//! the compiler pass, at the cost of two signals per access. The handlers
+//! stay async-signal-safe: regions are looked up without locking, each
+//! region's fetches are tracked in a bitmap sized when it is watched, a fault
+//! while another thread holds that bitmap goes unrecorded, and reports are
+//! formatted by hand into a stack buffer.
+//!
+//! Faults that aren't on a trapped page are passed on to whatever handled
+//! `SIGSEGV` before, the target's own handler or the default action, and so
+//! are `SIGTRAP`s the trap mode didn't cause. Trapped pages are put back to
+//! the protection they had before they were watched. A region tagged by the
+//! `mpk` mode can't be trapped as well.
+
+use std::cell::Cell;
+use std::fs;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use once_cell::sync::OnceCell;
+
+use crate::bitmap::BitmapTracker;
+use crate::sigchain::Chain;
+use crate::signal_safe::{self, StackWriter};
+use crate::span::{Span, SpanRelation};
+use crate::swap::SwapList;
+use crate::Address;
+
+/// EFLAGS trap flag, raises `SIGTRAP` after the next instruction
+const TRAP_FLAG: libc::greg_t = 0x100;
+
+/// Page-fault error code bit set for write accesses
+const PF_WRITE: libc::greg_t = 0x2;
+
+/// A region watched in trap mode
+struct TrapRegion {
+    span: Span,
+    /// First page of the region
+    first_page: Address,
+    /// Protection of each of the region's pages before it was watched
+    prots: Vec<c_int>,
+    /// Bytes fetched in the current window, sized when the region is
+    /// watched so that tracking a fault never allocates
+    fetched: Mutex<BitmapTracker>,
+}
+
+struct Trap {
+    page_size: usize,
+    /// Lock-free to look up from the handlers
+    regions: SwapList<Vec<Arc<TrapRegion>>>,
+}
+
+static TRAP: OnceCell<Trap> = OnceCell::new();
+
+static SEGV_CHAIN: Chain = Chain::new();
+static TRAP_CHAIN: Chain = Chain::new();
+
+thread_local! {
+    /// Page unprotected for the instruction currently being single-stepped
+    static STEPPING_PAGE: Cell<Address> = const { Cell::new(0) };
+}
+
+fn set_protection(page: Address, len: usize, prot: c_int) {
+    unsafe { libc::mprotect(page as *mut libc::c_void, len, prot) };
+}
+
+/// The protection of each page of `[start, end)` as mapped now, or `None`
+/// if any of them isn't mapped
+fn protections(start: Address, end: Address, page_size: usize) -> Option<Vec<c_int>> {
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    let mappings: Vec<(Span, c_int)> = maps
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (lo, hi) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?.as_bytes();
+            let span = Span::new(
+                Address::from_str_radix(lo, 16).ok()?,
+                Address::from_str_radix(hi, 16).ok()?,
+            );
+            let prot = [
+                (b'r', libc::PROT_READ),
+                (b'w', libc::PROT_WRITE),
+                (b'x', libc::PROT_EXEC),
+            ]
+            .iter()
+            .zip(perms)
+            .filter(|((flag, _), perm)| flag == *perm)
+            .fold(libc::PROT_NONE, |prot, ((_, bit), _)| prot | bit);
+            Some((span, prot))
+        })
+        .collect();
+
+    (start..end)
+        .step_by(page_size)
+        .map(|page| {
+            mappings
+                .iter()
+                .find(|(span, _)| span.start() <= page && page < span.end())
+                .map(|(_, prot)| *prot)
+        })
+        .collect()
+}
+
+impl Trap {
+    fn page_of(&self, addr: Address) -> Address {
+        addr & !(self.page_size - 1)
+    }
+
+    /// The pages of `span`, as `(first, end)`
+    fn pages(&self, span: &Span) -> (Address, Address) {
+        let end = span.end().saturating_add(self.page_size - 1);
+        (self.page_of(span.start()), self.page_of(end))
+    }
+
+    /// The region sharing bytes with `target`
+    fn region_of(&self, target: &Span) -> Option<Arc<TrapRegion>> {
+        self.regions
+            .read()
+            .iter()
+            .find(|region| target.relation(&region.span) != SpanRelation::None)
+            .cloned()
+    }
+}
+
+/// Whether any of `span` is in a region watched in trap mode
+#[cfg(feature = "mpk")]
+pub(crate) fn traps(span: &Span) -> bool {
+    TRAP.get()
+        .is_some_and(|trap| trap.region_of(span).is_some())
+}
+
+impl TrapRegion {
+    /// The protection `page` of the region had before it was watched
+    fn prot(&self, page: Address, page_size: usize) -> c_int {
+        self.prots[(page - self.first_page) / page_size]
+    }
+
+    /// Records a fault at `addr` by the instruction at `pc`. A fault while
+    /// another thread holds the region's tracker goes unrecorded.
+    fn on_fault(&self, addr: Address, pc: Address, is_write: bool) {
+        let mut fetched = match signal_safe::try_lock(self.fetched.try_lock()) {
+            Some(fetched) => fetched,
+            None => return,
+        };
+
+        if is_write {
+            fetched.remove_access(addr, 1);
+        } else if fetched.check(addr, 1).is_err() {
+            let mut w = StackWriter::new();
+            w.push(b"(runtime) double-fetch detected! (trap) addr ");
+            w.push_hex(addr);
+            w.push(b" re-fetched at pc ");
+            w.push_hex(pc);
+            w.push(b"\n");
+            w.flush();
+        } else {
+            fetched.track_access(addr, 1);
+        }
+    }
+}
+
+extern "C" fn segv_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    crate::ffi::guard("segv_handler", (), || {
+        let addr = unsafe { (*info).si_addr() } as Address;
+        let trap = TRAP.get();
+        let region = trap.and_then(|trap| Some((trap, trap.region_of(&Span::with_len(addr, 1))?)));
+        let (trap, region) = match region {
+            // a fault on the page being stepped is the instruction's own,
+            // e.g. a write to a page that was read-only to begin with
+            Some((trap, region)) if STEPPING_PAGE.with(Cell::get) != trap.page_of(addr) => {
+                (trap, region)
+            }
+            _ => return SEGV_CHAIN.pass_on(sig, info, ctx),
+        };
+
+        let ctx = unsafe { &mut *(ctx as *mut libc::ucontext_t) };
+        let pc = ctx.uc_mcontext.gregs[libc::REG_RIP as usize] as Address;
+        let is_write = ctx.uc_mcontext.gregs[libc::REG_ERR as usize] & PF_WRITE != 0;
+
+        region.on_fault(addr, pc, is_write);
+
+        let page = trap.page_of(addr);
+        set_protection(page, trap.page_size, region.prot(page, trap.page_size));
+        STEPPING_PAGE.with(|stepping| stepping.set(page));
+        ctx.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+    })
+}
+
+extern "C" fn trap_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    crate::ffi::guard("trap_handler", (), || {
+        let page = STEPPING_PAGE.with(|stepping| stepping.replace(0));
+        let trap = match TRAP.get() {
+            Some(trap) if page != 0 => trap,
+            _ => return TRAP_CHAIN.pass_on(sig, info, ctx),
+        };
+
+        // unwatched since, if the region is gone
+        if trap
+            .region_of(&Span::with_len(page, trap.page_size))
+            .is_some()
+        {
+            set_protection(page, trap.page_size, libc::PROT_NONE);
+        }
+
+        let ctx = unsafe { &mut *(ctx as *mut libc::ucontext_t) };
+        ctx.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+    })
+}
+
+fn trap() -> &'static Trap {
+    TRAP.get_or_init(|| {
+        // the crash handler has to come before ours in the chain, so that it
+        // never sees the faults we handle
+        crate::crash::init();
+        SEGV_CHAIN.install(libc::SIGSEGV, segv_handler);
+        TRAP_CHAIN.install(libc::SIGTRAP, trap_handler);
+
+        Trap {
+            page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize,
+            regions: SwapList::new(Vec::new()),
+        }
+    })
+}
+
+/// Watches a region in trap mode, protecting all of its pages.
+///
+/// The region must be page aligned or share its pages only with memory that
+/// is itself safe to trap on. Returns 0 on success, -1 if its pages aren't
+/// all mapped, it is tagged by the `mpk` mode, or `mprotect` fails.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("trap_watch_region"))]
+pub extern "C" fn __asan_trap_watch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_trap_watch_region", -1, || {
+        let trap = trap();
+        let span = Span::with_len(addr, len);
+        let (start, end) = trap.pages(&span);
+
+        #[cfg(feature = "mpk")]
+        if crate::mpk::tags(&span) {
+            log::error!(
+                "{:#X} len={:#X} is tagged for MPK, not trapping it",
+                addr,
+                len
+            );
+            return -1;
+        }
+
+        let prots = match protections(start, end, trap.page_size) {
+            Some(prots) => prots,
+            None => {
+                log::error!("{:#X}..{:#X} isn't all mapped, not trapping", start, end);
+                return -1;
+            }
+        };
+
+        if unsafe { libc::mprotect(start as *mut libc::c_void, end - start, libc::PROT_NONE) } == -1
+        {
+            log::error!(
+                "failed to protect {:#X}..{:#X}: {}",
+                start,
+                end,
+                std::io::Error::last_os_error()
+            );
+            return -1;
+        }
+
+        trap.regions.write().push(Arc::new(TrapRegion {
+            first_page: start,
+            prots,
+            fetched: Mutex::new(BitmapTracker::new(span.clone())),
+            span,
+        }));
+        0
+    })
+}
+
+/// Stops watching the trap-mode region containing `addr` and restores the
+/// protection its pages had before
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("trap_unwatch_region"))]
+pub extern "C" fn __asan_trap_unwatch_region(addr: Address) -> c_int {
+    crate::ffi::guard("__asan_trap_unwatch_region", -1, || {
+        let trap = trap();
+        let target = Span::with_len(addr, 1);
+
+        let mut regions = trap.regions.write();
+        let idx = match regions
+            .iter()
+            .position(|region| target.relation(&region.span) != SpanRelation::None)
+        {
+            Some(idx) => idx,
+            None => return -1,
+        };
+        let region = regions.remove(idx);
+        drop(regions);
+
+        let (start, end) = trap.pages(&region.span);
+        for page in (start..end).step_by(trap.page_size) {
+            set_protection(page, trap.page_size, region.prot(page, trap.page_size));
+        }
+
+        0
+    })
+}
+
+/// Forgets all trapped fetches, starting a new detection window
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("trap_reset_window"))]
+pub extern "C" fn __asan_trap_reset_window() {
+    crate::ffi::guard("__asan_trap_reset_window", (), || {
+        for region in trap().regions.read().iter() {
+            region
+                .fetched
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    fn map_page(prot: c_int) -> (*mut u32, usize) {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        (page.cast(), page_size)
+    }
+
+    fn fetched_twice(addr: Address) -> bool {
+        let region = trap().region_of(&Span::with_len(addr, 1)).unwrap();
+        let fetched = region.fetched.lock().unwrap();
+        fetched.check(addr, 1).is_err()
+    }
+
+    #[test]
+    fn traps_and_resumes() {
+        let (page, page_size) = map_page(libc::PROT_READ | libc::PROT_WRITE);
+        let field = unsafe { page.add(1) };
+
+        unsafe { field.write_volatile(0x4141_4141) };
+        assert_eq!(__asan_trap_watch_region(page as Address, page_size), 0);
+
+        assert_eq!(unsafe { field.read_volatile() }, 0x4141_4141);
+        assert_eq!(unsafe { field.read_volatile() }, 0x4141_4141);
+        assert!(fetched_twice(field as Address));
+
+        assert_eq!(__asan_trap_unwatch_region(page as Address), 0);
+        unsafe { libc::munmap(page.cast(), page_size) };
+    }
+
+    #[test]
+    fn restores_the_original_protection() {
+        let (page, page_size) = map_page(libc::PROT_READ);
+        let start = page as Address;
+
+        assert_eq!(__asan_trap_watch_region(start, page_size), 0);
+        assert_eq!(
+            protections(start, start + page_size, page_size),
+            Some(vec![libc::PROT_NONE])
+        );
+        assert_eq!(unsafe { page.read_volatile() }, 0);
+        // stepped with the page read-only, as it was
+        assert_eq!(unsafe { page.read_volatile() }, 0);
+        assert!(fetched_twice(start));
+
+        assert_eq!(__asan_trap_unwatch_region(start), 0);
+        assert_eq!(
+            protections(start, start + page_size, page_size),
+            Some(vec![libc::PROT_READ])
+        );
+        unsafe { libc::munmap(page.cast(), page_size) };
+    }
+}
@@ -0,0 +1,182 @@
+//! Mutating double-fetched bytes
+//!
+//! The built-in mutator is [`mutate`]. An embedder can replace it with a
+//! [`MutationHook`] of its own through
+//! [`__asan_double_fetch_set_mutation_hook`], e.g. to mutate the way a
+//! structure-aware fuzzer would.
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use rand::Rng;
+
+use crate::config::Endianness;
+use crate::Address;
+
+/// Mutates the `len` double-fetched bytes at `data`, fetched from `addr`, in
+/// place of the built-in mutator
+pub type MutationHook = extern "C" fn(addr: Address, data: *mut u8, len: usize);
+
+/// The embedder's [`MutationHook`], null if none is set
+static MUTATION_HOOK: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Mutates double-fetched bytes with `hook` instead of the built-in mutator,
+/// or with the built-in one again if `hook` is null. Planned mutations, see
+/// the `mutation_plan` option, are still replayed as planned.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("set_mutation_hook"))]
+pub extern "C" fn __asan_double_fetch_set_mutation_hook(
+    // spelled out, as the header generator can't see through the alias here
+    hook: Option<extern "C" fn(addr: Address, data: *mut u8, len: usize)>,
+) {
+    let hook = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut c_void);
+    MUTATION_HOOK.store(hook, Ordering::Release);
+}
+
+/// Mutates `data`, fetched from `addr`, with the embedder's [`MutationHook`]
+/// if one is set and [`mutate`] otherwise
+pub fn mutate_fetched<R: Rng + ?Sized>(
+    addr: Address,
+    data: &mut [u8],
+    endianness: Endianness,
+    rng: &mut R,
+) {
+    let hook = MUTATION_HOOK.load(Ordering::Acquire);
+    // only ever set from a `MutationHook`
+    let hook = (!hook.is_null())
+        .then(|| unsafe { core::mem::transmute::<*mut c_void, MutationHook>(hook) });
+    mutate_with(hook, addr, data, endianness, rng)
+}
+
+fn mutate_with<R: Rng + ?Sized>(
+    hook: Option<MutationHook>,
+    addr: Address,
+    data: &mut [u8],
+    endianness: Endianness,
+    rng: &mut R,
+) {
+    match hook {
+        Some(hook) => hook(addr, data.as_mut_ptr(), data.len()),
+        None => mutate(data, endianness, rng),
+    }
+}
+
+/// Mutates double-fetched bytes in place.
+///
+/// Fetches of 2, 4, or 8 bytes are most likely integers (lengths, counts,
+/// offsets), so they're decoded using the target's byte order and replaced
+/// with a boundary value or an off-by-one of the original. Other sizes get
+/// random bytes.
+pub fn mutate<R: Rng + ?Sized>(data: &mut [u8], endianness: Endianness, rng: &mut R) {
+    match data.len() {
+        2 | 4 | 8 => {
+            let value = read_int(data, endianness);
+            let mutated = mutate_int(value, data.len(), rng);
+            write_int(data, mutated, endianness);
+        }
+        _ => data.iter_mut().for_each(|b| *b = rng.gen()),
+    }
+}
+
+/// Picks an "interesting" replacement for an integer of `width` bytes
+fn mutate_int<R: Rng + ?Sized>(value: u64, width: usize, rng: &mut R) -> u64 {
+    let mask = if width == 8 {
+        u64::MAX
+    } else {
+        (1u64 << (width * 8)) - 1
+    };
+    let signed_max = mask >> 1;
+
+    let mutated = match rng.gen_range(0..7) {
+        0 => 0,
+        1 => 1,
+        2 => mask,
+        3 => signed_max,
+        4 => signed_max + 1,
+        5 => value.wrapping_add(1),
+        _ => value.wrapping_sub(1),
+    };
+
+    mutated & mask
+}
+
+pub(crate) fn read_int(data: &[u8], endianness: Endianness) -> u64 {
+    let fold = |acc: u64, b: &u8| (acc << 8) | u64::from(*b);
+    match endianness {
+        Endianness::Big => data.iter().fold(0, fold),
+        Endianness::Little => data.iter().rev().fold(0, fold),
+    }
+}
+
+fn write_int(data: &mut [u8], mut value: u64, endianness: Endianness) {
+    let mut store = |b: &mut u8| {
+        *b = value as u8;
+        value >>= 8;
+    };
+    match endianness {
+        Endianness::Big => data.iter_mut().rev().for_each(&mut store),
+        Endianness::Little => data.iter_mut().for_each(&mut store),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "no_std"))]
+    fn rng() -> impl Rng {
+        rand::thread_rng()
+    }
+
+    #[cfg(feature = "linux_kasan")]
+    fn rng() -> impl Rng {
+        crate::kmod::KernelRng
+    }
+
+    #[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+    fn rng() -> impl Rng {
+        crate::bare::BareRng
+    }
+
+    #[test]
+    fn int_round_trip() {
+        let mut data = [0u8; 4];
+
+        write_int(&mut data, 0x1122_3344, Endianness::Big);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(read_int(&data, Endianness::Big), 0x1122_3344);
+
+        write_int(&mut data, 0x1122_3344, Endianness::Little);
+        assert_eq!(data, [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(read_int(&data, Endianness::Little), 0x1122_3344);
+    }
+
+    #[test]
+    fn hook_replaces_mutator() {
+        extern "C" fn invert(addr: Address, data: *mut u8, len: usize) {
+            assert_eq!(addr, 0x1000);
+            let data = unsafe { core::slice::from_raw_parts_mut(data, len) };
+            data.iter_mut().for_each(|b| *b = !*b);
+        }
+
+        let mut data = [0x0f; 3];
+        mutate_with(
+            Some(invert),
+            0x1000,
+            &mut data,
+            Endianness::Little,
+            &mut rng(),
+        );
+        assert_eq!(data, [0xf0; 3]);
+    }
+
+    #[test]
+    fn int_mutation_stays_in_width() {
+        let mut rng = rng();
+
+        for _ in 0..64 {
+            assert!(mutate_int(0, 2, &mut rng) <= u64::from(u16::MAX));
+            assert!(mutate_int(0, 4, &mut rng) <= u64::from(u32::MAX));
+        }
+    }
+}
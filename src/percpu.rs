@@ -0,0 +1,109 @@
+//! Per-CPU access trackers for kernel builds
+//!
+//! A single tracker per region means every instrumented access on every CPU
+//! writes the same lock and tree, bouncing their cachelines between CPUs under
+//! load. [`PerCpuTracker`] instead gives each CPU its own tracker, so the hot
+//! path only writes CPU-local state. Every [`MERGE_INTERVAL_MS`], whichever CPU
+//! notices first folds all CPUs' history into every tracker, so a task that
+//! migrates between its two fetches is still caught once a merge has run.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel::bindings;
+
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::Tracker;
+
+/// How often per-CPU access histories are merged
+pub const MERGE_INTERVAL_MS: u64 = 100;
+
+pub struct PerCpuTracker {
+    /// Indexed by CPU id
+    cpus: Vec<SpinLock<Tracker>>,
+    /// Jiffies at the last merge. Only written when merging, so reading it on
+    /// the hot path keeps the cacheline shared rather than bouncing it.
+    last_merge: AtomicU64,
+}
+
+impl Default for PerCpuTracker {
+    fn default() -> Self {
+        let nr_cpus = unsafe { bindings::nr_cpu_ids } as usize;
+
+        Self {
+            cpus: (0..nr_cpus).map(|_| Default::default()).collect(),
+            last_merge: AtomicU64::new(unsafe { bindings::jiffies }),
+        }
+    }
+}
+
+impl PerCpuTracker {
+    /// Locks the current CPU's tracker, merging all CPUs' histories first if
+    /// the merge interval has elapsed
+    ///
+    /// Being migrated between reading the CPU id and taking the lock only
+    /// means locking another CPU's tracker, which is still correct.
+    pub fn lock(&self) -> SpinLockGuard<'_, Tracker> {
+        let now = unsafe { bindings::jiffies };
+        let last = self.last_merge.load(Ordering::Relaxed);
+        let interval = unsafe { bindings::msecs_to_jiffies(MERGE_INTERVAL_MS as _) } as u64;
+
+        if now.wrapping_sub(last) >= interval
+            && self
+                .last_merge
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.merge();
+        }
+
+        let cpu = unsafe { bindings::raw_smp_processor_id() } as usize;
+        self.cpus[cpu % self.cpus.len()].lock()
+    }
+
+    /// Folds every CPU's tracked spans into every CPU's tracker. Locks are
+    /// taken one at a time, so accesses racing with a merge are picked up by
+    /// the next one.
+    fn merge(&self) {
+        let mut merged = Tracker::default();
+        for cpu in &self.cpus {
+            for (start, len) in cpu.lock().redzones() {
+                crate::log_tracker_error(merged.track_access(start, len));
+            }
+        }
+
+        for cpu in &self.cpus {
+            let mut tracker = cpu.lock();
+            for (start, len) in merged.redzones() {
+                crate::log_tracker_error(tracker.track_access(start, len));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::sim;
+
+    use super::*;
+
+    #[test]
+    fn cpus_merge_after_interval() {
+        let _serial = sim::serialize();
+        let tracker = PerCpuTracker::default();
+
+        sim::set_cpu(0);
+        crate::log_tracker_error(tracker.lock().track_access(0x1000, 8));
+        assert_eq!(tracker.lock().check(0x1004, 4), Err(0x1000));
+        // a migrated task isn't caught before the next merge
+        sim::set_cpu(1);
+        assert!(tracker.lock().check(0x1004, 4).is_ok());
+
+        sim::advance_jiffies(unsafe { bindings::msecs_to_jiffies(MERGE_INTERVAL_MS as _) });
+        assert_eq!(tracker.lock().check(0x1004, 4), Err(0x1000));
+        // CPU ids past the trackers wrap around
+        sim::set_cpu(6);
+        assert_eq!(tracker.lock().check(0x1004, 4), Err(0x1000));
+        sim::set_cpu(0);
+    }
+}
@@ -0,0 +1,217 @@
+//! Output backend for runtime messages
+//!
+//! All runtime output goes through the `log` crate: watching and unwatching
+//! at info, detections at warn and runtime failures at error. Checks that
+//! don't detect anything log nothing. Unless the host already installed a
+//! logger of its own, [`install`] sets up one that hands the runtime's
+//! records to the [`Printer`] for the build: stdout in userspace and
+//! `printk` at `KERN_INFO` in kernel builds, where there is no `println!` at
+//! all. Bare-metal builds have no console of their own and leave records,
+//! and their printer's lines, to the logger the firmware installs.
+//!
+//! How much that logger shows is set by the `verbosity` option: 0 is
+//! detections and failures only, 1, the default, adds info such as regions
+//! being watched and unwatched, and 2 adds a trace line for every check of
+//! a watched region. Builds with the `min_logging` feature leave
+//! anything below warn out altogether, formatting included, so heat maps
+//! and other info output are never printed.
+//!
+//! In userspace, a C embedder can take the output over instead with
+//! [`__asan_double_fetch_set_print_hook`], the way sanitizer runtimes let
+//! their report printing be overridden.
+
+use core::fmt;
+#[cfg(not(feature = "no_std"))]
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(feature = "no_std"))]
+use std::ffi::{c_char, c_void};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Something that can emit one line of runtime output
+pub trait Printer: Sync {
+    /// Prints `args` followed by a newline
+    fn print(&self, args: fmt::Arguments);
+
+    /// Writes out anything buffered
+    fn flush(&self) {}
+}
+
+/// Writes to stdout, like `println!`
+#[cfg(not(feature = "no_std"))]
+pub struct StdoutPrinter;
+
+#[cfg(not(feature = "no_std"))]
+impl Printer for StdoutPrinter {
+    fn print(&self, args: fmt::Arguments) {
+        std::println!("{}", args);
+    }
+
+    fn flush(&self) {
+        use std::io::Write;
+
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Writes to the kernel log with `pr_info`
+#[cfg(feature = "linux_kasan")]
+pub struct PrintkPrinter;
+
+#[cfg(feature = "linux_kasan")]
+impl Printer for PrintkPrinter {
+    fn print(&self, args: fmt::Arguments) {
+        kernel::pr_info!("{}\n", args);
+    }
+}
+
+/// Hands each line to the firmware's `log` logger at info
+#[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+pub struct LogPrinter;
+
+#[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+impl Printer for LogPrinter {
+    fn print(&self, args: fmt::Arguments) {
+        log::info!("{}", args);
+    }
+
+    fn flush(&self) {
+        log::logger().flush();
+    }
+}
+
+/// Receives each line of runtime output, `len` bytes at `line` followed by
+/// a NUL, in place of stdout
+#[cfg(not(feature = "no_std"))]
+pub type PrintHook = extern "C" fn(line: *const c_char, len: usize);
+
+/// The embedder's [`PrintHook`], null if none is set
+#[cfg(not(feature = "no_std"))]
+static PRINT_HOOK: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+#[cfg(not(feature = "no_std"))]
+fn print_hook() -> Option<PrintHook> {
+    let hook = PRINT_HOOK.load(Ordering::Acquire);
+    // only ever set from a `PrintHook`
+    (!hook.is_null()).then(|| unsafe { core::mem::transmute::<*mut c_void, PrintHook>(hook) })
+}
+
+/// Hands each line to the embedder's [`PrintHook`]
+#[cfg(not(feature = "no_std"))]
+struct HookPrinter;
+
+#[cfg(not(feature = "no_std"))]
+impl Printer for HookPrinter {
+    fn print(&self, args: fmt::Arguments) {
+        match print_hook() {
+            Some(hook) => {
+                let mut line = std::fmt::format(args);
+                let len = line.len();
+                line.push('\0');
+                hook(line.as_ptr().cast(), len);
+            }
+            // unset since
+            None => StdoutPrinter.print(args),
+        }
+    }
+}
+
+/// Sends the runtime's output to `hook` instead of stdout, or back to stdout
+/// if `hook` is null
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("set_print_hook"))]
+#[cfg(not(feature = "no_std"))]
+pub extern "C" fn __asan_double_fetch_set_print_hook(
+    // spelled out, as the header generator can't see through the alias here
+    hook: Option<extern "C" fn(line: *const c_char, len: usize)>,
+) {
+    let hook = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut c_void);
+    PRINT_HOOK.store(hook, Ordering::Release);
+}
+
+/// The printer for this build
+pub fn printer() -> &'static dyn Printer {
+    #[cfg(not(feature = "no_std"))]
+    return match print_hook() {
+        Some(_) => &HookPrinter,
+        None => &StdoutPrinter,
+    };
+    #[cfg(feature = "linux_kasan")]
+    return &PrintkPrinter;
+    #[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+    return &LogPrinter;
+}
+
+/// Target of records that are complete reports, printed as they are rather
+/// than as a `(runtime)` line
+pub(crate) const REPORT_TARGET: &str = "asan_double_fetch::report";
+
+/// Logs the runtime's own records to the build's [`Printer`]
+struct RuntimeLogger;
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if record.target() == REPORT_TARGET {
+            printer().print(*record.args());
+        } else {
+            printer().print(format_args!("(runtime) {}", record.args()));
+        }
+    }
+
+    fn flush(&self) {
+        printer().flush();
+    }
+}
+
+/// Set once the runtime's logger is the one installed
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the runtime's logger at the default level, unless a logger is
+/// already installed. Safe to call more than once. Bare-metal builds leave
+/// logging to the firmware's logger, which their printer writes to.
+pub(crate) fn install() {
+    if cfg!(all(feature = "no_std", not(feature = "linux_kasan"))) {
+        return;
+    }
+    if log::set_logger(&RuntimeLogger).is_ok() {
+        INSTALLED.store(true, Ordering::Relaxed);
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Applies the `verbosity` option to the runtime's logger, if it is the
+/// one installed. A host's logger keeps its own filtering.
+pub(crate) fn set_verbosity(verbosity: u8) {
+    if INSTALLED.load(Ordering::Relaxed) {
+        log::set_max_level(match verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            _ => LevelFilter::Trace,
+        });
+    }
+}
+
+#[cfg(all(test, feature = "linux_kasan"))]
+mod tests {
+    use kernel::sim;
+
+    #[test]
+    fn logs_to_printk() {
+        crate::config::get();
+        sim::take_log();
+
+        log::info!("watching");
+        log::debug!("checking");
+        log::warn!(target: super::REPORT_TARGET, "a report");
+        assert_eq!(sim::take_log(), ["<6>(runtime) watching", "<6>a report"]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Prometheus export of the runtime counters
+//!
+//! Long-running instrumented services are monitored through node_exporter's
+//! textfile collector rather than an HTTP endpoint of their own: with the
+//! `metrics_file` option set, a background thread rewrites that file with
+//! the [`Stats`] every `metrics_interval` seconds, and once more on
+//! shutdown. Harnesses can also write a file on demand with
+//! [`__asan_double_fetch_write_metrics`].
+//!
+//! Files are written next to their destination and renamed over it, so the
+//! collector never sees a partial one. Every sample is labelled with the
+//! process id, so several processes can export into the same directory.
+
+use core::ffi::{c_char, c_int};
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config;
+use crate::stats::{self, Stats};
+
+/// Renders `stats` in the Prometheus text exposition format
+fn render(stats: &Stats) -> String {
+    let pid = std::process::id();
+    let metrics = [
+        ("checks_total", "counter", "Checks made", stats.checks),
+        (
+            "region_hits_total",
+            "counter",
+            "Checks that hit a watched region",
+            stats.region_hits,
+        ),
+        (
+            "detections_total",
+            "counter",
+            "Double-fetches detected",
+            stats.detections,
+        ),
+        (
+            "mutations_total",
+            "counter",
+            "Detections whose bytes were mutated",
+            stats.mutations,
+        ),
+        (
+            "watched_regions",
+            "gauge",
+            "Regions currently watched",
+            stats.watched_regions,
+        ),
+        (
+            "tracked_bytes",
+            "gauge",
+            "Bytes currently tracked as fetched",
+            stats.tracked_bytes,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP asan_double_fetch_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE asan_double_fetch_{} {}", name, kind);
+        let _ = writeln!(
+            out,
+            "asan_double_fetch_{}{{pid=\"{}\"}} {}",
+            name, pid, value
+        );
+    }
+    out
+}
+
+/// Atomically replaces `path` with the current metrics
+fn write(path: &Path) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+
+    std::fs::write(&tmp, render(&stats::get()))?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Writes the `metrics_file`, if one is configured
+pub(crate) fn write_configured() {
+    if let Some(path) = &config::get().metrics_file {
+        if let Err(e) = write(Path::new(path)) {
+            log::error!("failed to write metrics to {}: {}", path, e);
+        }
+    }
+}
+
+/// Starts the exporter thread if a `metrics_file` is configured. Called
+/// once, from runtime init.
+pub(crate) fn init() {
+    let config = config::get();
+    if config.metrics_file.is_none() {
+        return;
+    }
+
+    let interval = Duration::from_secs(config.metrics_interval);
+    let spawned = std::thread::Builder::new()
+        .name("asan-df-metrics".into())
+        .spawn(move || loop {
+            write_configured();
+            std::thread::sleep(interval);
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start metrics exporter: {}", e);
+    }
+}
+
+/// Writes the current metrics to the file at `path`. Returns 0, or -1 on
+/// failure.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("write_metrics"))]
+pub unsafe extern "C" fn __asan_double_fetch_write_metrics(path: *const c_char) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_write_metrics", -1, || {
+        if path.is_null() {
+            return -1;
+        }
+
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(_) => return -1,
+        };
+
+        match write(Path::new(path)) {
+            Ok(()) => 0,
+            Err(e) => {
+                log::error!("failed to write metrics to {}: {}", path, e);
+                -1
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_exposition_format() {
+        let stats = Stats {
+            checks: 3,
+            detections: 1,
+            ..Stats::default()
+        };
+        let rendered = render(&stats);
+        let pid = std::process::id();
+
+        assert!(rendered.contains("# TYPE asan_double_fetch_checks_total counter\n"));
+        assert!(rendered.contains(&format!(
+            "asan_double_fetch_checks_total{{pid=\"{}\"}} 3\n",
+            pid
+        )));
+        assert!(rendered.contains(&format!(
+            "asan_double_fetch_detections_total{{pid=\"{}\"}} 1\n",
+            pid
+        )));
+        assert!(rendered.contains("# TYPE asan_double_fetch_tracked_bytes gauge\n"));
+    }
+
+    #[test]
+    fn writes_file() {
+        let path =
+            std::env::temp_dir().join(format!("asan-df-metrics-{}.prom", std::process::id()));
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            unsafe { __asan_double_fetch_write_metrics(c_path.as_ptr()) },
+            0
+        );
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("asan_double_fetch_checks_total"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Python bindings for harness control
+//!
+//! With the `python` feature the runtime library is also a Python extension
+//! module, so harnesses written in Python, such as pytest suites or emulator
+//! controllers, can drive it in-process:
+//!
+//! ```python
+//! import asan_double_fetch as df
+//!
+//! df.on_detection(lambda d: print(f"double fetch at {d.addr:#x}"))
+//! with df.Window():
+//!     df.watch(addr, size)
+//!     run_target()
+//!     print(df.stats())
+//! ```
+//!
+//! Install `libasan_double_fetch.so` as `asan_double_fetch.so` (maturin does
+//! this) and import it before loading instrumented code, so that code's
+//! entry points resolve to the same library and both drive one runtime.
+//! Build with `PYO3_BUILD_EXTENSION_MODULE` set to leave `libpython` for
+//! the interpreter to provide.
+
+use std::sync::{PoisonError, RwLock};
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::span::Span;
+use crate::Address;
+
+/// A detection, as handed to the `on_detection` callback
+#[pyclass(frozen, get_all, module = "asan_double_fetch")]
+#[derive(Debug)]
+struct Detection {
+    addr: Address,
+    len: usize,
+    /// PC of the re-fetch, `None` if unknown
+    pc: Option<Address>,
+    region_start: Address,
+    region_len: usize,
+}
+
+#[pymethods]
+impl Detection {
+    fn __repr__(&self) -> String {
+        format!(
+            "Detection(addr={:#x}, len={:#x}, pc={}, region_start={:#x}, region_len={:#x})",
+            self.addr,
+            self.len,
+            self.pc
+                .map_or_else(|| "None".to_owned(), |pc| format!("{:#x}", pc)),
+            self.region_start,
+            self.region_len
+        )
+    }
+}
+
+static CALLBACK: RwLock<Option<Py<PyAny>>> = RwLock::new(None);
+
+/// Invokes the registered `on_detection` callback, if any. Exceptions it
+/// raises are printed and otherwise ignored, like in `__del__`.
+pub(crate) fn report(addr: Address, len: usize, pc: Option<Address>, region: &Span) {
+    let callback = || CALLBACK.read().unwrap_or_else(PoisonError::into_inner);
+    if callback().is_none() {
+        return;
+    }
+
+    Python::attach(|py| {
+        // not called under the lock, so it can replace itself
+        let callback = match callback().as_ref() {
+            Some(callback) => callback.clone_ref(py),
+            None => return,
+        };
+        let detection = Detection {
+            addr,
+            len,
+            pc,
+            region_start: region.start(),
+            region_len: region.len(),
+        };
+        if let Err(e) = callback.call1(py, (detection,)) {
+            e.write_unraisable(py, Some(callback.bind(py)));
+        }
+    })
+}
+
+/// Initializes the runtime if it isn't already
+#[pyfunction]
+fn init(py: Python) {
+    py.detach(|| crate::__asan_shared_memory_region_init())
+}
+
+/// Flushes pending reports, prints a summary, and forgets all watched regions
+/// and detection counts, ending a detection window
+#[pyfunction]
+fn shutdown(py: Python) {
+    py.detach(|| crate::__asan_shared_memory_region_shutdown())
+}
+
+/// Watches `[addr, addr + len)` in granules of `granularity` bytes, a power of
+/// two; 0 uses the `granularity` option. Raises `ValueError` if it isn't
+/// watched, e.g. for overlapping a watched region with `overlap=reject`.
+#[pyfunction]
+#[pyo3(signature = (addr, len, granularity = 0))]
+fn watch(py: Python, addr: Address, len: usize, granularity: usize) -> PyResult<()> {
+    if granularity != 0 && !granularity.is_power_of_two() {
+        return Err(PyValueError::new_err(format!(
+            "granularity {:#x} is not a power of two",
+            granularity
+        )));
+    }
+    match py.detach(|| crate::__asan_watch_shared_memory_region_granular(addr, len, granularity)) {
+        0 => Ok(()),
+        _ => Err(PyValueError::new_err(format!(
+            "{:#x} wasn't watched, see the log",
+            addr
+        ))),
+    }
+}
+
+/// Stops watching the region containing `addr`. Returns whether there was
+/// one.
+#[pyfunction]
+fn unwatch(py: Python, addr: Address) -> bool {
+    py.detach(|| crate::__asan_unwatch_shared_memory_region(addr)) == 0
+}
+
+/// Checks an access; detections are reported to the `on_detection` callback
+#[pyfunction]
+#[pyo3(signature = (addr, len, is_write = false))]
+fn check(py: Python, addr: Address, len: usize, is_write: bool) {
+    py.detach(|| {
+        crate::__asan_double_fetch_check(addr, len, is_write);
+    })
+}
+
+/// The runtime's statistics, as a dict
+#[pyfunction]
+fn stats(py: Python) -> PyResult<Bound<PyDict>> {
+    let stats = py.detach(crate::stats::get);
+    let dict = PyDict::new(py);
+    dict.set_item("checks", stats.checks)?;
+    dict.set_item("region_hits", stats.region_hits)?;
+    dict.set_item("detections", stats.detections)?;
+    dict.set_item("mutations", stats.mutations)?;
+    dict.set_item("watched_regions", stats.watched_regions)?;
+    dict.set_item("tracked_bytes", stats.tracked_bytes)?;
+    Ok(dict)
+}
+
+/// Zeroes the counters in `stats()`
+#[pyfunction]
+fn reset_stats(py: Python) {
+    py.detach(|| crate::stats::__asan_double_fetch_reset_stats())
+}
+
+/// Calls `callback` with a `Detection` for every double fetch, replacing any
+/// previous callback. Pass `None` to unregister. The callback runs on the
+/// detecting thread and must not call back into the runtime.
+#[pyfunction]
+#[pyo3(signature = (callback))]
+fn on_detection(callback: Option<Py<PyAny>>) {
+    *CALLBACK.write().unwrap_or_else(PoisonError::into_inner) = callback;
+}
+
+/// A detection window as a context manager: initializes the runtime on entry
+/// and shuts it down on exit, see `shutdown()`
+#[pyclass(module = "asan_double_fetch")]
+struct Window;
+
+#[pymethods]
+impl Window {
+    #[new]
+    fn new() -> Self {
+        Window
+    }
+
+    fn __enter__<'py>(slf: PyRef<'py, Self>, py: Python<'py>) -> PyRef<'py, Self> {
+        init(py);
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python, _args: &Bound<pyo3::types::PyTuple>) -> bool {
+        shutdown(py);
+        false
+    }
+}
+
+#[pymodule]
+fn asan_double_fetch(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add("ABI_VERSION", crate::ffi::ABI_VERSION)?;
+    m.add_class::<Detection>()?;
+    m.add_class::<Window>()?;
+    m.add_function(wrap_pyfunction!(init, m)?)?;
+    m.add_function(wrap_pyfunction!(shutdown, m)?)?;
+    m.add_function(wrap_pyfunction!(watch, m)?)?;
+    m.add_function(wrap_pyfunction!(unwatch, m)?)?;
+    m.add_function(wrap_pyfunction!(check, m)?)?;
+    m.add_function(wrap_pyfunction!(stats, m)?)?;
+    m.add_function(wrap_pyfunction!(reset_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(on_detection, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drives_the_runtime() {
+        let buf = Box::leak(Box::new([0u8; 64]));
+        let addr = buf.as_ptr() as Address;
+
+        Python::initialize();
+        Python::attach(|py| {
+            let df = pyo3::wrap_pymodule!(asan_double_fetch)(py);
+            pyo3::py_run!(
+                py,
+                df addr,
+                r#"
+                seen = []
+                df.on_detection(seen.append)
+                df.watch(addr, 64)
+                df.check(addr, 4)
+                df.check(addr + 2, 4)
+                df.unwatch(addr)
+                df.on_detection(None)
+
+                assert [(d.addr, d.len, d.pc, d.region_start, d.region_len) for d in seen] == [
+                    (addr + 2, 4, None, addr, 64)
+                ]
+                assert repr(seen[0]).startswith("Detection(addr=0x")
+                assert df.stats()["detections"] >= 1
+
+                try:
+                    df.watch(addr, 64, 3)
+                    assert False
+                except ValueError:
+                    pass
+                "#
+            );
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Integration with QEMU TCG plugins
+//!
+//! A TCG plugin sees every guest memory access along with its guest-physical
+//! address, which makes whole-VM double-fetch analysis of firmware and kernels
+//! possible. Guest-physical addresses aren't dereferenceable from the host and
+//! can exceed the host's pointer width, so this path keeps its own region list
+//! of `MemoryTracker<u64>`s and only detects and reports; it never mutates
+//! guest memory. See `examples/qemu/` for a plugin that forwards accesses here.
+
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use once_cell::sync::OnceCell;
+
+use crate::memory_tracking::MemoryTracker;
+use crate::span::{Span, SpanRelation};
+use crate::swap::SwapList;
+
+/// A guest-physical address
+pub type GuestPhysAddr = u64;
+
+type GuestTracker = Arc<Mutex<MemoryTracker<GuestPhysAddr>>>;
+
+/// Looked up on every guest memory access, sorted by start address
+type GuestRegions = SwapList<Vec<(Span<GuestPhysAddr>, GuestTracker)>>;
+
+static GUEST_REGIONS: OnceCell<GuestRegions> = OnceCell::new();
+
+fn guest_regions() -> &'static GuestRegions {
+    GUEST_REGIONS.get_or_init(|| SwapList::new(Vec::new()))
+}
+
+/// `gpa` relative to the start of `region`; accesses may start before it
+fn offset(region: &Span<GuestPhysAddr>, gpa: GuestPhysAddr) -> i64 {
+    gpa.wrapping_sub(region.start()) as i64
+}
+
+/// Watches `[gpa, gpa + len)` in guest-physical memory
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("qemu_watch_gpa"))]
+pub extern "C" fn __asan_qemu_watch_gpa(gpa: GuestPhysAddr, len: u64) {
+    crate::ffi::guard("__asan_qemu_watch_gpa", (), || {
+        log::info!(
+            "watching guest-physical region at {:#X}, len={:#X}",
+            gpa,
+            len
+        );
+
+        let region = Span::with_len(gpa, len);
+        let mut regions = guest_regions().write();
+        let idx = regions.partition_point(|(other, _)| other.start() <= region.start());
+        regions.insert(idx, (region, GuestTracker::default()));
+    })
+}
+
+/// Stops watching the guest-physical region containing `gpa`
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("qemu_unwatch_gpa"))]
+pub extern "C" fn __asan_qemu_unwatch_gpa(gpa: GuestPhysAddr) {
+    crate::ffi::guard("__asan_qemu_unwatch_gpa", (), || {
+        let target = Span::with_len(gpa, 1);
+        guest_regions()
+            .write()
+            .retain(|(region, _)| target.relation(region) == SpanRelation::None);
+    })
+}
+
+/// Forgets all fetches in every guest region, starting a new window
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("qemu_reset_window"))]
+pub extern "C" fn __asan_qemu_reset_window() {
+    crate::ffi::guard("__asan_qemu_reset_window", (), || {
+        for (_, tracker) in guest_regions().read().iter() {
+            tracker
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    })
+}
+
+/// Records a guest memory access made by `vcpu` at guest virtual `pc`.
+///
+/// Returns 1 if the access was a double fetch, 0 otherwise.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("qemu_mem_access"))]
+pub extern "C" fn __asan_qemu_mem_access(
+    vcpu: u32,
+    gpa: GuestPhysAddr,
+    len: u32,
+    is_write: c_int,
+    pc: u64,
+) -> c_int {
+    crate::ffi::guard("__asan_qemu_mem_access", 0, || {
+        let len = u64::from(len);
+        let target = Span::with_len(gpa, len);
+
+        let regions = guest_regions().read();
+        let (region, tracker) = match crate::find_region(&regions, &target) {
+            Some(idx) => &regions[idx],
+            None => return 0,
+        };
+        let mut tracker = tracker.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if is_write == 0 && tracker.check(gpa, len).is_err() {
+            log::warn!(
+                "double-fetch detected! vcpu {} re-fetched gpa {:#X} (region {:#X}, offset {}) len {:#X} at pc {:#X}",
+                vcpu,
+                gpa,
+                region.start(),
+                offset(region, gpa),
+                len,
+                pc
+            );
+            return 1;
+        }
+
+        crate::log_tracker_error(tracker.track_access(gpa, len));
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_guest_addresses() {
+        let base = 0x1_2000_0000;
+        __asan_qemu_watch_gpa(base, 0x1000);
+
+        assert_eq!(
+            __asan_qemu_mem_access(0, base + 0x10, 4, 0, 0xffff_8000_0000_1000),
+            0
+        );
+        assert_eq!(
+            __asan_qemu_mem_access(1, base + 0x12, 2, 0, 0xffff_8000_0000_1004),
+            1
+        );
+        assert_eq!(__asan_qemu_mem_access(0, base + 0x2000, 4, 0, 0), 0);
+
+        __asan_qemu_reset_window();
+        assert_eq!(__asan_qemu_mem_access(0, base + 0x10, 4, 0, 0), 0);
+
+        __asan_qemu_unwatch_gpa(base);
+        assert_eq!(__asan_qemu_mem_access(0, base + 0x10, 4, 0, 0), 0);
+    }
+
+    #[test]
+    fn accesses_straddling_the_region_start() {
+        let base = 0x2_4000_0000;
+        __asan_qemu_watch_gpa(base, 0x1000);
+
+        assert_eq!(__asan_qemu_mem_access(0, base - 4, 8, 0, 0), 0);
+        assert_eq!(__asan_qemu_mem_access(0, base - 2, 4, 0, 0), 1);
+        assert_eq!(offset(&Span::with_len(base, 0x1000), base - 2), -2);
+
+        __asan_qemu_unwatch_gpa(base);
+    }
+}
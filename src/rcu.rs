@@ -0,0 +1,186 @@
+//! RCU-protected, read-mostly list for kernel builds
+//!
+//! Every instrumented access looks up the region list, while watch/unwatch
+//! are rare. [`RcuVec`] lets readers run inside an RCU read-side critical
+//! section without taking any lock; writers serialize on a spinlock, publish
+//! a modified copy of the list, and free the old copy with `call_rcu` once
+//! all readers that could still see it are gone. `call_rcu` rather than
+//! `synchronize_rcu` keeps writers usable from atomic context.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use kernel::bindings;
+
+use crate::sync::{SpinLock, SpinLockGuard};
+
+/// A published version of the list
+#[repr(C)]
+struct Snapshot<T> {
+    /// Must stay first so the RCU callback can recover the snapshot
+    head: bindings::callback_head,
+    items: Vec<T>,
+}
+
+impl<T> Snapshot<T> {
+    fn publish(items: Vec<T>) -> *mut Self {
+        Box::into_raw(Box::new(Snapshot {
+            head: unsafe { core::mem::zeroed() },
+            items,
+        }))
+    }
+
+    unsafe extern "C" fn free(head: *mut bindings::callback_head) {
+        drop(Box::from_raw(head as *mut Self));
+    }
+}
+
+pub struct RcuVec<T> {
+    current: AtomicPtr<Snapshot<T>>,
+    writer: SpinLock<()>,
+}
+
+// SAFETY: readers only get shared references to the items, writers are
+// serialized by `writer`
+unsafe impl<T: Send + Sync> Send for RcuVec<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuVec<T> {}
+
+impl<T> Default for RcuVec<T> {
+    fn default() -> Self {
+        Self {
+            current: AtomicPtr::new(Snapshot::publish(Vec::new())),
+            writer: SpinLock::new(()),
+        }
+    }
+}
+
+impl<T: Clone> RcuVec<T> {
+    /// Enters an RCU read-side critical section on the current list. Must not
+    /// be held across anything that sleeps.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        unsafe { bindings::rcu_read_lock() };
+        let snapshot = self.current.load(Ordering::Acquire);
+
+        RcuReadGuard {
+            items: unsafe { &(*snapshot).items },
+        }
+    }
+
+    /// Returns a private copy of the list that replaces the published one
+    /// when the guard is dropped
+    pub fn write(&self) -> RcuWriteGuard<'_, T> {
+        let lock = self.writer.lock();
+        let items = unsafe { (*self.current.load(Ordering::Relaxed)).items.clone() };
+
+        RcuWriteGuard {
+            list: self,
+            items,
+            _lock: lock,
+        }
+    }
+}
+
+pub struct RcuReadGuard<'a, T> {
+    items: &'a [T],
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.items
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { bindings::rcu_read_unlock() };
+    }
+}
+
+pub struct RcuWriteGuard<'a, T> {
+    list: &'a RcuVec<T>,
+    items: Vec<T>,
+    _lock: SpinLockGuard<'a, ()>,
+}
+
+impl<T> Deref for RcuWriteGuard<'_, T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl<T> DerefMut for RcuWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.items
+    }
+}
+
+impl<T> Drop for RcuWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let items = core::mem::take(&mut self.items);
+        let old = self
+            .list
+            .current
+            .swap(Snapshot::publish(items), Ordering::AcqRel);
+
+        unsafe { bindings::call_rcu(&mut (*old).head, Some(Snapshot::<T>::free)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use kernel::sim;
+
+    use super::*;
+
+    #[test]
+    fn readers_keep_their_snapshot() {
+        let first = Arc::new(1);
+        let list = RcuVec::default();
+        list.write().push(Arc::clone(&first));
+
+        let reader = list.read();
+        list.write().clear();
+        assert_eq!(list.read().len(), 0);
+        // the replaced snapshot can't be freed under the reader
+        assert_eq!(reader.len(), 1);
+        assert_eq!(Arc::strong_count(&first), 2);
+        assert!(sim::pending_rcu_callbacks() > 0);
+
+        drop(reader);
+        // readers in other tests delay the grace period, but not for long
+        while Arc::strong_count(&first) > 1 {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn writers_serialize() {
+        let list = Arc::new(RcuVec::default());
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let list = Arc::clone(&list);
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        list.write().push(i * 100 + j);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut items = list.read().to_vec();
+        items.sort_unstable();
+        assert_eq!(items, (0..400).collect::<Vec<_>>());
+    }
+}
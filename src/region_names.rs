@@ -0,0 +1,225 @@
+//! Region names for reports
+//!
+//! Mapping addresses change from run to run, so reports give every address
+//! in a watched region both absolutely and as `<name>+<offset>`, plus the
+//! shm id the region was attached from when known. A region is named by the
+//! harness with [`__asan_double_fetch_name_region`], or else `region<N>`
+//! for the `N`th region watched since init or the last shutdown, which is
+//! stable for a deterministic harness.
+
+use core::ffi::{c_char, c_int};
+use std::ffi::CStr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::report::offset;
+use crate::span::Span;
+use crate::{find_region, Address, TRACKED_MEMORY_REGIONS};
+
+pub(crate) struct Entry {
+    region: Span,
+    name: String,
+    shm_id: Option<c_int>,
+}
+
+struct Names {
+    entries: Vec<Entry>,
+    /// Regions watched since init or the last shutdown
+    watched: usize,
+}
+
+static NAMES: Mutex<Names> = Mutex::new(Names {
+    entries: Vec::new(),
+    watched: 0,
+});
+
+fn names() -> std::sync::MutexGuard<'static, Names> {
+    NAMES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Gives a newly watched region its default name
+pub(crate) fn watched(region: &Span) {
+    let mut names = names();
+    let name = format!("region{}", names.watched);
+    names.watched += 1;
+    names.entries.retain(|entry| entry.region != *region);
+    names.entries.push(Entry {
+        region: region.clone(),
+        name,
+        shm_id: None,
+    });
+}
+
+/// Forgets the name of a region that stopped being watched
+pub(crate) fn unwatched(region: &Span) {
+    names().entries.retain(|entry| entry.region != *region);
+}
+
+/// Takes the name of a region about to be merged into another, for
+/// [`restore`]
+pub(crate) fn take(region: &Span) -> Option<Entry> {
+    let mut names = names();
+    let idx = names
+        .entries
+        .iter()
+        .position(|entry| entry.region == *region)?;
+    Some(names.entries.remove(idx))
+}
+
+/// Gives a region merged from watched ones the name [`take`]n from one of
+/// them
+pub(crate) fn restore(region: &Span, entry: Entry) {
+    let mut names = names();
+    names.entries.retain(|entry| entry.region != *region);
+    names.entries.push(Entry {
+        region: region.clone(),
+        ..entry
+    });
+}
+
+/// Forgets every name and starts counting regions anew, once all regions
+/// are unwatched
+pub(crate) fn clear() {
+    let mut names = names();
+    names.entries.clear();
+    names.watched = 0;
+}
+
+/// Notes that the region starting at `addr` was attached from shm `id`
+pub(crate) fn set_shm_id(addr: Address, id: c_int) {
+    if let Some(entry) = names()
+        .entries
+        .iter_mut()
+        .find(|entry| entry.region.start() == addr)
+    {
+        entry.shm_id = Some(id);
+    }
+}
+
+/// The name of `region`, `region` if it isn't watched
+pub(crate) fn name(region: &Span) -> String {
+    names()
+        .entries
+        .iter()
+        .find(|entry| entry.region == *region)
+        .map_or_else(|| "region".to_owned(), |entry| entry.name.clone())
+}
+
+/// `a` as `<name>+<offset>` into `region`
+pub(crate) fn relative(region: &Span, a: Address) -> String {
+    format!("{}{}", name(region), offset(region, a))
+}
+
+/// The shm id `region` was attached from, if known
+pub(crate) fn shm_id(region: &Span) -> Option<c_int> {
+    names()
+        .entries
+        .iter()
+        .find(|entry| entry.region == *region)
+        .and_then(|entry| entry.shm_id)
+}
+
+/// `<name>+<offset>` for an offset into a region, followed by the shm id
+/// it was attached from if known
+pub(crate) fn format_at(name: &str, offset: &str, shm_id: Option<c_int>) -> String {
+    match shm_id {
+        Some(id) => format!("{}{}, shm id {:#x}", name, offset, id),
+        None => format!("{}{}", name, offset),
+    }
+}
+
+/// `a` as `<name>+<offset>` into `region`, followed by the shm id the
+/// region was attached from if known
+pub(crate) fn at(region: &Span, a: Address) -> String {
+    format_at(&name(region), &offset(region, a), shm_id(region))
+}
+
+/// [`at`] for the watched region containing `a`, if any
+pub(crate) fn locate(a: Address) -> Option<String> {
+    let (region, _) = crate::get_memory_tracker(a, 1)?;
+    Some(at(&region, a))
+}
+
+/// Names the watched region containing `addr` `name` in reports, in place of
+/// `region<N>`. Returns 0, or -1 if `name` is invalid or empty or no watched
+/// region contains `addr`.
+///
+/// # Safety
+///
+/// `name` must be a valid NUL-terminated string.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("name_region"))]
+pub unsafe extern "C" fn __asan_double_fetch_name_region(
+    addr: Address,
+    name: *const c_char,
+) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_name_region", -1, || {
+        if name.is_null() {
+            return -1;
+        }
+        let name = match CStr::from_ptr(name).to_str() {
+            Ok(name) if !name.is_empty() => name,
+            _ => return -1,
+        };
+        let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+            Some(mem_regions) => mem_regions.read(),
+            None => return -1,
+        };
+        let region = match find_region(&mem_regions, &Span::with_len(addr, 1)) {
+            Some(idx) => &mem_regions[idx].0,
+            None => {
+                log::warn!("{:#X} isn't watched, not naming it {:?}", addr, name);
+                return -1;
+            }
+        };
+
+        match names()
+            .entries
+            .iter_mut()
+            .find(|entry| entry.region == *region)
+        {
+            Some(entry) => {
+                log::info!("naming region {} {:?}", region, name);
+                entry.name = name.to_owned();
+                0
+            }
+            None => -1,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_regions() {
+        let buf = Box::leak(Box::new([0u8; 32]));
+        let addr = buf.as_ptr() as Address;
+        let region = Span::with_len(addr, 32);
+        crate::__asan_watch_shared_memory_region(addr, 32);
+
+        assert!(name(&region).starts_with("region"));
+        assert_ne!(name(&region), "region");
+        unsafe {
+            assert_eq!(
+                __asan_double_fetch_name_region(addr + 4, "ring\0".as_ptr().cast()),
+                0
+            );
+            assert_eq!(
+                __asan_double_fetch_name_region(addr, "\0".as_ptr().cast()),
+                -1
+            );
+            assert_eq!(
+                __asan_double_fetch_name_region(0x10, "ring\0".as_ptr().cast()),
+                -1
+            );
+        }
+        assert_eq!(relative(&region, addr + 0x10), "ring+0x10");
+        set_shm_id(addr, 0x8001);
+        assert_eq!(locate(addr + 8).unwrap(), "ring+0x8, shm id 0x8001");
+
+        crate::__asan_unwatch_shared_memory_region(addr);
+        assert_eq!(relative(&region, addr - 1), "region-0x1");
+        assert_eq!(locate(addr), None);
+    }
+}
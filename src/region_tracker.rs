@@ -0,0 +1,440 @@
+//! Per-region choice of tracker backend and granularity
+//!
+//! Regions up to the `bitmap_max_len` option long are shadowed by a
+//! [`BitmapTracker`], larger ones by a [`MemoryTracker`]. The bitmap makes
+//! every access O(1) for dense access patterns, while the tree keeps large
+//! or sparsely fetched regions from paying for a shadow of their whole size.
+//! Regions of at least `chunked_min_len`, e.g. multi-GB mappings, get a
+//! [`ChunkedTracker`] instead, whose per-page bitmaps are only allocated
+//! once fetched from and are capped at `chunk_memory_cap`.
+//!
+//! Either way, accesses are rounded out to the region's granularity before
+//! they reach the backend, so a coarse granularity keeps the tree small and
+//! the bitmap short. With the `heatmap` option, fetches are also counted in
+//! a [`HeatMap`], and with `reporting=observed-change` or `both` the
+//! bytes of granules fetched for the first time are copied into a
+//! [`Snapshot`]. Fetches, detections and
+//! mutations are counted in the region's [`RegionCounters`]. Trees are kept
+//! within `max_tracked_spans`, see [`budget`]. The region's
+//! [`access_hook`](crate::access_hook), if any, is kept here too.
+
+use core::sync::atomic::Ordering;
+
+use crate::access_hook::HookSlot;
+use crate::bitmap::BitmapTracker;
+use crate::budget;
+use crate::chunked::ChunkedTracker;
+use crate::heatmap::HeatMap;
+use crate::memory_tracking::{MemoryTracker, TrackerError};
+use crate::snapshot::Snapshot;
+use crate::span::Span;
+use crate::stats::RegionCounters;
+use crate::{config, Address, TrackerAlloc};
+
+/// A watched region's access history
+#[derive(Debug)]
+pub(crate) struct RegionTracker {
+    /// Power of two size of the aligned granules accesses are rounded to
+    granularity: usize,
+    backend: Backend,
+    heat_map: Option<HeatMap>,
+    snapshot: Option<Snapshot>,
+    counters: RegionCounters,
+    access_hook: HookSlot,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Tree(MemoryTracker<Address, TrackerAlloc>),
+    Bitmap(BitmapTracker),
+    Chunked(ChunkedTracker),
+}
+
+/// Iterator returned by [`RegionTracker::check_all`]
+pub(crate) enum Conflicts<T, B, C> {
+    Tree(T),
+    Bitmap(B),
+    Chunked(C),
+}
+
+impl<T, B, C> Iterator for Conflicts<T, B, C>
+where
+    T: Iterator<Item = Span>,
+    B: Iterator<Item = Span>,
+    C: Iterator<Item = Span>,
+{
+    type Item = Span;
+
+    fn next(&mut self) -> Option<Span> {
+        match self {
+            Conflicts::Tree(spans) => spans.next(),
+            Conflicts::Bitmap(spans) => spans.next(),
+            Conflicts::Chunked(spans) => spans.next(),
+        }
+    }
+}
+
+impl RegionTracker {
+    /// Tracker for `region` tracking `granularity`-sized granules, with the
+    /// backend the config picks for its size
+    pub fn new(region: &Span, granularity: usize) -> Self {
+        let config = config::get();
+
+        let backend = if config.chunked_min_len != 0 && region.len() >= config.chunked_min_len {
+            let chunk_size = config::page_size().max(granularity);
+            let chunk_shadow = (chunk_size / granularity).div_ceil(8);
+            Backend::Chunked(ChunkedTracker::new(
+                region.clone(),
+                chunk_size,
+                granularity,
+                (config.chunk_memory_cap / chunk_shadow).max(1),
+            ))
+        } else if region.len() <= config.bitmap_max_len {
+            Backend::Bitmap(BitmapTracker::with_granularity(region.clone(), granularity))
+        } else {
+            Backend::Tree(MemoryTracker::default())
+        };
+
+        Self {
+            granularity,
+            backend,
+            heat_map: config.heatmap.then(|| HeatMap::new(region, granularity)),
+            snapshot: (config.reporting != config::Reporting::Strict)
+                .then(|| Snapshot::new(region)),
+            counters: RegionCounters::default(),
+            access_hook: HookSlot::default(),
+        }
+    }
+
+    pub fn granularity(&self) -> usize {
+        self.granularity
+    }
+
+    /// Name of the backend, for dumps
+    pub fn backend_name(&self) -> &'static str {
+        match self.backend {
+            Backend::Tree(_) => "tree",
+            Backend::Bitmap(_) => "bitmap",
+            Backend::Chunked(_) => "chunked",
+        }
+    }
+
+    /// The region's fetch counts, if the `heatmap` option is on
+    pub fn heat_map(&self) -> Option<&HeatMap> {
+        self.heat_map.as_ref()
+    }
+
+    /// The region's first-fetched bytes, if `reporting` compares them
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn counters(&self) -> &RegionCounters {
+        &self.counters
+    }
+
+    pub fn access_hook(&self) -> &HookSlot {
+        &self.access_hook
+    }
+
+    /// Copies the bytes of `[a, a + out.len())` as they were first fetched
+    /// into `out`. Returns false if `reporting` doesn't keep them or some of
+    /// them weren't fetched yet.
+    pub fn first_fetched(&self, a: Address, out: &mut [u8]) -> bool {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.get(a, out),
+            None => false,
+        }
+    }
+
+    /// Whether the bytes of `[a, a + sz)` fetched before changed since, or
+    /// `None` if `reporting` doesn't compare them
+    pub fn changed(&self, a: Address, sz: usize) -> Option<bool> {
+        let snapshot = self.snapshot.as_ref()?;
+        let fetch = Span::with_len(a, sz);
+        Some(
+            self.check_all(a, sz)
+                .filter_map(|earlier| earlier.intersect(&fetch))
+                .any(|span| {
+                    let now = unsafe {
+                        core::slice::from_raw_parts(span.start() as *const u8, span.len())
+                    };
+                    snapshot.differs(span.start(), now)
+                }),
+        )
+    }
+
+    /// Counts a fetch, and records it in the heat map if there is one. An
+    /// empty fetch reads nothing and isn't counted.
+    pub fn count_fetch(&self, a: Address, sz: usize) {
+        if sz == 0 {
+            return;
+        }
+        self.counters.fetches.fetch_add(1, Ordering::Relaxed);
+        if let Some(heat_map) = &self.heat_map {
+            let (a, sz) = self.round(a, sz);
+            heat_map.record(a, sz);
+        }
+    }
+
+    /// `[a, a + sz)` rounded out to whole granules
+    fn round(&self, a: Address, sz: usize) -> (Address, usize) {
+        if sz == 0 {
+            return (a, 0);
+        }
+
+        let mask = self.granularity - 1;
+        let start = a & !mask;
+        let end = a.saturating_add(sz).saturating_add(mask) & !mask;
+        (start, end.saturating_sub(start))
+    }
+
+    /// Tracks a fetch of `[a, a + sz)`, first copying the bytes of the
+    /// granules it fetches for the first time if `reporting` compares them
+    pub fn track_fetch(&mut self, a: Address, sz: usize) -> Result<(), TrackerError> {
+        if let Some(snapshot) = &self.snapshot {
+            let (a, sz) = self.round(a, sz);
+            let mut earlier: Vec<Span> = self.check_all(a, sz).collect();
+            earlier.sort_by_key(Span::start);
+            let mut at = a;
+            for fetched in earlier {
+                if fetched.start() > at {
+                    snapshot.record(at, fetched.start() - at);
+                }
+                at = at.max(fetched.end());
+            }
+            if at < a + sz {
+                snapshot.record(at, a + sz - at);
+            }
+        }
+
+        self.track_access(a, sz)
+    }
+
+    /// Tracks an access of `[a, a + sz)` without copying any bytes, e.g. a
+    /// write or one carried over from a merged region
+    pub fn track_access(&mut self, a: Address, sz: usize) -> Result<(), TrackerError> {
+        let (a, sz) = self.round(a, sz);
+
+        match &mut self.backend {
+            Backend::Tree(tracker) => {
+                tracker.track_access(a, sz)?;
+                budget::limit_spans(tracker, a, sz)
+            }
+            Backend::Bitmap(tracker) => {
+                tracker.track_access(a, sz);
+                Ok(())
+            }
+            Backend::Chunked(tracker) => {
+                let evictions = tracker.evictions();
+                tracker.track_access(a, sz);
+                if evictions == 0 && tracker.evictions() > 0 {
+                    log::warn!(
+                        "chunk memory cap reached for region at {:#X}, forgetting least recently fetched pages",
+                        tracker.region().start()
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn check(&self, a: Address, sz: usize) -> Result<(), Address> {
+        let (a, sz) = self.round(a, sz);
+
+        match &self.backend {
+            Backend::Tree(tracker) => tracker.check(a, sz),
+            Backend::Bitmap(tracker) => tracker.check(a, sz),
+            Backend::Chunked(tracker) => tracker.check(a, sz),
+        }
+    }
+
+    pub fn check_all(
+        &self,
+        a: Address,
+        sz: usize,
+    ) -> Conflicts<
+        impl Iterator<Item = Span> + '_,
+        impl Iterator<Item = Span> + '_,
+        impl Iterator<Item = Span> + '_,
+    > {
+        let (a, sz) = self.round(a, sz);
+
+        match &self.backend {
+            Backend::Tree(tracker) => Conflicts::Tree(tracker.check_all(a, sz).cloned()),
+            Backend::Bitmap(tracker) => Conflicts::Bitmap(tracker.check_all(a, sz)),
+            Backend::Chunked(tracker) => Conflicts::Chunked(tracker.check_all(a, sz)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match &mut self.backend {
+            Backend::Tree(tracker) => tracker.clear(),
+            Backend::Bitmap(tracker) => tracker.clear(),
+            Backend::Chunked(tracker) => tracker.clear(),
+        }
+        if let Some(heat_map) = &self.heat_map {
+            heat_map.clear();
+        }
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.clear();
+        }
+    }
+
+    pub fn occupied_len(&self) -> usize {
+        match &self.backend {
+            Backend::Tree(tracker) => tracker.occupied_len(),
+            Backend::Bitmap(tracker) => tracker.occupied_len(),
+            Backend::Chunked(tracker) => tracker.occupied_len(),
+        }
+    }
+
+    pub fn coverage(&self, total_len: usize) -> f64 {
+        match &self.backend {
+            Backend::Tree(tracker) => tracker.coverage(total_len),
+            Backend::Bitmap(tracker) => tracker.coverage(total_len),
+            Backend::Chunked(tracker) => tracker.coverage(total_len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: Span = Span::new(0x1000, 0x1100);
+    const LARGE: Span = Span::new(0x1000, 0x1000_1000);
+    const HUGE: Span = Span::new(0x1000, 0x10_0000_1000);
+
+    #[test]
+    fn backend_by_size() {
+        // with the default options
+        for region in [&SMALL, &LARGE, &HUGE] {
+            let mut tracker = RegionTracker::new(region, 1);
+            let expected = match *region {
+                SMALL => matches!(tracker.backend, Backend::Bitmap(_)),
+                LARGE => matches!(tracker.backend, Backend::Tree(_)),
+                _ => matches!(tracker.backend, Backend::Chunked(_)),
+            };
+            assert!(expected, "{:?}", tracker.backend);
+
+            tracker.track_access(0x1010, 0x10).unwrap();
+            tracker.track_access(0x1030, 0x10).unwrap();
+            assert_eq!(tracker.check(0x1018, 0x20), Err(0x1030));
+            assert_eq!(tracker.check_all(0x1018, 0x20).count(), 2);
+            assert_eq!(tracker.coverage(0x100), 0.125);
+
+            tracker.clear();
+            assert!(tracker.check(0x1018, 0x20).is_ok());
+        }
+    }
+
+    #[test]
+    fn rounds_to_granules() {
+        for region in [&SMALL, &LARGE, &HUGE] {
+            let mut tracker = RegionTracker::new(region, 0x10);
+
+            tracker.track_access(0x1012, 2).unwrap();
+            assert_eq!(tracker.check(0x101f, 1), Err(0x1010));
+            assert_eq!(tracker.check(0x100f, 1), Ok(()));
+            assert_eq!(
+                tracker.check_all(0x1000, 0x100).collect::<Vec<_>>(),
+                [Span::new(0x1010, 0x1020)]
+            );
+        }
+    }
+
+    #[test]
+    fn empty_and_repeated_fetches() {
+        for region in [&SMALL, &LARGE, &HUGE] {
+            let mut tracker = RegionTracker::new(region, 1);
+
+            tracker.count_fetch(0x1010, 0);
+            tracker.track_access(0x1010, 0).unwrap();
+            assert_eq!(tracker.occupied_len(), 0);
+            assert_eq!(tracker.counters().fetches.load(Ordering::Relaxed), 0);
+
+            tracker.track_access(0x1010, 0x10).unwrap();
+            tracker.track_access(0x1010, 0x10).unwrap();
+            assert_eq!(tracker.occupied_len(), 0x10);
+            assert_eq!(tracker.check(0x1018, 0), Ok(()));
+            assert_eq!(tracker.check_all(0x1018, 0).count(), 0);
+            assert_eq!(tracker.check(0x1010, 0x10), Err(0x1010));
+            assert_eq!(
+                tracker.check_all(0x1010, 0x10).collect::<Vec<_>>(),
+                [Span::new(0x1010, 0x1020)]
+            );
+        }
+    }
+
+    #[test]
+    fn first_fetched_bytes() {
+        let buf = Box::leak(Box::new([0x10u8; 0x40]));
+        let region = Span::with_len(buf.as_ptr() as Address, buf.len());
+        let base = region.start();
+        let mut tracker = RegionTracker::new(&region, 4);
+        let mut out = [0; 8];
+
+        // `reporting=strict` keeps no copy
+        tracker.track_fetch(base, 8).unwrap();
+        assert!(tracker.snapshot().is_none());
+        assert!(!tracker.first_fetched(base, &mut out));
+
+        tracker.snapshot = Some(Snapshot::new(&region));
+        tracker.clear();
+        // a 2-byte fetch keeps its whole granule
+        tracker.track_fetch(base + 1, 2).unwrap();
+        buf[..8].copy_from_slice(&[0xff; 8]);
+        assert!(tracker.first_fetched(base, &mut out[..4]));
+        assert_eq!(out[..4], [0x10; 4]);
+        // the next granule on the same page wasn't fetched
+        assert!(!tracker.first_fetched(base, &mut out));
+
+        // re-fetches and writes don't replace the first fetch
+        tracker.track_fetch(base, 8).unwrap();
+        assert!(tracker.first_fetched(base, &mut out));
+        assert_eq!(out, [0x10, 0x10, 0x10, 0x10, 0xff, 0xff, 0xff, 0xff]);
+        tracker.track_access(base + 8, 4).unwrap();
+        buf[8..12].copy_from_slice(&[0xee; 4]);
+        tracker.track_fetch(base + 8, 4).unwrap();
+        assert!(!tracker.first_fetched(base + 8, &mut out[..4]));
+    }
+
+    #[test]
+    fn merging_keeps_first_fetches() {
+        let buf = Box::leak(Box::new([0x10u8; 0x40]));
+        let base = buf.as_ptr() as Address;
+        let with_snapshot = |region: &Span| {
+            let tracker = crate::new_tracker(region, 4);
+            tracker.write().unwrap().snapshot = Some(Snapshot::new(region));
+            tracker
+        };
+
+        let left = Span::with_len(base, 0x20);
+        let right = Span::with_len(base + 0x20, 0x20);
+        let (old_left, old_right) = (with_snapshot(&left), with_snapshot(&right));
+        old_left
+            .write()
+            .unwrap()
+            .track_fetch(base + 0x1c, 4)
+            .unwrap();
+        old_right
+            .write()
+            .unwrap()
+            .track_fetch(base + 0x20, 4)
+            .unwrap();
+        buf[0x1c..0x24].copy_from_slice(&[0xff; 8]);
+
+        let tracker = with_snapshot(&Span::with_len(base, 0x40));
+        crate::carry_over(&left, &old_left, &tracker);
+        crate::carry_over(&right, &old_right, &tracker);
+
+        let mut tracker = tracker.write().unwrap();
+        // nor is a re-fetch after the merge a first fetch
+        tracker.track_fetch(base + 0x1c, 8).unwrap();
+        let mut out = [0; 8];
+        assert!(tracker.first_fetched(base + 0x1c, &mut out));
+        assert_eq!(out, [0x10; 8]);
+        assert_eq!(tracker.changed(base + 0x1c, 8), Some(true));
+    }
+}
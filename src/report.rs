@@ -0,0 +1,416 @@
+//! AddressSanitizer-style detection reports
+//!
+//! With `report_style=asan`, detections are reported the way ASAN reports
+//! memory errors, so tooling and people used to reading those can consume
+//! them: a header naming the error, the access with its offset into the
+//! region, the stack of the re-fetch, the earlier fetches it overlaps, and a
+//! shadow map of which bytes around it were fetched. Trackers only remember
+//! which bytes were fetched, not from where, so earlier fetches come without
+//! a stack.
+
+use std::backtrace::Backtrace;
+use std::fmt::Write as _;
+use std::io::IsTerminal;
+
+use crate::config::{self, Color};
+use crate::printer::REPORT_TARGET;
+use crate::span::Span;
+use crate::{groups, region_names, Address, Tracker};
+
+/// Bytes per row of the shadow map
+const SHADOW_ROW: usize = 16;
+/// Rows of the shadow map shown before and after the access
+const SHADOW_CONTEXT: usize = 2;
+
+/// ANSI escapes, or nothing when not coloring
+struct Palette(bool);
+
+impl Palette {
+    fn code(&self, code: &'static str) -> &'static str {
+        if self.0 {
+            code
+        } else {
+            ""
+        }
+    }
+
+    fn error(&self) -> &'static str {
+        self.code("\x1b[1m\x1b[31m")
+    }
+
+    fn access(&self) -> &'static str {
+        self.code("\x1b[1m\x1b[34m")
+    }
+
+    fn location(&self) -> &'static str {
+        self.code("\x1b[1m\x1b[32m")
+    }
+
+    fn earlier(&self) -> &'static str {
+        self.code("\x1b[1m\x1b[35m")
+    }
+
+    fn reset(&self) -> &'static str {
+        self.code("\x1b[1m\x1b[0m")
+    }
+}
+
+/// `a` relative to the start of `region`, e.g. `+0x10`
+pub(crate) fn offset(region: &Span, a: Address) -> String {
+    if a >= region.start() {
+        format!("+{:#x}", a - region.start())
+    } else {
+        format!("-{:#x}", region.start() - a)
+    }
+}
+
+/// Frames of a [`Backtrace`] rendered by its `Display` impl, as
+/// `symbol file:line`, starting at the caller of the runtime's entry point
+pub(crate) fn frames(stack: &str) -> Vec<String> {
+    let mut frames: Vec<String> = Vec::new();
+    for line in stack.lines() {
+        let line = line.trim();
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                frame.push(' ');
+                frame.push_str(location);
+            }
+        } else if let Some((index, symbol)) = line.split_once(": ") {
+            if index.bytes().all(|b| b.is_ascii_digit()) {
+                frames.push(symbol.to_owned());
+            }
+        }
+    }
+
+    if let Some(entry) = frames
+        .iter()
+        .rposition(|frame| frame.starts_with("__asan_"))
+    {
+        return frames.split_off(entry + 1);
+    }
+
+    // not called through an entry point, e.g. from an interceptor: skip the
+    // runtime's frames, including closures it calls through, which show up
+    // as `<F as FnOnce>` frames
+    let is_runtime = |frame: &String| {
+        let symbol = frame.trim_start_matches('<');
+        ["std::", "core::", "asan_double_fetch::", "__rust_try"]
+            .iter()
+            .any(|prefix| symbol.starts_with(prefix))
+            || symbol.contains(" as core::ops::function::")
+    };
+    let first = frames.iter().position(|frame| !is_runtime(frame));
+    frames.split_off(first.unwrap_or(0))
+}
+
+/// Appends the shadow map of the bytes around `[addr, addr + len)`
+fn shadow(
+    out: &mut String,
+    p: &Palette,
+    addr: Address,
+    len: usize,
+    region: &Span,
+    tracker: &Tracker,
+) {
+    let access = Span::with_len(addr, len);
+    let row_of = |a: Address| a & !(SHADOW_ROW - 1);
+    let first = row_of(addr.max(region.start())).saturating_sub(SHADOW_CONTEXT * SHADOW_ROW);
+    let last = row_of(access.end().min(region.end()).saturating_sub(1))
+        .saturating_add(SHADOW_CONTEXT * SHADOW_ROW);
+
+    let _ = writeln!(out, "Shadow bytes around the buggy address:");
+    let mut row = first.max(row_of(region.start()));
+    while row <= last && row < region.end() {
+        let fetched: Vec<Span> = tracker.check_all(row, SHADOW_ROW).collect();
+        let marker = if row == row_of(addr) { "=>" } else { "  " };
+        let _ = write!(out, "{}{:#x}:", marker, row);
+
+        for a in row..row + SHADOW_ROW {
+            let cell = if !region.contains(a) {
+                " "
+            } else if !fetched.iter().any(|span| span.contains(a)) {
+                "."
+            } else if access.contains(a) {
+                "F"
+            } else {
+                "f"
+            };
+            let color = match cell {
+                "F" => p.error(),
+                "f" => p.earlier(),
+                _ => "",
+            };
+            let reset = if color.is_empty() { "" } else { p.reset() };
+            let _ = write!(out, " {}{}{}", color, cell, reset);
+        }
+        let _ = writeln!(out);
+
+        row += SHADOW_ROW;
+    }
+
+    let _ = writeln!(
+        out,
+        "Shadow byte legend (one shadow byte per application byte):"
+    );
+    let _ = writeln!(out, "  Not fetched:               .");
+    let _ = writeln!(
+        out,
+        "  Fetched earlier:           {}f{}",
+        p.earlier(),
+        p.reset()
+    );
+    let _ = writeln!(
+        out,
+        "  Re-fetched by this access: {}F{}",
+        p.error(),
+        p.reset()
+    );
+    let _ = writeln!(out, "  Outside the region:        (blank)");
+}
+
+/// The report for a re-fetch of `[addr, addr + len)` in `region`, with
+/// `stack` being the re-fetch's [`Backtrace`] if one was captured and
+/// `changed` whether the bytes changed since first fetched, if known
+#[allow(clippy::too_many_arguments)]
+fn render(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    tracker: &Tracker,
+    changed: Option<bool>,
+    stack: Option<&str>,
+    color: bool,
+) -> String {
+    let p = Palette(color);
+    let mut out = String::new();
+
+    let _ = writeln!(out, "{}", "=".repeat(65));
+    let _ = write!(
+        out,
+        "{}=={}==ERROR: DoubleFetchSanitizer: double-fetch on address {:#x}",
+        p.error(),
+        std::process::id(),
+        addr
+    );
+    if let Some(pc) = pc {
+        let _ = write!(out, " at pc {:#x}", pc);
+    }
+    let _ = writeln!(out, "{}", p.reset());
+
+    let _ = writeln!(
+        out,
+        "{}READ of size {} at {:#x} in region {:#x} ({}){}",
+        p.access(),
+        len,
+        addr,
+        region.start(),
+        region_names::at(region, addr),
+        p.reset()
+    );
+    match stack {
+        Some(stack) => {
+            for (i, frame) in frames(stack).iter().enumerate() {
+                let _ = writeln!(out, "    #{} {}", i, frame);
+            }
+        }
+        None => {
+            let _ = writeln!(out, "    <stack not captured>");
+        }
+    }
+    let _ = writeln!(out);
+
+    let _ = writeln!(
+        out,
+        "{}{:#x} is at {} in the {:#x}-byte region [{:#x},{:#x}){}",
+        p.location(),
+        addr,
+        region_names::relative(region, addr),
+        region.len(),
+        region.start(),
+        region.end(),
+        p.reset()
+    );
+    if let Some(group) = groups::describe(region) {
+        let _ = writeln!(
+            out,
+            "{}region is in group {}{}",
+            p.location(),
+            group,
+            p.reset()
+        );
+    }
+    for fetched in tracker.check_all(addr, len) {
+        let _ = writeln!(
+            out,
+            "{}previously fetched [{:#x},{:#x}) ({}), stack not recorded{}",
+            p.earlier(),
+            fetched.start(),
+            fetched.end(),
+            region_names::relative(region, fetched.start()),
+            p.reset()
+        );
+    }
+    match changed {
+        Some(true) => {
+            let _ = writeln!(
+                out,
+                "{}the previously fetched bytes changed since they were first fetched{}",
+                p.error(),
+                p.reset()
+            );
+        }
+        Some(false) => {
+            let _ = writeln!(
+                out,
+                "the previously fetched bytes are unchanged since they were first fetched"
+            );
+        }
+        None => (),
+    }
+    let _ = writeln!(out);
+
+    shadow(&mut out, &p, addr, len, region, tracker);
+
+    let _ = writeln!(
+        out,
+        "SUMMARY: DoubleFetchSanitizer: double-fetch in region {:#x} ({})",
+        region.start(),
+        region_names::relative(region, addr)
+    );
+    let _ = write!(out, "{}", "=".repeat(65));
+    out
+}
+
+/// The uncolored shadow map of the bytes around `[addr, addr + len)`
+pub(crate) fn shadow_map(addr: Address, len: usize, region: &Span, tracker: &Tracker) -> String {
+    let mut out = String::new();
+    shadow(&mut out, &Palette(false), addr, len, region, tracker);
+    out
+}
+
+/// Whether reports should be colored
+fn colored() -> bool {
+    match config::get().color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => std::io::stdout().is_terminal(),
+    }
+}
+
+/// Reports a re-fetch of `[addr, addr + len)` in `region`, capturing the
+/// current stack if `capture_stack`, i.e. when called from the re-fetch.
+/// Re-fetches of bytes that `changed` are reported as errors.
+pub(crate) fn emit(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    tracker: &Tracker,
+    changed: Option<bool>,
+    capture_stack: bool,
+) {
+    let stack = if capture_stack {
+        Some(Backtrace::force_capture().to_string())
+    } else {
+        None
+    };
+    let level = if changed == Some(true) {
+        log::Level::Error
+    } else {
+        log::Level::Warn
+    };
+
+    log::log!(
+        target: REPORT_TARGET,
+        level,
+        "{}",
+        render(
+            addr,
+            len,
+            pc,
+            region,
+            tracker,
+            changed,
+            stack.as_deref(),
+            colored()
+        )
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STACK: &str = "   0: asan_double_fetch::report::emit
+             at ./src/report.rs:10:5
+   1: <F as core::ops::function::FnOnce<()>>::call_once
+   2: target::parse_header
+             at ./src/parse.rs:42:13
+   3: main
+";
+
+    #[test]
+    fn frames_skip_the_runtime() {
+        let through_entry = "   0: __rust_try
+   1: asan_double_fetch::check_access
+   2: __asan_double_fetch_check
+             at ./src/lib.rs:422:5
+   3: asan_double_fetch::tests::t
+";
+        assert_eq!(frames(through_entry), ["asan_double_fetch::tests::t"]);
+        assert_eq!(
+            frames(STACK),
+            ["target::parse_header ./src/parse.rs:42:13", "main"]
+        );
+        assert_eq!(
+            frames("   0: asan_double_fetch::tests::t\n"),
+            ["asan_double_fetch::tests::t"]
+        );
+    }
+
+    #[test]
+    fn renders_report() {
+        let region = Span::with_len(0x1000, 0x100);
+        let mut tracker = Tracker::new(&region, 1);
+        tracker.track_access(0x1012, 2).unwrap();
+        tracker.track_access(0x1004, 1).unwrap();
+
+        let report = render(
+            0x1010,
+            4,
+            Some(0x4141),
+            &region,
+            &tracker,
+            None,
+            Some(STACK),
+            false,
+        );
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert!(lines[1]
+            .ends_with("ERROR: DoubleFetchSanitizer: double-fetch on address 0x1010 at pc 0x4141"));
+        assert_eq!(
+            lines[2],
+            "READ of size 4 at 0x1010 in region 0x1000 (region+0x10)"
+        );
+        assert_eq!(lines[3], "    #0 target::parse_header ./src/parse.rs:42:13");
+        assert_eq!(lines[4], "    #1 main");
+        assert!(
+            lines.contains(&"previously fetched [0x1012,0x1014) (region+0x12), stack not recorded")
+        );
+        assert!(lines.contains(&"  0x1000: . . . . f . . . . . . . . . . ."));
+        assert!(lines.contains(&"=>0x1010: . . F F . . . . . . . . . . . ."));
+        assert!(lines.contains(&"  0x1030: . . . . . . . . . . . . . . . ."));
+        assert!(!lines.iter().any(|line| line.contains("0x1040:")));
+        assert!(!report.contains('\x1b'));
+        assert!(!report.contains("first fetched"));
+
+        let changed = render(0x1010, 4, None, &region, &tracker, Some(true), None, false);
+        assert!(changed.contains("the previously fetched bytes changed since"));
+
+        let colored = render(0x1010, 4, None, &region, &tracker, None, None, true);
+        assert!(colored.contains("\x1b[1m\x1b[34mREAD of size 4"));
+        assert!(colored.contains("    <stack not captured>"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Deferred detection reports
+//!
+//! With the `no_alloc_hot_path` feature the check path never formats or
+//! allocates: detections are pushed into a fixed-capacity lock-free queue
+//! instead, and [`__asan_double_fetch_drain_reports`] prints them later from
+//! a context where that is safe (process context in the kernel, outside of
+//! signal handlers in userspace). When the queue is full, reports are dropped
+//! and counted.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Address;
+
+/// Reports that can be pending before new ones are dropped
+pub const REPORT_QUEUE_CAPACITY: usize = 256;
+
+/// A detection waiting to be printed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Report {
+    pub addr: Address,
+    pub len: usize,
+    pub pc: Option<Address>,
+}
+
+struct Slot<T> {
+    /// Sequence number relative to the slot's index, so that a zeroed slot is
+    /// ready for the first push into it
+    seq: AtomicUsize,
+    report: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Slot {
+            seq: AtomicUsize::new(0),
+            report: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+/// Bounded multi-producer queue with per-slot sequence numbers
+pub struct ReportQueue<T: Copy = Report> {
+    slots: [Slot<T>; REPORT_QUEUE_CAPACITY],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// SAFETY: a slot's report is only accessed by the producer or consumer that
+// claimed it through `head`/`tail`, as arbitrated by the slot's sequence
+unsafe impl<T: Copy + Send> Sync for ReportQueue<T> {}
+
+impl<T: Copy> ReportQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Slot::new() }; REPORT_QUEUE_CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn seq(&self, pos: usize) -> usize {
+        let index = pos % REPORT_QUEUE_CAPACITY;
+        self.slots[index]
+            .seq
+            .load(Ordering::Acquire)
+            .wrapping_add(index)
+    }
+
+    fn set_seq(&self, pos: usize, seq: usize) {
+        let index = pos % REPORT_QUEUE_CAPACITY;
+        self.slots[index]
+            .seq
+            .store(seq.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Queues `report`, or drops it and returns `false` if the queue is full
+    pub fn push(&self, report: T) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let diff = self.seq(pos).wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+
+        let slot = &self.slots[pos % REPORT_QUEUE_CAPACITY];
+        unsafe { (*slot.report.get()).write(report) };
+        self.set_seq(pos, pos.wrapping_add(1));
+        true
+    }
+
+    /// Takes the oldest pending report
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let diff = self.seq(pos).wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+
+        let slot = &self.slots[pos % REPORT_QUEUE_CAPACITY];
+        let report = unsafe { (*slot.report.get()).assume_init() };
+        self.set_seq(pos, pos.wrapping_add(REPORT_QUEUE_CAPACITY));
+        Some(report)
+    }
+
+    /// Returns and resets the number of reports dropped because the queue was
+    /// full
+    pub fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+static REPORTS: ReportQueue = ReportQueue::new();
+
+/// Defers `report` to the next [`__asan_double_fetch_drain_reports`]
+pub(crate) fn defer(report: Report) {
+    REPORTS.push(report);
+}
+
+/// Takes the oldest deferred report without printing it. Lock-free, so it
+/// can be called from a signal handler.
+#[cfg(all(unix, not(feature = "no_std")))]
+pub(crate) fn take() -> Option<Report> {
+    REPORTS.pop()
+}
+
+/// Prints all pending detections and returns how many there were. Call this
+/// periodically from a context that may allocate and block.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("drain_reports"))]
+pub extern "C" fn __asan_double_fetch_drain_reports() -> usize {
+    crate::ffi::guard("__asan_double_fetch_drain_reports", 0, || {
+        let mut drained = 0;
+        while let Some(report) = REPORTS.pop() {
+            crate::report_detection(report.addr, report.len, report.pc, None);
+            drained += 1;
+        }
+
+        let dropped = REPORTS.take_dropped();
+        if dropped > 0 {
+            log::warn!("{} reports dropped, report queue was full", dropped);
+        }
+
+        drained
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(addr: Address) -> Report {
+        Report {
+            addr,
+            len: 4,
+            pc: None,
+        }
+    }
+
+    #[test]
+    fn fifo() {
+        let queue = ReportQueue::new();
+
+        assert_eq!(queue.pop(), None);
+        assert!(queue.push(report(0x1000)));
+        assert!(queue.push(report(0x2000)));
+        assert_eq!(queue.pop(), Some(report(0x1000)));
+        assert_eq!(queue.pop(), Some(report(0x2000)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let queue = ReportQueue::new();
+
+        // wrap around a few times
+        for lap in 0..3 {
+            for i in 0..REPORT_QUEUE_CAPACITY {
+                assert!(queue.push(report(lap * 0x10000 + i)));
+            }
+            assert!(!queue.push(report(0xdead)));
+            assert_eq!(queue.take_dropped(), 1);
+
+            for i in 0..REPORT_QUEUE_CAPACITY {
+                assert_eq!(queue.pop(), Some(report(lap * 0x10000 + i)));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+}
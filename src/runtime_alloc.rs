@@ -0,0 +1,99 @@
+//! Allocator for the runtime's own data structures
+//!
+//! With the `allocator_api` feature (nightly), region trackers and the
+//! region list are allocated through [`RuntimeAlloc`] instead of the global
+//! allocator. It forwards to a backend chosen once, before the first
+//! allocation: a Rust embedder's [`Allocator`] via [`set_runtime_allocator`],
+//! a C harness's arena via [`__asan_double_fetch_set_allocator`], or else
+//! the default, which is the global allocator in userspace and bare-metal
+//! builds and `GFP_ATOMIC` kmalloc in kernel builds, since trackers grow from
+//! atomic context.
+
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ffi::c_int;
+use core::ptr::NonNull;
+
+use once_cell::sync::OnceCell;
+
+type Backend = &'static (dyn Allocator + Sync);
+
+static BACKEND: OnceCell<Backend> = OnceCell::new();
+
+#[cfg(not(feature = "no_std"))]
+static DEFAULT_BACKEND: std::alloc::Global = std::alloc::Global;
+#[cfg(all(feature = "no_std", not(feature = "linux_kasan")))]
+static DEFAULT_BACKEND: alloc::alloc::Global = alloc::alloc::Global;
+#[cfg(feature = "linux_kasan")]
+static DEFAULT_BACKEND: crate::kmod::AtomicKmalloc = crate::kmod::AtomicKmalloc;
+
+fn backend() -> Backend {
+    *BACKEND.get_or_init(|| &DEFAULT_BACKEND)
+}
+
+/// Allocates through the runtime's backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuntimeAlloc;
+
+unsafe impl Allocator for RuntimeAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        backend().allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        backend().deallocate(ptr, layout)
+    }
+}
+
+/// Sets the allocator the runtime allocates from. Fails, handing `alloc`
+/// back, once the backend is fixed, i.e. after the first allocation.
+pub fn set_runtime_allocator(alloc: Backend) -> Result<(), Backend> {
+    BACKEND.set(alloc)
+}
+
+/// `alloc(size, align)` callback of [`__asan_double_fetch_set_allocator`]
+pub type AllocFn = extern "C" fn(usize, usize) -> *mut u8;
+/// `free(ptr, size, align)` callback of [`__asan_double_fetch_set_allocator`]
+pub type FreeFn = extern "C" fn(*mut u8, usize, usize);
+
+/// Backend calling into a C harness's allocator
+struct CallbackAllocator {
+    alloc: AllocFn,
+    free: FreeFn,
+}
+
+unsafe impl Allocator for CallbackAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = (self.alloc)(layout.size(), layout.align());
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (self.free)(ptr.as_ptr(), layout.size(), layout.align())
+    }
+}
+
+static CALLBACK_BACKEND: OnceCell<CallbackAllocator> = OnceCell::new();
+
+/// Routes the runtime's allocations to `alloc`/`free`, e.g. an arena kept
+/// apart from the target's heap. Must be called before
+/// `__asan_shared_memory_region_init`; returns 0 on success and -1 if the
+/// allocator was already fixed.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("set_allocator"))]
+pub extern "C" fn __asan_double_fetch_set_allocator(alloc: AllocFn, free: FreeFn) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_set_allocator", -1, || {
+        if CALLBACK_BACKEND
+            .set(CallbackAllocator { alloc, free })
+            .is_err()
+        {
+            return -1;
+        }
+
+        match set_runtime_allocator(CALLBACK_BACKEND.get().unwrap()) {
+            Ok(()) => 0,
+            Err(_) => -1,
+        }
+    })
+}
@@ -0,0 +1,141 @@
+//! SanitizerCoverage correlation
+//!
+//! With the `sancov` feature the runtime provides SanitizerCoverage's
+//! `trace-pc-guard` callbacks, so a target built with
+//! `-fsanitize-coverage=trace-pc-guard` tells it which coverage edge each
+//! thread last took. Every fetch from a watched region is attributed to
+//! that edge, or to its PC where the caller passes one, and each pair of
+//! consecutive fetch sites on a thread bumps an 8-bit counter, hashed like
+//! AFL's edges.
+//!
+//! The counters live in libFuzzer's `__libfuzzer_extra_counters` section,
+//! so a statically linked libFuzzer counts them as coverage and keeps inputs
+//! that reach fetch-site pairs it hasn't seen, such as a length validated
+//! in one place and re-read in another. Other fuzzers can read them through
+//! [`__asan_double_fetch_fetch_pair_counters`].
+//!
+//! libFuzzer's own instrumentation uses `inline-8bit-counters`, which
+//! leaves the `trace-pc-guard` callbacks free, so both can be enabled.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::Address;
+
+/// Number of fetch-site pair counters, a power of two
+pub const PAIR_COUNTERS: usize = 1 << 16;
+
+#[cfg_attr(target_os = "linux", link_section = "__libfuzzer_extra_counters")]
+static COUNTERS: [AtomicU8; PAIR_COUNTERS] = [const { AtomicU8::new(0) }; PAIR_COUNTERS];
+
+/// Guards numbered so far, across every instrumented module
+static GUARDS: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    /// Guard of the last edge taken, 0 before the first
+    static LAST_EDGE: Cell<u32> = const { Cell::new(0) };
+    /// Site of the last fetch from a watched region
+    static LAST_SITE: Cell<u32> = const { Cell::new(0) };
+}
+
+// The callbacks are called by the names the compiler gave them, so they
+// keep them in `prefixed_symbols` builds. AddressSanitizer's own
+// definitions are weak and give way to these.
+
+/// Numbers the guards of an instrumented module, once per module
+///
+/// # Safety
+///
+/// `[start, stop)` must be the module's guard array.
+#[export_name = "__sanitizer_cov_trace_pc_guard_init"]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard_init(start: *mut u32, stop: *mut u32) {
+    if start == stop || *start != 0 {
+        return;
+    }
+    let len = stop.offset_from(start) as u32;
+    let first = GUARDS.fetch_add(len, Ordering::Relaxed) + 1;
+    for i in 0..len {
+        *start.add(i as usize) = first + i;
+    }
+}
+
+/// Records the edge guarded by `guard` as the current thread's last
+///
+/// # Safety
+///
+/// `guard` must be a guard numbered by [`__sanitizer_cov_trace_pc_guard_init`].
+#[export_name = "__sanitizer_cov_trace_pc_guard"]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard(guard: *mut u32) {
+    let edge = *guard;
+    // thread teardown
+    let _ = LAST_EDGE.try_with(|last| last.set(edge));
+}
+
+/// The edge the current thread last took, `None` without `trace-pc-guard`
+/// instrumentation
+pub(crate) fn edge() -> Option<u32> {
+    LAST_EDGE.try_with(Cell::get).ok().filter(|&edge| edge != 0)
+}
+
+/// Attributes a fetch from a watched region to its site, the last edge or
+/// else `pc`, and counts the pair it forms with the previous fetch's site
+pub(crate) fn fetched(pc: Option<Address>) {
+    let site = match edge() {
+        Some(edge) => edge,
+        // folded to the width of a guard
+        None => pc.map_or(0, |pc| (pc ^ (pc >> 32)) as u32),
+    };
+    let last = LAST_SITE.try_with(|last| last.replace(site)).unwrap_or(0);
+
+    let pair = ((last >> 1) ^ site) as usize % PAIR_COUNTERS;
+    COUNTERS[pair].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the fetch-site pair counters and stores their number to `len`,
+/// for fuzzers that don't pick up `__libfuzzer_extra_counters`
+///
+/// # Safety
+///
+/// `len` must be null or valid for writes.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("fetch_pair_counters"))]
+pub unsafe extern "C" fn __asan_double_fetch_fetch_pair_counters(len: *mut usize) -> *const u8 {
+    if !len.is_null() {
+        *len = PAIR_COUNTERS;
+    }
+    COUNTERS.as_ptr().cast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_fetch_site_pairs() {
+        let mut guards = [0u32; 4];
+        let range = guards.as_mut_ptr_range();
+        unsafe {
+            __sanitizer_cov_trace_pc_guard_init(range.start, range.end);
+        }
+        assert!(guards[0] != 0);
+        assert_eq!(guards[3], guards[0] + 3);
+
+        // runs on its own thread, so the pairs are its own
+        std::thread::spawn(move || {
+            assert_eq!(edge(), None);
+            unsafe { __sanitizer_cov_trace_pc_guard(&mut guards[1]) };
+            assert_eq!(edge(), Some(guards[1]));
+            fetched(None);
+            unsafe { __sanitizer_cov_trace_pc_guard(&mut guards[2]) };
+            fetched(None);
+
+            let pair = ((guards[1] >> 1) ^ guards[2]) as usize % PAIR_COUNTERS;
+            let mut len = 0;
+            let counters = unsafe { __asan_double_fetch_fetch_pair_counters(&mut len) };
+            assert_eq!(len, PAIR_COUNTERS);
+            assert!(unsafe { *counters.add(pair) } > 0);
+        })
+        .join()
+        .unwrap();
+    }
+}
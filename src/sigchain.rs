@@ -0,0 +1,128 @@
+//! Chaining to the signal handlers the runtime's own replace
+//!
+//! The trap modes and the crash handler take over `SIGSEGV`, `SIGTRAP` and
+//! other signals the target may well handle itself: JITs and garbage
+//! collectors rely on `SIGSEGV` for guard pages and write barriers. Each of
+//! the runtime's handlers is installed through a [`Chain`], which keeps the
+//! action it replaced and hands on every signal that isn't the runtime's
+//! business. Handlers installed one after the other, e.g. with both
+//! `mprotect_trap` and `mpk` enabled, thus form a chain in which each passes
+//! on what it doesn't recognize, down to the target's own handler or the
+//! default action.
+
+use std::mem;
+use std::os::raw::c_int;
+
+use once_cell::sync::OnceCell;
+
+/// A `SA_SIGINFO` signal handler
+pub(crate) type Handler = extern "C" fn(c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+/// One of the runtime's handlers and the action it replaced
+pub(crate) struct Chain {
+    previous: OnceCell<libc::sigaction>,
+}
+
+impl Chain {
+    pub const fn new() -> Self {
+        Self {
+            previous: OnceCell::new(),
+        }
+    }
+
+    /// Installs `handler` for `signal`, run on the thread's alternate signal
+    /// stack if it has one, and keeps the action it replaces. Returns false
+    /// if the handler couldn't be installed.
+    pub fn install(&self, signal: c_int, handler: Handler) -> bool {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) != 0 {
+                log::error!(
+                    "failed to install the handler for signal {}: {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                );
+                return false;
+            }
+            // installed once per chain; a second install would chain to itself
+            let _ = self.previous.set(previous);
+        }
+        true
+    }
+
+    /// Hands `sig`, which the runtime's handler isn't handling, on to the
+    /// action that handler replaced. Async-signal-safe.
+    pub fn pass_on(&self, sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+        let previous = match self.previous.get() {
+            Some(previous) => previous,
+            None => return default_action(sig, info),
+        };
+
+        match previous.sa_sigaction {
+            // a fault can't be ignored, the kernel takes the default action
+            libc::SIG_IGN if unsafe { (*info).si_code } <= 0 => (),
+            libc::SIG_IGN | libc::SIG_DFL => default_action(sig, info),
+            handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                let handler: Handler = unsafe { mem::transmute(handler) };
+                handler(sig, info, ctx);
+            }
+            handler => {
+                let handler: extern "C" fn(c_int) = unsafe { mem::transmute(handler) };
+                handler(sig);
+            }
+        }
+    }
+}
+
+/// Restores the default action for `sig`, for it to take its course once
+/// the handler returns. A fault re-executes the faulting instruction,
+/// anything else is raised again.
+fn default_action(sig: c_int, info: *mut libc::siginfo_t) {
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        if (*info).si_code <= 0 {
+            libc::raise(sig);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn previous(_sig: c_int) {
+        SEEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    static CHAIN: Chain = Chain::new();
+
+    extern "C" fn ours(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+        CHAIN.pass_on(sig, info, ctx);
+    }
+
+    #[test]
+    fn passes_on_to_the_replaced_handler() {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = previous as *const () as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGUSR2, &action, ptr::null_mut());
+        }
+
+        assert!(CHAIN.install(libc::SIGUSR2, ours));
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert_eq!(SEEN.load(Ordering::SeqCst), 1);
+
+        unsafe { libc::signal(libc::SIGUSR2, libc::SIG_DFL) };
+    }
+}
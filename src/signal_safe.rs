@@ -0,0 +1,258 @@
+//! Async-signal-safe checks
+//!
+//! Instrumented code may fetch from watched memory inside a signal handler,
+//! possibly one that interrupted the runtime itself on the same thread. The
+//! regular check path can't run there: it blocks on tracker locks the
+//! interrupted code may hold, allocates when recording a fetch, and formats
+//! its output.
+//!
+//! Checks that find the current thread already inside the runtime, and all
+//! checks made through [`__asan_double_fetch_check_signal_safe`], take the
+//! path below instead. It only ever *tries* to take locks, skipping the
+//! check when one is contended, never allocates, and defers its work into
+//! pre-allocated lock-free queues: detections into the report queue, drained
+//! by `__asan_double_fetch_drain_reports`, and fetches to record into
+//! [`PENDING_FETCHES`], applied by the next regular check. Detected bytes are
+//! not mutated.
+
+use core::cell::Cell;
+#[cfg(unix)]
+use core::fmt;
+use std::sync::{PoisonError, TryLockError, TryLockResult};
+
+use crate::report_queue::{self, Report, ReportQueue};
+use crate::span::Span;
+use crate::{Address, DETECTIONS, TRACKED_MEMORY_REGIONS};
+
+thread_local! {
+    /// Whether the current thread is inside a regular check
+    static IN_CHECK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Fetches seen by signal-safe checks that still have to be tracked
+static PENDING_FETCHES: ReportQueue = ReportQueue::new();
+
+/// Marks the current thread as inside a regular check until dropped
+pub(crate) struct CheckGuard(());
+
+impl Drop for CheckGuard {
+    fn drop(&mut self) {
+        IN_CHECK.with(|in_check| in_check.set(false));
+    }
+}
+
+/// Enters a regular check, or returns `None` if the current thread is
+/// already inside one, i.e. this is a signal handler that interrupted it
+pub(crate) fn enter() -> Option<CheckGuard> {
+    IN_CHECK.with(|in_check| {
+        if in_check.replace(true) {
+            None
+        } else {
+            Some(CheckGuard(()))
+        }
+    })
+}
+
+/// Formats into a fixed stack buffer so reports can be written from a signal
+/// handler without allocating
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+pub(crate) struct StackWriter {
+    buf: [u8; 256],
+    len: usize,
+}
+
+#[cfg(unix)]
+impl StackWriter {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    /// What was formatted so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Writes out what was formatted to stderr
+    pub fn flush(&mut self) {
+        write_stderr(self.as_bytes());
+        self.len = 0;
+    }
+
+    /// Appends `bytes`, cut off where the buffer ends
+    pub fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    /// Appends `n` in decimal, without going through `core::fmt`
+    pub fn push_dec(&mut self, n: usize) {
+        self.push_digits(n, 10);
+    }
+
+    /// Appends `n` in `0x` prefixed hex, without going through `core::fmt`
+    pub fn push_hex(&mut self, n: usize) {
+        self.push(b"0x");
+        self.push_digits(n, 16);
+    }
+
+    fn push_digits(&mut self, mut n: usize, radix: usize) {
+        // enough for any usize in decimal
+        let mut digits = [0; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b"0123456789abcdef"[n % radix];
+            n /= radix;
+            if n == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..]);
+    }
+}
+
+#[cfg(unix)]
+impl fmt::Write for StackWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes `bytes` to stderr with a single `write(2)`
+#[cfg(unix)]
+pub(crate) fn write_stderr(bytes: &[u8]) {
+    unsafe { libc::write(libc::STDERR_FILENO, bytes.as_ptr().cast(), bytes.len()) };
+}
+
+/// The lock's guard, recovered if poisoned, or `None` if it is held
+pub(crate) fn try_lock<G>(result: TryLockResult<G>) -> Option<G> {
+    match result {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Checks an access without blocking, allocating or formatting
+pub(crate) fn check(addr: Address, len: usize, is_write: bool, pc: Option<Address>) -> bool {
+    let mem_regions = match TRACKED_MEMORY_REGIONS.get() {
+        Some(mem_regions) => mem_regions,
+        None => return false,
+    };
+    // lock-free, the list is only ever swapped out
+    let mem_regions = mem_regions.read();
+
+    let target_span = Span::with_len(addr, len);
+    let tracker = match crate::find_region(&mem_regions, &target_span) {
+        Some(idx) => &mem_regions[idx].1,
+        None => return false,
+    };
+
+    if !is_write {
+        if let Some(tracker) = try_lock(tracker.try_read()) {
+            if tracker.check(addr, len).is_err() {
+                DETECTIONS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                report_queue::defer(Report { addr, len, pc });
+                return false;
+            }
+        }
+    }
+
+    PENDING_FETCHES.push(Report { addr, len, pc });
+    false
+}
+
+/// Tracks the fetches queued by signal-safe checks
+pub(crate) fn apply_pending() {
+    while let Some(fetch) = PENDING_FETCHES.pop() {
+        if let Some((_region, tracker)) = crate::get_memory_tracker(fetch.addr, fetch.len) {
+            crate::log_tracker_error(
+                tracker
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .track_access(fetch.addr, fetch.len),
+            );
+        }
+    }
+
+    let dropped = PENDING_FETCHES.take_dropped();
+    if dropped > 0 {
+        log::warn!(
+            "{} fetches from signal handlers not tracked, queue was full",
+            dropped
+        );
+    }
+}
+
+/// [`__asan_double_fetch_check`](crate::__asan_double_fetch_check) for use
+/// inside signal handlers
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("check_signal_safe"))]
+pub extern "C" fn __asan_double_fetch_check_signal_safe(
+    addr: Address,
+    len: usize,
+    is_write: bool,
+) -> bool {
+    crate::ffi::guard("__asan_double_fetch_check_signal_safe", false, || {
+        check(addr, len, is_write, None)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_check_is_deferred() {
+        crate::ensure_initialized();
+
+        let guard = enter().unwrap();
+        assert!(enter().is_none());
+        drop(guard);
+        assert!(enter().is_some());
+
+        let data = [0u8; 8];
+        let base = data.as_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(base, data.len());
+
+        // tracked by the next regular check at the latest
+        check(base, 4, false, None);
+        apply_pending();
+        assert!(crate::get_memory_tracker(base, 4)
+            .unwrap()
+            .1
+            .read()
+            .unwrap()
+            .check(base, 4)
+            .is_err());
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn formats_numbers_by_hand() {
+        let mut w = StackWriter::new();
+        w.push_dec(0);
+        w.push(b" ");
+        w.push_dec(usize::MAX);
+        w.push(b" ");
+        w.push_hex(0x7f3a_2c00_1000);
+        assert_eq!(
+            w.as_bytes(),
+            format!("0 {} 0x7f3a2c001000", usize::MAX).as_bytes()
+        );
+
+        // cut off where the buffer ends
+        for _ in 0..100 {
+            w.push(b"abc");
+        }
+        assert_eq!(w.as_bytes().len(), 256);
+    }
+}
@@ -0,0 +1,282 @@
+//! Snapshots of first-fetched bytes
+//!
+//! With `reporting=observed-change` or `both`, every watched region keeps a
+//! copy of its bytes as they were when first fetched, so a re-fetch can tell
+//! whether they actually changed in between instead of only that they were
+//! read twice. Bytes the runtime mutates itself are updated in the copy, so
+//! its own writes don't count as changes on the next re-fetch.
+//!
+//! Bytes are copied when their granule is fetched for the first time, not
+//! when it is written or fetched again, and carried over when regions are
+//! merged. Only pages that have been fetched from are allocated, and the
+//! copy is dropped along with the region's access history.
+//!
+//! Harnesses can read the copy back with
+//! [`__asan_double_fetch_first_fetched`], e.g. to log that a re-fetched
+//! length was 0x10 at first and is 0x7fffffff now, or to hand the target the
+//! stale value again.
+
+use core::ffi::c_int;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+
+use crate::span::Span;
+use crate::Address;
+
+/// Bytes copied together
+const PAGE: usize = 0x1000;
+
+/// A page's copy, and which of its bytes were copied
+#[derive(Debug)]
+struct Page {
+    bytes: Box<[u8]>,
+    /// One bit per byte
+    copied: Box<[u64]>,
+}
+
+impl Page {
+    fn new() -> Self {
+        Self {
+            bytes: vec![0; PAGE].into_boxed_slice(),
+            copied: vec![0; PAGE / 64].into_boxed_slice(),
+        }
+    }
+
+    fn copied(&self, i: usize) -> bool {
+        self.copied[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn copy(&mut self, i: usize, byte: u8) {
+        self.bytes[i] = byte;
+        self.copied[i / 64] |= 1 << (i % 64);
+    }
+}
+
+/// A region's first-fetched bytes
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    region: Span,
+    /// Copies of pages by index into the region, updated under the region's
+    /// shared lock on mutations
+    pages: Mutex<BTreeMap<usize, Page>>,
+}
+
+impl Snapshot {
+    pub fn new(region: &Span) -> Self {
+        Self {
+            region: region.clone(),
+            pages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Copies `[a, a + sz)`, clipped to the region, from memory
+    ///
+    /// The region must be readable.
+    pub fn record(&self, a: Address, sz: usize) {
+        let span = match Span::with_len(a, sz).intersect(&self.region) {
+            Some(span) => span,
+            None => return,
+        };
+        let data = unsafe { core::slice::from_raw_parts(span.start() as *const u8, span.len()) };
+        self.for_each_page(span.start(), span.len(), true, |page, offset, range| {
+            for (i, &byte) in data[range].iter().enumerate() {
+                page.copy(offset + i, byte);
+            }
+            true
+        });
+    }
+
+    /// Replaces the copy of those of the `data.len()` bytes at `a` that were
+    /// copied with `data`
+    pub fn store(&self, a: Address, data: &[u8]) {
+        self.for_each_page(a, data.len(), false, |page, offset, range| {
+            for (i, &byte) in data[range].iter().enumerate() {
+                if page.copied(offset + i) {
+                    page.bytes[offset + i] = byte;
+                }
+            }
+            true
+        });
+    }
+
+    /// Whether the `data.len()` bytes at `a` differ from their copy. Bytes
+    /// never copied don't.
+    pub fn differs(&self, a: Address, data: &[u8]) -> bool {
+        !self.for_each_page(a, data.len(), false, |page, offset, range| {
+            data[range]
+                .iter()
+                .enumerate()
+                .all(|(i, &byte)| !page.copied(offset + i) || page.bytes[offset + i] == byte)
+        })
+    }
+
+    /// Copies the copy of the `out.len()` bytes at `a` into `out`. Returns
+    /// false if some of them were never copied.
+    pub fn get(&self, a: Address, out: &mut [u8]) -> bool {
+        let mut copied = 0;
+        self.for_each_page(a, out.len(), false, |page, offset, range| {
+            for (i, out) in out[range].iter_mut().enumerate() {
+                if page.copied(offset + i) {
+                    *out = page.bytes[offset + i];
+                    copied += 1;
+                }
+            }
+            true
+        });
+        copied == out.len()
+    }
+
+    /// Takes over `other`'s copy of the bytes of `[a, a + sz)` it copied,
+    /// e.g. from a region merged into this one
+    pub fn take_over(&self, other: &Snapshot, a: Address, sz: usize) {
+        other.for_each_page(a, sz, false, |from, from_offset, range| {
+            // both relative to `a + range.start`, which is at `from_offset`
+            // in `from`
+            self.for_each_page(a + range.start, range.len(), true, |to, to_offset, part| {
+                for i in part.clone() {
+                    if from.copied(from_offset + i) {
+                        to.copy(to_offset + i - part.start, from.bytes[from_offset + i]);
+                    }
+                }
+                true
+            });
+            true
+        });
+    }
+
+    pub fn clear(&self) {
+        self.pages().clear();
+    }
+
+    fn pages(&self) -> std::sync::MutexGuard<'_, BTreeMap<usize, Page>> {
+        self.pages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Calls `f` with each page's copy, the offset into it and the range of
+    /// `[a, a + sz)` it covers, until `f` returns false. Returns whether it
+    /// never did. Missing pages are allocated if `allocate`, else skipped.
+    fn for_each_page(
+        &self,
+        a: Address,
+        sz: usize,
+        allocate: bool,
+        mut f: impl FnMut(&mut Page, usize, core::ops::Range<usize>) -> bool,
+    ) -> bool {
+        let span = match Span::with_len(a, sz).intersect(&self.region) {
+            Some(span) => span,
+            None => return true,
+        };
+        let mut pages = self.pages();
+        let mut at = span.start();
+        while at < span.end() {
+            let offset = at - self.region.start();
+            let (index, in_page) = (offset / PAGE, offset % PAGE);
+            let len = (PAGE - in_page).min(span.end() - at);
+            let page = if allocate {
+                Some(pages.entry(index).or_insert_with(Page::new))
+            } else {
+                pages.get_mut(&index)
+            };
+            if let Some(page) = page {
+                if !f(page, in_page, at - a..at - a + len) {
+                    return false;
+                }
+            }
+            at += len;
+        }
+        true
+    }
+}
+
+/// Copies the `len` bytes at `addr`, as they were when first fetched, to
+/// `out`. Returns 0, or -1 if `out` is null, the bytes aren't all in one
+/// watched region and fetched from it before, or the `reporting` option is
+/// `strict`, which keeps no copies.
+///
+/// Not to be called from a mutation hook, which runs with the region's
+/// tracker locked.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing `len` bytes.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("first_fetched"))]
+pub unsafe extern "C" fn __asan_double_fetch_first_fetched(
+    addr: Address,
+    len: usize,
+    out: *mut u8,
+) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_first_fetched", -1, || {
+        if out.is_null() {
+            return -1;
+        }
+        let tracker = match crate::get_memory_tracker(addr, len) {
+            Some((_region, tracker)) => tracker,
+            None => return -1,
+        };
+        let tracker = tracker.read().unwrap_or_else(PoisonError::into_inner);
+        let out = core::slice::from_raw_parts_mut(out, len);
+        if tracker.first_fetched(addr, out) {
+            0
+        } else {
+            -1
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_changed_bytes_apart() {
+        let buf = Box::leak(Box::new([7u8; 0x2800]));
+        let addr = buf.as_ptr() as Address;
+        let snapshot = Snapshot::new(&Span::with_len(addr, buf.len()));
+
+        // straddling a page
+        snapshot.record(addr + 0xff8, 0x10);
+        buf[0x1002] = 8;
+        let now = |buf: &[u8; 0x2800]| buf[0xff8..0x1008].to_vec();
+        assert!(snapshot.differs(addr + 0xff8, &now(buf)));
+        assert!(!snapshot.differs(addr + 0xff8, &now(buf)[..8]));
+
+        snapshot.store(addr + 0x1002, &[8]);
+        assert!(!snapshot.differs(addr + 0xff8, &now(buf)));
+
+        let mut first = [0; 0x10];
+        assert!(snapshot.get(addr + 0xff8, &mut first));
+        assert_eq!(first[..10], [7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
+        assert_eq!(first[10], 8);
+
+        // on a copied page, but not copied themselves
+        assert!(!snapshot.get(addr + 0x1008, &mut first[..4]));
+        snapshot.store(addr + 0x1008, &[9]);
+        assert!(!snapshot.differs(addr + 0x1008, &[1]));
+
+        // never fetched
+        assert!(!snapshot.differs(addr + 0x2100, &[1, 2]));
+        assert!(!snapshot.get(addr + 0x2100, &mut first[..2]));
+        // past the region
+        assert!(!snapshot.get(addr + 0x27f8, &mut first));
+        snapshot.clear();
+        assert!(!snapshot.differs(addr + 0xff8, &[0; 0x10]));
+    }
+
+    #[test]
+    fn null_out() {
+        crate::ensure_initialized();
+
+        let data = Box::leak(Box::new([0u8; 8]));
+        let base = data.as_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(base, data.len());
+        crate::check_access(base, 4, false, None);
+
+        let ret = unsafe { __asan_double_fetch_first_fetched(base, 0, core::ptr::null_mut()) };
+        assert_eq!(ret, -1);
+        let ret = unsafe { __asan_double_fetch_first_fetched(base, 4, core::ptr::null_mut()) };
+        assert_eq!(ret, -1);
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+}
@@ -1,15 +1,65 @@
 use core::cmp::Ordering;
+#[cfg(feature = "serde")]
+use core::convert::TryFrom;
 use core::fmt;
 use core::ops::Range;
 
+use crate::address::AddressType;
 use crate::Address;
 
+/// A half-open range of addresses, `start..end`
+///
+/// Converts to and from `Range`, iterates over the addresses it covers,
+/// and with the `serde` feature (de)serializes as `{ "start": .., "end": .. }`.
+///
+/// A span never ends before it starts. [`Span::checked_new`] and
+/// [`Span::checked_with_len`] return `None` for bounds that would break
+/// that; the other constructors are for bounds known to be good, and debug
+/// builds assert they are when the span is measured or converted.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
-pub struct Span(Range<Address>);
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "SpanRepr<A>", into = "SpanRepr<A>")
+)]
+pub struct Span<A: AddressType = Address>(Range<A>);
 
+/// Serialized form of a [`Span`]
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SpanRepr<A> {
+    start: A,
+    end: A,
+}
+
+#[cfg(feature = "serde")]
+impl<A: AddressType> TryFrom<SpanRepr<A>> for Span<A> {
+    type Error = &'static str;
+
+    fn try_from(repr: SpanRepr<A>) -> Result<Self, Self::Error> {
+        Self::checked_new(repr.start, repr.end).ok_or("span ends before it starts")
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<A: AddressType> From<Span<A>> for SpanRepr<A> {
+    fn from(span: Span<A>) -> Self {
+        SpanRepr {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+/// Where another span lies relative to a span, from [`Span::relation`]
+///
+/// Zero-length spans contain no bytes, so they relate to nothing, not even
+/// an identical zero-length span.
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub enum SpanRelation {
     None,
+    /// Both spans cover exactly the same bytes
+    Equal,
     AdjacentStart,
     AdjacentEnd,
     OverlapStart,
@@ -18,29 +68,119 @@ pub enum SpanRelation {
     Break,
 }
 
-impl Span {
-    pub const fn new(start: Address, end: Address) -> Self {
+impl<A: AddressType> Span<A> {
+    pub const fn new(start: A, end: A) -> Self {
         Self(start..end)
     }
 
-    pub const fn with_len(start: Address, sz: usize) -> Self {
+    /// `start..end`, or `None` if `end` comes before `start`
+    pub fn checked_new(start: A, end: A) -> Option<Self> {
+        if start <= end {
+            Some(Self::new(start, end))
+        } else {
+            None
+        }
+    }
+
+    /// The `sz` bytes from `start`, cut short at the end of the address
+    /// space
+    pub fn with_len(start: A, sz: A) -> Self {
         Self(start..start.saturating_add(sz))
     }
 
-    pub const fn start(&self) -> Address {
+    /// The `sz` bytes from `start`, or `None` if they'd run past the end of
+    /// the address space
+    pub fn checked_with_len(start: A, sz: A) -> Option<Self> {
+        let span = Self::with_len(start, sz);
+        if span.len() == sz {
+            Some(span)
+        } else {
+            None
+        }
+    }
+
+    pub const fn start(&self) -> A {
         self.0.start
     }
 
-    pub const fn end(&self) -> Address {
+    pub const fn end(&self) -> A {
         self.0.end
     }
 
-    pub const fn len(&self) -> usize {
-        self.end() - self.start()
+    pub fn len(&self) -> A {
+        debug_assert!(
+            self.is_well_formed(),
+            "span {:x?} ends before it starts",
+            self.0
+        );
+        self.end().saturating_sub(self.start())
+    }
+
+    fn is_well_formed(&self) -> bool {
+        self.start() <= self.end()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start() >= self.end()
+    }
+
+    /// Whether `addr` falls within the span
+    pub fn contains(&self, addr: A) -> bool {
+        self.start() <= addr && addr < self.end()
+    }
+
+    /// The bytes both spans cover, or `None` if they share none
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        let start = self.start().max(other.start());
+        let end = self.end().min(other.end());
+
+        if start < end {
+            Some(Self::new(start, end))
+        } else {
+            None
+        }
+    }
+
+    /// The span covering both spans, or `None` if there is a gap between
+    /// them. An empty span contributes nothing.
+    pub fn union(&self, other: &Self) -> Option<Self> {
+        if other.is_empty() {
+            return Some(self.clone());
+        }
+        if self.is_empty() {
+            return Some(other.clone());
+        }
+        if self.start() > other.end() || other.start() > self.end() {
+            return None;
+        }
+
+        Some(Self::new(
+            self.start().min(other.start()),
+            self.end().max(other.end()),
+        ))
+    }
+
+    /// Splits the span into the bytes before `addr` and the rest. `addr` is
+    /// clamped to the span, so one side may be empty.
+    pub fn split_at(&self, addr: A) -> (Self, Self) {
+        let at = addr.max(self.start()).min(self.end());
+
+        (Self::new(self.start(), at), Self::new(at, self.end()))
+    }
+
+    /// Number of bytes both spans cover
+    pub fn overlap_len(&self, other: &Self) -> A {
+        self.intersect(other)
+            .map_or(A::ZERO, |overlap| overlap.len())
     }
 
     pub fn relation(&self, other: &Self) -> SpanRelation {
-        if other.start() <= self.start() && other.end() >= self.end() {
+        if self.is_empty() || other.is_empty() {
+            // empty spans have no bytes to relate
+            SpanRelation::None
+        } else if other.start() == self.start() && other.end() == self.end() {
+            SpanRelation::Equal
+        } else if other.start() <= self.start() && other.end() >= self.end() {
             // other span is engulfs redzone span
             SpanRelation::Engulf
         } else if other.end() == self.start() {
@@ -55,8 +195,8 @@ impl Span {
         } else if other.end() > self.end() && other.start() < self.end() {
             // other span overlaps right edge
             SpanRelation::OverlapEnd
-        } else if other.start() > self.start() && other.end() < self.end() {
-            // other span breaks an existing
+        } else if other.start() >= self.start() && other.end() <= self.end() {
+            // other span breaks an existing, possibly sharing an edge with it
             SpanRelation::Break
         } else {
             // span does not overlap
@@ -65,19 +205,79 @@ impl Span {
     }
 }
 
-impl fmt::Display for Span {
+impl<A: AddressType> fmt::Display for Span<A> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "0x{:016x}..0x{:016x}", self.start(), self.end())
     }
 }
 
-impl PartialOrd for Span {
+impl<A: AddressType> From<Range<A>> for Span<A> {
+    fn from(range: Range<A>) -> Self {
+        let span = Self(range);
+        debug_assert!(
+            span.is_well_formed(),
+            "range {:x?} ends before it starts",
+            span.0
+        );
+        span
+    }
+}
+
+impl<A: AddressType> From<Span<A>> for Range<A> {
+    fn from(span: Span<A>) -> Self {
+        span.0
+    }
+}
+
+/// Iterator over the addresses of a [`Span`], in ascending order
+#[derive(Clone, Debug)]
+pub struct Addresses<A: AddressType> {
+    next: A,
+    end: A,
+}
+
+impl<A: AddressType> Iterator for Addresses<A> {
+    type Item = A;
+
+    fn next(&mut self) -> Option<A> {
+        if self.next >= self.end {
+            return None;
+        }
+
+        let addr = self.next;
+        self.next = addr.saturating_add(A::ONE);
+        Some(addr)
+    }
+}
+
+impl<A: AddressType> IntoIterator for Span<A> {
+    type Item = A;
+    type IntoIter = Addresses<A>;
+
+    fn into_iter(self) -> Addresses<A> {
+        (&self).into_iter()
+    }
+}
+
+impl<A: AddressType> IntoIterator for &Span<A> {
+    type Item = A;
+    type IntoIter = Addresses<A>;
+
+    fn into_iter(self) -> Addresses<A> {
+        Addresses {
+            next: self.start(),
+            end: self.end(),
+        }
+    }
+}
+
+impl<A: AddressType> PartialOrd for Span<A> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Span {
+impl<A: AddressType> Ord for Span<A> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.start().cmp(&other.start())
     }
@@ -85,8 +285,12 @@ impl Ord for Span {
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
+    type Span = super::Span<Address>;
+
     #[test]
     fn with_len() {
         let a = Span::new(0x4141, 0x4142);
@@ -123,6 +327,32 @@ mod tests {
         assert_eq!(Span::new(0x4141, 0x4242).len(), 0x4242 - 0x4141);
     }
 
+    #[test]
+    fn checked() {
+        assert_eq!(
+            Span::checked_new(0x4141, 0x4141),
+            Some(Span::new(0x4141, 0x4141))
+        );
+        assert_eq!(Span::checked_new(0x4242, 0x4141), None);
+
+        assert_eq!(
+            Span::checked_with_len(0x4141, 0x101),
+            Some(Span::new(0x4141, 0x4242))
+        );
+        assert_eq!(
+            Span::checked_with_len(usize::MAX - 1, 1),
+            Some(Span::new(usize::MAX - 1, usize::MAX))
+        );
+        assert_eq!(Span::checked_with_len(usize::MAX - 1, 2), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "ends before it starts")]
+    fn inverted_len() {
+        Span::new(0x4242, 0x4141).len();
+    }
+
     #[test]
     fn break_engulf() {
         let a = Span::new(0, 0xffff);
@@ -130,6 +360,12 @@ mod tests {
 
         assert_eq!(a.relation(&b), SpanRelation::Break);
         assert_eq!(b.relation(&a), SpanRelation::Engulf);
+
+        // sharing an edge
+        for b in [Span::new(0, 0x4242), Span::new(0x4141, 0xffff)] {
+            assert_eq!(a.relation(&b), SpanRelation::Break);
+            assert_eq!(b.relation(&a), SpanRelation::Engulf);
+        }
     }
 
     #[test]
@@ -150,6 +386,116 @@ mod tests {
         assert_eq!(b.relation(&a), SpanRelation::OverlapStart);
     }
 
+    #[test]
+    fn generic_address() {
+        let a = super::Span::<u32>::with_len(0xffff_fff0, 0x20);
+        let b = super::Span::<u32>::new(0xffff_ff00, 0xffff_fff8);
+
+        assert_eq!(a.end(), u32::MAX);
+        assert_eq!(a.len(), 0xf);
+        assert_eq!(a.relation(&b), SpanRelation::OverlapStart);
+    }
+
+    #[test]
+    fn equal() {
+        let a = Span::new(0x4141, 0x4242);
+
+        assert_eq!(a.relation(&a.clone()), SpanRelation::Equal);
+        // sharing one edge is not enough
+        assert_eq!(a.relation(&Span::new(0x4141, 0x4243)), SpanRelation::Engulf);
+    }
+
+    #[test]
+    fn empty() {
+        let a = Span::new(0x4141, 0x4242);
+        let inside = Span::with_len(0x4150, 0);
+
+        assert!(inside.is_empty());
+        assert_eq!(a.relation(&inside), SpanRelation::None);
+        assert_eq!(inside.relation(&a), SpanRelation::None);
+        assert_eq!(inside.relation(&inside), SpanRelation::None);
+        // adjacent to nothing either
+        assert_eq!(a.relation(&Span::with_len(0x4242, 0)), SpanRelation::None);
+    }
+
+    #[test]
+    fn contains() {
+        let a = Span::new(0x4141, 0x4242);
+
+        assert!(a.contains(0x4141));
+        assert!(a.contains(0x4241));
+        assert!(!a.contains(0x4242));
+        assert!(!Span::new(0x4141, 0x4141).contains(0x4141));
+    }
+
+    #[test]
+    fn intersect() {
+        let a = Span::new(0x4040, 0x4150);
+        let b = Span::new(0x4141, 0x4242);
+
+        assert_eq!(a.intersect(&b), Some(Span::new(0x4141, 0x4150)));
+        assert_eq!(b.intersect(&a), Some(Span::new(0x4141, 0x4150)));
+        assert_eq!(a.overlap_len(&b), 0xf);
+
+        let c = Span::new(0x4242, 0x4343);
+        assert_eq!(b.intersect(&c), None);
+        assert_eq!(b.overlap_len(&c), 0);
+    }
+
+    #[test]
+    fn union() {
+        let a = Span::new(0x4040, 0x4141);
+        let b = Span::new(0x4141, 0x4242);
+
+        // adjacent spans are contiguous
+        assert_eq!(a.union(&b), Some(Span::new(0x4040, 0x4242)));
+        assert_eq!(a.union(&Span::new(0x4200, 0x4300)), None);
+        assert_eq!(a.union(&Span::with_len(0x9999, 0)), Some(a.clone()));
+    }
+
+    #[test]
+    fn split_at() {
+        let a = Span::new(0x4141, 0x4242);
+
+        assert_eq!(
+            a.split_at(0x4200),
+            (Span::new(0x4141, 0x4200), Span::new(0x4200, 0x4242))
+        );
+        let (before, rest) = a.split_at(0);
+        assert!(before.is_empty());
+        assert_eq!(rest, a);
+    }
+
+    #[test]
+    fn range_conversions() {
+        let a = Span::from(0x4141..0x4242);
+
+        assert_eq!(a, Span::new(0x4141, 0x4242));
+        assert_eq!(core::ops::Range::from(a), 0x4141..0x4242);
+    }
+
+    #[test]
+    fn addresses() {
+        let a = Span::with_len(0x4141, 3);
+
+        assert_eq!(a.into_iter().collect::<Vec<_>>(), [0x4141, 0x4142, 0x4143]);
+        assert_eq!(Span::new(0x4141, 0x4141).into_iter().count(), 0);
+        assert_eq!(
+            super::Span::<u8>::new(0xfe, 0xff).into_iter().last(),
+            Some(0xfe)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let a = Span::new(0x4141, 0x4242);
+        let json = serde_json::to_string(&a).unwrap();
+
+        assert_eq!(json, r#"{"start":16705,"end":16962}"#);
+        assert_eq!(serde_json::from_str::<Span>(&json).unwrap(), a);
+    }
+
     #[test]
     fn na() {
         let a = Span::new(0x4141, 0x4242);
@@ -158,4 +504,59 @@ mod tests {
         assert_eq!(a.relation(&b), SpanRelation::None);
         assert_eq!(b.relation(&a), SpanRelation::None);
     }
+
+    /// Spans over a small range of `u8`s, so they meet often and some end
+    /// at the end of the address space
+    fn small_span() -> impl Strategy<Value = super::Span<u8>> {
+        (0xe0u8.., 0u8..0x20).prop_map(|(start, len)| super::Span::with_len(start, len))
+    }
+
+    proptest! {
+        #[test]
+        fn relation_is_consistent(a in small_span(), b in small_span()) {
+            use SpanRelation::*;
+
+            let (a_start, a_end, b_start, b_end) = (a.start(), a.end(), b.start(), b.end());
+            // the one relation `b` has to `a`, worked out from the bounds
+            let expected = if a.is_empty() || b.is_empty() {
+                None
+            } else if (a_start, a_end) == (b_start, b_end) {
+                Equal
+            } else if b_start <= a_start && b_end >= a_end {
+                Engulf
+            } else if b_start >= a_start && b_end <= a_end {
+                Break
+            } else if b_end == a_start {
+                AdjacentStart
+            } else if b_start == a_end {
+                AdjacentEnd
+            } else if b_start < a_start && b_end > a_start {
+                OverlapStart
+            } else if b_start < a_end && b_end > a_end {
+                OverlapEnd
+            } else {
+                None
+            };
+            prop_assert_eq!(a.relation(&b), expected);
+
+            let converse = match expected {
+                Engulf => Break,
+                Break => Engulf,
+                AdjacentStart => AdjacentEnd,
+                AdjacentEnd => AdjacentStart,
+                OverlapStart => OverlapEnd,
+                OverlapEnd => OverlapStart,
+                symmetric => symmetric,
+            };
+            prop_assert_eq!(b.relation(&a), converse);
+
+            // spans relate exactly when they share bytes or touch
+            let touching = !a.is_empty() && !b.is_empty() && a.union(&b).is_some();
+            prop_assert_eq!(expected != None, touching);
+            prop_assert_eq!(
+                a.intersect(&b).is_some(),
+                touching && !matches!(expected, AdjacentStart | AdjacentEnd)
+            );
+        }
+    }
 }
@@ -0,0 +1,120 @@
+//! Stale lengths reaching copies
+//!
+//! The classic double fetch reads a length out of shared memory, validates
+//! it, and reads it again to size a copy. When a 2, 4 or 8-byte span is
+//! double fetched, the integer it held before and after any mutation is
+//! remembered for the fetching thread; a later `memcpy` or `memmove`
+//! through the interceptors of exactly that many bytes is reported as a
+//! stale length used for a copy, a finding far likelier to be exploitable
+//! than the double fetch alone.
+
+use std::cell::RefCell;
+
+use crate::{config, mutation, region_names, Address};
+
+/// Double-fetched values each thread remembers, the oldest being replaced
+const REMEMBERED: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+struct Fetched {
+    addr: Address,
+    pc: Option<Address>,
+    /// The value before and after mutating it
+    values: [u64; 2],
+}
+
+#[derive(Default)]
+struct Recent {
+    fetched: [Option<Fetched>; REMEMBERED],
+    next: usize,
+}
+
+thread_local! {
+    static RECENT: RefCell<Recent> = RefCell::new(Recent::default());
+}
+
+/// The integer in `data`, if it's sized like a length
+pub(crate) fn value(data: &[u8]) -> Option<u64> {
+    match data.len() {
+        2 | 4 | 8 => Some(mutation::read_int(data, config::get().endianness)),
+        _ => None,
+    }
+}
+
+/// Remembers the double fetch of `addr`, whose span held `before` and,
+/// after mutating it, `after`
+pub(crate) fn record(addr: Address, pc: Option<Address>, before: Option<u64>, after: Option<u64>) {
+    let (before, after) = match (before, after) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return,
+    };
+    let _ = RECENT.try_with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let next = recent.next;
+        recent.fetched[next] = Some(Fetched {
+            addr,
+            pc,
+            values: [before, after],
+        });
+        recent.next = (next + 1) % REMEMBERED;
+    });
+}
+
+/// Reports a copy of `n` bytes from `src` to `dst` by `function` if `n` was
+/// double fetched on this thread, forgetting the fetch. Returns whether it
+/// was.
+pub(crate) fn check_copy(function: &str, src: Address, dst: Address, n: usize) -> bool {
+    let fetched = RECENT
+        .try_with(|recent| {
+            let mut recent = recent.borrow_mut();
+            let slot = recent.fetched.iter_mut().find(|fetched| {
+                fetched.is_some_and(|fetched| fetched.values.contains(&(n as u64)))
+            })?;
+            slot.take()
+        })
+        .ok()
+        .flatten();
+    let fetched = match fetched {
+        Some(fetched) => fetched,
+        None => return false,
+    };
+
+    let at = region_names::locate(fetched.addr).map_or_else(String::new, |at| format!(" ({})", at));
+    log::error!(
+        "stale length used for copy! {} of {:#X} bytes from {:#X} to {:#X}, double-fetched at addr: {:#X}{}{}",
+        function,
+        n,
+        src,
+        dst,
+        fetched.addr,
+        at,
+        fetched
+            .pc
+            .map_or_else(String::new, |pc| format!(", pc: {:#X}", pc))
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_copies_of_stale_lengths() {
+        let header = Box::leak(Box::new(0x20u32.to_ne_bytes()));
+        let addr = header.as_ptr() as Address;
+        crate::__asan_watch_shared_memory_region(addr, 4);
+        crate::__asan_double_fetch_check(addr, 4, false);
+        crate::__asan_double_fetch_check(addr, 4, false);
+        crate::__asan_unwatch_shared_memory_region(addr);
+
+        // not an off-by-one, which the mutation may have picked
+        assert!(!check_copy("memcpy", 0x1000, 0x2000, 0x30));
+        assert!(check_copy("memcpy", 0x1000, 0x2000, 0x20));
+        // reported once
+        assert!(!check_copy("memcpy", 0x1000, 0x2000, 0x20));
+
+        assert_eq!(value(&[1, 0]), Some(u64::from(u16::from_ne_bytes([1, 0]))));
+        assert_eq!(value(&[1, 0, 0]), None);
+    }
+}
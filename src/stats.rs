@@ -0,0 +1,283 @@
+//! Runtime statistics for harnesses
+//!
+//! The check path bumps a few relaxed counters; harnesses read them, along
+//! with what is currently being tracked, through
+//! [`__asan_double_fetch_get_stats`] to log per-iteration overhead and
+//! findings, and zero them with [`__asan_double_fetch_reset_stats`] between
+//! iterations.
+//!
+//! Every watched region also counts its own fetches, detections and
+//! mutations, read through [`__asan_double_fetch_get_region_stats`] and
+//! logged for each region still watched at shutdown, to tell which shared
+//! structures are hot and which get double-fetched.
+
+use core::ffi::c_int;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::PoisonError;
+
+use crate::region_tracker::RegionTracker;
+use crate::span::Span;
+use crate::{region_names, Address, DETECTIONS, TRACKED_MEMORY_REGIONS};
+
+/// Checks made, whether or not they hit a watched region
+pub(crate) static CHECKS: AtomicUsize = AtomicUsize::new(0);
+/// Checks that hit a watched region
+pub(crate) static REGION_HITS: AtomicUsize = AtomicUsize::new(0);
+/// Double-fetched bytes that were mutated
+pub(crate) static MUTATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters and totals filled in by [`__asan_double_fetch_get_stats`]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Checks made since init or the last reset, except for fixed-size
+    /// checks of unwatched memory, which return before counting
+    pub checks: u64,
+    /// Of those, checks that hit a watched region
+    pub region_hits: u64,
+    /// Double-fetches detected
+    pub detections: u64,
+    /// Detections whose bytes were mutated
+    pub mutations: u64,
+    /// Regions currently watched
+    pub watched_regions: u64,
+    /// Bytes currently tracked as fetched, across all watched regions
+    pub tracked_bytes: u64,
+}
+
+/// Statistics of one watched region, filled in by
+/// [`__asan_double_fetch_get_region_stats`]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RegionStats {
+    /// Start of the region
+    pub start: u64,
+    /// Length of the region
+    pub len: u64,
+    /// Fetches from the region checked since it was watched or the last reset
+    pub fetches: u64,
+    /// Of those, double-fetches detected
+    pub detections: u64,
+    /// Detections whose bytes were mutated
+    pub mutations: u64,
+    /// Bytes of the region currently tracked as fetched
+    pub tracked_bytes: u64,
+}
+
+/// Counters kept by each watched region's tracker
+#[derive(Debug, Default)]
+pub(crate) struct RegionCounters {
+    pub fetches: AtomicUsize,
+    pub detections: AtomicUsize,
+    pub mutations: AtomicUsize,
+}
+
+impl RegionCounters {
+    /// Adds `other`'s counts, of a region merged into this one
+    pub fn add(&self, other: &Self) {
+        for (counter, other) in [
+            (&self.fetches, &other.fetches),
+            (&self.detections, &other.detections),
+            (&self.mutations, &other.mutations),
+        ] {
+            counter.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [&self.fetches, &self.detections, &self.mutations] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn load(counter: &AtomicUsize) -> u64 {
+    counter.load(Ordering::Relaxed) as u64
+}
+
+/// Snapshot of the counters and of what is currently tracked
+pub(crate) fn get() -> Stats {
+    let mut stats = Stats {
+        checks: load(&CHECKS),
+        region_hits: load(&REGION_HITS),
+        detections: load(&DETECTIONS),
+        mutations: load(&MUTATIONS),
+        ..Stats::default()
+    };
+
+    if let Some(mem_regions) = TRACKED_MEMORY_REGIONS.get() {
+        let mem_regions = mem_regions.read();
+
+        stats.watched_regions = mem_regions.len() as u64;
+        stats.tracked_bytes = mem_regions
+            .iter()
+            .map(|(_span, tracker)| {
+                tracker
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .occupied_len() as u64
+            })
+            .sum();
+    }
+
+    stats
+}
+
+/// Snapshot of `region`'s counters and of what `tracker` currently tracks
+pub(crate) fn region(region: &Span, tracker: &RegionTracker) -> RegionStats {
+    let counters = tracker.counters();
+    RegionStats {
+        start: region.start() as u64,
+        len: region.len() as u64,
+        fetches: load(&counters.fetches),
+        detections: load(&counters.detections),
+        mutations: load(&counters.mutations),
+        tracked_bytes: tracker.occupied_len() as u64,
+    }
+}
+
+/// Logs `region`'s statistics, for the summary printed at shutdown
+pub(crate) fn summarize(region: &Span, tracker: &RegionTracker) {
+    let stats = self::region(region, tracker);
+    log::info!(
+        "{} at {:#X}: {} fetches, {} double-fetches, {} mutated, {:#X} of {:#X} bytes tracked",
+        region_names::name(region),
+        region.start(),
+        stats.fetches,
+        stats.detections,
+        stats.mutations,
+        stats.tracked_bytes,
+        stats.len
+    );
+}
+
+/// Fills in `*out` with the current statistics. Returns 0, or -1 if `out` is
+/// null.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing a `Stats`.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("get_stats"))]
+pub unsafe extern "C" fn __asan_double_fetch_get_stats(out: *mut Stats) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_get_stats", -1, || {
+        if out.is_null() {
+            return -1;
+        }
+
+        out.write(get());
+        0
+    })
+}
+
+/// Fills in `*out` with the statistics of the watched region containing
+/// `addr`. Returns 0, or -1 if `out` is null or no watched region contains
+/// `addr`.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing a `RegionStats`.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("get_region_stats"))]
+pub unsafe extern "C" fn __asan_double_fetch_get_region_stats(
+    addr: Address,
+    out: *mut RegionStats,
+) -> c_int {
+    crate::ffi::guard("__asan_double_fetch_get_region_stats", -1, || {
+        if out.is_null() {
+            return -1;
+        }
+        let (span, tracker) = match crate::get_memory_tracker(addr, 1) {
+            Some(found) => found,
+            None => return -1,
+        };
+
+        let tracker = tracker.read().unwrap_or_else(PoisonError::into_inner);
+        out.write(region(&span, &tracker));
+        0
+    })
+}
+
+/// Zeroes the counters, the global ones and those of every watched region.
+/// What is currently tracked is left alone.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("reset_stats"))]
+pub extern "C" fn __asan_double_fetch_reset_stats() {
+    crate::ffi::guard("__asan_double_fetch_reset_stats", (), || {
+        for counter in [&CHECKS, &REGION_HITS, &DETECTIONS, &MUTATIONS] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        if let Some(mem_regions) = TRACKED_MEMORY_REGIONS.get() {
+            for (_span, tracker) in mem_regions.read().iter() {
+                tracker
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .counters()
+                    .reset();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_checks() {
+        crate::ensure_initialized();
+
+        let data = [0u8; 8];
+        let base = data.as_ptr() as crate::Address;
+        crate::__asan_watch_shared_memory_region(base, data.len());
+
+        crate::__asan_double_fetch_check(base, 4, false);
+        crate::__asan_double_fetch_check(base, 4, false);
+
+        // other tests check concurrently, so only lower bounds hold
+        let mut stats = Stats::default();
+        assert_eq!(unsafe { __asan_double_fetch_get_stats(&mut stats) }, 0);
+        assert!(stats.checks >= 2);
+        assert!(stats.region_hits >= 2);
+        assert!(stats.detections >= 1);
+        assert!(stats.watched_regions >= 1);
+        assert!(stats.tracked_bytes >= 4);
+        assert_eq!(
+            unsafe { __asan_double_fetch_get_stats(core::ptr::null_mut()) },
+            -1
+        );
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+
+    #[test]
+    fn counts_per_region() {
+        let data = Box::leak(Box::new([0u8; 16]));
+        let base = data.as_ptr() as crate::Address;
+        crate::__asan_watch_shared_memory_region(base, data.len());
+
+        crate::__asan_double_fetch_check(base, 4, false);
+        crate::__asan_double_fetch_check(base + 8, 4, false);
+        crate::__asan_double_fetch_check(base, 4, false);
+        crate::__asan_double_fetch_check(base, 4, true);
+
+        // the region is this test's own, so its counts are exact
+        let mut stats = RegionStats::default();
+        assert_eq!(
+            unsafe { __asan_double_fetch_get_region_stats(base + 15, &mut stats) },
+            0
+        );
+        assert_eq!(stats.start, base as u64);
+        assert_eq!(stats.len, 16);
+        assert_eq!(stats.fetches, 3);
+        assert_eq!(stats.detections, 1);
+        assert!(stats.mutations <= 1);
+        assert_eq!(stats.tracked_bytes, 8);
+        assert_eq!(
+            unsafe { __asan_double_fetch_get_region_stats(base + 16, &mut stats) },
+            -1
+        );
+
+        crate::__asan_unwatch_shared_memory_region(base);
+    }
+}
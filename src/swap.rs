@@ -0,0 +1,92 @@
+//! Read-mostly list for userspace builds
+//!
+//! The userspace counterpart of the kernel's RCU list: every instrumented
+//! access looks up the region list while watch/unwatch are rare, so readers
+//! load the currently published snapshot through an [`ArcSwap`] without
+//! taking any lock, and never contend with each other. Writers serialize on
+//! a mutex, modify a private copy, and publish it atomically; the old
+//! snapshot is freed once the last reader holding it lets go.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use arc_swap::{ArcSwap, Guard};
+
+pub struct SwapList<V> {
+    current: ArcSwap<V>,
+    writer: Mutex<()>,
+}
+
+impl<V: Clone> SwapList<V> {
+    pub fn new(items: V) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(items),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The currently published list. Never blocks, though a thread's first
+    /// read may allocate its slot in `ArcSwap`'s debt list. Holding on to the
+    /// guard only keeps that snapshot alive, it doesn't block writers.
+    pub fn read(&self) -> Guard<Arc<V>> {
+        self.current.load()
+    }
+
+    /// Returns a private copy of the list that replaces the published one
+    /// when the guard is dropped
+    pub fn write(&self) -> SwapWriteGuard<'_, V> {
+        let lock = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let items = V::clone(&self.current.load());
+
+        SwapWriteGuard {
+            list: self,
+            items: Some(items),
+            _lock: lock,
+        }
+    }
+}
+
+pub struct SwapWriteGuard<'a, V> {
+    list: &'a SwapList<V>,
+    /// Only `None` while being published
+    items: Option<V>,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl<V> Deref for SwapWriteGuard<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.items.as_ref().expect("published twice")
+    }
+}
+
+impl<V> DerefMut for SwapWriteGuard<'_, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.items.as_mut().expect("published twice")
+    }
+}
+
+impl<V> Drop for SwapWriteGuard<'_, V> {
+    fn drop(&mut self) {
+        if let Some(items) = self.items.take() {
+            self.list.current.store(Arc::new(items));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readers_keep_their_snapshot() {
+        let list = SwapList::new(vec![1, 2]);
+
+        let before = list.read();
+        list.write().push(3);
+
+        assert_eq!(**before, [1, 2]);
+        assert_eq!(**list.read(), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Locking for `no_std` builds
+//!
+//! The check path is reached from atomic and interrupt context, where the
+//! sleeping `kernel::sync::Mutex` is fatal. [`SpinLock`] wraps a raw kernel
+//! spinlock instead and keeps interrupts disabled while it is held, so an
+//! interrupt handler hitting the check path can't deadlock against the code
+//! it interrupted.
+//!
+//! Bare-metal builds, `no_std` without `linux_kasan`, have no kernel to ask.
+//! There [`SpinLock`] spins on a flag inside a `critical_section`, whose
+//! implementation the platform provides, e.g. `cortex-m`'s
+//! `critical-section-single-core` feature.
+
+use core::cell::UnsafeCell;
+#[cfg(feature = "linux_kasan")]
+use core::ffi::c_ulong;
+use core::ops::{Deref, DerefMut};
+#[cfg(not(feature = "linux_kasan"))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "linux_kasan")]
+use kernel::bindings;
+
+/// A raw kernel spinlock; an all-zero `raw_spinlock_t` is unlocked
+#[cfg(feature = "linux_kasan")]
+type RawLock = bindings::raw_spinlock_t;
+/// What a held lock restores on release, the saved interrupt flags
+#[cfg(feature = "linux_kasan")]
+type RestoreState = c_ulong;
+
+#[cfg(not(feature = "linux_kasan"))]
+type RawLock = AtomicBool;
+#[cfg(not(feature = "linux_kasan"))]
+type RestoreState = critical_section::RestoreState;
+
+/// A spinlock protecting `T`, taken with interrupts disabled
+///
+/// The lock starts out all-zero, so unlike the `kernel::sync` locks this
+/// needs no pinned initialization and can be created in place, stored in
+/// statics, and moved until first use.
+pub struct SpinLock<T> {
+    lock: UnsafeCell<RawLock>,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: `data` is only reachable through a guard holding `lock`
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Disables local interrupts and spins until the lock is acquired
+    #[cfg(feature = "linux_kasan")]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let restore = unsafe { bindings::_raw_spin_lock_irqsave(self.lock.get()) };
+        SpinLockGuard {
+            lock: self,
+            restore,
+        }
+    }
+
+    /// Enters a critical section and spins until the lock is acquired.
+    /// Guards of nested locks have to be dropped in reverse order.
+    #[cfg(not(feature = "linux_kasan"))]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let restore = unsafe { critical_section::acquire() };
+        let locked = unsafe { &*self.lock.get() };
+        while locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard {
+            lock: self,
+            restore,
+        }
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Releases the lock and restores the interrupt state when dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    restore: RestoreState,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    #[cfg(feature = "linux_kasan")]
+    fn drop(&mut self) {
+        unsafe { bindings::_raw_spin_unlock_irqrestore(self.lock.lock.get(), self.restore) };
+    }
+
+    #[cfg(not(feature = "linux_kasan"))]
+    fn drop(&mut self) {
+        unsafe {
+            (*self.lock.lock.get()).store(false, Ordering::Release);
+            critical_section::release(self.restore);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    #[cfg(feature = "linux_kasan")]
+    use kernel::sim;
+
+    use super::*;
+
+    #[cfg(feature = "linux_kasan")]
+    #[test]
+    fn disables_irqs_while_held() {
+        let outer = SpinLock::new(());
+        let inner = SpinLock::new(());
+
+        let guard = outer.lock();
+        assert!(sim::irqs_disabled());
+        drop(inner.lock());
+        // releasing a nested lock restores the state it found, still disabled
+        assert!(sim::irqs_disabled());
+        drop(guard);
+        assert!(!sim::irqs_disabled());
+    }
+
+    #[test]
+    fn excludes() {
+        let counter = Arc::new(SpinLock::new(0usize));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        let mut count = counter.lock();
+                        // a read and a separate write, lost without exclusion
+                        let seen = *count;
+                        thread::yield_now();
+                        *count = seen + 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*counter.lock(), 1000);
+    }
+}
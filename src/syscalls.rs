@@ -0,0 +1,115 @@
+//! Syscall tagging for kernel builds
+//!
+//! A kernel double fetch is fixed per syscall handler, so reports name the
+//! syscall the current task was in, with the command for an ioctl, and
+//! shutdown prints how many reports each syscall got. `kernel/syscall.c`
+//! looks the syscall up.
+
+use core::ffi::{c_char, c_long, c_ulong};
+use core::fmt;
+
+use kernel::{bindings, pr_err};
+
+use crate::sync::SpinLock;
+
+/// Mirrors `struct asan_double_fetch_syscall` in `kernel/syscall.c`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Syscall {
+    pub nr: c_long,
+    /// The command of an ioctl, 0 for other syscalls
+    pub ioctl_cmd: c_ulong,
+    pub is_ioctl: bool,
+}
+
+extern "C" {
+    fn __asan_double_fetch_current_syscall(syscall: *mut Syscall) -> bool;
+}
+
+impl Syscall {
+    /// The syscall the current task is in, `None` in kernel threads and
+    /// interrupts
+    pub(crate) fn current() -> Option<Self> {
+        let mut syscall = Self::default();
+        unsafe { __asan_double_fetch_current_syscall(&mut syscall) }.then_some(syscall)
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ioctl {
+            write!(f, "ioctl {:#x}", self.ioctl_cmd)
+        } else {
+            write!(f, "syscall {}", self.nr)
+        }
+    }
+}
+
+/// Syscalls reports are counted for separately, the rest being lumped
+/// together
+const MAX_SYSCALLS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Count {
+    syscall: Syscall,
+    reports: usize,
+    /// `comm` and pid of the last task reported in it
+    comm: [c_char; 16],
+    pid: i32,
+}
+
+struct Counts {
+    counts: [Option<Count>; MAX_SYSCALLS],
+    /// Reports in syscalls that didn't fit
+    other: usize,
+}
+
+static COUNTS: SpinLock<Counts> = SpinLock::new(Counts {
+    counts: [None; MAX_SYSCALLS],
+    other: 0,
+});
+
+/// Counts a report in `syscall`, made by the current task
+pub(crate) fn count(syscall: Syscall) {
+    let task = unsafe { &*bindings::get_current() };
+    let mut counts = COUNTS.lock();
+
+    let slot = counts
+        .counts
+        .iter()
+        .position(|count| matches!(count, Some(count) if count.syscall == syscall))
+        .or_else(|| counts.counts.iter().position(Option::is_none));
+    match slot {
+        Some(slot) => {
+            let count = counts.counts[slot].get_or_insert(Count {
+                syscall,
+                reports: 0,
+                comm: [0; 16],
+                pid: 0,
+            });
+            count.reports += 1;
+            let len = count.comm.len().min(task.comm.len());
+            count.comm[..len].copy_from_slice(&task.comm[..len]);
+            count.pid = task.pid;
+        }
+        None => counts.other += 1,
+    }
+}
+
+/// Prints the reports per syscall since the last summary and starts over
+pub(crate) fn summarize() {
+    let mut counts = COUNTS.lock();
+    for count in counts.counts.iter_mut().filter_map(Option::take) {
+        pr_err!(
+            "asan-double-fetch: {} reports in {}, last by task {}/{}\n",
+            count.reports,
+            count.syscall,
+            crate::kasan::current_comm(&count.comm),
+            count.pid
+        );
+    }
+    let other = core::mem::take(&mut counts.other);
+    if other > 0 {
+        pr_err!("asan-double-fetch: {} reports in other syscalls\n", other);
+    }
+}
@@ -0,0 +1,191 @@
+//! Detections sent to the system log
+//!
+//! With the `syslog` option, instrumented daemons report detections where
+//! the rest of their logs go. Each detection is sent to journald with
+//! structured fields, so `journalctl DOUBLE_FETCH_REGION=ring` and log
+//! collectors can filter on them:
+//!
+//! ```text
+//! MESSAGE=double-fetch detected! addr: 0x7F3A2C001008 (ring+0x8), len: 0x4
+//! PRIORITY=4
+//! DOUBLE_FETCH_ADDR=0x7f3a2c001008
+//! DOUBLE_FETCH_LEN=4
+//! DOUBLE_FETCH_PC=0x55d0c2a01234
+//! DOUBLE_FETCH_REGION=ring
+//! DOUBLE_FETCH_OFFSET=0x8
+//! DOUBLE_FETCH_REGION_START=0x7f3a2c001000
+//! DOUBLE_FETCH_REGION_LEN=4096
+//! DOUBLE_FETCH_CHANGED=1
+//! ```
+//!
+//! `DOUBLE_FETCH_OFFSET` is negative, e.g. `-0x4`, for accesses starting
+//! before the region. `DOUBLE_FETCH_PC` and `DOUBLE_FETCH_CHANGED` are left
+//! out when unknown.
+//! Without journald, detections go to `syslog(3)` instead, with the fields
+//! appended to the message as `key=value` pairs.
+
+use std::ffi::CString;
+use std::io::Write as _;
+use std::os::unix::net::UnixDatagram;
+
+use once_cell::sync::OnceCell;
+
+use crate::span::Span;
+use crate::{config, region_names, Address};
+
+/// Where journald takes native protocol datagrams
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+enum Sink {
+    Journal(UnixDatagram),
+    Syslog,
+}
+
+static SINK: OnceCell<Option<Sink>> = OnceCell::new();
+
+fn sink() -> Option<&'static Sink> {
+    SINK.get_or_init(|| {
+        if !config::get().syslog {
+            return None;
+        }
+        if std::path::Path::new(JOURNAL_SOCKET).exists() {
+            match UnixDatagram::unbound() {
+                Ok(socket) => return Some(Sink::Journal(socket)),
+                Err(e) => log::error!("failed to create journal socket: {}", e),
+            }
+        }
+        unsafe { libc::openlog(core::ptr::null(), libc::LOG_PID, libc::LOG_USER) };
+        Some(Sink::Syslog)
+    })
+    .as_ref()
+}
+
+/// `addr` relative to the start of `region`, e.g. `0x8` or `-0x4`, as
+/// accesses may start before it
+fn offset(region: &Span, addr: Address) -> String {
+    let offset = addr.wrapping_sub(region.start()) as isize;
+    if offset < 0 {
+        format!("-{:#x}", offset.unsigned_abs())
+    } else {
+        format!("{:#x}", offset)
+    }
+}
+
+/// A detection's fields, as journal field names and values
+fn fields(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    changed: Option<bool>,
+) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("DOUBLE_FETCH_ADDR", format!("{:#x}", addr)),
+        ("DOUBLE_FETCH_LEN", len.to_string()),
+    ];
+    if let Some(pc) = pc {
+        fields.push(("DOUBLE_FETCH_PC", format!("{:#x}", pc)));
+    }
+    fields.push(("DOUBLE_FETCH_REGION", region_names::name(region)));
+    fields.push(("DOUBLE_FETCH_OFFSET", offset(region, addr)));
+    fields.push((
+        "DOUBLE_FETCH_REGION_START",
+        format!("{:#x}", region.start()),
+    ));
+    fields.push(("DOUBLE_FETCH_REGION_LEN", region.len().to_string()));
+    if let Some(changed) = changed {
+        fields.push(("DOUBLE_FETCH_CHANGED", u8::from(changed).to_string()));
+    }
+    fields
+}
+
+/// Appends `key=value` to a journal datagram, in the binary form if `value`
+/// spans lines
+fn append_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    if value.contains('\n') {
+        let _ = writeln!(entry, "{}", key);
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    } else {
+        let _ = writeln!(entry, "{}={}", key, value);
+    }
+}
+
+/// Sends the detection of `len` bytes at `addr` in `region` to the system
+/// log, if the `syslog` option is on. `changed` says whether the bytes
+/// changed since first fetched, if known; changed ones are errors.
+pub(crate) fn report(
+    addr: Address,
+    len: usize,
+    pc: Option<Address>,
+    region: &Span,
+    changed: Option<bool>,
+) {
+    let sink = match sink() {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    let priority = if changed == Some(true) {
+        libc::LOG_ERR
+    } else {
+        libc::LOG_WARNING
+    };
+    let message = format!(
+        "double-fetch detected! addr: {:#X} ({}), len: {:#X}",
+        addr,
+        region_names::relative(region, addr),
+        len
+    );
+    let fields = fields(addr, len, pc, region, changed);
+
+    match sink {
+        Sink::Journal(socket) => {
+            let mut entry = Vec::new();
+            append_field(&mut entry, "MESSAGE", &message);
+            append_field(&mut entry, "PRIORITY", &priority.to_string());
+            append_field(&mut entry, "SYSLOG_IDENTIFIER", "asan-double-fetch");
+            for (key, value) in &fields {
+                append_field(&mut entry, key, value);
+            }
+            if let Err(e) = socket.send_to(&entry, JOURNAL_SOCKET) {
+                log::error!("failed to send detection to journald: {}", e);
+            }
+        }
+        Sink::Syslog => {
+            let mut line = message;
+            for (key, value) in &fields {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            // region names come from the harness
+            let line = CString::new(line.replace('\0', "")).unwrap_or_default();
+            unsafe { libc::syslog(priority, "%s\0".as_ptr().cast(), line.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_fields() {
+        let region = Span::with_len(0x1000, 0x100);
+        let fields = fields(0x1008, 4, None, &region, Some(true));
+        assert!(fields.iter().all(|(key, _)| *key != "DOUBLE_FETCH_PC"));
+        assert!(fields.contains(&("DOUBLE_FETCH_OFFSET", "0x8".to_owned())));
+        assert!(fields.contains(&("DOUBLE_FETCH_CHANGED", "1".to_owned())));
+
+        let straddling = super::fields(0x0ffc, 8, None, &region, None);
+        assert!(straddling.contains(&("DOUBLE_FETCH_OFFSET", "-0x4".to_owned())));
+
+        let mut entry = Vec::new();
+        append_field(&mut entry, "DOUBLE_FETCH_LEN", "4");
+        append_field(&mut entry, "DOUBLE_FETCH_REGION", "a\nb");
+        assert_eq!(
+            entry,
+            b"DOUBLE_FETCH_LEN=4\nDOUBLE_FETCH_REGION\n\x03\0\0\0\0\0\0\0a\nb\n"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Structured `tracing` telemetry for Rust embedders
+//!
+//! With the `tracing` feature, the runtime emits events for watching and
+//! unwatching regions and for detections, and enters a span for every check
+//! that hits a watched region, so a harness can route them through the
+//! subscriber it already has instead of scraping stdout. Regions are
+//! identified by their start address; offsets are relative to it, and
+//! negative for accesses starting before it.
+//!
+//! | Target | Level | Fields |
+//! |---|---|---|
+//! | `watch` event | info | `region`, `len`, `granularity` |
+//! | `unwatch` event | info | `region`, `len` |
+//! | `check` span | trace | `region`, `offset`, `len`, `is_write` |
+//! | `detection` event | warn | `region`, `offset`, `len`, `pc` when known |
+//!
+//! Without a subscriber interested in them, these cost a relaxed load each.
+
+use crate::span::Span;
+use crate::Address;
+
+/// `addr` relative to the start of `region`; accesses may start before it
+fn offset(region: &Span, addr: Address) -> isize {
+    addr.wrapping_sub(region.start()) as isize
+}
+
+pub(crate) fn watch(region: &Span, granularity: usize) {
+    tracing::info!(
+        target: "asan_double_fetch::watch",
+        region = region.start(),
+        len = region.len(),
+        granularity,
+        "watching region"
+    );
+}
+
+pub(crate) fn unwatch(region: &Span) {
+    tracing::info!(
+        target: "asan_double_fetch::unwatch",
+        region = region.start(),
+        len = region.len(),
+        "unwatching region"
+    );
+}
+
+/// Enters the span a check of `[addr, addr + len)` inside `region` runs in
+pub(crate) fn check(
+    region: &Span,
+    addr: Address,
+    len: usize,
+    is_write: bool,
+) -> tracing::span::EnteredSpan {
+    tracing::trace_span!(
+        target: "asan_double_fetch::check",
+        "check",
+        region = region.start(),
+        offset = offset(region, addr),
+        len,
+        is_write
+    )
+    .entered()
+}
+
+pub(crate) fn detection(region: &Span, addr: Address, len: usize, pc: Option<Address>) {
+    tracing::warn!(
+        target: "asan_double_fetch::detection",
+        region = region.start(),
+        offset = offset(region, addr),
+        len,
+        pc,
+        "double fetch detected"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the target and fields of every event and span
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut line = span.metadata().target().to_owned();
+            span.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut line = event.metadata().target().to_owned();
+            event.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn emits_events() {
+        crate::ensure_initialized();
+
+        let data = [0u8; 8];
+        let base = data.as_ptr() as crate::Address;
+        let recorder = Recorder::default();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            crate::__asan_watch_shared_memory_region(base, data.len());
+            crate::__asan_double_fetch_check(base + 2, 4, false);
+            crate::__asan_double_fetch_check(base + 4, 2, false);
+            crate::__asan_unwatch_shared_memory_region(base);
+        });
+
+        let lines = recorder.0.lock().unwrap();
+        let has = |line: String| lines.contains(&line);
+        assert!(has(format!(
+            "asan_double_fetch::watch message=watching region region={} len=8 granularity=1",
+            base
+        )));
+        assert!(has(format!(
+            "asan_double_fetch::check region={} offset=4 len=2 is_write=false",
+            base
+        )));
+        assert!(has(format!(
+            "asan_double_fetch::detection message=double fetch detected region={} offset=4 len=2",
+            base
+        )));
+        assert!(has(format!(
+            "asan_double_fetch::unwatch message=unwatching region region={} len=8",
+            base
+        )));
+    }
+}
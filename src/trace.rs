@@ -0,0 +1,431 @@
+//! Binary recording of the runtime's events
+//!
+//! With the `trace_recorder` feature and the `trace_file` option set to a
+//! path, every watch, unwatch, check of a watched region and shutdown is
+//! appended to that file, so analyses can be re-run offline and traces
+//! attached to bug reports. [`Reader`] parses them back.
+//!
+//! # Format
+//!
+//! The file starts with the magic `ADFTRACE` and a version byte, followed by
+//! records. Integers are unsigned LEB128 varints. Every record is a tag
+//! byte, the nanoseconds since the previous record (the first one counts
+//! from when recording started), a small per-thread id, and then per tag:
+//!
+//! | Tag | Event | Fields |
+//! |---|---|---|
+//! | `0x01` | watch | address, length, granularity |
+//! | `0x02` | unwatch | address |
+//! | `0x03` | shutdown | |
+//! | `0x10` to `0x13` | check | address, length, PC + 1 or 0 if unknown |
+//!
+//! Check tags carry flags in their low bits: `0x1` for writes and `0x2` if
+//! the runtime reported the check as a double fetch.
+
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+
+use crate::{config, Address};
+
+/// Start of every trace file
+pub const MAGIC: &[u8; 8] = b"ADFTRACE";
+/// Version of the format written
+pub const VERSION: u8 = 1;
+
+const WATCH: u8 = 0x01;
+const UNWATCH: u8 = 0x02;
+const SHUTDOWN: u8 = 0x03;
+const CHECK: u8 = 0x10;
+const CHECK_WRITE: u8 = 0x01;
+const CHECK_DOUBLE_FETCH: u8 = 0x02;
+
+/// Something the runtime did
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    Watch {
+        addr: Address,
+        len: usize,
+        granularity: usize,
+    },
+    Unwatch {
+        addr: Address,
+    },
+    /// [`__asan_shared_memory_region_shutdown`](crate::__asan_shared_memory_region_shutdown)
+    /// forgot every region
+    Shutdown,
+    /// An access to a watched region was checked
+    Check {
+        addr: Address,
+        len: usize,
+        pc: Option<Address>,
+        is_write: bool,
+        /// Whether the runtime reported it
+        double_fetch: bool,
+    },
+}
+
+/// An [`Event`] with when and where it happened
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Nanoseconds since recording started
+    pub time_ns: u64,
+    /// Id of the thread, numbered from 0 in order of first event
+    pub thread: u32,
+    pub event: Event,
+}
+
+fn write_varint<W: Write>(w: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut n = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[n] = byte;
+            n += 1;
+            break;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+    w.write_all(&buf[..n])
+}
+
+/// Writes records in the trace format
+pub struct Writer<W: Write> {
+    inner: W,
+    last_ns: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header to `inner`
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(Self { inner, last_ns: 0 })
+    }
+
+    /// Appends `record`. Records must be written in time order.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let tag = match record.event {
+            Event::Watch { .. } => WATCH,
+            Event::Unwatch { .. } => UNWATCH,
+            Event::Shutdown => SHUTDOWN,
+            Event::Check {
+                is_write,
+                double_fetch,
+                ..
+            } => {
+                let mut tag = CHECK;
+                if is_write {
+                    tag |= CHECK_WRITE;
+                }
+                if double_fetch {
+                    tag |= CHECK_DOUBLE_FETCH;
+                }
+                tag
+            }
+        };
+
+        self.inner.write_all(&[tag])?;
+        write_varint(&mut self.inner, record.time_ns.saturating_sub(self.last_ns))?;
+        self.last_ns = self.last_ns.max(record.time_ns);
+        write_varint(&mut self.inner, record.thread.into())?;
+
+        match record.event {
+            Event::Watch {
+                addr,
+                len,
+                granularity,
+            } => {
+                write_varint(&mut self.inner, addr as u64)?;
+                write_varint(&mut self.inner, len as u64)?;
+                write_varint(&mut self.inner, granularity as u64)
+            }
+            Event::Unwatch { addr } => write_varint(&mut self.inner, addr as u64),
+            Event::Shutdown => Ok(()),
+            Event::Check { addr, len, pc, .. } => {
+                write_varint(&mut self.inner, addr as u64)?;
+                write_varint(&mut self.inner, len as u64)?;
+                write_varint(&mut self.inner, pc.map_or(0, |pc| pc as u64 + 1))
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads records in the trace format
+pub struct Reader<R: Read> {
+    inner: R,
+    last_ns: u64,
+}
+
+impl<R: Read> Reader<R> {
+    /// Checks the file header at the start of `inner`
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; 9];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a double-fetch trace",
+            ));
+        }
+        if header[8] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported trace version {}", header[8]),
+            ));
+        }
+
+        Ok(Self { inner, last_ns: 0 })
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.inner.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "varint too long",
+        ))
+    }
+
+    fn address(&mut self) -> io::Result<usize> {
+        let value = self.varint()?;
+        usize::try_from(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "address out of range"))
+    }
+
+    /// The next record, or `None` at the end of the trace
+    pub fn read(&mut self) -> io::Result<Option<Record>> {
+        let tag = match self.byte() {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.last_ns = self.last_ns.saturating_add(self.varint()?);
+        let thread = u32::try_from(self.varint()?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "thread id out of range"))?;
+
+        let event = match tag {
+            WATCH => Event::Watch {
+                addr: self.address()?,
+                len: self.address()?,
+                granularity: self.address()?,
+            },
+            UNWATCH => Event::Unwatch {
+                addr: self.address()?,
+            },
+            SHUTDOWN => Event::Shutdown,
+            _ if tag & !(CHECK_WRITE | CHECK_DOUBLE_FETCH) == CHECK => Event::Check {
+                addr: self.address()?,
+                len: self.address()?,
+                pc: self.address()?.checked_sub(1),
+                is_write: tag & CHECK_WRITE != 0,
+                double_fetch: tag & CHECK_DOUBLE_FETCH != 0,
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record tag {:#x}", tag),
+                ))
+            }
+        };
+
+        Ok(Some(Record {
+            time_ns: self.last_ns,
+            thread,
+            event,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+struct Recorder {
+    start: Instant,
+    writer: Writer<BufWriter<File>>,
+}
+
+/// The recorder, if a `trace_file` is configured and could be created
+static RECORDER: OnceCell<Option<Mutex<Recorder>>> = OnceCell::new();
+
+fn recorder() -> Option<&'static Mutex<Recorder>> {
+    RECORDER
+        .get_or_init(|| {
+            let path = config::get().trace_file.as_ref()?;
+            let writer = File::create(path).and_then(|file| Writer::new(BufWriter::new(file)));
+            match writer {
+                Ok(writer) => Some(Mutex::new(Recorder {
+                    start: Instant::now(),
+                    writer,
+                })),
+                Err(e) => {
+                    log::error!("failed to create trace file {}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// This thread's id in the trace
+fn thread_id() -> u32 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    std::thread_local! {
+        static ID: u32 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ID.with(|id| *id)
+}
+
+/// Appends `event` to the trace, if one is being recorded
+pub(crate) fn record(event: Event) {
+    let recorder = match recorder() {
+        Some(recorder) => recorder,
+        None => return,
+    };
+
+    let thread = thread_id();
+    let mut recorder = recorder.lock().unwrap_or_else(PoisonError::into_inner);
+    // taken under the lock, so records are in time order
+    let time_ns = recorder.start.elapsed().as_nanos() as u64;
+    let record = Record {
+        time_ns,
+        thread,
+        event,
+    };
+    if let Err(e) = recorder.writer.write(&record) {
+        log::error!("failed to record trace event: {}", e);
+    }
+}
+
+/// Writes out buffered records
+pub(crate) fn flush() {
+    if let Some(recorder) = recorder() {
+        let mut recorder = recorder.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = recorder.writer.flush() {
+            log::error!("failed to flush trace file: {}", e);
+        }
+    }
+}
+
+extern "C" fn flush_at_exit() {
+    crate::ffi::guard("flush_at_exit", (), flush)
+}
+
+/// Opens the trace file and registers the exit hook flushing it, if a
+/// `trace_file` is configured. Called once, from runtime init.
+pub(crate) fn init() {
+    if recorder().is_some() && unsafe { libc::atexit(flush_at_exit) } != 0 {
+        log::error!("failed to register the trace exit hook");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let records = [
+            Record {
+                time_ns: 5,
+                thread: 0,
+                event: Event::Watch {
+                    addr: 0x7fff_0000_1000,
+                    len: 0x100,
+                    granularity: 1,
+                },
+            },
+            Record {
+                time_ns: 1000,
+                thread: 3,
+                event: Event::Check {
+                    addr: 0x7fff_0000_1010,
+                    len: 4,
+                    pc: Some(0),
+                    is_write: false,
+                    double_fetch: true,
+                },
+            },
+            Record {
+                time_ns: 1000,
+                thread: 0,
+                event: Event::Check {
+                    addr: 0x7fff_0000_1010,
+                    len: 4,
+                    pc: None,
+                    is_write: true,
+                    double_fetch: false,
+                },
+            },
+            Record {
+                time_ns: 1 << 40,
+                thread: 0,
+                event: Event::Unwatch {
+                    addr: 0x7fff_0000_1000,
+                },
+            },
+            Record {
+                time_ns: 1 << 40,
+                thread: 1,
+                event: Event::Shutdown,
+            },
+        ];
+
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let bytes = writer.inner;
+        assert!(bytes.starts_with(b"ADFTRACE\x01"));
+        // a check is a handful of bytes
+        assert!(bytes.len() < 9 + records.len() * 16);
+
+        let read: Vec<Record> = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Reader::new(&b"ADFTRACE\x02"[..]).is_err());
+        assert!(Reader::new(&b"NOTATRACE"[..]).is_err());
+
+        let mut reader = Reader::new(&b"ADFTRACE\x01\x7f\x00\x00"[..]).unwrap();
+        assert!(reader.read().is_err());
+        // truncated mid-record
+        let mut reader = Reader::new(&b"ADFTRACE\x01\x01\x00"[..]).unwrap();
+        assert!(reader.read().is_err());
+    }
+}
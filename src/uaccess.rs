@@ -0,0 +1,415 @@
+//! Drop-in wrappers for the kernel's copy-from-user helpers
+//!
+//! Reading syscall arguments out of user memory is the primary kernel
+//! double-fetch pattern, and instrumenting it by hand at every call site is
+//! tedious. `include/asan_double_fetch_uaccess.h` redirects `copy_from_user`,
+//! `__copy_from_user_inatomic`, `get_user`, `strnlen_user` and
+//! `strncpy_from_user` to the wrappers below, which
+//!
+//! 1. run the access through the double-fetch check, against the fetches the
+//!    current task made in its syscall so far, so a second copy of the same
+//!    bytes within the syscall is reported, and
+//! 2. perform the real copy.
+//!
+//! User addresses only mean something within an address space, and two
+//! threads of a process may well copy the same argument in concurrent
+//! syscalls, so user fetches are kept in a tracker of the current task's own
+//! rather than in the watched regions, which are shared by all tasks.
+//!
+//! User memory is never read or written outside the real copy: a
+//! double fetch is reported, but its bytes are neither dumped nor mutated.
+//!
+//! Strings get extra modelling: the length `strnlen_user` measured is
+//! remembered, and a later copy of the same string that finds a different
+//! length is reported as a stale length, since the buffer sized from the
+//! first scan no longer matches what was copied.
+//!
+//! Kernels that can't be rebuilt with the header can instead load the
+//! kprobes companion module in `examples/kprobes/`, which feeds
+//! `_copy_from_user` through [`__asan_double_fetch_kprobe_fetch`].
+//!
+//! A task's fetches are forgotten again when its syscall exit path calls
+//! [`__asan_double_fetch_syscall_exit`].
+
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, c_long, c_ulong, c_void};
+
+use kernel::bindings;
+use once_cell::sync::OnceCell;
+
+use crate::span::Span;
+use crate::{Address, Lock, ThreadSafeMemoryTracker};
+
+/// User memory touched by in-flight syscalls
+#[derive(Default)]
+struct SyscallState {
+    /// User fetches of each task in a syscall
+    trackers: Vec<(Address, ThreadSafeMemoryTracker)>,
+    /// `(task, string, length including the terminator)` measured by
+    /// `strnlen_user`
+    string_lens: Vec<(Address, Address, usize)>,
+}
+
+static SYSCALL_STATE: OnceCell<Lock<SyscallState>> = OnceCell::new();
+
+pub(crate) fn init() {
+    SYSCALL_STATE
+        .set(Default::default())
+        .unwrap_or_else(|_| panic!("failed to init syscall state"));
+}
+
+/// Forgets all in-flight syscalls' fetches and string lengths
+pub(crate) fn reset() {
+    if let Some(state) = SYSCALL_STATE.get() {
+        let state = core::mem::take(&mut *state.lock());
+        for (_task, _tracker) in state.trackers {
+            #[cfg(feature = "heapless")]
+            crate::TRACKER_POOL.release(_tracker);
+        }
+    }
+}
+
+fn state() -> &'static Lock<SyscallState> {
+    SYSCALL_STATE
+        .get()
+        .expect("syscall state is not initialized")
+}
+
+fn current_task() -> Address {
+    unsafe { bindings::get_current() as Address }
+}
+
+/// The tracker of the current task's user fetches, set up on its first one
+fn task_tracker() -> Option<ThreadSafeMemoryTracker> {
+    let task = current_task();
+    let mut state = state().lock();
+    if let Some((_, tracker)) = state.trackers.iter().find(|(owner, _)| *owner == task) {
+        return Some(ThreadSafeMemoryTracker::clone(tracker));
+    }
+
+    // kernel trackers aren't sized to a region
+    #[cfg(not(feature = "heapless"))]
+    let tracker = crate::new_tracker(&Span::new(0, Address::MAX), 1);
+    #[cfg(feature = "heapless")]
+    let tracker = match crate::TRACKER_POOL.claim() {
+        Some(tracker) => {
+            tracker.lock().clear();
+            tracker
+        }
+        None => {
+            log::warn!("tracker pool empty, not checking user fetches");
+            return None;
+        }
+    };
+    state
+        .trackers
+        .push((task, ThreadSafeMemoryTracker::clone(&tracker)));
+    Some(tracker)
+}
+
+/// Checks `[from, from + n)` as a fetch of the current task's syscall
+fn fetch_user(from: Address, n: usize) {
+    if n == 0 {
+        return;
+    }
+
+    if let Some(tracker) = task_tracker() {
+        crate::check_tracked(
+            &Span::with_len(from, n),
+            tracker,
+            true,
+            from,
+            n,
+            false,
+            None,
+        );
+    }
+}
+
+/// Length of the string at `from`, including the terminator, as measured by
+/// `strnlen_user` earlier in the current syscall
+fn measured_len(from: Address) -> Option<usize> {
+    let task = current_task();
+    state()
+        .lock()
+        .string_lens
+        .iter()
+        .find(|(owner, string, _)| *owner == task && *string == from)
+        .map(|(_, _, len)| *len)
+}
+
+fn check_string_len(from: Address, measured: usize, copied: usize) {
+    if measured != copied {
+        crate::kasan::report("stale-string-length", from, measured, None);
+    }
+}
+
+/// `copy_from_user` that checks the user range as one fetch
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("copy_from_user"))]
+pub unsafe extern "C" fn __asan_double_fetch_copy_from_user(
+    to: *mut c_void,
+    from: *const c_void,
+    n: c_ulong,
+) -> c_ulong {
+    fetch_user(from as Address, n as usize);
+    let ret = bindings::_copy_from_user(to, from, n);
+
+    match measured_len(from as Address) {
+        // a copy sized by `strnlen_user` should end at the string's terminator
+        Some(measured) if ret == 0 && measured == n as usize => {
+            let copied = core::slice::from_raw_parts(to as *const u8, n as usize);
+            let len = copied
+                .iter()
+                .position(|&b| b == 0)
+                .map_or(usize::MAX, |nul| nul + 1);
+            check_string_len(from as Address, measured, len);
+        }
+        _ => (),
+    }
+    ret
+}
+
+/// `__copy_from_user_inatomic` that checks the user range as one fetch
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("copy_from_user_inatomic"))]
+pub unsafe extern "C" fn __asan_double_fetch_copy_from_user_inatomic(
+    to: *mut c_void,
+    from: *const c_void,
+    n: c_ulong,
+) -> c_ulong {
+    fetch_user(from as Address, n as usize);
+    bindings::__copy_from_user_inatomic(to, from, n)
+}
+
+/// `get_user` of a `size`-byte value. Returns 0 on success and `-EFAULT`
+/// otherwise, like the macro it replaces.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("get_user"))]
+pub unsafe extern "C" fn __asan_double_fetch_get_user(
+    dst: *mut c_void,
+    from: *const c_void,
+    size: usize,
+) -> c_int {
+    fetch_user(from as Address, size);
+    if bindings::_copy_from_user(dst, from, size as c_ulong) == 0 {
+        0
+    } else {
+        -(bindings::EFAULT as c_int)
+    }
+}
+
+/// `strnlen_user` that checks the scanned bytes as one fetch and remembers
+/// the measured length for the copy that usually follows
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("strnlen_user"))]
+pub unsafe extern "C" fn __asan_double_fetch_strnlen_user(s: *const c_char, n: c_long) -> c_long {
+    let ret = bindings::strnlen_user(s, n);
+    if ret <= 0 {
+        return ret;
+    }
+
+    let scanned = (ret as usize).min(n as usize);
+    fetch_user(s as Address, scanned);
+
+    let task = current_task();
+    let mut state = state().lock();
+    state
+        .string_lens
+        .retain(|(owner, string, _)| !(*owner == task && *string == s as Address));
+    state.string_lens.push((task, s as Address, ret as usize));
+    ret
+}
+
+/// `strncpy_from_user` that checks the copied bytes as one fetch and flags a
+/// length that differs from an earlier `strnlen_user` of the same string
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("strncpy_from_user"))]
+pub unsafe extern "C" fn __asan_double_fetch_strncpy_from_user(
+    dst: *mut c_char,
+    src: *const c_char,
+    count: c_long,
+) -> c_long {
+    let ret = bindings::strncpy_from_user(dst, src, count);
+    if ret < 0 {
+        return ret;
+    }
+
+    // `ret` excludes the terminator, which was only read if it fit
+    let len = ret as usize;
+    let scanned = if ret < count { len + 1 } else { len };
+    fetch_user(src as Address, scanned);
+
+    // a truncated copy can't tell us anything about the length
+    match measured_len(src as Address) {
+        Some(measured) if measured <= count as usize => {
+            let copied = if ret < count { len + 1 } else { usize::MAX };
+            check_string_len(src as Address, measured, copied);
+        }
+        _ => (),
+    }
+    ret
+}
+
+/// Entry point for probes placed on copy-from-user paths. Called before the
+/// probed copy runs, with the user range it is about to read.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("kprobe_fetch"))]
+pub extern "C" fn __asan_double_fetch_kprobe_fetch(from: Address, n: usize) {
+    fetch_user(from, n);
+}
+
+/// Forgets the user fetches the current task's syscall made. Call this from
+/// the syscall exit path.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("syscall_exit"))]
+pub extern "C" fn __asan_double_fetch_syscall_exit() {
+    let state = match SYSCALL_STATE.get() {
+        Some(state) => state,
+        None => return,
+    };
+    let task = current_task();
+
+    let mut state = state.lock();
+    if let Some(idx) = state.trackers.iter().position(|(owner, _)| *owner == task) {
+        let (_task, _tracker) = state.trackers.swap_remove(idx);
+        #[cfg(feature = "heapless")]
+        crate::TRACKER_POOL.release(_tracker);
+    }
+    state.string_lens.retain(|(owner, _, _)| *owner != task);
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel::sim;
+
+    use super::*;
+
+    fn bugs() -> Vec<(String, Address)> {
+        sim::take_trace()
+            .into_iter()
+            .map(|report| (report.bug, report.addr))
+            .collect()
+    }
+
+    #[test]
+    fn copy_twice_in_a_syscall() {
+        let _serial = sim::serialize();
+        crate::ensure_initialized();
+        crate::kasan::tests::reset();
+
+        let user = Box::leak(Box::new(8u32)) as *mut u32 as Address;
+        let mut len = 0u32;
+        let mut copy = || unsafe {
+            __asan_double_fetch_copy_from_user((&mut len as *mut u32).cast(), user as _, 4)
+        };
+
+        sim::enter_syscall(sim::Syscall::default());
+        assert_eq!(copy(), 0);
+        assert!(bugs().is_empty());
+        assert_eq!(copy(), 0);
+        assert_eq!(bugs(), [("double-fetch".to_owned(), user)]);
+        assert!(sim::take_log().contains(&"<3>In syscall 0".to_owned()));
+
+        // the next syscall starts over
+        __asan_double_fetch_syscall_exit();
+        sim::exit_syscall();
+        assert!(crate::get_memory_tracker(user, 4).is_none());
+        assert_eq!(copy(), 0);
+        assert!(bugs().is_empty());
+        __asan_double_fetch_syscall_exit();
+    }
+
+    #[test]
+    fn tasks_fetch_apart() {
+        let _serial = sim::serialize();
+        crate::ensure_initialized();
+        crate::kasan::tests::reset();
+
+        let user = Box::leak(Box::new(8u32)) as *mut u32 as Address;
+        let copy = || unsafe {
+            let mut len = 0u32;
+            __asan_double_fetch_copy_from_user((&mut len as *mut u32).cast(), user as _, 4)
+        };
+
+        sim::enter_syscall(sim::Syscall::default());
+        assert_eq!(copy(), 0);
+
+        // another task copying from the same address in its own syscall
+        // neither double-fetches nor ends the first one's
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                sim::enter_syscall(sim::Syscall::default());
+                assert_eq!(copy(), 0);
+                assert!(bugs().is_empty());
+                __asan_double_fetch_syscall_exit();
+                sim::exit_syscall();
+            });
+        });
+        assert!(bugs().is_empty());
+
+        assert_eq!(copy(), 0);
+        assert_eq!(bugs(), [("double-fetch".to_owned(), user)]);
+        __asan_double_fetch_syscall_exit();
+        sim::exit_syscall();
+    }
+
+    #[test]
+    fn user_memory_is_left_alone() {
+        let _serial = sim::serialize();
+        crate::ensure_initialized();
+        crate::kasan::tests::reset();
+
+        let backing = Box::leak(Box::new(0x1122_3344u32)) as *mut u32;
+        // faults if the runtime reads or writes it directly
+        let user = sim::user_addr(backing);
+        let copy = || unsafe {
+            let mut value = 0u32;
+            assert_eq!(
+                __asan_double_fetch_copy_from_user((&mut value as *mut u32).cast(), user as _, 4),
+                0
+            );
+            value
+        };
+
+        sim::enter_syscall(sim::Syscall::default());
+        for _ in 0..8 {
+            assert_eq!(copy(), 0x1122_3344);
+        }
+        assert_eq!(bugs().len(), 7);
+        assert_eq!(unsafe { *backing }, 0x1122_3344);
+        __asan_double_fetch_syscall_exit();
+        sim::exit_syscall();
+    }
+
+    #[test]
+    fn faulting_get_user() {
+        crate::ensure_initialized();
+
+        let mut value = 0u64;
+        let ret = unsafe {
+            __asan_double_fetch_get_user((&mut value as *mut u64).cast(), core::ptr::null(), 8)
+        };
+        assert_eq!(ret, -(bindings::EFAULT as c_int));
+        __asan_double_fetch_syscall_exit();
+    }
+
+    #[test]
+    fn string_grew_after_strnlen() {
+        let _serial = sim::serialize();
+        crate::ensure_initialized();
+        crate::kasan::tests::reset();
+
+        let user = Box::leak(Box::new(*b"abc\0\0\0\0\0"));
+        let s = user.as_ptr() as *const c_char;
+        let mut copied = [0 as c_char; 16];
+
+        assert_eq!(unsafe { __asan_double_fetch_strnlen_user(s, 16) }, 4);
+        user[3] = b'd';
+        let ret = unsafe { __asan_double_fetch_strncpy_from_user(copied.as_mut_ptr(), s, 16) };
+        assert_eq!(ret, 4);
+        assert!(bugs().contains(&("stale-string-length".to_owned(), s as Address)));
+
+        __asan_double_fetch_syscall_exit();
+    }
+}
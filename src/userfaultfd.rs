@@ -0,0 +1,528 @@
+//! userfaultfd-based detection backend
+//!
+//! This backend observes fetches without any compiler instrumentation, which
+//! makes it usable against uninstrumented binaries. It works at page
+//! granularity on shmem-backed mappings (SysV shm, memfd, POSIX shm):
+//!
+//! 1. Watched pages are registered for minor faults and their PTEs are zapped
+//!    with `MADV_DONTNEED`. The page cache keeps the contents, so the next
+//!    access raises a minor fault that is delivered to our handler thread.
+//! 2. The first fault on a page in the current window is recorded as a fetch.
+//!    The page is mapped back in with `UFFDIO_CONTINUE`, write-protected, and
+//!    queued to be re-armed.
+//! 3. A write-protect fault means the target itself wrote to the page, so its
+//!    fetch history is forgotten before the write is allowed through.
+//! 4. Any further minor fault on a page already fetched in this window is a
+//!    re-fetch and gets reported as a double fetch, and the page is queued to
+//!    be re-armed again.
+//!
+//! Queued pages are re-armed after every batch of faults, once they've been
+//! mapped for [`REARM_INTERVAL_MS`] so the faulting thread gets to finish
+//! its access first.
+//!
+//! The window is reset with [`__asan_uffd_reset_window`].
+
+use std::collections::BTreeMap;
+use std::io;
+use std::os::raw::c_int;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+
+use crate::memory_tracking::MemoryTracker;
+use crate::span::Span;
+use crate::Address;
+
+const UFFD_API: u64 = 0xaa;
+
+const UFFDIO_API: libc::c_ulong = 0xc018_aa3f;
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020_aa00;
+const UFFDIO_UNREGISTER: libc::c_ulong = 0x8010_aa01;
+const UFFDIO_WRITEPROTECT: libc::c_ulong = 0xc018_aa06;
+const UFFDIO_CONTINUE: libc::c_ulong = 0xc020_aa07;
+
+const UFFD_FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+const UFFD_FEATURE_MINOR_SHMEM: u64 = 1 << 10;
+
+const UFFDIO_REGISTER_MODE_WP: u64 = 1 << 1;
+const UFFDIO_REGISTER_MODE_MINOR: u64 = 1 << 2;
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
+
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_PAGEFAULT_FLAG_WRITE: u64 = 1 << 0;
+const UFFD_PAGEFAULT_FLAG_WP: u64 = 1 << 1;
+
+/// How long a page stays mapped after a fault before it's re-armed, and how
+/// long the handler thread waits for faults before re-arming pages
+const REARM_INTERVAL_MS: c_int = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioWriteprotect {
+    range: UffdioRange,
+    mode: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioContinue {
+    range: UffdioRange,
+    mode: u64,
+    mapped: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    _pad: u32,
+}
+
+/// What a fault on a watched page means for double-fetch detection
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum FaultKind {
+    /// First fetch of this page in the current window
+    FirstFetch,
+    /// The page was already fetched in this window
+    Refetch,
+    /// The target wrote to the page
+    Write,
+}
+
+/// Page-granular bookkeeping of faults within the current window
+#[derive(Debug, Default)]
+pub struct FaultLog {
+    page_size: usize,
+    fetched: MemoryTracker,
+    /// Pages to re-arm, and when they were queued
+    pending_rearm: BTreeMap<Address, Instant>,
+}
+
+impl FaultLog {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            ..Default::default()
+        }
+    }
+
+    /// Records a fault at `addr` and classifies it
+    pub fn record(&mut self, addr: Address, is_write: bool) -> FaultKind {
+        let page = addr & !(self.page_size - 1);
+
+        if is_write {
+            crate::log_tracker_error(self.fetched.remove_access(page, self.page_size));
+            return FaultKind::Write;
+        }
+
+        let kind = if self.fetched.check(page, self.page_size).is_err() {
+            FaultKind::Refetch
+        } else {
+            crate::log_tracker_error(self.fetched.track_access(page, self.page_size));
+            FaultKind::FirstFetch
+        };
+        self.pending_rearm.insert(page, Instant::now());
+        kind
+    }
+
+    /// Forgets all fetches, starting a new window
+    pub fn reset(&mut self) {
+        self.fetched.clear();
+        self.pending_rearm.clear();
+    }
+
+    /// Takes the pages queued at least [`REARM_INTERVAL_MS`] before `now`
+    fn take_due(&mut self, now: Instant) -> Vec<Address> {
+        let interval = Duration::from_millis(REARM_INTERVAL_MS as u64);
+        let mut due = Vec::new();
+        self.pending_rearm.retain(|page, queued| {
+            let ready = now.saturating_duration_since(*queued) >= interval;
+            if ready {
+                due.push(*page);
+            }
+            !ready
+        });
+        due
+    }
+}
+
+struct Uffd {
+    fd: c_int,
+    page_size: usize,
+    regions: Mutex<Vec<Span>>,
+    log: Mutex<FaultLog>,
+}
+
+static UFFD: OnceCell<Uffd> = OnceCell::new();
+
+fn ioctl<T>(fd: c_int, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, request, arg as *mut T) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn range(addr: Address, len: usize) -> UffdioRange {
+    UffdioRange {
+        start: addr as u64,
+        len: len as u64,
+    }
+}
+
+impl Uffd {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) }
+            as c_int;
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: UFFD_FEATURE_PAGEFAULT_FLAG_WP | UFFD_FEATURE_MINOR_SHMEM,
+            ..Default::default()
+        };
+        if let Err(e) = ioctl(fd, UFFDIO_API, &mut api) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        Ok(Self {
+            fd,
+            page_size,
+            regions: Default::default(),
+            log: Mutex::new(FaultLog::new(page_size)),
+        })
+    }
+
+    fn page_span(&self, addr: Address, len: usize) -> Span {
+        let start = addr & !(self.page_size - 1);
+        let end =
+            addr.saturating_add(len).saturating_add(self.page_size - 1) & !(self.page_size - 1);
+        Span::new(start, end)
+    }
+
+    fn zap(&self, addr: Address, len: usize) -> io::Result<()> {
+        if unsafe { libc::madvise(addr as *mut libc::c_void, len, libc::MADV_DONTNEED) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_protect(&self, addr: Address, len: usize, protect: bool) -> io::Result<()> {
+        let mut wp = UffdioWriteprotect {
+            range: range(addr, len),
+            mode: if protect {
+                UFFDIO_WRITEPROTECT_MODE_WP
+            } else {
+                0
+            },
+        };
+        ioctl(self.fd, UFFDIO_WRITEPROTECT, &mut wp)
+    }
+
+    fn resume(&self, page: Address) -> io::Result<()> {
+        let mut cont = UffdioContinue {
+            range: range(page, self.page_size),
+            ..Default::default()
+        };
+        match ioctl(self.fd, UFFDIO_CONTINUE, &mut cont) {
+            // another thread faulting on the same page already resolved it
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            res => res,
+        }
+    }
+
+    fn watch(&self, addr: Address, len: usize) -> io::Result<()> {
+        let span = self.page_span(addr, len);
+
+        let mut reg = UffdioRegister {
+            range: range(span.start(), span.len()),
+            mode: UFFDIO_REGISTER_MODE_MINOR | UFFDIO_REGISTER_MODE_WP,
+            ..Default::default()
+        };
+        ioctl(self.fd, UFFDIO_REGISTER, &mut reg)?;
+        self.zap(span.start(), span.len())?;
+
+        self.regions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(span);
+        Ok(())
+    }
+
+    fn unwatch(&self, addr: Address, len: usize) -> io::Result<()> {
+        let span = self.page_span(addr, len);
+
+        let mut unreg = range(span.start(), span.len());
+        ioctl(self.fd, UFFDIO_UNREGISTER, &mut unreg)?;
+
+        self.regions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|region| *region != span);
+        crate::log_tracker_error(
+            self.log
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .fetched
+                .remove_access(span.start(), span.len()),
+        );
+        Ok(())
+    }
+
+    fn handle(&self, msg: &UffdMsg) {
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            return;
+        }
+
+        let addr = msg.address as Address;
+        let page = addr & !(self.page_size - 1);
+        let is_write = msg.flags & UFFD_PAGEFAULT_FLAG_WRITE != 0;
+
+        let kind = self
+            .log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record(addr, is_write);
+
+        if kind == FaultKind::Refetch {
+            log::warn!(
+                "double-fetch detected! (userfaultfd) page {:#X} re-fetched by tid {}",
+                page,
+                msg.ptid
+            );
+        }
+
+        let res = if msg.flags & UFFD_PAGEFAULT_FLAG_WP != 0 {
+            self.write_protect(page, self.page_size, false)
+        } else {
+            self.resume(page).and_then(|_| {
+                if is_write {
+                    Ok(())
+                } else {
+                    self.write_protect(page, self.page_size, true)
+                }
+            })
+        };
+
+        if let Err(e) = res {
+            log::error!("failed to resolve fault at {:#X}: {}", addr, e);
+        }
+    }
+
+    fn rearm_pending(&self) {
+        let due = self
+            .log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take_due(Instant::now());
+        for page in due {
+            if let Err(e) = self.zap(page, self.page_size) {
+                log::error!("failed to re-arm page {:#X}: {}", page, e);
+            }
+        }
+    }
+
+    fn run(&self) {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        loop {
+            let ready = unsafe { libc::poll(&mut pollfd, 1, REARM_INTERVAL_MS) };
+            if ready > 0 {
+                // the fd is non-blocking, so this handles every queued fault
+                loop {
+                    let mut msg = UffdMsg::default();
+                    let read = unsafe {
+                        libc::read(
+                            self.fd,
+                            &mut msg as *mut UffdMsg as *mut libc::c_void,
+                            std::mem::size_of::<UffdMsg>(),
+                        )
+                    };
+                    if read != std::mem::size_of::<UffdMsg>() as isize {
+                        break;
+                    }
+                    self.handle(&msg);
+                }
+            }
+            // a steady stream of faults mustn't keep pages from being re-armed
+            self.rearm_pending();
+        }
+    }
+}
+
+fn status(res: io::Result<()>, what: &str) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            log::error!("userfaultfd {} failed: {}", what, e);
+            -1
+        }
+    }
+}
+
+/// Opens the userfaultfd and starts the fault handler thread.
+///
+/// Returns 0 on success and -1 if userfaultfd is unavailable (e.g. the
+/// `vm.unprivileged_userfaultfd` sysctl forbids it).
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("uffd_init"))]
+pub extern "C" fn __asan_uffd_init() -> c_int {
+    crate::ffi::guard("__asan_uffd_init", -1, || {
+        if UFFD.get().is_some() {
+            return 0;
+        }
+
+        let uffd = match Uffd::open() {
+            Ok(uffd) => uffd,
+            Err(e) => return status(Err(e), "init"),
+        };
+
+        if UFFD.set(uffd).is_err() {
+            // lost an init race, the winner owns the handler thread
+            return 0;
+        }
+
+        thread::Builder::new()
+            .name("asan-df-uffd".into())
+            .spawn(|| UFFD.get().unwrap().run())
+            .map(|_| 0)
+            .unwrap_or_else(|e| status(Err(e), "handler spawn"))
+    })
+}
+
+/// Watches a shmem-backed region through userfaultfd
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("uffd_watch_region"))]
+pub extern "C" fn __asan_uffd_watch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_uffd_watch_region", -1, || match UFFD.get() {
+        Some(uffd) => status(uffd.watch(addr, len), "watch"),
+        None => -1,
+    })
+}
+
+/// Stops watching a region previously passed to [`__asan_uffd_watch_region`]
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("uffd_unwatch_region"))]
+pub extern "C" fn __asan_uffd_unwatch_region(addr: Address, len: usize) -> c_int {
+    crate::ffi::guard("__asan_uffd_unwatch_region", -1, || match UFFD.get() {
+        Some(uffd) => status(uffd.unwatch(addr, len), "unwatch"),
+        None => -1,
+    })
+}
+
+/// Forgets all page fetches and re-arms every watched page, starting a new
+/// detection window
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("uffd_reset_window"))]
+pub extern "C" fn __asan_uffd_reset_window() -> c_int {
+    crate::ffi::guard("__asan_uffd_reset_window", -1, || {
+        let uffd = match UFFD.get() {
+            Some(uffd) => uffd,
+            None => return -1,
+        };
+
+        uffd.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reset();
+
+        let regions = uffd
+            .regions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let res = regions.iter().try_for_each(|span| {
+            uffd.write_protect(span.start(), span.len(), false)?;
+            uffd.zap(span.start(), span.len())
+        });
+
+        status(res, "window reset")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refetch_in_window() {
+        let mut log = FaultLog::new(0x1000);
+
+        assert_eq!(log.record(0x4010, false), FaultKind::FirstFetch);
+        assert_eq!(log.record(0x4ff0, false), FaultKind::Refetch);
+        assert_eq!(log.record(0x5000, false), FaultKind::FirstFetch);
+    }
+
+    #[test]
+    fn write_forgets_page() {
+        let mut log = FaultLog::new(0x1000);
+
+        log.record(0x4010, false);
+        assert_eq!(log.record(0x4020, true), FaultKind::Write);
+        assert_eq!(log.record(0x4010, false), FaultKind::FirstFetch);
+    }
+
+    #[test]
+    fn refetched_pages_are_rearmed() {
+        let mut log = FaultLog::new(0x1000);
+        let later = || Instant::now() + Duration::from_millis(REARM_INTERVAL_MS as u64);
+
+        let queued = Instant::now();
+        log.record(0x4010, false);
+        assert!(log.take_due(queued).is_empty());
+        assert_eq!(log.take_due(later()), vec![0x4000]);
+
+        assert_eq!(log.record(0x4020, false), FaultKind::Refetch);
+        assert_eq!(log.take_due(later()), vec![0x4000]);
+    }
+
+    #[test]
+    fn reset_starts_new_window() {
+        let mut log = FaultLog::new(0x1000);
+
+        log.record(0x4010, false);
+        log.reset();
+        assert_eq!(log.record(0x4010, false), FaultKind::FirstFetch);
+    }
+}
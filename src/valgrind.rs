@@ -0,0 +1,112 @@
+//! Valgrind client-request interop
+//!
+//! `include/asan_double_fetch_valgrind.h` defines `DF_*` client requests in
+//! the Valgrind tool-request space. Built against the real `valgrind.h` they
+//! are harmless no-ops under Valgrind and natively; with
+//! `ASAN_DOUBLE_FETCH_CLIENT_REQUESTS` defined, the header instead routes
+//! every `VALGRIND_DO_CLIENT_REQUEST` to [`__asan_valgrind_client_request`],
+//! so targets already annotated for Valgrind tools can opt into this runtime
+//! by swapping a header.
+
+use crate::Address;
+
+/// `VG_USERREQ_TOOL_BASE` from `valgrind.h`
+pub const fn tool_base(a: u8, b: u8) -> usize {
+    ((a as usize) << 24) | ((b as usize) << 16)
+}
+
+pub const DF_USERREQ_BASE: usize = tool_base(b'D', b'F');
+pub const DF_USERREQ_WATCH: usize = DF_USERREQ_BASE;
+pub const DF_USERREQ_UNWATCH: usize = DF_USERREQ_BASE + 1;
+pub const DF_USERREQ_CHECK: usize = DF_USERREQ_BASE + 2;
+
+/// Handles a client request, returning `default` for anything that isn't
+/// ours, exactly like running natively without Valgrind. Watching and
+/// unwatching return 0, or `usize::MAX` (-1) on failure; checks return
+/// whether the access was to a watched region.
+#[no_mangle]
+#[cfg_attr(feature = "prefixed_symbols", export_name = symbol!("valgrind_client_request"))]
+pub extern "C" fn __asan_valgrind_client_request(
+    default: usize,
+    request: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    _arg4: usize,
+    _arg5: usize,
+) -> usize {
+    crate::ffi::guard(
+        "__asan_valgrind_client_request",
+        default,
+        || match request {
+            DF_USERREQ_WATCH => {
+                crate::ensure_initialized();
+                crate::__asan_watch_shared_memory_region(arg1 as Address, arg2) as usize
+            }
+            DF_USERREQ_UNWATCH => {
+                crate::ensure_initialized();
+                crate::__asan_unwatch_shared_memory_region(arg1 as Address) as usize
+            }
+            DF_USERREQ_CHECK => {
+                crate::ensure_initialized();
+                let watched = crate::in_watched_region(arg1 as Address, arg2);
+                crate::__asan_double_fetch_check(arg1 as Address, arg2, arg3 != 0);
+                watched as usize
+            }
+            _ => default,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_match_header() {
+        // VG_USERREQ_TOOL_BASE('D','F')
+        assert_eq!(DF_USERREQ_BASE, 0x4446_0000);
+    }
+
+    #[test]
+    fn watch_and_unwatch() {
+        let buf = [0u8; 64];
+        let addr = buf.as_ptr() as usize;
+
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_WATCH, addr, buf.len(), 0, 0, 0),
+            0
+        );
+        assert!(crate::get_memory_tracker(addr + 8, 4).is_some());
+
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_CHECK, addr + 8, 4, 0, 0, 0),
+            1
+        );
+
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_UNWATCH, addr, 0, 0, 0, 0),
+            0
+        );
+        assert!(crate::get_memory_tracker(addr + 8, 4).is_none());
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_CHECK, addr + 8, 4, 0, 0, 0),
+            0
+        );
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_UNWATCH, addr, 0, 0, 0, 0),
+            usize::MAX
+        );
+        // a zero-length region is rejected
+        assert_eq!(
+            __asan_valgrind_client_request(0, DF_USERREQ_WATCH, addr, 0, 0, 0, 0),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn foreign_requests_return_default() {
+        // VG_USERREQ__RUNNING_ON_VALGRIND
+        assert_eq!(__asan_valgrind_client_request(7, 0x1001, 0, 0, 0, 0, 0), 7);
+    }
+}
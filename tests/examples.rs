@@ -0,0 +1,81 @@
+//! Runs the vulnerable example programs and checks what the runtime made of
+//! them: the double fetch detected, the re-fetched bytes mutated or left
+//! alone, and the detection reported
+//!
+//! Each example runs in a process of its own, as the runtime is global.
+//! `cargo test` builds them; run `cargo build --examples` first when only
+//! running this test.
+
+use std::process::Command;
+
+/// Output of the example `name` run with `options`
+fn run(name: &str, options: &str) -> String {
+    // test binaries live in `deps`, next to `examples`
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    path.pop();
+    path.push("examples");
+    path.push(format!("{}{}", name, std::env::consts::EXE_SUFFIX));
+    assert!(
+        path.exists(),
+        "{} isn't built, run `cargo build --examples`",
+        path.display()
+    );
+
+    let output = Command::new(&path)
+        .env("ASAN_DOUBLE_FETCH_OPTIONS", options)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{} failed:\n{}", name, stdout);
+    stdout
+}
+
+#[test]
+#[cfg(unix)]
+fn sysv_shm() {
+    let output = run("sysv_shm", "mutation=canary");
+    assert!(output.contains("double-fetch detected!"), "{}", output);
+    assert!(output.contains("shm id"), "{}", output);
+    assert!(
+        output.contains("length changed to 0xcadf after validation"),
+        "{}",
+        output
+    );
+    assert!(output.contains("detections: 1, mutations: 1"), "{}", output);
+}
+
+#[test]
+#[cfg(unix)]
+fn report_only() {
+    let output = run("sysv_shm", "report_only=1");
+    assert!(output.contains("double-fetch detected!"), "{}", output);
+    assert!(output.contains("handled a 16-byte payload"), "{}", output);
+    assert!(output.contains("detections: 1, mutations: 0"), "{}", output);
+}
+
+#[test]
+#[cfg(unix)]
+fn posix_shm() {
+    let output = run("posix_shm", "mutation=canary");
+    assert!(output.contains("double-fetch detected!"), "{}", output);
+    assert!(
+        output.contains("index changed to 0xcadf after the bounds check"),
+        "{}",
+        output
+    );
+    assert!(output.contains("detections: 1, mutations: 1"), "{}", output);
+}
+
+#[test]
+fn threaded() {
+    let output = run("threaded", "mutation=canary");
+    assert_eq!(
+        output.matches("double-fetch detected!").count(),
+        4,
+        "{}",
+        output
+    );
+    assert_eq!(output.matches("copy overflows").count(), 4, "{}", output);
+    assert!(output.contains("detections: 4, mutations: 4"), "{}", output);
+}